@@ -0,0 +1,16 @@
+-- Ingest sessions, one per upload, so a failed upload only removes its own rows
+CREATE TABLE IF NOT EXISTS ingest_session (
+  id SERIAL PRIMARY KEY,
+  source VARCHAR NOT NULL, -- file path, or 'create' for uploads sent in the request body
+  status VARCHAR(20) NOT NULL DEFAULT 'running', -- running, completed, failed
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  completed_at TIMESTAMPTZ
+);
+
+ALTER TABLE mbp ADD COLUMN ingest_id INTEGER;
+ALTER TABLE mbp ADD CONSTRAINT fk_ingest_session_mbp
+  FOREIGN KEY(ingest_id)
+    REFERENCES ingest_session(id)
+    ON DELETE SET NULL;
+
+CREATE INDEX idx_mbp_ingest_id ON mbp (ingest_id);
@@ -0,0 +1,22 @@
+-- Background bulk uploads, tracked independently of the HTTP request that submitted them
+CREATE TABLE IF NOT EXISTS ingest_job (
+  id SERIAL PRIMARY KEY,
+  ingest_id INTEGER, -- set once the loader has opened its ingest session
+  file_path VARCHAR NOT NULL,
+  mode VARCHAR(20) NOT NULL,
+  status VARCHAR(20) NOT NULL DEFAULT 'queued', -- queued, running, completed, failed, cancelled
+  cancel_requested BOOL NOT NULL DEFAULT FALSE,
+  records_decoded BIGINT NOT NULL DEFAULT 0,
+  records_committed BIGINT NOT NULL DEFAULT 0,
+  records_skipped BIGINT NOT NULL DEFAULT 0,
+  current_batch INTEGER NOT NULL DEFAULT 0,
+  errors TEXT[] NOT NULL DEFAULT '{}',
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  CONSTRAINT fk_ingest_session_job
+    FOREIGN KEY(ingest_id)
+      REFERENCES ingest_session(id)
+      ON DELETE SET NULL
+);
+
+CREATE INDEX idx_ingest_job_status ON ingest_job (status);
@@ -0,0 +1,6 @@
+-- Checkpoint written in the same transaction as every committed batch, so a failed
+-- resumable upload can continue from the last committed record instead of starting over
+ALTER TABLE ingest_session ADD COLUMN mode VARCHAR(20) NOT NULL DEFAULT 'strict';
+ALTER TABLE ingest_session ADD COLUMN resumable BOOL NOT NULL DEFAULT FALSE;
+ALTER TABLE ingest_session ADD COLUMN checkpoint_record BIGINT NOT NULL DEFAULT 0; -- records consumed from the source
+ALTER TABLE ingest_session ADD COLUMN checkpoint_batch INTEGER NOT NULL DEFAULT 0; -- last committed batch
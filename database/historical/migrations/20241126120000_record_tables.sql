@@ -0,0 +1,82 @@
+-- Records uploaded as trades, bars or quotes, served directly instead of derived from mbp
+CREATE TABLE IF NOT EXISTS trade (
+  id SERIAL PRIMARY KEY,
+  instrument_id INTEGER NOT NULL,
+  ts_event BIGINT NOT NULL,
+  price BIGINT NOT NULL,
+  size INTEGER NOT NULL,
+  action INTEGER NOT NULL,
+  side INTEGER NOT NULL,
+  flags INTEGER NOT NULL,
+  ts_recv BIGINT NOT NULL,
+  ts_in_delta INTEGER NOT NULL,
+  sequence INTEGER NOT NULL,
+  ingest_id INTEGER,
+  CONSTRAINT fk_instrument_trade
+    FOREIGN KEY(instrument_id)
+      REFERENCES instrument(id)
+      ON DELETE CASCADE,
+  CONSTRAINT fk_ingest_session_trade
+    FOREIGN KEY(ingest_id)
+      REFERENCES ingest_session(id)
+      ON DELETE SET NULL,
+  CONSTRAINT unique_trade UNIQUE (instrument_id, ts_event, price, size, flags, sequence, ts_recv, action, side)
+);
+
+CREATE TABLE IF NOT EXISTS ohlcv (
+  id SERIAL PRIMARY KEY,
+  instrument_id INTEGER NOT NULL,
+  ts_event BIGINT NOT NULL, -- start of the bar
+  interval_ns BIGINT NOT NULL, -- bar length in nanoseconds
+  open BIGINT NOT NULL,
+  high BIGINT NOT NULL,
+  low BIGINT NOT NULL,
+  close BIGINT NOT NULL,
+  volume BIGINT NOT NULL,
+  ingest_id INTEGER,
+  CONSTRAINT fk_instrument_ohlcv
+    FOREIGN KEY(instrument_id)
+      REFERENCES instrument(id)
+      ON DELETE CASCADE,
+  CONSTRAINT fk_ingest_session_ohlcv
+    FOREIGN KEY(ingest_id)
+      REFERENCES ingest_session(id)
+      ON DELETE SET NULL,
+  CONSTRAINT unique_ohlcv UNIQUE (instrument_id, interval_ns, ts_event)
+);
+
+CREATE TABLE IF NOT EXISTS bbo (
+  id SERIAL PRIMARY KEY,
+  instrument_id INTEGER NOT NULL,
+  ts_event BIGINT NOT NULL,
+  ts_recv BIGINT NOT NULL, -- end of the interval
+  interval_ns BIGINT NOT NULL, -- sampling interval in nanoseconds
+  price BIGINT NOT NULL, -- last trade
+  size INTEGER NOT NULL,
+  side INTEGER NOT NULL,
+  flags INTEGER NOT NULL,
+  sequence INTEGER NOT NULL,
+  bid_px BIGINT NOT NULL,
+  bid_sz INTEGER NOT NULL,
+  bid_ct INTEGER NOT NULL,
+  ask_px BIGINT NOT NULL,
+  ask_sz INTEGER NOT NULL,
+  ask_ct INTEGER NOT NULL,
+  ingest_id INTEGER,
+  CONSTRAINT fk_instrument_bbo
+    FOREIGN KEY(instrument_id)
+      REFERENCES instrument(id)
+      ON DELETE CASCADE,
+  CONSTRAINT fk_ingest_session_bbo
+    FOREIGN KEY(ingest_id)
+      REFERENCES ingest_session(id)
+      ON DELETE SET NULL,
+  CONSTRAINT unique_bbo UNIQUE (instrument_id, interval_ns, ts_recv)
+);
+
+CREATE INDEX idx_trade_instrument_ts_recv ON trade (instrument_id, ts_recv);
+CREATE INDEX idx_ohlcv_instrument_interval_ts_event ON ohlcv (instrument_id, interval_ns, ts_event);
+CREATE INDEX idx_bbo_instrument_interval_ts_recv ON bbo (instrument_id, interval_ns, ts_recv);
+CREATE INDEX idx_trade_ingest_id ON trade (ingest_id);
+CREATE INDEX idx_ohlcv_ingest_id ON ohlcv (ingest_id);
+CREATE INDEX idx_bbo_ingest_id ON bbo (ingest_id);
@@ -0,0 +1,27 @@
+-- Market-by-order events, replayed into order books on retrieval
+CREATE TABLE IF NOT EXISTS mbo (
+  id SERIAL PRIMARY KEY,
+  instrument_id INTEGER NOT NULL,
+  ts_event BIGINT NOT NULL,
+  ts_recv BIGINT NOT NULL,
+  order_id BIGINT NOT NULL,
+  action INTEGER NOT NULL, -- add, cancel, modify, fill, trade or clear
+  side INTEGER NOT NULL,
+  price BIGINT NOT NULL,
+  size INTEGER NOT NULL,
+  flags INTEGER NOT NULL,
+  sequence INTEGER NOT NULL,
+  ingest_id INTEGER,
+  CONSTRAINT fk_instrument_mbo
+    FOREIGN KEY(instrument_id)
+      REFERENCES instrument(id)
+      ON DELETE CASCADE,
+  CONSTRAINT fk_ingest_session_mbo
+    FOREIGN KEY(ingest_id)
+      REFERENCES ingest_session(id)
+      ON DELETE SET NULL,
+  CONSTRAINT unique_mbo UNIQUE (instrument_id, ts_event, order_id, sequence, action)
+);
+
+CREATE INDEX idx_mbo_instrument_ts_recv ON mbo (instrument_id, ts_recv);
+CREATE INDEX idx_mbo_ingest_id ON mbo (ingest_id);
@@ -0,0 +1,26 @@
+-- Files of a job submitted as a directory, glob or manifest, loaded in position order
+CREATE TABLE IF NOT EXISTS ingest_job_file (
+  id SERIAL PRIMARY KEY,
+  job_id INTEGER NOT NULL,
+  position INTEGER NOT NULL,
+  file_path VARCHAR NOT NULL,
+  status VARCHAR(20) NOT NULL DEFAULT 'pending', -- pending, running, loaded, skipped, failed
+  expected_records BIGINT,
+  expected_checksum VARCHAR(64),
+  checksum VARCHAR(64), -- sha256 of the file as stored
+  ingest_id INTEGER,
+  records_loaded BIGINT NOT NULL DEFAULT 0,
+  error TEXT,
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  CONSTRAINT fk_ingest_job_file
+    FOREIGN KEY(job_id)
+      REFERENCES ingest_job(id)
+      ON DELETE CASCADE,
+  CONSTRAINT fk_ingest_session_job_file
+    FOREIGN KEY(ingest_id)
+      REFERENCES ingest_session(id)
+      ON DELETE SET NULL,
+  CONSTRAINT unique_job_file_position UNIQUE (job_id, position)
+);
+
+CREATE INDEX idx_ingest_job_file_checksum ON ingest_job_file (checksum, status);
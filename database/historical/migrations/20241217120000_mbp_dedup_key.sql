@@ -0,0 +1,28 @@
+-- Compact mbp identity: a 64-bit key over the record and its levels replaces the hex order book
+-- hash and the 11-column unique constraint
+ALTER TABLE mbp ADD COLUMN dedup_key BIGINT;
+
+-- Same bytes as compute_dedup_key, every field encoded as its column type in big-endian order
+UPDATE mbp m
+SET dedup_key = ('x' || encode(substring(sha256(
+    int4send(m.instrument_id) || int8send(m.ts_event) || int8send(m.price) || int4send(m.size)
+    || int4send(m.action) || int4send(m.side) || int4send(m.flags) || int8send(m.ts_recv)
+    || int4send(m.sequence) || int4send(m.discriminator)
+    || COALESCE((
+      SELECT string_agg(
+        int8send(b.bid_px) || int8send(b.ask_px) || int4send(b.bid_sz) || int4send(b.ask_sz)
+        || int4send(b.bid_ct) || int4send(b.ask_ct),
+        ''::bytea ORDER BY b.depth
+      )
+      FROM bid_ask b
+      WHERE b.mbp_id = m.id
+    ), ''::bytea)
+  ) FROM 1 FOR 8), 'hex'))::bit(64)::bigint;
+
+ALTER TABLE mbp ALTER COLUMN dedup_key SET NOT NULL;
+ALTER TABLE mbp DROP CONSTRAINT unique_instrument_ts_sequence_event;
+ALTER TABLE mbp DROP COLUMN order_book_hash;
+ALTER TABLE mbp ADD CONSTRAINT unique_mbp_dedup_key UNIQUE (instrument_id, ts_event, dedup_key);
+
+-- The unique index leads with (instrument_id, ts_event) and serves the same range scans
+DROP INDEX IF EXISTS idx_mbp_instrument_ts_event;
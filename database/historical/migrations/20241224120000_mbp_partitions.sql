@@ -0,0 +1,112 @@
+-- Range partitioning of mbp and bid_ask on ts_recv. Each partition is a pair of tables,
+-- mbp_<name> and bid_ask_<name>, over the same ts_recv range. The service creates new ones
+-- as data arrives; existing rows are moved into monthly partitions.
+CREATE TABLE IF NOT EXISTS mbp_partition (
+  name VARCHAR(20) PRIMARY KEY, -- e.g. p20240101, suffix of the partition tables
+  range_start BIGINT NOT NULL, -- ts_recv, inclusive
+  range_end BIGINT NOT NULL, -- ts_recv, exclusive
+  attached BOOL NOT NULL DEFAULT TRUE,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  CONSTRAINT valid_mbp_partition_range CHECK (range_start < range_end)
+);
+
+-- Free the names of the existing tables, their constraints and indexes
+ALTER TABLE bid_ask DROP CONSTRAINT fk_mbp_bid_ask;
+ALTER TABLE mbp RENAME TO mbp_legacy;
+ALTER TABLE bid_ask RENAME TO bid_ask_legacy;
+ALTER TABLE mbp_legacy DROP CONSTRAINT mbp_pkey;
+ALTER TABLE mbp_legacy DROP CONSTRAINT unique_mbp_dedup_key;
+ALTER TABLE bid_ask_legacy DROP CONSTRAINT bid_ask_pkey;
+DROP INDEX idx_mbp_ingest_id;
+DROP INDEX idx_bid_ask_mbp_id_depth;
+
+-- Ids are kept, the sequences move to the new tables and widen to BIGINT
+ALTER SEQUENCE mbp_id_seq AS BIGINT;
+ALTER SEQUENCE bid_ask_id_seq AS BIGINT;
+
+CREATE TABLE mbp (
+  id BIGINT NOT NULL DEFAULT nextval('mbp_id_seq'),
+  instrument_id INTEGER NOT NULL,
+  ts_event BIGINT NOT NULL,
+  price BIGINT NOT NULL,
+  size INTEGER NOT NULL,
+  action INTEGER NOT NULL,
+  side INTEGER NOT NULL,
+  flags INTEGER NOT NULL,
+  ts_recv BIGINT NOT NULL, -- partition key
+  ts_in_delta INTEGER NOT NULL,
+  sequence INTEGER NOT NULL,
+  discriminator INTEGER NOT NULL,
+  ingest_id INTEGER,
+  dedup_key BIGINT NOT NULL,
+  CONSTRAINT mbp_pkey PRIMARY KEY (id, ts_recv),
+  CONSTRAINT fk_instrument_mbp
+    FOREIGN KEY(instrument_id)
+      REFERENCES instrument(id)
+      ON DELETE CASCADE,
+  CONSTRAINT fk_ingest_session_mbp
+    FOREIGN KEY(ingest_id)
+      REFERENCES ingest_session(id)
+      ON DELETE SET NULL,
+  -- dedup_key already covers ts_recv, which every unique constraint has to include
+  CONSTRAINT unique_mbp_dedup_key UNIQUE (instrument_id, ts_event, dedup_key, ts_recv)
+) PARTITION BY RANGE (ts_recv);
+
+CREATE TABLE bid_ask (
+  id BIGINT NOT NULL DEFAULT nextval('bid_ask_id_seq'),
+  mbp_id BIGINT NOT NULL,
+  ts_recv BIGINT NOT NULL, -- ts_recv of the mbp row, partition key
+  depth INTEGER NOT NULL,
+  bid_px BIGINT NOT NULL,
+  bid_sz INTEGER NOT NULL,
+  bid_ct INTEGER NOT NULL,
+  ask_px BIGINT NOT NULL,
+  ask_sz INTEGER NOT NULL,
+  ask_ct INTEGER NOT NULL,
+  CONSTRAINT bid_ask_pkey PRIMARY KEY (id, ts_recv),
+  CONSTRAINT fk_mbp_bid_ask
+    FOREIGN KEY(mbp_id, ts_recv)
+      REFERENCES mbp(id, ts_recv)
+      ON DELETE CASCADE
+) PARTITION BY RANGE (ts_recv);
+
+CREATE INDEX idx_mbp_ingest_id ON mbp (ingest_id);
+CREATE INDEX idx_mbp_instrument_ts_recv ON mbp (instrument_id, ts_recv);
+CREATE INDEX idx_bid_ask_mbp_id_depth ON bid_ask (mbp_id, depth);
+
+ALTER SEQUENCE mbp_id_seq OWNED BY mbp.id;
+ALTER SEQUENCE bid_ask_id_seq OWNED BY bid_ask.id;
+
+-- Monthly partitions for the existing rows
+DO $$
+DECLARE
+  month TIMESTAMP;
+  suffix TEXT;
+  range_start BIGINT;
+  range_end BIGINT;
+BEGIN
+  FOR month IN
+    SELECT DISTINCT date_trunc('month', to_timestamp(ts_recv / 1000000000) AT TIME ZONE 'UTC')
+    FROM mbp_legacy
+  LOOP
+    suffix := 'p' || to_char(month, 'YYYYMMDD');
+    range_start := EXTRACT(EPOCH FROM month)::BIGINT * 1000000000;
+    range_end := EXTRACT(EPOCH FROM month + INTERVAL '1 month')::BIGINT * 1000000000;
+
+    EXECUTE format('CREATE TABLE mbp_%s PARTITION OF mbp FOR VALUES FROM (%s) TO (%s)', suffix, range_start, range_end);
+    EXECUTE format('CREATE TABLE bid_ask_%s PARTITION OF bid_ask FOR VALUES FROM (%s) TO (%s)', suffix, range_start, range_end);
+    INSERT INTO mbp_partition (name, range_start, range_end) VALUES (suffix, range_start, range_end);
+  END LOOP;
+END $$;
+
+INSERT INTO mbp (id, instrument_id, ts_event, price, size, action, side, flags, ts_recv, ts_in_delta, sequence, discriminator, ingest_id, dedup_key)
+SELECT id, instrument_id, ts_event, price, size, action, side, flags, ts_recv, ts_in_delta, sequence, discriminator, ingest_id, dedup_key
+FROM mbp_legacy;
+
+INSERT INTO bid_ask (id, mbp_id, ts_recv, depth, bid_px, bid_sz, bid_ct, ask_px, ask_sz, ask_ct)
+SELECT b.id, b.mbp_id, m.ts_recv, b.depth, b.bid_px, b.bid_sz, b.bid_ct, b.ask_px, b.ask_sz, b.ask_ct
+FROM bid_ask_legacy b
+INNER JOIN mbp_legacy m ON m.id = b.mbp_id;
+
+DROP TABLE bid_ask_legacy;
+DROP TABLE mbp_legacy;
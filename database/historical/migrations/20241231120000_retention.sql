@@ -0,0 +1,39 @@
+-- How many months of mbp data stay in the database, per instrument or per dataset. An
+-- instrument's own policy takes precedence over the policy of its dataset.
+CREATE TABLE IF NOT EXISTS retention_policy (
+  id SERIAL PRIMARY KEY,
+  instrument_id INTEGER UNIQUE,
+  dataset VARCHAR(50) UNIQUE,
+  retention_months INTEGER NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  CONSTRAINT fk_instrument_retention_policy
+    FOREIGN KEY(instrument_id)
+      REFERENCES instrument(id)
+      ON DELETE CASCADE,
+  CONSTRAINT retention_policy_scope CHECK ((instrument_id IS NULL) <> (dataset IS NULL)),
+  CONSTRAINT valid_retention_months CHECK (retention_months > 0)
+);
+
+-- Expired ranges exported to mbn files. Rows are only deleted once the file has been verified,
+-- archives are kept after their instrument is deleted.
+CREATE TABLE IF NOT EXISTS retention_archive (
+  id SERIAL PRIMARY KEY,
+  policy_id INTEGER,
+  instrument_id INTEGER NOT NULL,
+  ticker VARCHAR(50) NOT NULL,
+  range_start BIGINT NOT NULL, -- ts_recv, inclusive
+  range_end BIGINT NOT NULL, -- ts_recv, exclusive
+  file_path VARCHAR NOT NULL,
+  records BIGINT NOT NULL DEFAULT 0,
+  status VARCHAR(20) NOT NULL DEFAULT 'exporting', -- exporting, verified, deleted, failed
+  error TEXT,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  CONSTRAINT fk_retention_policy_archive
+    FOREIGN KEY(policy_id)
+      REFERENCES retention_policy(id)
+      ON DELETE SET NULL
+);
+
+CREATE INDEX idx_retention_archive_instrument ON retention_archive (instrument_id, range_start);
@@ -0,0 +1,53 @@
+-- OHLCV bars of the mbp trades, kept up to date as batches are inserted. 1s bars are built from
+-- the trades and every longer interval from the one below it (1m, 1h, 1d).
+CREATE TABLE IF NOT EXISTS ohlcv_rollup (
+  instrument_id INTEGER NOT NULL,
+  interval_ns BIGINT NOT NULL,
+  ts_event BIGINT NOT NULL, -- start of the bar, in ts_recv
+  open BIGINT NOT NULL,
+  close BIGINT NOT NULL,
+  low BIGINT NOT NULL,
+  high BIGINT NOT NULL,
+  volume BIGINT NOT NULL,
+  CONSTRAINT ohlcv_rollup_pkey PRIMARY KEY (instrument_id, interval_ns, ts_event),
+  CONSTRAINT fk_instrument_ohlcv_rollup
+    FOREIGN KEY(instrument_id)
+      REFERENCES instrument(id)
+      ON DELETE CASCADE
+);
+
+-- Bars of the trades already loaded
+INSERT INTO ohlcv_rollup (instrument_id, interval_ns, ts_event, open, close, low, high, volume)
+SELECT
+  instrument_id,
+  1000000000,
+  (ts_recv / 1000000000) * 1000000000,
+  (array_agg(price ORDER BY ts_recv, id))[1],
+  (array_agg(price ORDER BY ts_recv DESC, id DESC))[1],
+  MIN(price),
+  MAX(price),
+  SUM(size)::BIGINT
+FROM mbp
+WHERE action = 84 -- Trades only, 'T' in ASCII
+GROUP BY instrument_id, ts_recv / 1000000000;
+
+DO $$
+DECLARE
+  intervals BIGINT[] := ARRAY[1000000000, 60000000000, 3600000000000, 86400000000000];
+BEGIN
+  FOR level IN 2..array_length(intervals, 1) LOOP
+    INSERT INTO ohlcv_rollup (instrument_id, interval_ns, ts_event, open, close, low, high, volume)
+    SELECT
+      instrument_id,
+      intervals[level],
+      (ts_event / intervals[level]) * intervals[level],
+      (array_agg(open ORDER BY ts_event))[1],
+      (array_agg(close ORDER BY ts_event DESC))[1],
+      MIN(low),
+      MAX(high),
+      SUM(volume)::BIGINT
+    FROM ohlcv_rollup
+    WHERE interval_ns = intervals[level - 1]
+    GROUP BY instrument_id, ts_event / intervals[level];
+  END LOOP;
+END $$;
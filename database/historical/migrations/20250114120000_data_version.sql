@@ -0,0 +1,48 @@
+-- Dataset versions. Every completed ingest, replacement and range deletion creates one. Rows a
+-- correction removes from mbp are kept and marked with the version that superseded them, so
+-- reads can be served as of any earlier version.
+CREATE TABLE IF NOT EXISTS data_version (
+  id SERIAL PRIMARY KEY,
+  kind VARCHAR(20) NOT NULL, -- ingest, replace, delete
+  ingest_id INTEGER UNIQUE, -- session whose rows the version adds, NULL for deletions
+  created_at TIMESTAMPTZ NOT NULL DEFAULT clock_timestamp(),
+  CONSTRAINT fk_ingest_session_data_version
+    FOREIGN KEY(ingest_id)
+      REFERENCES ingest_session(id)
+      ON DELETE SET NULL
+);
+
+-- Sessions completed so far, in completion order. Rows without a session predate versions and
+-- belong to every version.
+INSERT INTO data_version (kind, ingest_id, created_at)
+SELECT CASE WHEN mode = 'replace' THEN 'replace' ELSE 'ingest' END, id, COALESCE(completed_at, created_at)
+FROM ingest_session
+WHERE status = 'completed'
+ORDER BY COALESCE(completed_at, created_at), id;
+
+ALTER TABLE mbp ADD COLUMN superseded_by INTEGER; -- NULL while the row is current
+ALTER TABLE mbp ADD CONSTRAINT fk_data_version_mbp
+  FOREIGN KEY(superseded_by)
+    REFERENCES data_version(id);
+
+-- Only current rows have to be unique, a correction may load a row identical to one it supersedes
+ALTER TABLE mbp DROP CONSTRAINT unique_mbp_dedup_key;
+CREATE UNIQUE INDEX unique_mbp_dedup_key ON mbp (instrument_id, ts_event, dedup_key, ts_recv)
+  WHERE superseded_by IS NULL;
+
+-- Detached partitions have to match the table again before they can be attached
+DO $$
+DECLARE
+  part RECORD;
+  con RECORD;
+BEGIN
+  FOR part IN SELECT name FROM mbp_partition WHERE NOT attached LOOP
+    EXECUTE format('ALTER TABLE %I ADD COLUMN superseded_by INTEGER', 'mbp_' || part.name);
+    FOR con IN
+      SELECT conname FROM pg_constraint
+      WHERE conrelid = ('mbp_' || part.name)::regclass AND contype = 'u'
+    LOOP
+      EXECUTE format('ALTER TABLE %I DROP CONSTRAINT %I', 'mbp_' || part.name, con.conname);
+    END LOOP;
+  END LOOP;
+END $$;
@@ -0,0 +1,11 @@
+-- Seconds with new trades whose rollup bars are still to be recomputed. Writers only add to the
+-- queue, so concurrent batches of one instrument do not wait on each other, and the bars are
+-- refreshed once the batches are committed. Keys may repeat.
+CREATE TABLE IF NOT EXISTS ohlcv_rollup_queue (
+  instrument_id INTEGER NOT NULL,
+  ts_event BIGINT NOT NULL, -- start of the 1s bar, in ts_recv
+  CONSTRAINT fk_instrument_ohlcv_rollup_queue
+    FOREIGN KEY(instrument_id)
+      REFERENCES instrument(id)
+      ON DELETE CASCADE
+);
@@ -0,0 +1,14 @@
+-- Versions are ordered by when their change committed, not by id. A replacement creates its
+-- version when the upload starts, so ids do not follow the order changes become visible in.
+CREATE SEQUENCE IF NOT EXISTS data_version_seq;
+
+ALTER TABLE data_version ADD COLUMN seq BIGINT UNIQUE; -- NULL until the change commits
+ALTER TABLE data_version ADD COLUMN committed_at TIMESTAMPTZ;
+
+-- Versions so far, in the order they were stamped
+UPDATE data_version v
+SET seq = o.seq, committed_at = v.created_at
+FROM (SELECT id, row_number() OVER (ORDER BY created_at, id) AS seq FROM data_version) o
+WHERE o.id = v.id;
+
+SELECT setval('data_version_seq', COALESCE(MAX(seq), 0) + 1, false) FROM data_version;
@@ -0,0 +1,2 @@
+-- Clear events of each instrument, where replays start from. 82 is the clear action, 'R'.
+CREATE INDEX idx_mbo_clear ON mbo (instrument_id, ts_recv) WHERE action = 82;
@@ -0,0 +1,3 @@
+-- Book levels below the top of archived mbp-10 rows, which mbn files cannot hold. Set when the
+-- range had any, the mbn file keeps the top level of every row.
+ALTER TABLE retention_archive ADD COLUMN levels_path VARCHAR;
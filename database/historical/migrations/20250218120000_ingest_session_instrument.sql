@@ -0,0 +1,23 @@
+-- Instruments with rows inserted by each ingest session, recorded when the session's rows are
+-- committed, so the last ingest of an instrument is found without going through its rows.
+CREATE TABLE IF NOT EXISTS ingest_session_instrument (
+  instrument_id INTEGER NOT NULL,
+  ingest_id INTEGER NOT NULL,
+  PRIMARY KEY (instrument_id, ingest_id),
+  CONSTRAINT fk_instrument_ingest_session_instrument
+    FOREIGN KEY(instrument_id)
+      REFERENCES instrument(id)
+      ON DELETE CASCADE,
+  CONSTRAINT fk_ingest_session_instrument
+    FOREIGN KEY(ingest_id)
+      REFERENCES ingest_session(id)
+      ON DELETE CASCADE
+);
+
+-- Sessions so far
+INSERT INTO ingest_session_instrument (instrument_id, ingest_id)
+SELECT instrument_id, ingest_id FROM mbp WHERE ingest_id IS NOT NULL
+UNION SELECT instrument_id, ingest_id FROM trade WHERE ingest_id IS NOT NULL
+UNION SELECT instrument_id, ingest_id FROM ohlcv WHERE ingest_id IS NOT NULL
+UNION SELECT instrument_id, ingest_id FROM bbo WHERE ingest_id IS NOT NULL
+UNION SELECT instrument_id, ingest_id FROM mbo WHERE ingest_id IS NOT NULL;
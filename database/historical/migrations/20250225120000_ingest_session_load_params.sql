@@ -0,0 +1,3 @@
+-- Parameters and column mapping of resumable file uploads, reused when the upload is resumed.
+-- NULL for other sessions and for sessions started before the column existed.
+ALTER TABLE ingest_session ADD COLUMN load_params JSONB;
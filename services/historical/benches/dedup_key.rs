@@ -0,0 +1,177 @@
+//! Inserts the same mbp-1 rows keyed by the legacy order book hash and by `dedup_key`, each with
+//! its unique index, and prints their throughput and footprint. The `dedup_key` table is indexed
+//! with the current definition of `unique_mbp_dedup_key`. Runs against the database of
+//! `HISTORICAL_DATABASE_URL`:
+//!
+//! ```sh
+//! cargo bench -p historical --bench dedup_key
+//! ```
+use historical::database::init::init_db;
+use historical::database::market_data::create::compute_dedup_key;
+use mbn::enums::{Action, Side};
+use mbn::records::{BidAskPair, Mbp1Msg, RecordHeader};
+use sha2::{Digest, Sha256};
+use std::os::raw::c_char;
+use std::time::Instant;
+
+const RECORDS: u64 = 100_000;
+const BATCH_SIZE: usize = 20_000;
+
+const COLUMNS: &str = r#"
+    instrument_id INTEGER NOT NULL,
+    ts_event BIGINT NOT NULL,
+    price BIGINT NOT NULL,
+    size INTEGER NOT NULL,
+    action INTEGER NOT NULL,
+    side INTEGER NOT NULL,
+    flags INTEGER NOT NULL,
+    ts_recv BIGINT NOT NULL,
+    sequence INTEGER NOT NULL,
+    discriminator INTEGER NOT NULL,
+"#;
+
+/// 64-char hex hash of the levels, the identity before `dedup_key`.
+fn legacy_hash(record: &Mbp1Msg) -> String {
+    let mut hasher = Sha256::new();
+    for level in &record.levels {
+        hasher.update(level.bid_px.to_be_bytes());
+        hasher.update(level.ask_px.to_be_bytes());
+        hasher.update(level.bid_sz.to_be_bytes());
+        hasher.update(level.ask_sz.to_be_bytes());
+        hasher.update(level.bid_ct.to_be_bytes());
+        hasher.update(level.ask_ct.to_be_bytes());
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    dotenv::dotenv().ok();
+    let pool = init_db().await?;
+
+    let records: Vec<Mbp1Msg> = (0..RECORDS)
+        .map(|i| Mbp1Msg {
+            hd: RecordHeader::new::<Mbp1Msg>(1, 1704295503644092562 + i),
+            price: 6870,
+            size: 2,
+            action: Action::Add as c_char,
+            side: Side::Bid as c_char,
+            depth: 0,
+            flags: 0,
+            ts_recv: 1704209103644092562 + i,
+            ts_in_delta: 17493,
+            sequence: i as u32,
+            discriminator: 0,
+            levels: [BidAskPair {
+                bid_px: 1,
+                ask_px: 1,
+                bid_sz: 1,
+                ask_sz: 1,
+                bid_ct: 10,
+                ask_ct: 20,
+            }],
+        })
+        .collect();
+    let legacy_hashes: Vec<String> = records.iter().map(legacy_hash).collect();
+    let dedup_keys: Vec<i64> = records.iter().map(compute_dedup_key).collect();
+
+    // The index as the migrations left it, from USING on
+    let index: String = sqlx::query_scalar(
+        "SELECT indexdef FROM pg_indexes WHERE indexname = 'unique_mbp_dedup_key'",
+    )
+    .fetch_one(&pool)
+    .await?;
+    let index_definition = &index[index.find(" USING ").unwrap_or(0)..];
+
+    let tables = [
+        (
+            "mbp_bench_legacy_key",
+            "order_book_hash VARCHAR NOT NULL".to_string(),
+            "CREATE UNIQUE INDEX ON mbp_bench_legacy_key (instrument_id, ts_event, price, size, flags, sequence, order_book_hash, ts_recv, action, side, discriminator)".to_string(),
+            "order_book_hash",
+            "text",
+        ),
+        (
+            "mbp_bench_dedup_key",
+            "dedup_key BIGINT NOT NULL, superseded_by INTEGER".to_string(),
+            format!("CREATE UNIQUE INDEX ON mbp_bench_dedup_key{}", index_definition),
+            "dedup_key",
+            "bigint",
+        ),
+    ];
+
+    for (table, key, index, key_column, key_type) in tables {
+        sqlx::query(&format!("CREATE TABLE {} ({} {})", table, COLUMNS, key))
+            .execute(&pool)
+            .await?;
+        sqlx::query(&index).execute(&pool).await?;
+
+        let query = format!(
+            r#"
+            INSERT INTO {} (instrument_id, ts_event, price, size, action, side, flags, ts_recv, sequence, discriminator, {})
+            SELECT * FROM UNNEST($1::int[], $2::bigint[], $3::bigint[], $4::int[], $5::int[], $6::int[], $7::int[], $8::bigint[], $9::int[], $10::int[], $11::{}[])
+            "#,
+            table, key_column, key_type
+        );
+        let start = Instant::now();
+        for (idx, chunk) in records.chunks(BATCH_SIZE).enumerate() {
+            let range = idx * BATCH_SIZE..idx * BATCH_SIZE + chunk.len();
+            let mut insert = sqlx::query(&query)
+                .bind(
+                    chunk
+                        .iter()
+                        .map(|r| r.hd.instrument_id as i32)
+                        .collect::<Vec<_>>(),
+                )
+                .bind(
+                    chunk
+                        .iter()
+                        .map(|r| r.hd.ts_event as i64)
+                        .collect::<Vec<_>>(),
+                )
+                .bind(chunk.iter().map(|r| r.price).collect::<Vec<_>>())
+                .bind(chunk.iter().map(|r| r.size as i32).collect::<Vec<_>>())
+                .bind(chunk.iter().map(|r| r.action as i32).collect::<Vec<_>>())
+                .bind(chunk.iter().map(|r| r.side as i32).collect::<Vec<_>>())
+                .bind(chunk.iter().map(|r| r.flags as i32).collect::<Vec<_>>())
+                .bind(chunk.iter().map(|r| r.ts_recv as i64).collect::<Vec<_>>())
+                .bind(chunk.iter().map(|r| r.sequence as i32).collect::<Vec<_>>())
+                .bind(
+                    chunk
+                        .iter()
+                        .map(|r| r.discriminator as i32)
+                        .collect::<Vec<_>>(),
+                );
+            insert = if key_type == "text" {
+                insert.bind(legacy_hashes[range].to_vec())
+            } else {
+                insert.bind(dedup_keys[range].to_vec())
+            };
+            let mut tx = pool.begin().await?;
+            insert.execute(&mut tx).await?;
+            tx.commit().await?;
+        }
+        let rate = records.len() as f64 / start.elapsed().as_secs_f64();
+
+        let (table_bytes, index_bytes): (i64, i64) = sqlx::query_as(&format!(
+            "SELECT pg_table_size('{0}'), pg_indexes_size('{0}')",
+            table
+        ))
+        .fetch_one(&pool)
+        .await?;
+        println!(
+            "{}: {:.0} records/s, table {} bytes, index {} bytes",
+            key_column, rate, table_bytes, index_bytes
+        );
+
+        sqlx::query(&format!("DROP TABLE {}", table))
+            .execute(&pool)
+            .await?;
+    }
+
+    Ok(())
+}
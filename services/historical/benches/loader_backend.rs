@@ -0,0 +1,88 @@
+//! Loads the same mbp-1 records with each `LoaderBackend` and prints their throughput. Runs against
+//! the database of `HISTORICAL_DATABASE_URL`:
+//!
+//! ```sh
+//! cargo bench -p historical --bench loader_backend
+//! ```
+use historical::database::init::init_db;
+use historical::database::market_data::create::{IngestMode, InsertBatch, LoaderBackend};
+use historical::database::symbols::InstrumentsQueries;
+use mbn::enums::{Action, Side};
+use mbn::records::{BidAskPair, Mbp1Msg, RecordHeader};
+use mbn::symbols::{Instrument, Vendors};
+use std::os::raw::c_char;
+use std::time::Instant;
+
+const RECORDS: u64 = 100_000;
+const BATCH_SIZE: usize = 20_000;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    dotenv::dotenv().ok();
+    let pool = init_db().await?;
+
+    let instrument = Instrument::new(
+        None,
+        "BENCH",
+        "Loader benchmark",
+        Vendors::Databento,
+        Some("continuous".to_string()),
+        Some("GLBX.MDP3".to_string()),
+        1704672000000000000,
+        1704672000000000000,
+        true,
+    );
+    let mut tx = pool.begin().await?;
+    let instrument_id = instrument.insert_instrument(&mut tx).await?;
+    tx.commit().await?;
+
+    let records: Vec<Mbp1Msg> = (0..RECORDS)
+        .map(|i| Mbp1Msg {
+            hd: RecordHeader::new::<Mbp1Msg>(instrument_id as u32, 1704295503644092562 + i),
+            price: 6870,
+            size: 2,
+            action: Action::Add as c_char,
+            side: Side::Bid as c_char,
+            depth: 0,
+            flags: 0,
+            ts_recv: 1704209103644092562 + i,
+            ts_in_delta: 17493,
+            sequence: i as u32,
+            discriminator: 0,
+            levels: [BidAskPair {
+                bid_px: 1,
+                ask_px: 1,
+                bid_sz: 1,
+                ask_sz: 1,
+                bid_ct: 10,
+                ask_ct: 20,
+            }],
+        })
+        .collect();
+
+    for backend in [LoaderBackend::Unnest, LoaderBackend::Copy] {
+        let start = Instant::now();
+        let mut batch = InsertBatch::new(IngestMode::Strict, None).with_backend(backend);
+        for chunk in records.chunks(BATCH_SIZE) {
+            for record in chunk {
+                batch.process(record).await?;
+            }
+            let mut tx = pool.begin().await?;
+            batch.execute(&mut tx).await?;
+            tx.commit().await?;
+        }
+        let rate = records.len() as f64 / start.elapsed().as_secs_f64();
+        println!("{}: {:.0} records/s", backend.as_str(), rate);
+
+        sqlx::query("DELETE FROM mbp WHERE instrument_id = $1")
+            .bind(instrument_id)
+            .execute(&pool)
+            .await?;
+    }
+
+    let mut tx = pool.begin().await?;
+    Instrument::delete_instrument(&mut tx, instrument_id).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
@@ -0,0 +1,367 @@
+use crate::database::market_data::create::IngestMode;
+use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Row, Transaction};
+use std::str::FromStr;
+use tracing::info;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IngestStatus {
+    Running,
+    Completed,
+    Failed,
+}
+
+impl IngestStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IngestStatus::Running => "running",
+            IngestStatus::Completed => "completed",
+            IngestStatus::Failed => "failed",
+        }
+    }
+}
+
+impl FromStr for IngestStatus {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "running" => Ok(IngestStatus::Running),
+            "completed" => Ok(IngestStatus::Completed),
+            "failed" => Ok(IngestStatus::Failed),
+            _ => Err(crate::error!(CustomError, "Invalid ingest status: {}", s)),
+        }
+    }
+}
+
+/// Running totals of a load, shared with whoever is tracking it (e.g. an ingest job).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LoadProgress {
+    pub records_decoded: u64,
+    pub records_committed: u64,
+    pub records_skipped: u64,
+    pub current_batch: u32,
+    pub errors: Vec<String>,
+}
+
+impl LoadProgress {
+    /// Combined totals of two loads, e.g. the files of one job.
+    pub fn merged(&self, other: &LoadProgress) -> LoadProgress {
+        LoadProgress {
+            records_decoded: self.records_decoded + other.records_decoded,
+            records_committed: self.records_committed + other.records_committed,
+            records_skipped: self.records_skipped + other.records_skipped,
+            current_batch: self.current_batch + other.current_batch,
+            errors: [self.errors.as_slice(), other.errors.as_slice()].concat(),
+        }
+    }
+}
+
+/// A single upload. Every row inserted by the upload is tagged with the session id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IngestSession {
+    pub id: i32,
+    pub source: String,
+    pub status: IngestStatus,
+    pub mode: IngestMode,
+    pub resumable: bool,
+    pub checkpoint_record: i64,
+    pub checkpoint_batch: i32,
+    /// Parameters of a resumable file upload, see `set_load_params`.
+    pub load_params: Option<serde_json::Value>,
+    pub created_at: i64,           // UNIX nanoseconds
+    pub completed_at: Option<i64>, // UNIX nanoseconds
+}
+
+impl sqlx::FromRow<'_, sqlx::postgres::PgRow> for IngestSession {
+    fn from_row(row: &sqlx::postgres::PgRow) -> std::result::Result<Self, sqlx::Error> {
+        let status: String = row.try_get("status")?;
+        let mode: String = row.try_get("mode")?;
+
+        Ok(IngestSession {
+            id: row.try_get("id")?,
+            source: row.try_get("source")?,
+            status: IngestStatus::from_str(&status).map_err(|e| sqlx::Error::Decode(e.into()))?,
+            mode: IngestMode::from_str(&mode).map_err(|e| sqlx::Error::Decode(e.into()))?,
+            resumable: row.try_get("resumable")?,
+            checkpoint_record: row.try_get("checkpoint_record")?,
+            checkpoint_batch: row.try_get("checkpoint_batch")?,
+            load_params: row.try_get("load_params")?,
+            created_at: row.try_get("created_at")?,
+            completed_at: row.try_get("completed_at")?,
+        })
+    }
+}
+
+const SESSION_COLUMNS: &str = r#"
+    id, source, status, mode, resumable, checkpoint_record, checkpoint_batch, load_params,
+    CAST(EXTRACT(EPOCH FROM created_at) * 1000000000 AS BIGINT) AS created_at,
+    CAST(EXTRACT(EPOCH FROM completed_at) * 1000000000 AS BIGINT) AS completed_at
+"#;
+
+impl IngestSession {
+    pub async fn create(
+        pool: &PgPool,
+        source: &str,
+        mode: IngestMode,
+        resumable: bool,
+    ) -> Result<i32> {
+        info!("Creating ingest session for {}", source);
+        let id: i32 = sqlx::query_scalar(
+            r#"
+            INSERT INTO ingest_session (source, status, mode, resumable)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id
+            "#,
+        )
+        .bind(source)
+        .bind(IngestStatus::Running.as_str())
+        .bind(mode.as_str())
+        .bind(resumable)
+        .fetch_one(pool)
+        .await?;
+
+        info!("Successfully created ingest session with id {}", id);
+        Ok(id)
+    }
+
+    pub async fn get(pool: &PgPool, id: i32) -> Result<Option<IngestSession>> {
+        let session: Option<IngestSession> = sqlx::query_as(&format!(
+            "SELECT {} FROM ingest_session WHERE id = $1",
+            SESSION_COLUMNS
+        ))
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(session)
+    }
+
+    pub async fn update_status(pool: &PgPool, id: i32, status: IngestStatus) -> Result<()> {
+        info!("Updating ingest session {} to {}", id, status.as_str());
+        sqlx::query(
+            r#"
+            UPDATE ingest_session
+            SET status = $1,
+                completed_at = CASE WHEN $1 = 'running' THEN NULL ELSE CURRENT_TIMESTAMP END
+            WHERE id = $2
+            "#,
+        )
+        .bind(status.as_str())
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Stores the parameters the session was started with, so resuming it reads the source the
+    /// same way.
+    pub async fn set_load_params(
+        pool: &PgPool,
+        id: i32,
+        load_params: &serde_json::Value,
+    ) -> Result<()> {
+        sqlx::query("UPDATE ingest_session SET load_params = $1 WHERE id = $2")
+            .bind(load_params)
+            .bind(id)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Records the instruments the session inserted rows of, for their last ingest. Runs in the
+    /// transaction committing the rows.
+    pub async fn record_instruments(
+        tx: &mut Transaction<'_, Postgres>,
+        id: i32,
+        instrument_ids: &[i32],
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO ingest_session_instrument (instrument_id, ingest_id)
+            SELECT UNNEST($1::int[]), $2
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(instrument_ids)
+        .bind(id)
+        .execute(tx)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Records how far into the source the session has committed. Runs in the batch's transaction.
+    pub async fn checkpoint(
+        tx: &mut Transaction<'_, Postgres>,
+        id: i32,
+        record: u64,
+        batch: u32,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE ingest_session
+            SET checkpoint_record = $1, checkpoint_batch = $2
+            WHERE id = $3
+            "#,
+        )
+        .bind(record as i64)
+        .bind(batch as i32)
+        .bind(id)
+        .execute(tx)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Sessions still marked as running, e.g. after the service was restarted mid-load.
+    pub async fn list_running(pool: &PgPool) -> Result<Vec<IngestSession>> {
+        let sessions: Vec<IngestSession> = sqlx::query_as(&format!(
+            "SELECT {} FROM ingest_session WHERE status = $1",
+            SESSION_COLUMNS
+        ))
+        .bind(IngestStatus::Running.as_str())
+        .fetch_all(pool)
+        .await?;
+
+        Ok(sessions)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::init::init_db;
+    use crate::database::market_data::create::{rollback_all_batches, InsertBatch};
+    use crate::database::symbols::InstrumentsQueries;
+    use mbn::records::{BidAskPair, Mbp1Msg, RecordHeader};
+    use mbn::symbols::{Instrument, Vendors};
+    use serial_test::serial;
+
+    async fn create_instrument(pool: &PgPool) -> Result<i32> {
+        let mut transaction = pool
+            .begin()
+            .await
+            .expect("Error setting up test transaction.");
+
+        let instrument = Instrument::new(
+            None,
+            "AAPL",
+            "Apple Inc.",
+            Vendors::Databento,
+            Some("continuous".to_string()),
+            Some("GLBX.MDP3".to_string()),
+            1704672000000000000,
+            1704672000000000000,
+            true,
+        );
+        let id = instrument
+            .insert_instrument(&mut transaction)
+            .await
+            .expect("Error inserting symbol.");
+        transaction.commit().await?;
+        Ok(id)
+    }
+
+    fn mbp(instrument_id: i32, ts: u64) -> Mbp1Msg {
+        Mbp1Msg {
+            hd: { RecordHeader::new::<Mbp1Msg>(instrument_id as u32, ts) },
+            price: 6770,
+            size: 1,
+            action: 1,
+            side: 2,
+            depth: 0,
+            flags: 0,
+            ts_recv: ts,
+            ts_in_delta: 17493,
+            sequence: 739763,
+            discriminator: 0,
+            levels: [BidAskPair {
+                bid_px: 1,
+                ask_px: 1,
+                bid_sz: 1,
+                ask_sz: 1,
+                bid_ct: 10,
+                ask_ct: 20,
+            }],
+        }
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_session_status() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+
+        // Test
+        let id = IngestSession::create(&pool, "test_file.bin", IngestMode::Strict, false).await?;
+        IngestSession::update_status(&pool, id, IngestStatus::Completed).await?;
+
+        // Validate
+        let session = IngestSession::get(&pool, id).await?.unwrap();
+        assert_eq!(session.source, "test_file.bin");
+        assert_eq!(session.status, IngestStatus::Completed);
+        assert!(session.completed_at.is_some());
+
+        // Cleanup
+        sqlx::query("DELETE FROM ingest_session WHERE id = $1")
+            .bind(id)
+            .execute(&pool)
+            .await?;
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_rollback_only_removes_session_rows() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let instrument_id = create_instrument(&pool).await?;
+
+        let session_a = IngestSession::create(&pool, "a.bin", IngestMode::Strict, false).await?;
+        let session_b = IngestSession::create(&pool, "b.bin", IngestMode::Strict, false).await?;
+
+        for (session, ts) in [
+            (session_a, 1704209103644092564),
+            (session_b, 1704209103644092565),
+        ] {
+            let mut batch = InsertBatch::new(IngestMode::Strict, Some(session));
+            batch.process(&mbp(instrument_id, ts)).await?;
+            let mut tx = pool.begin().await?;
+            batch.execute(&mut tx).await?;
+            tx.commit().await?;
+        }
+
+        // Test
+        let mut tx = pool.begin().await?;
+        let deleted = rollback_all_batches(session_a, &mut tx).await?;
+        tx.commit().await?;
+
+        // Validate
+        let remaining: Vec<i32> =
+            sqlx::query_scalar("SELECT ingest_id FROM mbp WHERE instrument_id = $1")
+                .bind(instrument_id)
+                .fetch_all(&pool)
+                .await?;
+        assert_eq!(deleted, 1);
+        assert_eq!(remaining, vec![session_b]);
+
+        // Cleanup
+        let mut transaction = pool.begin().await?;
+        Instrument::delete_instrument(&mut transaction, instrument_id).await?;
+        transaction.commit().await?;
+        sqlx::query("DELETE FROM ingest_session WHERE id = ANY($1)")
+            .bind(vec![session_a, session_b])
+            .execute(&pool)
+            .await?;
+
+        Ok(())
+    }
+}
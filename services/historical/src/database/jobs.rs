@@ -0,0 +1,519 @@
+use crate::database::ingest::LoadProgress;
+use crate::database::market_data::create::IngestMode;
+use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Row};
+use std::str::FromStr;
+use tracing::info;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Queued => "queued",
+            JobStatus::Running => "running",
+            JobStatus::Completed => "completed",
+            JobStatus::Failed => "failed",
+            JobStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl FromStr for JobStatus {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "queued" => Ok(JobStatus::Queued),
+            "running" => Ok(JobStatus::Running),
+            "completed" => Ok(JobStatus::Completed),
+            "failed" => Ok(JobStatus::Failed),
+            "cancelled" => Ok(JobStatus::Cancelled),
+            _ => Err(crate::error!(CustomError, "Invalid job status: {}", s)),
+        }
+    }
+}
+
+/// A bulk upload running in the background.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IngestJob {
+    pub id: i32,
+    pub ingest_id: Option<i32>,
+    pub file_path: String,
+    pub mode: IngestMode,
+    pub status: JobStatus,
+    pub cancel_requested: bool,
+    pub records_decoded: i64,
+    pub records_committed: i64,
+    pub records_skipped: i64,
+    pub current_batch: i32,
+    pub errors: Vec<String>,
+    pub created_at: i64, // UNIX nanoseconds
+    pub updated_at: i64, // UNIX nanoseconds
+}
+
+impl sqlx::FromRow<'_, sqlx::postgres::PgRow> for IngestJob {
+    fn from_row(row: &sqlx::postgres::PgRow) -> std::result::Result<Self, sqlx::Error> {
+        let mode: String = row.try_get("mode")?;
+        let status: String = row.try_get("status")?;
+
+        Ok(IngestJob {
+            id: row.try_get("id")?,
+            ingest_id: row.try_get("ingest_id")?,
+            file_path: row.try_get("file_path")?,
+            mode: IngestMode::from_str(&mode).map_err(|e| sqlx::Error::Decode(e.into()))?,
+            status: JobStatus::from_str(&status).map_err(|e| sqlx::Error::Decode(e.into()))?,
+            cancel_requested: row.try_get("cancel_requested")?,
+            records_decoded: row.try_get("records_decoded")?,
+            records_committed: row.try_get("records_committed")?,
+            records_skipped: row.try_get("records_skipped")?,
+            current_batch: row.try_get("current_batch")?,
+            errors: row.try_get("errors")?,
+            created_at: row.try_get("created_at")?,
+            updated_at: row.try_get("updated_at")?,
+        })
+    }
+}
+
+const JOB_COLUMNS: &str = r#"
+    id, ingest_id, file_path, mode, status, cancel_requested,
+    records_decoded, records_committed, records_skipped, current_batch, errors,
+    CAST(EXTRACT(EPOCH FROM created_at) * 1000000000 AS BIGINT) AS created_at,
+    CAST(EXTRACT(EPOCH FROM updated_at) * 1000000000 AS BIGINT) AS updated_at
+"#;
+
+impl IngestJob {
+    pub async fn create(pool: &PgPool, file_path: &str, mode: IngestMode) -> Result<i32> {
+        info!("Creating ingest job for {}", file_path);
+        let id: i32 = sqlx::query_scalar(
+            r#"
+            INSERT INTO ingest_job (file_path, mode, status)
+            VALUES ($1, $2, $3)
+            RETURNING id
+            "#,
+        )
+        .bind(file_path)
+        .bind(mode.as_str())
+        .bind(JobStatus::Queued.as_str())
+        .fetch_one(pool)
+        .await?;
+
+        info!("Successfully created ingest job with id {}", id);
+        Ok(id)
+    }
+
+    pub async fn get(pool: &PgPool, id: i32) -> Result<Option<IngestJob>> {
+        let job: Option<IngestJob> = sqlx::query_as(&format!(
+            "SELECT {} FROM ingest_job WHERE id = $1",
+            JOB_COLUMNS
+        ))
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(job)
+    }
+
+    pub async fn list(pool: &PgPool) -> Result<Vec<IngestJob>> {
+        let jobs: Vec<IngestJob> = sqlx::query_as(&format!(
+            "SELECT {} FROM ingest_job ORDER BY id DESC",
+            JOB_COLUMNS
+        ))
+        .fetch_all(pool)
+        .await?;
+
+        Ok(jobs)
+    }
+
+    pub async fn start(pool: &PgPool, id: i32, ingest_id: i32) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE ingest_job
+            SET status = $1, ingest_id = $2, updated_at = CURRENT_TIMESTAMP
+            WHERE id = $3
+            "#,
+        )
+        .bind(JobStatus::Running.as_str())
+        .bind(ingest_id)
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Persists the loader's totals and returns whether a cancel was requested in the meantime.
+    pub async fn update_progress(pool: &PgPool, id: i32, progress: &LoadProgress) -> Result<bool> {
+        let cancel_requested: bool = sqlx::query_scalar(
+            r#"
+            UPDATE ingest_job
+            SET records_decoded = $1,
+                records_committed = $2,
+                records_skipped = $3,
+                current_batch = $4,
+                errors = $5,
+                updated_at = CURRENT_TIMESTAMP
+            WHERE id = $6
+            RETURNING cancel_requested
+            "#,
+        )
+        .bind(progress.records_decoded as i64)
+        .bind(progress.records_committed as i64)
+        .bind(progress.records_skipped as i64)
+        .bind(progress.current_batch as i32)
+        .bind(&progress.errors)
+        .bind(id)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(cancel_requested)
+    }
+
+    pub async fn finish(
+        pool: &PgPool,
+        id: i32,
+        status: JobStatus,
+        errors: &[String],
+    ) -> Result<()> {
+        info!("Ingest job {} finished as {}", id, status.as_str());
+        sqlx::query(
+            r#"
+            UPDATE ingest_job
+            SET status = $1, errors = errors || $2, updated_at = CURRENT_TIMESTAMP
+            WHERE id = $3
+            "#,
+        )
+        .bind(status.as_str())
+        .bind(errors)
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Flags a queued or running job for cancellation. Returns false if the job already finished.
+    pub async fn request_cancel(pool: &PgPool, id: i32) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE ingest_job
+            SET cancel_requested = TRUE, updated_at = CURRENT_TIMESTAMP
+            WHERE id = $1 AND status IN ('queued', 'running')
+            "#,
+        )
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Marks jobs left queued or running by a previous process as failed.
+    pub async fn fail_interrupted(pool: &PgPool) -> Result<Vec<IngestJob>> {
+        let jobs: Vec<IngestJob> = sqlx::query_as(&format!(
+            r#"
+            UPDATE ingest_job
+            SET status = 'failed',
+                errors = errors || ARRAY['Interrupted by service restart.'],
+                updated_at = CURRENT_TIMESTAMP
+            WHERE status IN ('queued', 'running')
+            RETURNING {}
+            "#,
+            JOB_COLUMNS
+        ))
+        .fetch_all(pool)
+        .await?;
+
+        Ok(jobs)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileStatus {
+    Pending,
+    Running,
+    Loaded,
+    /// Same checksum as a file loaded by an earlier job.
+    Skipped,
+    Failed,
+}
+
+impl FileStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FileStatus::Pending => "pending",
+            FileStatus::Running => "running",
+            FileStatus::Loaded => "loaded",
+            FileStatus::Skipped => "skipped",
+            FileStatus::Failed => "failed",
+        }
+    }
+}
+
+impl FromStr for FileStatus {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pending" => Ok(FileStatus::Pending),
+            "running" => Ok(FileStatus::Running),
+            "loaded" => Ok(FileStatus::Loaded),
+            "skipped" => Ok(FileStatus::Skipped),
+            "failed" => Ok(FileStatus::Failed),
+            _ => Err(crate::error!(CustomError, "Invalid file status: {}", s)),
+        }
+    }
+}
+
+/// A file to load, with what the manifest expects of it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub file_path: String,
+    #[serde(default)]
+    pub expected_records: Option<i64>,
+    /// Hex encoded sha256 of the file.
+    #[serde(default)]
+    pub checksum: Option<String>,
+}
+
+/// One file of a job submitted as a directory, glob or manifest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobFile {
+    pub id: i32,
+    pub job_id: i32,
+    pub position: i32,
+    pub file_path: String,
+    pub status: FileStatus,
+    pub expected_records: Option<i64>,
+    pub expected_checksum: Option<String>,
+    pub checksum: Option<String>,
+    pub ingest_id: Option<i32>,
+    pub records_loaded: i64,
+    pub error: Option<String>,
+}
+
+impl sqlx::FromRow<'_, sqlx::postgres::PgRow> for JobFile {
+    fn from_row(row: &sqlx::postgres::PgRow) -> std::result::Result<Self, sqlx::Error> {
+        let status: String = row.try_get("status")?;
+
+        Ok(JobFile {
+            id: row.try_get("id")?,
+            job_id: row.try_get("job_id")?,
+            position: row.try_get("position")?,
+            file_path: row.try_get("file_path")?,
+            status: FileStatus::from_str(&status).map_err(|e| sqlx::Error::Decode(e.into()))?,
+            expected_records: row.try_get("expected_records")?,
+            expected_checksum: row.try_get("expected_checksum")?,
+            checksum: row.try_get("checksum")?,
+            ingest_id: row.try_get("ingest_id")?,
+            records_loaded: row.try_get("records_loaded")?,
+            error: row.try_get("error")?,
+        })
+    }
+}
+
+const FILE_COLUMNS: &str = r#"
+    id, job_id, position, file_path, status, expected_records, expected_checksum,
+    checksum, ingest_id, records_loaded, error
+"#;
+
+impl JobFile {
+    pub async fn create_all(pool: &PgPool, job_id: i32, entries: &[ManifestEntry]) -> Result<()> {
+        let positions: Vec<i32> = (0..entries.len() as i32).collect();
+        let paths: Vec<&str> = entries.iter().map(|e| e.file_path.as_str()).collect();
+        let records: Vec<Option<i64>> = entries.iter().map(|e| e.expected_records).collect();
+        let checksums: Vec<Option<String>> = entries
+            .iter()
+            .map(|e| e.checksum.as_ref().map(|checksum| checksum.to_lowercase()))
+            .collect();
+
+        sqlx::query(
+            r#"
+            INSERT INTO ingest_job_file (job_id, position, file_path, expected_records, expected_checksum)
+            SELECT $1, * FROM UNNEST($2::int[], $3::text[], $4::bigint[], $5::text[])
+            "#,
+        )
+        .bind(job_id)
+        .bind(&positions)
+        .bind(&paths)
+        .bind(&records)
+        .bind(&checksums)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn list(pool: &PgPool, job_id: i32) -> Result<Vec<JobFile>> {
+        let files: Vec<JobFile> = sqlx::query_as(&format!(
+            "SELECT {} FROM ingest_job_file WHERE job_id = $1 ORDER BY position",
+            FILE_COLUMNS
+        ))
+        .bind(job_id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(files)
+    }
+
+    /// Whether a file with this checksum was loaded by any job.
+    pub async fn checksum_loaded(pool: &PgPool, checksum: &str) -> Result<bool> {
+        let loaded: bool = sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM ingest_job_file WHERE checksum = $1 AND status = 'loaded')",
+        )
+        .bind(checksum)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(loaded)
+    }
+
+    pub async fn start(pool: &PgPool, id: i32, checksum: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE ingest_job_file
+            SET status = $1, checksum = $2, updated_at = CURRENT_TIMESTAMP
+            WHERE id = $3
+            "#,
+        )
+        .bind(FileStatus::Running.as_str())
+        .bind(checksum)
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn finish(
+        pool: &PgPool,
+        id: i32,
+        status: FileStatus,
+        ingest_id: Option<i32>,
+        records_loaded: i64,
+        error: Option<&str>,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE ingest_job_file
+            SET status = $1, ingest_id = $2, records_loaded = $3, error = $4,
+                updated_at = CURRENT_TIMESTAMP
+            WHERE id = $5
+            "#,
+        )
+        .bind(status.as_str())
+        .bind(ingest_id)
+        .bind(records_loaded)
+        .bind(error)
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Marks files left running by a previous process as failed.
+    pub async fn fail_interrupted(pool: &PgPool) -> Result<u64> {
+        let result = sqlx::query(
+            r#"
+            UPDATE ingest_job_file
+            SET status = 'failed', error = 'Interrupted by service restart.',
+                updated_at = CURRENT_TIMESTAMP
+            WHERE status = 'running'
+            "#,
+        )
+        .execute(pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::init::init_db;
+    use serial_test::serial;
+
+    async fn delete_job(pool: &PgPool, id: i32) -> anyhow::Result<()> {
+        sqlx::query("DELETE FROM ingest_job WHERE id = $1")
+            .bind(id)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_job_progress() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let id = IngestJob::create(&pool, "test_file.bin", IngestMode::SkipDuplicates).await?;
+
+        // Test
+        let progress = LoadProgress {
+            records_decoded: 10,
+            records_committed: 8,
+            records_skipped: 2,
+            current_batch: 1,
+            errors: vec![],
+        };
+        let cancel_requested = IngestJob::update_progress(&pool, id, &progress).await?;
+
+        // Validate
+        let job = IngestJob::get(&pool, id).await?.unwrap();
+        assert!(!cancel_requested);
+        assert_eq!(job.status, JobStatus::Queued);
+        assert_eq!(job.mode, IngestMode::SkipDuplicates);
+        assert_eq!(job.records_decoded, 10);
+        assert_eq!(job.records_committed, 8);
+        assert_eq!(job.records_skipped, 2);
+        assert_eq!(job.current_batch, 1);
+
+        // Cleanup
+        delete_job(&pool, id).await?;
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_job_cancel() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let id = IngestJob::create(&pool, "test_file.bin", IngestMode::Strict).await?;
+
+        // Test
+        let requested = IngestJob::request_cancel(&pool, id).await?;
+        let cancel_requested =
+            IngestJob::update_progress(&pool, id, &LoadProgress::default()).await?;
+        IngestJob::finish(&pool, id, JobStatus::Cancelled, &[]).await?;
+        let requested_after_finish = IngestJob::request_cancel(&pool, id).await?;
+
+        // Validate
+        assert!(requested);
+        assert!(cancel_requested);
+        assert!(!requested_after_finish);
+        assert_eq!(
+            IngestJob::get(&pool, id).await?.unwrap().status,
+            JobStatus::Cancelled
+        );
+
+        // Cleanup
+        delete_job(&pool, id).await?;
+
+        Ok(())
+    }
+}
@@ -1,2 +1,5 @@
 pub mod create;
+pub mod mbo;
+pub mod mbp10;
 pub mod read;
+pub mod rollup;
@@ -1,93 +1,465 @@
-use crate::Result;
+use crate::database::market_data::mbp10::Mbp10Msg;
+use crate::database::market_data::rollup::{queue_rollups, refresh_rollups};
+use crate::database::partitions::{ensure_partitions, PartitionInterval};
+use crate::database::symbols::refresh_availability;
+use crate::{Error, Result};
 use async_trait::async_trait;
-use mbn::records::{BidAskPair, Mbp1Msg};
+use mbn::records::{BboMsg, Mbp1Msg, OhlcvMsg, TradeMsg};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use sqlx::{Postgres, Transaction};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::str::FromStr;
+
+
+/// Identity of an mbp row used for deduplication: the first 8 bytes of a SHA-256 over the
+/// record fields and every book level, as a big-endian `BIGINT`. Current rows are unique on
+/// `(instrument_id, ts_event, dedup_key)`, so a collision needs two different records of the same
+/// instrument at the same nanosecond. The byte layout matches the backfill in the
+/// `mbp_dedup_key` migration, each field encoded as its column type.
+pub fn compute_dedup_key(record: &Mbp1Msg) -> i64 {
+    let (row, levels) = mbp1_rows(record);
+    dedup_key(&row, &levels)
+}
 
+/// Dedup key of an MBP-10 record, over its ten book levels.
+pub fn compute_mbp10_dedup_key(record: &Mbp10Msg) -> i64 {
+    let (row, levels) = record.rows();
+    dedup_key(&row, &levels)
+}
 
-// Function to compute a unique hash for the order book state
-fn compute_order_book_hash(levels: &[BidAskPair]) -> String {
+/// Dedup key of an mbp row and its book levels, see [`compute_dedup_key`].
+fn dedup_key(row: &MbpRow, levels: &[BidAskRow]) -> i64 {
     let mut hasher = Sha256::new();
-
-    for level in levels {
-        hasher.update(level.bid_px.to_be_bytes());
-        hasher.update(level.ask_px.to_be_bytes());
-        hasher.update(level.bid_sz.to_be_bytes());
-        hasher.update(level.ask_sz.to_be_bytes());
-        hasher.update(level.bid_ct.to_be_bytes());
-        hasher.update(level.ask_ct.to_be_bytes());
+    hasher.update(row.instrument_id.to_be_bytes());
+    hasher.update(row.ts_event.to_be_bytes());
+    hasher.update(row.price.to_be_bytes());
+    hasher.update(row.size.to_be_bytes());
+    hasher.update(row.action.to_be_bytes());
+    hasher.update(row.side.to_be_bytes());
+    hasher.update(row.flags.to_be_bytes());
+    hasher.update(row.ts_recv.to_be_bytes());
+    hasher.update(row.sequence.to_be_bytes());
+    hasher.update(row.discriminator.to_be_bytes());
+
+    for &(_, bid_px, bid_sz, bid_ct, ask_px, ask_sz, ask_ct) in levels {
+        hasher.update(bid_px.to_be_bytes());
+        hasher.update(ask_px.to_be_bytes());
+        hasher.update(bid_sz.to_be_bytes());
+        hasher.update(ask_sz.to_be_bytes());
+        hasher.update(bid_ct.to_be_bytes());
+        hasher.update(ask_ct.to_be_bytes());
     }
 
-    let result = hasher.finalize();
+    let digest = hasher.finalize();
+    let mut key = [0u8; 8];
+    key.copy_from_slice(&digest[..8]);
+    i64::from_be_bytes(key)
+}
 
-    // Convert hash result to a hex string
-    result.iter().map(|byte| format!("{:02x}", byte)).collect()
+/// The mbp row and book levels of an MBP-1 record, without its dedup key.
+fn mbp1_rows(record: &Mbp1Msg) -> (MbpRow, Vec<BidAskRow>) {
+    let row = MbpRow {
+        instrument_id: record.hd.instrument_id as i32,
+        ts_event: record.hd.ts_event as i64,
+        price: record.price,
+        size: record.size as i32,
+        action: record.action as i32,
+        side: record.side as i32,
+        flags: record.flags as i32,
+        ts_recv: record.ts_recv as i64,
+        ts_in_delta: record.ts_in_delta,
+        sequence: record.sequence as i32,
+        discriminator: record.discriminator as i32,
+        dedup_key: 0,
+    };
+    let levels = record
+        .levels
+        .iter()
+        .enumerate()
+        .map(|(depth, level)| {
+            (
+                depth as i32,        // Depth
+                level.bid_px,        // Bid price
+                level.bid_sz as i32, // Bid size
+                level.bid_ct as i32, // Bid count
+                level.ask_px,        // Ask price
+                level.ask_sz as i32, // Ask size
+                level.ask_ct as i32, // Ask count
+            )
+        })
+        .collect();
+    (row, levels)
 }
 
-pub async fn rollback_all_batches(last_id: i32, tx: &mut Transaction<'_, Postgres>) -> Result<()> {
-    sqlx::query("DELETE FROM mbp WHERE id > $1")
-        .bind(last_id)
-        .execute(tx)
+/// Conflict target of the `unique_mbp_dedup_key` index, which only covers current rows.
+const MBP_CONFLICT_TARGET: &str =
+    "(instrument_id, ts_event, dedup_key, ts_recv) WHERE superseded_by IS NULL";
+
+/// Tables whose rows are tagged with the ingest session that inserted them.
+const SESSION_TABLES: [&str; 5] = ["mbp", "trade", "ohlcv", "bbo", "mbo"];
+
+/// Instruments with rows inserted by the given ingest session.
+pub async fn session_instruments(
+    ingest_id: i32,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<Vec<i32>> {
+    let query = SESSION_TABLES
+        .iter()
+        .map(|table| format!("SELECT instrument_id FROM {} WHERE ingest_id = $1", table))
+        .collect::<Vec<_>>()
+        .join(" UNION ");
+
+    let ids: Vec<i32> = sqlx::query_scalar(&query)
+        .bind(ingest_id)
+        .fetch_all(&mut *tx)
         .await?;
 
+    Ok(ids)
+}
+
+/// Removes every row inserted by the given ingest session and refreshes the availability and
+/// rollup bars of the instruments it touched.
+pub async fn rollback_all_batches(ingest_id: i32, tx: &mut Transaction<'_, Postgres>) -> Result<u64> {
+    let instrument_ids = session_instruments(ingest_id, tx).await?;
+    let trades: Vec<(i32, i64)> = sqlx::query_as(
+        "SELECT DISTINCT instrument_id, ts_recv - ts_recv % 1000000000 FROM mbp WHERE ingest_id = $1 AND action = 84",
+    )
+    .bind(ingest_id)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut deleted = 0;
+    for table in SESSION_TABLES {
+        let result = sqlx::query(&format!("DELETE FROM {} WHERE ingest_id = $1", table))
+            .bind(ingest_id)
+            .execute(&mut *tx)
+            .await?;
+        deleted += result.rows_affected();
+    }
+    refresh_availability(tx, Some(&instrument_ids)).await?;
+    refresh_rollups(tx, &trades).await?;
+
+    Ok(deleted)
+}
+
+/// Supersedes the current mbp rows of the instruments with `ts_recv` in `[start, end)` by
+/// `version`, and refreshes the availability and rollup bars of the instruments. The rows and
+/// their levels are kept for reads as of earlier versions.
+pub async fn supersede_mbp_range(
+    tx: &mut Transaction<'_, Postgres>,
+    version: i32,
+    instrument_ids: &[i32],
+    start: i64,
+    end: i64,
+) -> Result<u64> {
+    let trades: Vec<(i32, i64)> = sqlx::query_as(
+        r#"
+        SELECT DISTINCT instrument_id, ts_recv - ts_recv % 1000000000
+        FROM mbp
+        WHERE instrument_id = ANY($1) AND ts_recv >= $2 AND ts_recv < $3 AND action = 84
+        AND superseded_by IS NULL
+        "#,
+    )
+    .bind(instrument_ids)
+    .bind(start)
+    .bind(end)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let result = sqlx::query(
+        r#"
+        UPDATE mbp SET superseded_by = $4
+        WHERE instrument_id = ANY($1) AND ts_recv >= $2 AND ts_recv < $3 AND superseded_by IS NULL
+        "#,
+    )
+    .bind(instrument_ids)
+    .bind(start)
+    .bind(end)
+    .bind(version)
+    .execute(&mut *tx)
+    .await?;
+    refresh_availability(tx, Some(instrument_ids)).await?;
+    refresh_rollups(tx, &trades).await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Positions of the records that already exist in `mbp`.
+pub async fn existing_mbp(pool: &PgPool, records: &[Mbp1Msg]) -> Result<Vec<usize>> {
+    let mut batch = InsertBatch::new(IngestMode::Strict, None);
+    for record in records {
+        batch.process(record).await?;
+    }
+
+    let mut instrument_ids = Vec::new();
+    let mut ts_events = Vec::new();
+    let mut dedup_keys = Vec::new();
 
-    Ok(())
+    for row in &batch.mbp_values {
+        instrument_ids.push(row.instrument_id);
+        ts_events.push(row.ts_event);
+        dedup_keys.push(row.dedup_key);
+    }
+
+    let positions: Vec<i64> = sqlx::query_scalar(
+        r#"
+        SELECT inp.idx
+        FROM UNNEST($1::int[], $2::bigint[], $3::bigint[])
+        WITH ORDINALITY AS inp(instrument_id, ts_event, dedup_key, idx)
+        WHERE EXISTS (
+            SELECT 1 FROM mbp m
+            WHERE m.instrument_id = inp.instrument_id
+            AND m.ts_event = inp.ts_event
+            AND m.dedup_key = inp.dedup_key
+            AND m.superseded_by IS NULL
+        )
+        ORDER BY inp.idx
+        "#
+    )
+    .bind(&instrument_ids)
+    .bind(&ts_events)
+    .bind(&dedup_keys)
+    .fetch_all(pool)
+    .await?;
+
+    // WITH ORDINALITY is 1-based
+    Ok(positions.into_iter().map(|idx| (idx - 1) as usize).collect())
 }
 
- 
+/// Controls how `InsertBatch::execute` handles rows that already exist in `mbp`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IngestMode {
+    /// Any duplicate fails the batch, and the loader rolls back the whole upload.
+    #[default]
+    Strict,
+    /// Duplicates are skipped with `ON CONFLICT DO NOTHING`.
+    SkipDuplicates,
+    /// Deletes the upload's range of its instruments and loads the file in one transaction.
+    /// Batches insert as in `Strict`.
+    Replace,
+}
+
+impl IngestMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IngestMode::Strict => "strict",
+            IngestMode::SkipDuplicates => "skip_duplicates",
+            IngestMode::Replace => "replace",
+        }
+    }
+}
+
+impl FromStr for IngestMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "strict" => Ok(IngestMode::Strict),
+            "skip_duplicates" => Ok(IngestMode::SkipDuplicates),
+            "replace" => Ok(IngestMode::Replace),
+            _ => Err(crate::error!(CustomError, "Invalid ingest mode: {}", s)),
+        }
+    }
+}
+
+/// Selects how `InsertBatch::execute` writes a batch to `mbp` and `bid_ask`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoaderBackend {
+    /// Column arrays bound to `INSERT ... SELECT * FROM UNNEST(...)`.
+    #[default]
+    Unnest,
+    /// Binary `COPY` into temporary staging tables, then a set-based merge.
+    Copy,
+}
+
+impl LoaderBackend {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoaderBackend::Unnest => "unnest",
+            LoaderBackend::Copy => "copy",
+        }
+    }
+}
+
+impl FromStr for LoaderBackend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "unnest" => Ok(LoaderBackend::Unnest),
+            "copy" => Ok(LoaderBackend::Copy),
+            _ => Err(crate::error!(CustomError, "Invalid loader backend: {}", s)),
+        }
+    }
+}
+
+/// Rows encoded in the Postgres binary `COPY` format.
+struct CopyBuffer {
+    buf: Vec<u8>,
+}
+
+impl CopyBuffer {
+    fn new() -> Self {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(b"PGCOPY\n\xff\r\n\0");
+        buf.extend_from_slice(&0i32.to_be_bytes()); // Flags
+        buf.extend_from_slice(&0i32.to_be_bytes()); // Header extension length
+        CopyBuffer { buf }
+    }
+
+    fn row(&mut self, fields: i16) {
+        self.buf.extend_from_slice(&fields.to_be_bytes());
+    }
+
+    fn int4(&mut self, value: i32) {
+        self.buf.extend_from_slice(&4i32.to_be_bytes());
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn int8(&mut self, value: i64) {
+        self.buf.extend_from_slice(&8i32.to_be_bytes());
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.buf.extend_from_slice(&(-1i16).to_be_bytes());
+        self.buf
+    }
+}
+
+/// Outcome of a single batch execution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BatchSummary {
+    pub inserted: usize,
+    pub skipped: usize,
+}
+
+/// An mbp row of a batch, with the columns as they are inserted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MbpRow {
+    pub instrument_id: i32,
+    pub ts_event: i64,
+    pub price: i64,
+    pub size: i32,
+    pub action: i32,
+    pub side: i32,
+    pub flags: i32,
+    pub ts_recv: i64,
+    pub ts_in_delta: i32,
+    pub sequence: i32,
+    pub discriminator: i32,
+    pub dedup_key: i64,
+}
+
+/// A book level of an mbp row: depth, bid price, size and count, ask price, size and count.
+pub type BidAskRow = (i32, i64, i32, i32, i64, i32, i32);
 
 /// Primary insert method
 pub struct InsertBatch {
-    pub mbp_values: Vec<(i32, i64, i64, i32, i32, i32, i32, i64, i32, i32, i32, String)>,
-    pub bid_ask_batches: Vec<Vec<(i32, i64, i32, i32, i64, i32, i32)>>,
-
+    pub mode: IngestMode,
+    pub backend: LoaderBackend,
+    pub ingest_id: Option<i32>,
+    pub mbp_values: Vec<MbpRow>,
+    pub bid_ask_batches: Vec<Vec<BidAskRow>>,
+    /// Bar or sampling interval of the upload, required for ohlcv and bbo records.
+    pub interval_ns: Option<i64>,
+    pub trade_values: Vec<TradeMsg>,
+    pub ohlcv_values: Vec<OhlcvMsg>,
+    pub bbo_values: Vec<BboMsg>,
 }
+
 impl InsertBatch {
-    pub fn new() -> Self {
+    pub fn new(mode: IngestMode, ingest_id: Option<i32>) -> Self {
         InsertBatch {
+            mode,
+            backend: LoaderBackend::default(),
+            ingest_id,
             mbp_values: Vec::new(),
             bid_ask_batches: Vec::new(),
+            interval_ns: None,
+            trade_values: Vec::new(),
+            ohlcv_values: Vec::new(),
+            bbo_values: Vec::new(),
         }
     }
 
-    pub async fn process(&mut self, record: &Mbp1Msg) -> Result<()> {
-        // Compute the order book hash based on levels
-        let order_book_hash = compute_order_book_hash(&record.levels);
-
-        // Add the current mbp row to the batch
-        self.mbp_values.push((
-            record.hd.instrument_id as i32,
-            record.hd.ts_event as i64,
-            record.price,
-            record.size as i32,
-            record.action as i32,
-            record.side as i32,
-            record.flags as i32,
-            record.ts_recv as i64,
-            record.ts_in_delta,
-            record.sequence as i32,
-            record.discriminator as i32, 
-            order_book_hash
-        ));
-
-        // Collect bid_ask rows associated with this mbp record
-        let mut bid_ask_for_mbp: Vec<(i32, i64, i32, i32, i64, i32, i32)> = Vec::new();
-        for (depth, level) in record.levels.iter().enumerate() {
-            bid_ask_for_mbp.push((
-                depth as i32,       // Depth
-                level.bid_px,       // Bid price
-                level.bid_sz as i32, // Bid size
-                level.bid_ct as i32, // Bid count
-                level.ask_px,       // Ask price
-                level.ask_sz as i32, // Ask size
-                level.ask_ct as i32  // Ask count
-            ));
+    pub fn with_interval(mut self, interval_ns: Option<i64>) -> Self {
+        self.interval_ns = interval_ns;
+        self
+    }
+
+    pub fn with_backend(mut self, backend: LoaderBackend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.mbp_values.len()
+            + self.trade_values.len()
+            + self.ohlcv_values.len()
+            + self.bbo_values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn interval(&self) -> Result<i64> {
+        self.interval_ns.ok_or_else(|| {
+            crate::error!(
+                CustomError,
+                "Bar and quote records require the schema of the upload, e.g. ohlcv-1m."
+            )
+        })
+    }
+
+    fn on_conflict(&self, target: &str) -> String {
+        match self.mode {
+            IngestMode::Strict | IngestMode::Replace => String::new(),
+            IngestMode::SkipDuplicates => format!("ON CONFLICT {} DO NOTHING", target),
         }
-        self.bid_ask_batches.push(bid_ask_for_mbp);
+    }
+
+    pub async fn process_trade(&mut self, record: &TradeMsg) -> Result<()> {
+        self.trade_values.push(*record);
+        Ok(())
+    }
+
+    pub async fn process_ohlcv(&mut self, record: &OhlcvMsg) -> Result<()> {
+        self.interval()?;
+        self.ohlcv_values.push(*record);
+        Ok(())
+    }
+
+    pub async fn process_bbo(&mut self, record: &BboMsg) -> Result<()> {
+        self.interval()?;
+        self.bbo_values.push(*record);
+        Ok(())
+    }
+
+    pub async fn process(&mut self, record: &Mbp1Msg) -> Result<()> {
+        let (row, levels) = mbp1_rows(record);
+        self.push_mbp(row, levels);
+        Ok(())
+    }
+
+    /// Adds an MBP-10 record: one mbp row and its ten book levels, depth 0 being the top.
+    pub async fn process_mbp10(&mut self, record: &Mbp10Msg) -> Result<()> {
+        let (row, levels) = record.rows();
+        self.push_mbp(row, levels);
         Ok(())
     }
 
-    pub async fn execute(&mut self, tx: &mut Transaction<'_, Postgres>) -> Result<()> {
+    fn push_mbp(&mut self, mut row: MbpRow, levels: Vec<BidAskRow>) {
+        row.dedup_key = dedup_key(&row, &levels);
+        self.mbp_values.push(row);
+        self.bid_ask_batches.push(levels);
+    }
+
+    /// Inserts the mbp rows and returns `(mbp_id, batch index)` for every row actually inserted.
+    async fn insert_mbp(&self, tx: &mut Transaction<'_, Postgres>) -> Result<Vec<(i64, usize)>> {
         // Manually unpack mbp_values into separate vectors
         let mut instrument_ids = Vec::new();
         let mut ts_events = Vec::new();
@@ -100,32 +472,169 @@ impl InsertBatch {
         let mut ts_in_deltas = Vec::new();
         let mut sequences = Vec::new();
         let mut discriminators = Vec::new();
-        let mut order_book_hashes = Vec::new();
-
-        for (id, ts, price, size, action, side, flag, recv, delta, seq, discrim, hash) in &self.mbp_values {
-            instrument_ids.push(*id);
-            ts_events.push(*ts);
-            prices.push(*price);
-            sizes.push(*size);
-            actions.push(*action);
-            sides.push(*side);
-            flags.push(*flag);
-            ts_recvs.push(*recv);
-            ts_in_deltas.push(*delta);
-            sequences.push(*seq);
-            discriminators.push(*discrim);
-            order_book_hashes.push(hash.as_str()); // Use &str for the string
+        let mut dedup_keys = Vec::new();
+
+        for row in &self.mbp_values {
+            instrument_ids.push(row.instrument_id);
+            ts_events.push(row.ts_event);
+            prices.push(row.price);
+            sizes.push(row.size);
+            actions.push(row.action);
+            sides.push(row.side);
+            flags.push(row.flags);
+            ts_recvs.push(row.ts_recv);
+            ts_in_deltas.push(row.ts_in_delta);
+            sequences.push(row.sequence);
+            discriminators.push(row.discriminator);
+            dedup_keys.push(row.dedup_key);
+        }
+
+        match self.mode {
+            IngestMode::Strict | IngestMode::Replace => {
+                let mbp_ids: Vec<i64> = sqlx::query_scalar(
+                    r#"
+                    INSERT INTO mbp (instrument_id, ts_event, price, size, action, side, flags, ts_recv, ts_in_delta, sequence, discriminator, dedup_key, ingest_id)
+                    SELECT *, $13::int FROM UNNEST($1::int[], $2::bigint[], $3::bigint[], $4::int[], $5::int[], $6::int[], $7::int[], $8::bigint[], $9::int[], $10::int[], $11::int[], $12::bigint[])
+                    RETURNING id
+                    "#
+                )
+                .bind(&instrument_ids)
+                .bind(&ts_events)
+                .bind(&prices)
+                .bind(&sizes)
+                .bind(&actions)
+                .bind(&sides)
+                .bind(&flags)
+                .bind(&ts_recvs)
+                .bind(&ts_in_deltas)
+                .bind(&sequences)
+                .bind(&discriminators)
+                .bind(&dedup_keys)
+                .bind(self.ingest_id)
+                .fetch_all(&mut *tx)
+                .await?;
+
+                Ok(mbp_ids.into_iter().enumerate().map(|(idx, id)| (id, idx)).collect())
+            }
+            IngestMode::SkipDuplicates => {
+                // RETURNING only covers inserted rows, so join them back to their input
+                // position to keep bid_ask aligned. DISTINCT ON handles duplicates within the batch.
+                let rows: Vec<(i64, i64)> = sqlx::query_as(
+                    r#"
+                    WITH input AS (
+                        SELECT * FROM UNNEST($1::int[], $2::bigint[], $3::bigint[], $4::int[], $5::int[], $6::int[], $7::int[], $8::bigint[], $9::int[], $10::int[], $11::int[], $12::bigint[])
+                        WITH ORDINALITY AS t(instrument_id, ts_event, price, size, action, side, flags, ts_recv, ts_in_delta, sequence, discriminator, dedup_key, idx)
+                    ),
+                    inserted AS (
+                        INSERT INTO mbp (instrument_id, ts_event, price, size, action, side, flags, ts_recv, ts_in_delta, sequence, discriminator, dedup_key, ingest_id)
+                        SELECT instrument_id, ts_event, price, size, action, side, flags, ts_recv, ts_in_delta, sequence, discriminator, dedup_key, $13::int
+                        FROM input
+                        ORDER BY idx
+                        ON CONFLICT (instrument_id, ts_event, dedup_key, ts_recv) WHERE superseded_by IS NULL DO NOTHING
+                        RETURNING id, instrument_id, ts_event, dedup_key
+                    )
+                    SELECT DISTINCT ON (ins.id) ins.id, inp.idx
+                    FROM inserted ins
+                    INNER JOIN input inp
+                        ON ins.instrument_id = inp.instrument_id
+                        AND ins.ts_event = inp.ts_event
+                        AND ins.dedup_key = inp.dedup_key
+                    ORDER BY ins.id, inp.idx
+                    "#
+                )
+                .bind(&instrument_ids)
+                .bind(&ts_events)
+                .bind(&prices)
+                .bind(&sizes)
+                .bind(&actions)
+                .bind(&sides)
+                .bind(&flags)
+                .bind(&ts_recvs)
+                .bind(&ts_in_deltas)
+                .bind(&sequences)
+                .bind(&discriminators)
+                .bind(&dedup_keys)
+                .bind(self.ingest_id)
+                .fetch_all(&mut *tx)
+                .await?;
+
+                // WITH ORDINALITY is 1-based
+                let mut ids: Vec<(i64, usize)> = rows
+                    .into_iter()
+                    .map(|(id, idx)| (id, (idx - 1) as usize))
+                    .collect();
+                ids.sort_by_key(|(_, idx)| *idx);
+                Ok(ids)
+            }
+        }
+    }
+
+    /// Inserts the batch in the transaction. The rollup bars of its trades are only queued, they
+    /// are refreshed by `refresh_queued_rollups` once the transaction is committed.
+    pub async fn execute(&mut self, tx: &mut Transaction<'_, Postgres>) -> Result<BatchSummary> {
+        let mut inserted = 0;
+        if !self.mbp_values.is_empty() {
+            let ts_recvs: Vec<i64> = self.mbp_values.iter().map(|row| row.ts_recv).collect();
+            ensure_partitions(tx, &ts_recvs, PartitionInterval::from_env()?).await?;
+
+            inserted += match self.backend {
+                LoaderBackend::Unnest => self.execute_unnest(tx).await?,
+                LoaderBackend::Copy => self.execute_copy(tx).await?,
+            };
+
+            let trades: Vec<(i32, i64)> = self
+                .mbp_values
+                .iter()
+                .filter(|row| row.action == 84) // 'T' in ASCII
+                .map(|row| (row.instrument_id, row.ts_recv))
+                .collect();
+            queue_rollups(tx, &trades).await?;
+        }
+        inserted += self.insert_trades(tx).await?;
+        inserted += self.insert_ohlcv(tx).await?;
+        inserted += self.insert_bbo(tx).await?;
+
+        let summary = BatchSummary {
+            inserted,
+            skipped: self.len() - inserted,
+        };
+
+        // Clear the batch after committing
+        self.mbp_values.clear();
+        self.bid_ask_batches.clear();
+        self.trade_values.clear();
+        self.ohlcv_values.clear();
+        self.bbo_values.clear();
+
+        Ok(summary)
+    }
+
+    /// Returns the number of trade rows inserted.
+    async fn insert_trades(&self, tx: &mut Transaction<'_, Postgres>) -> Result<usize> {
+        if self.trade_values.is_empty() {
+            return Ok(0);
         }
 
-        // INsert into mbp
-        // ON CONFLICT DO NOT/* H */ING
-        let mbp_ids: Vec<i32> = sqlx::query_scalar(
+        let records = &self.trade_values;
+        let instrument_ids: Vec<i32> = records.iter().map(|r| r.hd.instrument_id as i32).collect();
+        let ts_events: Vec<i64> = records.iter().map(|r| r.hd.ts_event as i64).collect();
+        let prices: Vec<i64> = records.iter().map(|r| r.price).collect();
+        let sizes: Vec<i32> = records.iter().map(|r| r.size as i32).collect();
+        let actions: Vec<i32> = records.iter().map(|r| r.action as i32).collect();
+        let sides: Vec<i32> = records.iter().map(|r| r.side as i32).collect();
+        let flags: Vec<i32> = records.iter().map(|r| r.flags as i32).collect();
+        let ts_recvs: Vec<i64> = records.iter().map(|r| r.ts_recv as i64).collect();
+        let ts_in_deltas: Vec<i32> = records.iter().map(|r| r.ts_in_delta).collect();
+        let sequences: Vec<i32> = records.iter().map(|r| r.sequence as i32).collect();
+
+        let result = sqlx::query(&format!(
             r#"
-            INSERT INTO mbp (instrument_id, ts_event, price, size, action, side, flags, ts_recv, ts_in_delta, sequence, discriminator, order_book_hash)
-            SELECT * FROM UNNEST($1::int[], $2::bigint[], $3::bigint[], $4::int[], $5::int[], $6::int[], $7::int[], $8::bigint[], $9::int[], $10::int[], $11::int[], $12::text[])
-            RETURNING id
-            "#
-        )
+            INSERT INTO trade (instrument_id, ts_event, price, size, action, side, flags, ts_recv, ts_in_delta, sequence, ingest_id)
+            SELECT *, $11::int FROM UNNEST($1::int[], $2::bigint[], $3::bigint[], $4::int[], $5::int[], $6::int[], $7::int[], $8::bigint[], $9::int[], $10::int[])
+            {}
+            "#,
+            self.on_conflict("ON CONSTRAINT unique_trade")
+        ))
         .bind(&instrument_ids)
         .bind(&ts_events)
         .bind(&prices)
@@ -136,12 +645,243 @@ impl InsertBatch {
         .bind(&ts_recvs)
         .bind(&ts_in_deltas)
         .bind(&sequences)
-        .bind(&discriminators)
-        .bind(&order_book_hashes) 
-        .fetch_all(&mut *tx)
+        .bind(self.ingest_id)
+        .execute(&mut *tx)
+        .await?;
+
+        Ok(result.rows_affected() as usize)
+    }
+
+    /// Returns the number of ohlcv rows inserted.
+    async fn insert_ohlcv(&self, tx: &mut Transaction<'_, Postgres>) -> Result<usize> {
+        if self.ohlcv_values.is_empty() {
+            return Ok(0);
+        }
+
+        let records = &self.ohlcv_values;
+        let instrument_ids: Vec<i32> = records.iter().map(|r| r.hd.instrument_id as i32).collect();
+        let ts_events: Vec<i64> = records.iter().map(|r| r.hd.ts_event as i64).collect();
+        let opens: Vec<i64> = records.iter().map(|r| r.open).collect();
+        let highs: Vec<i64> = records.iter().map(|r| r.high).collect();
+        let lows: Vec<i64> = records.iter().map(|r| r.low).collect();
+        let closes: Vec<i64> = records.iter().map(|r| r.close).collect();
+        let volumes: Vec<i64> = records.iter().map(|r| r.volume as i64).collect();
+
+        let result = sqlx::query(&format!(
+            r#"
+            INSERT INTO ohlcv (instrument_id, ts_event, open, high, low, close, volume, interval_ns, ingest_id)
+            SELECT *, $8::bigint, $9::int FROM UNNEST($1::int[], $2::bigint[], $3::bigint[], $4::bigint[], $5::bigint[], $6::bigint[], $7::bigint[])
+            {}
+            "#,
+            self.on_conflict("ON CONSTRAINT unique_ohlcv")
+        ))
+        .bind(&instrument_ids)
+        .bind(&ts_events)
+        .bind(&opens)
+        .bind(&highs)
+        .bind(&lows)
+        .bind(&closes)
+        .bind(&volumes)
+        .bind(self.interval()?)
+        .bind(self.ingest_id)
+        .execute(&mut *tx)
+        .await?;
+
+        Ok(result.rows_affected() as usize)
+    }
+
+    /// Returns the number of bbo rows inserted.
+    async fn insert_bbo(&self, tx: &mut Transaction<'_, Postgres>) -> Result<usize> {
+        if self.bbo_values.is_empty() {
+            return Ok(0);
+        }
+
+        let records = &self.bbo_values;
+        let instrument_ids: Vec<i32> = records.iter().map(|r| r.hd.instrument_id as i32).collect();
+        let ts_events: Vec<i64> = records.iter().map(|r| r.hd.ts_event as i64).collect();
+        let ts_recvs: Vec<i64> = records.iter().map(|r| r.ts_recv as i64).collect();
+        let prices: Vec<i64> = records.iter().map(|r| r.price).collect();
+        let sizes: Vec<i32> = records.iter().map(|r| r.size as i32).collect();
+        let sides: Vec<i32> = records.iter().map(|r| r.side as i32).collect();
+        let flags: Vec<i32> = records.iter().map(|r| r.flags as i32).collect();
+        let sequences: Vec<i32> = records.iter().map(|r| r.sequence as i32).collect();
+        let bid_px: Vec<i64> = records.iter().map(|r| r.levels[0].bid_px).collect();
+        let bid_sz: Vec<i32> = records.iter().map(|r| r.levels[0].bid_sz as i32).collect();
+        let bid_ct: Vec<i32> = records.iter().map(|r| r.levels[0].bid_ct as i32).collect();
+        let ask_px: Vec<i64> = records.iter().map(|r| r.levels[0].ask_px).collect();
+        let ask_sz: Vec<i32> = records.iter().map(|r| r.levels[0].ask_sz as i32).collect();
+        let ask_ct: Vec<i32> = records.iter().map(|r| r.levels[0].ask_ct as i32).collect();
+
+        let result = sqlx::query(&format!(
+            r#"
+            INSERT INTO bbo (instrument_id, ts_event, ts_recv, price, size, side, flags, sequence, bid_px, bid_sz, bid_ct, ask_px, ask_sz, ask_ct, interval_ns, ingest_id)
+            SELECT *, $15::bigint, $16::int FROM UNNEST($1::int[], $2::bigint[], $3::bigint[], $4::bigint[], $5::int[], $6::int[], $7::int[], $8::int[], $9::bigint[], $10::int[], $11::int[], $12::bigint[], $13::int[], $14::int[])
+            {}
+            "#,
+            self.on_conflict("ON CONSTRAINT unique_bbo")
+        ))
+        .bind(&instrument_ids)
+        .bind(&ts_events)
+        .bind(&ts_recvs)
+        .bind(&prices)
+        .bind(&sizes)
+        .bind(&sides)
+        .bind(&flags)
+        .bind(&sequences)
+        .bind(&bid_px)
+        .bind(&bid_sz)
+        .bind(&bid_ct)
+        .bind(&ask_px)
+        .bind(&ask_sz)
+        .bind(&ask_ct)
+        .bind(self.interval()?)
+        .bind(self.ingest_id)
+        .execute(&mut *tx)
+        .await?;
+
+        Ok(result.rows_affected() as usize)
+    }
+
+    /// Writes the batch to staging tables with binary `COPY`, then merges it into `mbp` and
+    /// `bid_ask` in a single statement. Returns the number of mbp rows inserted.
+    async fn execute_copy(&self, tx: &mut Transaction<'_, Postgres>) -> Result<usize> {
+        // Temporary tables live for the connection, so pooled connections reuse them
+        sqlx::query(
+            r#"
+            CREATE TEMP TABLE IF NOT EXISTS mbp_staging (
+                idx INTEGER NOT NULL,
+                instrument_id INTEGER NOT NULL,
+                ts_event BIGINT NOT NULL,
+                price BIGINT NOT NULL,
+                size INTEGER NOT NULL,
+                action INTEGER NOT NULL,
+                side INTEGER NOT NULL,
+                flags INTEGER NOT NULL,
+                ts_recv BIGINT NOT NULL,
+                ts_in_delta INTEGER NOT NULL,
+                sequence INTEGER NOT NULL,
+                discriminator INTEGER NOT NULL,
+                dedup_key BIGINT NOT NULL
+            )
+            "#,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+            CREATE TEMP TABLE IF NOT EXISTS bid_ask_staging (
+                idx INTEGER NOT NULL,
+                depth INTEGER NOT NULL,
+                bid_px BIGINT NOT NULL,
+                bid_sz INTEGER NOT NULL,
+                bid_ct INTEGER NOT NULL,
+                ask_px BIGINT NOT NULL,
+                ask_sz INTEGER NOT NULL,
+                ask_ct INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query("TRUNCATE mbp_staging, bid_ask_staging")
+            .execute(&mut *tx)
+            .await?;
+
+        let mut mbp_rows = CopyBuffer::new();
+        let mut bid_ask_rows = CopyBuffer::new();
+        for (idx, row) in self.mbp_values.iter().enumerate() {
+            mbp_rows.row(13);
+            mbp_rows.int4(idx as i32);
+            mbp_rows.int4(row.instrument_id);
+            mbp_rows.int8(row.ts_event);
+            mbp_rows.int8(row.price);
+            mbp_rows.int4(row.size);
+            mbp_rows.int4(row.action);
+            mbp_rows.int4(row.side);
+            mbp_rows.int4(row.flags);
+            mbp_rows.int8(row.ts_recv);
+            mbp_rows.int4(row.ts_in_delta);
+            mbp_rows.int4(row.sequence);
+            mbp_rows.int4(row.discriminator);
+            mbp_rows.int8(row.dedup_key);
+
+            for (depth, bid_px, bid_sz, bid_ct, ask_px, ask_sz, ask_ct) in &self.bid_ask_batches[idx] {
+                bid_ask_rows.row(8);
+                bid_ask_rows.int4(idx as i32);
+                bid_ask_rows.int4(*depth);
+                bid_ask_rows.int8(*bid_px);
+                bid_ask_rows.int4(*bid_sz);
+                bid_ask_rows.int4(*bid_ct);
+                bid_ask_rows.int8(*ask_px);
+                bid_ask_rows.int4(*ask_sz);
+                bid_ask_rows.int4(*ask_ct);
+            }
+        }
+
+        let mut copy = tx
+            .copy_in_raw(
+                "COPY mbp_staging (idx, instrument_id, ts_event, price, size, action, side, flags, ts_recv, ts_in_delta, sequence, discriminator, dedup_key) FROM STDIN (FORMAT binary)",
+            )
+            .await?;
+        copy.send(mbp_rows.finish()).await?;
+        copy.finish().await?;
+
+        let mut copy = tx
+            .copy_in_raw(
+                "COPY bid_ask_staging (idx, depth, bid_px, bid_sz, bid_ct, ask_px, ask_sz, ask_ct) FROM STDIN (FORMAT binary)",
+            )
+            .await?;
+        copy.send(bid_ask_rows.finish()).await?;
+        copy.finish().await?;
+
+        let on_conflict = self.on_conflict(MBP_CONFLICT_TARGET);
+
+        // Inserted rows are joined back to their staging position so their levels follow them
+        let inserted: i64 = sqlx::query_scalar(&format!(
+            r#"
+            WITH inserted AS (
+                INSERT INTO mbp (instrument_id, ts_event, price, size, action, side, flags, ts_recv, ts_in_delta, sequence, discriminator, dedup_key, ingest_id)
+                SELECT instrument_id, ts_event, price, size, action, side, flags, ts_recv, ts_in_delta, sequence, discriminator, dedup_key, $1::int
+                FROM mbp_staging
+                ORDER BY idx
+                {}
+                RETURNING id, instrument_id, ts_event, dedup_key, ts_recv
+            ),
+            matched AS (
+                SELECT DISTINCT ON (ins.id) ins.id, ins.ts_recv, stg.idx
+                FROM inserted ins
+                INNER JOIN mbp_staging stg
+                    ON ins.instrument_id = stg.instrument_id
+                    AND ins.ts_event = stg.ts_event
+                    AND ins.dedup_key = stg.dedup_key
+                ORDER BY ins.id, stg.idx
+            ),
+            levels AS (
+                INSERT INTO bid_ask (mbp_id, ts_recv, depth, bid_px, bid_sz, bid_ct, ask_px, ask_sz, ask_ct)
+                SELECT m.id, m.ts_recv, b.depth, b.bid_px, b.bid_sz, b.bid_ct, b.ask_px, b.ask_sz, b.ask_ct
+                FROM matched m
+                INNER JOIN bid_ask_staging b ON b.idx = m.idx
+            )
+            SELECT COUNT(*) FROM matched
+            "#,
+            on_conflict
+        ))
+        .bind(self.ingest_id)
+        .fetch_one(&mut *tx)
         .await?;
 
+        Ok(inserted as usize)
+    }
+
+    /// Inserts the batch with `UNNEST` and returns the number of mbp rows inserted.
+    async fn execute_unnest(&self, tx: &mut Transaction<'_, Postgres>) -> Result<usize> {
+        let mbp_ids = self.insert_mbp(tx).await?;
+
         // Create separate vectors for each field
+        let mut ids = Vec::new();
+        let mut ts_recvs = Vec::new();
         let mut depths = Vec::new();
         let mut bid_px = Vec::new();
         let mut bid_sz = Vec::new();
@@ -150,12 +890,17 @@ impl InsertBatch {
         let mut ask_sz = Vec::new();
         let mut ask_ct = Vec::new();
 
-        for (idx, _mbp_id) in mbp_ids.iter().enumerate() {
-            let bid_ask_for_current_mbp = &self.bid_ask_batches[idx];
-
+        // Only the levels of mbp rows that were actually inserted
+        for (mbp_id, idx) in &mbp_ids {
+            let bid_ask_for_current_mbp = &self.bid_ask_batches[*idx];
+            let ts_recv = self.mbp_values[*idx].ts_recv;
 
             // Unpack the tuples into their respective vectors
-            for (depth, bid_price, bid_size, bid_count, ask_price, ask_size, ask_count) in bid_ask_for_current_mbp {
+            for (depth, bid_price, bid_size, bid_count, ask_price, ask_size, ask_count) in
+                bid_ask_for_current_mbp
+            {
+                ids.push(*mbp_id);
+                ts_recvs.push(ts_recv);
                 depths.push(*depth);
                 bid_px.push(*bid_price);
                 bid_sz.push(*bid_size);
@@ -166,14 +911,15 @@ impl InsertBatch {
             }
         }
 
-        // Insert all bid_ask levels associated with this mbp_id
+        // Insert all bid_ask levels associated with the inserted mbp rows
         sqlx::query(
             r#"
-            INSERT INTO bid_ask (mbp_id, depth, bid_px, bid_sz, bid_ct, ask_px, ask_sz, ask_ct)
-            SELECT * FROM UNNEST($1::int[], $2::int[], $3::bigint[], $4::int[], $5::int[], $6::bigint[], $7::int[], $8::int[])
+            INSERT INTO bid_ask (mbp_id, ts_recv, depth, bid_px, bid_sz, bid_ct, ask_px, ask_sz, ask_ct)
+            SELECT * FROM UNNEST($1::bigint[], $2::bigint[], $3::int[], $4::bigint[], $5::int[], $6::int[], $7::bigint[], $8::int[], $9::int[])
             "#
         )
-        .bind(mbp_ids)
+        .bind(&ids)
+        .bind(&ts_recvs)
         .bind(&depths)
         .bind(&bid_px)
         .bind(&bid_sz)
@@ -184,16 +930,10 @@ impl InsertBatch {
         .execute(&mut *tx)
         .await?;
 
-        // Clear the batch after committing
-        self.mbp_values.clear();
-        self.bid_ask_batches.clear();
-
-        Ok(())
+        Ok(mbp_ids.len())
     }
-
 }
 
-
 // Utility function for testing
 #[async_trait]
 pub trait RecordInsertQueries {
@@ -204,13 +944,12 @@ pub trait RecordInsertQueries {
 #[async_trait]
 impl RecordInsertQueries for Mbp1Msg {
     async fn insert_query(&self, tx: &mut Transaction<'_, Postgres>) -> Result<()> {
-        // Compute the order book hash based on levels
-        let order_book_hash = compute_order_book_hash(&self.levels);
+        ensure_partitions(tx, &[self.ts_recv as i64], PartitionInterval::from_env()?).await?;
 
         // Insert into mbp table
-        let mbp_id: i32 = sqlx::query_scalar(
+        let mbp_id: i64 = sqlx::query_scalar(
             r#"
-            INSERT INTO mbp (instrument_id, ts_event, price, size, action, side,flags, ts_recv, ts_in_delta, sequence, discriminator, order_book_hash)
+            INSERT INTO mbp (instrument_id, ts_event, price, size, action, side,flags, ts_recv, ts_in_delta, sequence, discriminator, dedup_key)
             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)
             RETURNING id
             "#
@@ -226,7 +965,7 @@ impl RecordInsertQueries for Mbp1Msg {
         .bind(self.ts_in_delta)
         .bind(self.sequence as i32)
         .bind(self.discriminator as i32)
-        .bind(&order_book_hash) // Bind the computed hash
+        .bind(compute_dedup_key(self))
         .fetch_one(&mut *tx)
         .await?;
      
@@ -234,11 +973,12 @@ impl RecordInsertQueries for Mbp1Msg {
         for (depth, level) in self.levels.iter().enumerate() {
             let _ = sqlx::query(
                 r#"
-                INSERT INTO bid_ask (mbp_id, depth, bid_px, bid_sz, bid_ct, ask_px, ask_sz, ask_ct)
-                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                INSERT INTO bid_ask (mbp_id, ts_recv, depth, bid_px, bid_sz, bid_ct, ask_px, ask_sz, ask_ct)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
                 "#,
             )
             .bind(mbp_id)
+            .bind(self.ts_recv as i64)
             .bind(depth as i32) // Using the index as depth
             .bind(level.bid_px)
             .bind(level.bid_sz as i32)
@@ -250,6 +990,10 @@ impl RecordInsertQueries for Mbp1Msg {
             .await?;
         }
 
+        if self.action == 84 {
+            refresh_rollups(tx, &[(self.hd.instrument_id as i32, self.ts_recv as i64)]).await?;
+        }
+
         Ok(())
     }
 }
@@ -259,7 +1003,10 @@ impl RecordInsertQueries for Mbp1Msg {
 mod test {
     use super::*;
     use crate::database::init::init_db;
+    use crate::database::market_data::rollup::refresh_queued_rollups;
     use crate::database::symbols::*;
+    use crate::database::versions::{DataVersion, VersionKind};
+    use crate::test_utils::{delete_instrument, mbp1};
     use mbn::enums::{Action, Side};
     use mbn::record_enum::RecordEnum;
     use mbn::symbols::Instrument;
@@ -314,7 +1061,7 @@ mod test {
     }
 
     async fn insert_batch_records( pool: PgPool, filepath: PathBuf) -> anyhow::Result<()> {
-        let mut insert_batch = InsertBatch::new();
+        let mut insert_batch = InsertBatch::new(IngestMode::Strict, None);
 
         // Create a stream to send updates
         let mut decoder = RecordDecoder::<BufReader<File>>::from_file(&filepath)?;
@@ -575,7 +1322,7 @@ mod test {
             },
         ];
 
-        let mut insert_batch = InsertBatch::new();
+        let mut insert_batch = InsertBatch::new(IngestMode::Strict, None);
 
         for record in &records {
             insert_batch.process(record).await?;
@@ -607,5 +1354,386 @@ mod test {
         Ok(())
 
     }
-}
 
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_create_duplicate_skip() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+
+        let instrument_id = create_instrument(&pool)
+            .await
+            .expect("Error creating instrument.");
+
+        // Mock data
+        let record = Mbp1Msg {
+            hd: { RecordHeader::new::<Mbp1Msg>(instrument_id as u32, 1704295503644092562) },
+            price: 6870,
+            size: 2,
+            action: Action::Add as c_char,
+            side: Side::Bid as c_char,
+            depth: 0,
+            flags: 0,
+            ts_recv: 1704209103644092562,
+            ts_in_delta: 17493,
+            sequence: 739763,
+            discriminator: 0,
+            levels: [BidAskPair {
+                bid_px: 1,
+                ask_px: 1,
+                bid_sz: 1,
+                ask_sz: 1,
+                bid_ct: 10,
+                ask_ct: 20,
+            }],
+        };
+
+        // First batch inserts, second batch is entirely duplicates
+        let mut insert_batch = InsertBatch::new(IngestMode::SkipDuplicates, None);
+        insert_batch.process(&record).await?;
+        insert_batch.process(&record).await?;
+
+        let mut transaction = pool.begin().await?;
+        let first = insert_batch.execute(&mut transaction).await?;
+        transaction.commit().await?;
+
+        insert_batch.process(&record).await?;
+        let mut transaction = pool.begin().await?;
+        let second = insert_batch.execute(&mut transaction).await?;
+        transaction.commit().await?;
+
+        // Validate
+        assert_eq!(first, BatchSummary { inserted: 1, skipped: 1 });
+        assert_eq!(second, BatchSummary { inserted: 0, skipped: 1 });
+
+        let bid_ask_rows: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM bid_ask b INNER JOIN mbp m ON m.id = b.mbp_id WHERE m.instrument_id = $1",
+        )
+        .bind(instrument_id)
+        .fetch_one(&pool)
+        .await?;
+        assert_eq!(bid_ask_rows, 1);
+
+        // Cleanup
+        let mut transaction = pool
+            .begin()
+            .await
+            .expect("Error setting up test transaction.");
+        Instrument::delete_instrument(&mut transaction, instrument_id)
+            .await
+            .expect("Error on delete.");
+        let _ = transaction.commit().await;
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_supersede_mbp_range() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+
+        let instrument_id = create_instrument(&pool)
+            .await
+            .expect("Error creating instrument.");
+
+        // Mock data, a trade on each of two days
+        let day: i64 = 86_400_000_000_000;
+        let first: i64 = 1704209103644092562; // 2024-01-02
+        let records: Vec<Mbp1Msg> = [first, first + day]
+            .iter()
+            .map(|ts| mbp1(instrument_id, *ts as u64, 6870, Action::Trade))
+            .collect();
+
+        let mut insert_batch = InsertBatch::new(IngestMode::Strict, None);
+        for record in &records {
+            insert_batch.process(record).await?;
+        }
+        let mut transaction = pool.begin().await?;
+        insert_batch.execute(&mut transaction).await?;
+        transaction.commit().await?;
+        refresh_queued_rollups(&pool).await?;
+
+        // Test
+        let start = first - first % day;
+        let mut transaction = pool.begin().await?;
+        let version = DataVersion::create(&mut transaction, VersionKind::Delete, None).await?;
+        let superseded =
+            supersede_mbp_range(&mut transaction, version, &[instrument_id], start, start + day)
+                .await?;
+        transaction.commit().await?;
+
+        // Validate
+        assert_eq!(superseded, 1);
+
+        let rows: Vec<(i64, Option<i32>)> = sqlx::query_as(
+            "SELECT ts_recv, superseded_by FROM mbp WHERE instrument_id = $1 ORDER BY ts_recv",
+        )
+        .bind(instrument_id)
+        .fetch_all(&pool)
+        .await?;
+        assert_eq!(rows, vec![(first, Some(version)), (first + day, None)]);
+
+        let bid_ask_rows: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM bid_ask b INNER JOIN mbp m ON m.id = b.mbp_id WHERE m.instrument_id = $1",
+        )
+        .bind(instrument_id)
+        .fetch_one(&pool)
+        .await?;
+        assert_eq!(bid_ask_rows, 2);
+
+        let first_available: i64 =
+            sqlx::query_scalar("SELECT first_available FROM instrument WHERE id = $1")
+                .bind(instrument_id)
+                .fetch_one(&pool)
+                .await?;
+        assert_eq!(first_available, first + day);
+
+        let bars: Vec<i64> = sqlx::query_scalar(
+            "SELECT ts_event FROM ohlcv_rollup WHERE instrument_id = $1 AND interval_ns = $2",
+        )
+        .bind(instrument_id)
+        .bind(day)
+        .fetch_all(&pool)
+        .await?;
+        assert_eq!(bars, vec![start + day]);
+
+        // Cleanup
+        delete_instrument(&pool, instrument_id).await?;
+        sqlx::query("DELETE FROM data_version WHERE id = $1")
+            .bind(version)
+            .execute(&pool)
+            .await?;
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_insert_batch_copy() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+
+        let instrument_id = create_instrument(&pool)
+            .await
+            .expect("Error creating instrument.");
+
+        // Mock data
+        let record = Mbp1Msg {
+            hd: { RecordHeader::new::<Mbp1Msg>(instrument_id as u32, 1704295503644092562) },
+            price: 6870,
+            size: 2,
+            action: Action::Add as c_char,
+            side: Side::Bid as c_char,
+            depth: 0,
+            flags: 0,
+            ts_recv: 1704209103644092562,
+            ts_in_delta: 17493,
+            sequence: 739763,
+            discriminator: 0,
+            levels: [BidAskPair {
+                bid_px: 1,
+                ask_px: 1,
+                bid_sz: 1,
+                ask_sz: 1,
+                bid_ct: 10,
+                ask_ct: 20,
+            }],
+        };
+        let mut record_2 = record;
+        record_2.sequence = 739764;
+
+        // First batch inserts, second batch has one duplicate
+        let mut insert_batch =
+            InsertBatch::new(IngestMode::SkipDuplicates, None).with_backend(LoaderBackend::Copy);
+        insert_batch.process(&record).await?;
+
+        let mut transaction = pool.begin().await?;
+        let first = insert_batch.execute(&mut transaction).await?;
+        transaction.commit().await?;
+
+        insert_batch.process(&record).await?;
+        insert_batch.process(&record_2).await?;
+        let mut transaction = pool.begin().await?;
+        let second = insert_batch.execute(&mut transaction).await?;
+        transaction.commit().await?;
+
+        // Validate
+        assert_eq!(first, BatchSummary { inserted: 1, skipped: 0 });
+        assert_eq!(second, BatchSummary { inserted: 1, skipped: 1 });
+
+        let bid_ask_rows: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM bid_ask b INNER JOIN mbp m ON m.id = b.mbp_id WHERE m.instrument_id = $1",
+        )
+        .bind(instrument_id)
+        .fetch_one(&pool)
+        .await?;
+        assert_eq!(bid_ask_rows, 2);
+
+        // Strict mode still rejects the duplicate
+        let mut strict_batch =
+            InsertBatch::new(IngestMode::Strict, None).with_backend(LoaderBackend::Copy);
+        strict_batch.process(&record).await?;
+        let mut transaction = pool.begin().await?;
+        let result = strict_batch.execute(&mut transaction).await;
+        transaction.rollback().await?;
+        assert!(result.is_err());
+
+        // Cleanup
+        let mut transaction = pool
+            .begin()
+            .await
+            .expect("Error setting up test transaction.");
+        Instrument::delete_instrument(&mut transaction, instrument_id)
+            .await
+            .expect("Error on delete.");
+        let _ = transaction.commit().await;
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_dedup_key_footprint() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let mut conn = pool.acquire().await?;
+
+        let records: Vec<Mbp1Msg> = (0..100_000)
+            .map(|i| Mbp1Msg {
+                hd: { RecordHeader::new::<Mbp1Msg>(1, 1704295503644092562 + i) },
+                price: 6870,
+                size: 2,
+                action: Action::Add as c_char,
+                side: Side::Bid as c_char,
+                depth: 0,
+                flags: 0,
+                ts_recv: 1704209103644092562 + i,
+                ts_in_delta: 17493,
+                sequence: i as u32,
+                discriminator: 0,
+                levels: [BidAskPair {
+                    bid_px: 1,
+                    ask_px: 1,
+                    bid_sz: 1,
+                    ask_sz: 1,
+                    bid_ct: 10,
+                    ask_ct: 20,
+                }],
+            })
+            .collect();
+
+        // Previous identity: 64-char hex hash of the levels in an 11-column constraint
+        let legacy_hashes: Vec<String> = records
+            .iter()
+            .map(|record| {
+                let mut hasher = Sha256::new();
+                for level in &record.levels {
+                    hasher.update(level.bid_px.to_be_bytes());
+                    hasher.update(level.ask_px.to_be_bytes());
+                    hasher.update(level.bid_sz.to_be_bytes());
+                    hasher.update(level.ask_sz.to_be_bytes());
+                    hasher.update(level.bid_ct.to_be_bytes());
+                    hasher.update(level.ask_ct.to_be_bytes());
+                }
+                hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+            })
+            .collect();
+        let dedup_keys: Vec<i64> = records.iter().map(compute_dedup_key).collect();
+
+        let columns = r#"
+            instrument_id INTEGER NOT NULL,
+            ts_event BIGINT NOT NULL,
+            price BIGINT NOT NULL,
+            size INTEGER NOT NULL,
+            action INTEGER NOT NULL,
+            side INTEGER NOT NULL,
+            flags INTEGER NOT NULL,
+            ts_recv BIGINT NOT NULL,
+            sequence INTEGER NOT NULL,
+            discriminator INTEGER NOT NULL,
+        "#;
+        // The current index, partial on current rows, from USING on
+        let index: String = sqlx::query_scalar(
+            "SELECT indexdef FROM pg_indexes WHERE indexname = 'unique_mbp_dedup_key'",
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+        let index_definition = &index[index.find(" USING ").unwrap_or(0)..];
+        let tables = [
+            (
+                "mbp_legacy_key",
+                "order_book_hash VARCHAR NOT NULL".to_string(),
+                "CREATE UNIQUE INDEX ON mbp_legacy_key (instrument_id, ts_event, price, size, flags, sequence, order_book_hash, ts_recv, action, side, discriminator)".to_string(),
+                "order_book_hash",
+                "text",
+            ),
+            (
+                "mbp_dedup_key",
+                "dedup_key BIGINT NOT NULL, superseded_by INTEGER".to_string(),
+                format!("CREATE UNIQUE INDEX ON mbp_dedup_key{}", index_definition),
+                "dedup_key",
+                "bigint",
+            ),
+        ];
+
+        let mut sizes = Vec::new();
+        for (table, key, index, key_column, key_type) in tables {
+            sqlx::query(&format!("CREATE TEMP TABLE {} ({} {})", table, columns, key))
+                .execute(&mut *conn)
+                .await?;
+            sqlx::query(&index).execute(&mut *conn).await?;
+
+            for (idx, chunk) in records.chunks(20_000).enumerate() {
+                let range = idx * 20_000..idx * 20_000 + chunk.len();
+                let query = format!(
+                    r#"
+                    INSERT INTO {} (instrument_id, ts_event, price, size, action, side, flags, ts_recv, sequence, discriminator, {})
+                    SELECT * FROM UNNEST($1::int[], $2::bigint[], $3::bigint[], $4::int[], $5::int[], $6::int[], $7::int[], $8::bigint[], $9::int[], $10::int[], $11::{}[])
+                    "#,
+                    table, key_column, key_type
+                );
+                let mut insert = sqlx::query(&query)
+                    .bind(chunk.iter().map(|r| r.hd.instrument_id as i32).collect::<Vec<_>>())
+                    .bind(chunk.iter().map(|r| r.hd.ts_event as i64).collect::<Vec<_>>())
+                    .bind(chunk.iter().map(|r| r.price).collect::<Vec<_>>())
+                    .bind(chunk.iter().map(|r| r.size as i32).collect::<Vec<_>>())
+                    .bind(chunk.iter().map(|r| r.action as i32).collect::<Vec<_>>())
+                    .bind(chunk.iter().map(|r| r.side as i32).collect::<Vec<_>>())
+                    .bind(chunk.iter().map(|r| r.flags as i32).collect::<Vec<_>>())
+                    .bind(chunk.iter().map(|r| r.ts_recv as i64).collect::<Vec<_>>())
+                    .bind(chunk.iter().map(|r| r.sequence as i32).collect::<Vec<_>>())
+                    .bind(chunk.iter().map(|r| r.discriminator as i32).collect::<Vec<_>>());
+                insert = if key_type == "text" {
+                    insert.bind(legacy_hashes[range].to_vec())
+                } else {
+                    insert.bind(dedup_keys[range].to_vec())
+                };
+                insert.execute(&mut *conn).await?;
+            }
+
+            let (table_bytes, index_bytes): (i64, i64) = sqlx::query_as(&format!(
+                "SELECT pg_table_size('{0}'), pg_indexes_size('{0}')",
+                table
+            ))
+            .fetch_one(&mut *conn)
+            .await?;
+            sizes.push((table_bytes, index_bytes));
+
+            sqlx::query(&format!("DROP TABLE {}", table))
+                .execute(&mut *conn)
+                .await?;
+        }
+
+        // Validate, the key shrinks the rows and at least halves the unique index
+        let (legacy, dedup) = (sizes[0], sizes[1]);
+        assert!(dedup.0 < legacy.0, "table not smaller: {:?}", sizes);
+        assert!(dedup.1 * 2 <= legacy.1, "index not halved: {:?}", sizes);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+use crate::database::market_data::create::IngestMode;
+use crate::database::market_data::read::{FromRow, RecordsQuery, RetrieveParams};
+use crate::Result;
+use async_trait::async_trait;
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Row, Transaction};
+use std::os::raw::c_char;
+use std::pin::Pin;
+use tracing::info;
+
+/// A market-by-order event. mbn has no MBO message yet, so these are uploaded as JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MboMsg {
+    pub instrument_id: u32,
+    pub ts_event: u64,
+    pub ts_recv: u64,
+    pub order_id: u64,
+    pub action: c_char,
+    pub side: c_char,
+    pub price: i64,
+    pub size: u32,
+    pub flags: u8,
+    pub sequence: u32,
+}
+
+/// Inserts the events and returns how many rows were written.
+pub async fn insert_mbo(
+    tx: &mut Transaction<'_, Postgres>,
+    records: &[MboMsg],
+    mode: IngestMode,
+    ingest_id: Option<i32>,
+) -> Result<usize> {
+    let instrument_ids: Vec<i32> = records.iter().map(|r| r.instrument_id as i32).collect();
+    let ts_events: Vec<i64> = records.iter().map(|r| r.ts_event as i64).collect();
+    let ts_recvs: Vec<i64> = records.iter().map(|r| r.ts_recv as i64).collect();
+    let order_ids: Vec<i64> = records.iter().map(|r| r.order_id as i64).collect();
+    let actions: Vec<i32> = records.iter().map(|r| r.action as i32).collect();
+    let sides: Vec<i32> = records.iter().map(|r| r.side as i32).collect();
+    let prices: Vec<i64> = records.iter().map(|r| r.price).collect();
+    let sizes: Vec<i32> = records.iter().map(|r| r.size as i32).collect();
+    let flags: Vec<i32> = records.iter().map(|r| r.flags as i32).collect();
+    let sequences: Vec<i32> = records.iter().map(|r| r.sequence as i32).collect();
+
+    let on_conflict = match mode {
+        IngestMode::Strict | IngestMode::Replace => "",
+        IngestMode::SkipDuplicates => "ON CONFLICT ON CONSTRAINT unique_mbo DO NOTHING",
+    };
+
+    let result = sqlx::query(&format!(
+        r#"
+        INSERT INTO mbo (instrument_id, ts_event, ts_recv, order_id, action, side, price, size, flags, sequence, ingest_id)
+        SELECT *, $11::int FROM UNNEST($1::int[], $2::bigint[], $3::bigint[], $4::bigint[], $5::int[], $6::int[], $7::bigint[], $8::int[], $9::int[], $10::int[])
+        {}
+        "#,
+        on_conflict
+    ))
+    .bind(&instrument_ids)
+    .bind(&ts_events)
+    .bind(&ts_recvs)
+    .bind(&order_ids)
+    .bind(&actions)
+    .bind(&sides)
+    .bind(&prices)
+    .bind(&sizes)
+    .bind(&flags)
+    .bind(&sequences)
+    .bind(ingest_id)
+    .execute(tx)
+    .await?;
+
+    Ok(result.rows_affected() as usize)
+}
+
+#[async_trait]
+impl RecordsQuery for MboMsg {
+    /// Every event before `end_ts` from the last clear at or before `start_ts` of each instrument,
+    /// which empties its book, so replays do not go through the history before it. Instruments
+    /// without such a clear are replayed from their first event.
+    async fn retrieve_query(
+        pool: &PgPool,
+        params: RetrieveParams,
+    ) -> Result<
+        Pin<Box<dyn Stream<Item = std::result::Result<sqlx::postgres::PgRow, sqlx::Error>> + Send>>,
+    > {
+        let version = params.as_of(pool).await?;
+        info!(
+            "Retrieving mbo events for symbols: {:?} start: {:?} end: {:?} as_of {:?}",
+            params.symbols, params.start_ts, params.end_ts, version
+        );
+
+        let cursor = sqlx::query(
+            r#"
+            WITH replay_start AS (
+                SELECT i.id, i.ticker, COALESCE(c.ts_recv, 0) AS ts_recv
+                FROM instrument i
+                LEFT JOIN LATERAL (
+                    SELECT m.ts_recv
+                    FROM mbo m
+                    WHERE m.instrument_id = i.id
+                    AND m.action = 82 -- clear, a literal so the partial index on clears applies
+                    AND m.ts_recv <= $1
+                    AND ($4::bigint IS NULL OR m.ingest_id IS NULL
+                        OR m.ingest_id IN (SELECT ingest_id FROM data_version WHERE seq <= $4))
+                    ORDER BY m.ts_recv DESC
+                    LIMIT 1
+                ) c ON true
+                WHERE i.ticker = ANY($3)
+            )
+            SELECT m.instrument_id, m.ts_event, m.ts_recv, m.order_id, m.action, m.side, m.price, m.size, m.flags, m.sequence, s.ticker
+            FROM replay_start s
+            INNER JOIN mbo m ON m.instrument_id = s.id
+            WHERE m.ts_recv >= s.ts_recv AND m.ts_recv < $2
+            AND ($4::bigint IS NULL OR m.ingest_id IS NULL
+                OR m.ingest_id IN (SELECT ingest_id FROM data_version WHERE seq <= $4))
+            ORDER BY m.ts_recv, m.sequence, m.id
+            "#,
+        )
+        .bind(params.start_ts)
+        .bind(params.end_ts)
+        .bind(params.symbols)
+        .bind(version)
+        .fetch(pool);
+
+        Ok(cursor)
+    }
+}
+
+impl FromRow for MboMsg {
+    fn from_row(row: &sqlx::postgres::PgRow) -> Result<Self> {
+        Ok(MboMsg {
+            instrument_id: row.try_get::<i32, _>("instrument_id")? as u32,
+            ts_event: row.try_get::<i64, _>("ts_event")? as u64,
+            ts_recv: row.try_get::<i64, _>("ts_recv")? as u64,
+            order_id: row.try_get::<i64, _>("order_id")? as u64,
+            action: row.try_get::<i32, _>("action")? as c_char,
+            side: row.try_get::<i32, _>("side")? as c_char,
+            price: row.try_get::<i64, _>("price")?,
+            size: row.try_get::<i32, _>("size")? as u32,
+            flags: row.try_get::<i32, _>("flags")? as u8,
+            sequence: row.try_get::<i32, _>("sequence")? as u32,
+        })
+    }
+}
@@ -0,0 +1,215 @@
+use crate::database::market_data::create::{BidAskRow, MbpRow};
+use crate::database::market_data::read::{FromRow, RecordsQuery, RetrieveParams};
+use crate::Result;
+use async_trait::async_trait;
+use futures::Stream;
+use mbn::records::{BidAskPair, Mbp1Msg, RecordHeader};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Row};
+use std::os::raw::c_char;
+use std::pin::Pin;
+use tracing::info;
+
+/// Schema of MBP-10 requests. mbn has no MBP-10 schema, so it is only known to this server.
+pub const MBP10_SCHEMA: &str = "mbp-10";
+
+/// Book levels of an MBP-10 record.
+pub const MBP10_DEPTH: usize = 10;
+
+/// A book level of an MBP-10 record.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BidAskLevel {
+    pub bid_px: i64,
+    pub ask_px: i64,
+    pub bid_sz: u32,
+    pub ask_sz: u32,
+    pub bid_ct: u32,
+    pub ask_ct: u32,
+}
+
+/// A market-by-price event with the top ten levels of the book, depth 0 first. mbn has no
+/// MBP-10 message yet, so these are uploaded and served as JSON. Stored as an mbp row and its
+/// bid_ask levels, the same tables as MBP-1, whose records are the depth 0 level of these.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Mbp10Msg {
+    pub instrument_id: u32,
+    pub ts_event: u64,
+    pub price: i64,
+    pub size: u32,
+    pub action: c_char,
+    pub side: c_char,
+    pub flags: u8,
+    pub ts_recv: u64,
+    pub ts_in_delta: i32,
+    pub sequence: u32,
+    pub levels: [BidAskLevel; MBP10_DEPTH],
+}
+
+impl Mbp10Msg {
+    /// The mbp row and book levels of the record, without its dedup key.
+    pub fn rows(&self) -> (MbpRow, Vec<BidAskRow>) {
+        let row = MbpRow {
+            instrument_id: self.instrument_id as i32,
+            ts_event: self.ts_event as i64,
+            price: self.price,
+            size: self.size as i32,
+            action: self.action as i32,
+            side: self.side as i32,
+            flags: self.flags as i32,
+            ts_recv: self.ts_recv as i64,
+            ts_in_delta: self.ts_in_delta,
+            sequence: self.sequence as i32,
+            discriminator: 0,
+            dedup_key: 0,
+        };
+        let levels = self
+            .levels
+            .iter()
+            .enumerate()
+            .map(|(depth, level)| {
+                (
+                    depth as i32,
+                    level.bid_px,
+                    level.bid_sz as i32,
+                    level.bid_ct as i32,
+                    level.ask_px,
+                    level.ask_sz as i32,
+                    level.ask_ct as i32,
+                )
+            })
+            .collect();
+        (row, levels)
+    }
+
+    /// The MBP-1 record of the depth 0 level.
+    pub fn top_of_book(&self, discriminator: u32) -> Mbp1Msg {
+        let top = self.levels[0];
+        Mbp1Msg {
+            hd: RecordHeader::new::<Mbp1Msg>(self.instrument_id, self.ts_event),
+            price: self.price,
+            size: self.size,
+            action: self.action,
+            side: self.side,
+            depth: 0,
+            flags: self.flags,
+            ts_recv: self.ts_recv,
+            ts_in_delta: self.ts_in_delta,
+            sequence: self.sequence,
+            discriminator,
+            levels: [BidAskPair {
+                bid_px: top.bid_px,
+                ask_px: top.ask_px,
+                bid_sz: top.bid_sz,
+                ask_sz: top.ask_sz,
+                bid_ct: top.bid_ct,
+                ask_ct: top.ask_ct,
+            }],
+        }
+    }
+}
+
+#[async_trait]
+impl RecordsQuery for Mbp10Msg {
+    /// Every mbp row in the range with its levels in depth order. Rows uploaded as MBP-1 only have
+    /// depth 0, their deeper levels are served empty.
+    async fn retrieve_query(
+        pool: &PgPool,
+        params: RetrieveParams,
+    ) -> Result<
+        Pin<Box<dyn Stream<Item = std::result::Result<sqlx::postgres::PgRow, sqlx::Error>> + Send>>,
+    > {
+        let version = params.as_of(pool).await?;
+        info!(
+            "Retrieving mbp-10 records for symbols: {:?} start: {:?} end: {:?} as_of {:?}",
+            params.symbols, params.start_ts, params.end_ts, version
+        );
+
+        let cursor = sqlx::query(
+            r#"
+            SELECT m.instrument_id, m.ts_event, m.price, m.size, m.action, m.side, m.flags, m.ts_recv, m.ts_in_delta, m.sequence,
+                   b.depths, b.bid_px, b.ask_px, b.bid_sz, b.ask_sz, b.bid_ct, b.ask_ct
+            FROM mbp m
+            INNER JOIN instrument i ON m.instrument_id = i.id
+            CROSS JOIN LATERAL (
+                SELECT array_agg(depth ORDER BY depth) AS depths,
+                       array_agg(bid_px ORDER BY depth) AS bid_px,
+                       array_agg(ask_px ORDER BY depth) AS ask_px,
+                       array_agg(bid_sz ORDER BY depth) AS bid_sz,
+                       array_agg(ask_sz ORDER BY depth) AS ask_sz,
+                       array_agg(bid_ct ORDER BY depth) AS bid_ct,
+                       array_agg(ask_ct ORDER BY depth) AS ask_ct
+                FROM bid_ask
+                WHERE mbp_id = m.id AND ts_recv = m.ts_recv
+            ) b
+            WHERE m.ts_recv BETWEEN $1 AND $2
+            AND i.ticker = ANY($3)
+            -- Rows of the version, the current ones when there is none
+            AND (m.superseded_by IS NULL OR m.superseded_by IN (SELECT id FROM data_version WHERE seq > $4))
+            AND ($4::bigint IS NULL OR m.ingest_id IS NULL
+                OR m.ingest_id IN (SELECT ingest_id FROM data_version WHERE seq <= $4))
+            ORDER BY m.ts_recv, m.id
+            "#,
+        )
+        .bind(params.start_ts)
+        .bind(params.end_ts - 1)
+        .bind(params.symbols)
+        .bind(version)
+        .fetch(pool);
+
+        Ok(cursor)
+    }
+}
+
+impl FromRow for Mbp10Msg {
+    fn from_row(row: &sqlx::postgres::PgRow) -> Result<Self> {
+        let depths = row
+            .try_get::<Option<Vec<i32>>, _>("depths")?
+            .unwrap_or_default();
+        let bid_px = row
+            .try_get::<Option<Vec<i64>>, _>("bid_px")?
+            .unwrap_or_default();
+        let ask_px = row
+            .try_get::<Option<Vec<i64>>, _>("ask_px")?
+            .unwrap_or_default();
+        let bid_sz = row
+            .try_get::<Option<Vec<i32>>, _>("bid_sz")?
+            .unwrap_or_default();
+        let ask_sz = row
+            .try_get::<Option<Vec<i32>>, _>("ask_sz")?
+            .unwrap_or_default();
+        let bid_ct = row
+            .try_get::<Option<Vec<i32>>, _>("bid_ct")?
+            .unwrap_or_default();
+        let ask_ct = row
+            .try_get::<Option<Vec<i32>>, _>("ask_ct")?
+            .unwrap_or_default();
+
+        let mut levels = [BidAskLevel::default(); MBP10_DEPTH];
+        for (i, depth) in depths.iter().enumerate() {
+            if let Some(level) = levels.get_mut(*depth as usize) {
+                *level = BidAskLevel {
+                    bid_px: bid_px[i],
+                    ask_px: ask_px[i],
+                    bid_sz: bid_sz[i] as u32,
+                    ask_sz: ask_sz[i] as u32,
+                    bid_ct: bid_ct[i] as u32,
+                    ask_ct: ask_ct[i] as u32,
+                };
+            }
+        }
+
+        Ok(Mbp10Msg {
+            instrument_id: row.try_get::<i32, _>("instrument_id")? as u32,
+            ts_event: row.try_get::<i64, _>("ts_event")? as u64,
+            price: row.try_get::<i64, _>("price")?,
+            size: row.try_get::<i32, _>("size")? as u32,
+            action: row.try_get::<i32, _>("action")? as c_char,
+            side: row.try_get::<i32, _>("side")? as c_char,
+            flags: row.try_get::<i32, _>("flags")? as u8,
+            ts_recv: row.try_get::<i64, _>("ts_recv")? as u64,
+            ts_in_delta: row.try_get::<i32, _>("ts_in_delta")?,
+            sequence: row.try_get::<i32, _>("sequence")? as u32,
+            levels,
+        })
+    }
+}
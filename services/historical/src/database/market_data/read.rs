@@ -1,4 +1,7 @@
-use crate::Result;
+use crate::database::market_data::mbp10::MBP10_SCHEMA;
+use crate::database::market_data::rollup::ROLLUP_INTERVALS;
+use crate::database::versions::DataVersion;
+use crate::{Error, Result};
 use async_trait::async_trait;
 use futures::Stream;
 use mbn::enums::{RType, Schema};
@@ -11,11 +14,64 @@ use std::pin::Pin;
 use std::str::FromStr;
 use tracing::info;
 
-pub async fn get_lastest_mbp_id(pool: &PgPool) -> Result<i32> {
-        let last_id: i32 = sqlx::query_scalar("SELECT COALESCE(MAX(id), 0) FROM mbp")
-            .fetch_one(pool)
-            .await?;
-        Ok(last_id)
+/// Length of a schema's bars or sampling interval in nanoseconds, 1 for tick schemas.
+pub fn schema_interval(schema: Schema) -> i64 {
+    match schema {
+        Schema::Mbp1 => 1, // 1 nanosecond
+        Schema::Trade => 1,
+        Schema::Tbbo => 1,
+        Schema::Ohlcv1S => 1_000_000_000, // 1 second in nanoseconds
+        Schema::Ohlcv1M => 60_000_000_000, // 1 minute in nanoseconds
+        Schema::Ohlcv1H => 3_600_000_000_000, // 1 hour in nanoseconds
+        Schema::Ohlcv1D => 86_400_000_000_000, // 1 day in nanoseconds
+        Schema::Bbo1S => 1_000_000_000,
+        Schema::Bbo1M => 60_000_000_000,
+    }
+}
+
+/// Bar or sampling interval of an ohlcv or bbo retrieval, in nanoseconds or as a duration such as
+/// `"5s"`, `"15m"`, `"4h"` or `"1w"`. Bars are aligned to the UNIX epoch.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Interval {
+    Nanos(i64),
+    Duration(String),
+}
+
+impl Interval {
+    pub fn nanos(&self) -> Result<i64> {
+        let nanos = match self {
+            Interval::Nanos(nanos) => Some(*nanos),
+            Interval::Duration(duration) => parse_duration(duration),
+        };
+
+        match nanos {
+            Some(nanos) if nanos > 0 => Ok(nanos),
+            _ => Err(crate::error!(CustomError, "Invalid interval: {:?}", self)),
+        }
+    }
+}
+
+/// Nanoseconds of a count followed by a unit, ns, us, ms, s, m, h, d or w.
+fn parse_duration(duration: &str) -> Option<i64> {
+    let duration = duration.trim();
+    let split = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (count, unit) = duration.split_at(split);
+    let unit_ns: i64 = match unit {
+        "ns" => 1,
+        "us" => 1_000,
+        "ms" => 1_000_000,
+        "s" => 1_000_000_000,
+        "m" => 60_000_000_000,
+        "h" => 3_600_000_000_000,
+        "d" => 86_400_000_000_000,
+        "w" => 604_800_000_000_000,
+        _ => return None,
+    };
+
+    count.parse::<i64>().ok()?.checked_mul(unit_ns)
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -24,35 +80,82 @@ pub struct RetrieveParams {
     pub start_ts: i64,
     pub end_ts: i64,
     pub schema: String,
+    /// Serve the records as of a dataset version, instead of the current ones.
+    #[serde(default)]
+    pub as_of_version: Option<i32>,
+    /// Serve the records as of the latest version at a time, in UNIX nanoseconds.
+    #[serde(default)]
+    pub as_of_time: Option<i64>,
+    /// Interval of ohlcv bars or bbo quotes, instead of the one of the schema.
+    #[serde(default)]
+    pub interval: Option<Interval>,
 }
 
 impl RetrieveParams {
+    /// Commit sequence of the version the records are served as of, `None` for the current
+    /// records.
+    pub async fn as_of(&self, pool: &PgPool) -> Result<Option<i64>> {
+        match (self.as_of_version, self.as_of_time) {
+            (Some(_), Some(_)) => Err(crate::error!(
+                CustomError,
+                "Only one of as_of_version and as_of_time can be given."
+            )),
+            (Some(version), None) => Ok(Some(DataVersion::seq(pool, version).await?)),
+            (None, Some(time)) => Ok(Some(DataVersion::as_of_time(pool, time).await?)),
+            (None, None) => Ok(None),
+        }
+    }
+
     fn schema(&self) -> Result<Schema> {
+        if self.schema == MBP10_SCHEMA {
+            return Err(crate::error!(
+                CustomError,
+                "mbn has no {} schema, these records are served by /mbp10/get.",
+                MBP10_SCHEMA
+            ));
+        }
         let schema = Schema::from_str(&self.schema)?;
         Ok(schema)
     }
 
     pub fn rtype(&self) -> Result<RType> {
-        let schema = Schema::from_str(&self.schema)?;
-        Ok(RType::from(schema))
+        Ok(RType::from(self.schema()?))
     }
 
-    fn schema_interval(&self) -> Result<i64> {
-        let schema = Schema::from_str(&self.schema)?;
-
-        match schema {
-            Schema::Mbp1 => Ok(1), // 1 nanosecond
-            Schema::Trade => Ok(1),
-            Schema::Tbbo => Ok(1),
-            Schema::Ohlcv1S => Ok(1_000_000_000), // 1 second in nanoseconds
-            Schema::Ohlcv1M => Ok(60_000_000_000), // 1 minute in nanoseconds
-            Schema::Ohlcv1H => Ok(3_600_000_000_000), // 1 hour in nanoseconds
-            Schema::Ohlcv1D => Ok(86_400_000_000_000), // 1 day in nanoseconds
-            Schema::Bbo1S => Ok(1_000_000_000),
-            Schema::Bbo1M => Ok(60_000_000_000),
+    /// Bar or sampling interval of the records in nanoseconds, the requested one or the schema's.
+    pub fn schema_interval(&self) -> Result<i64> {
+        let schema = self.schema()?;
+        match &self.interval {
+            None => Ok(schema_interval(schema)),
+            Some(interval) => match RType::from(schema) {
+                RType::Ohlcv | RType::Bbo => interval.nanos(),
+                _ => Err(crate::error!(
+                    CustomError,
+                    "An interval only applies to ohlcv and bbo schemas, not {}.",
+                    self.schema
+                )),
+            },
         }
     }
 
+    /// Schema of the response metadata. mbn's `Metadata` has no interval, so it is recorded as the
+    /// fixed schema of the same interval when there is one, otherwise the requested schema is kept
+    /// and the interval is only given by the `x-interval-ns` response header.
+    pub fn metadata_schema(&self) -> Result<Schema> {
+        let schema = self.schema()?;
+        let fixed = match (RType::from(schema), self.schema_interval()?) {
+            (RType::Ohlcv, 1_000_000_000) => Schema::Ohlcv1S,
+            (RType::Ohlcv, 60_000_000_000) => Schema::Ohlcv1M,
+            (RType::Ohlcv, 3_600_000_000_000) => Schema::Ohlcv1H,
+            (RType::Ohlcv, 86_400_000_000_000) => Schema::Ohlcv1D,
+            (RType::Bbo, 1_000_000_000) => Schema::Bbo1S,
+            (RType::Bbo, 60_000_000_000) => Schema::Bbo1M,
+            _ => schema,
+        };
+
+        Ok(fixed)
+    }
+
     fn interval_adjust_ts_start(&mut self) -> Result<()> {
         let interval_ns =self.schema_interval()?;
 
@@ -109,11 +212,12 @@ impl RecordsQuery for Mbp1Msg {
         let _ = params.interval_adjust_ts_start()?;
         let _ = params.interval_adjust_ts_end()?; 
         let tbbo_flag = params.schema()? == Schema::Tbbo;
+        let version = params.as_of(pool).await?;
         let symbol_array: Vec<String> = params.symbols.iter().map(|s| s.clone()).collect(); // Ownership fix
 
         info!(
-            "Retrieving {:?} records for symbols: {:?} start: {:?} end: {:?} tbbo_flag {:?}",
-            params.schema, params.symbols, params.start_ts, params.end_ts, tbbo_flag
+            "Retrieving {:?} records for symbols: {:?} start: {:?} end: {:?} tbbo_flag {:?} as_of {:?}",
+            params.schema, params.symbols, params.start_ts, params.end_ts, tbbo_flag, version
         );
 
         // Query to Cursor
@@ -123,15 +227,21 @@ impl RecordsQuery for Mbp1Msg {
                    b.bid_px, b.bid_sz, b.bid_ct, b.ask_px, b.ask_sz, b.ask_ct
             FROM mbp m
             INNER JOIN instrument i ON m.instrument_id = i.id
-            LEFT JOIN bid_ask b ON m.id = b.mbp_id AND b.depth = 0
+            LEFT JOIN bid_ask b ON m.id = b.mbp_id AND m.ts_recv = b.ts_recv AND b.depth = 0
+                AND b.ts_recv BETWEEN $1 AND $2 -- Prunes bid_ask partitions as well
             WHERE m.ts_recv BETWEEN $1 AND $2
             AND i.ticker = ANY($3)
             AND ($4 IS FALSE OR m.action = 84)
+            -- Rows of the version, the current ones when there is none
+            AND (m.superseded_by IS NULL OR m.superseded_by IN (SELECT id FROM data_version WHERE seq > $5))
+            AND ($5::bigint IS NULL OR m.ingest_id IS NULL
+                OR m.ingest_id IN (SELECT ingest_id FROM data_version WHERE seq <= $5))
             "#)
             .bind(params.start_ts)
             .bind(params.end_ts - 1)
             .bind(symbol_array)
             .bind(tbbo_flag)
+            .bind(version)
             .fetch(pool);
 
         Ok(cursor)
@@ -183,28 +293,55 @@ impl RecordsQuery for TradeMsg {
        // Parameters
         let _ = params.interval_adjust_ts_start()?;
         let _ = params.interval_adjust_ts_end()?; 
+        let version = params.as_of(pool).await?;
         let symbol_array: Vec<String> = params.symbols.iter().map(|s| s.clone()).collect(); // Ownership fix
 
         info!(
-            "Retrieving {:?} records for symbols: {:?} start: {:?} end: {:?} ",
-            params.schema, params.symbols, params.start_ts, params.end_ts
+            "Retrieving {:?} records for symbols: {:?} start: {:?} end: {:?} as_of {:?}",
+            params.schema, params.symbols, params.start_ts, params.end_ts, version
         );
 
-        // Execute the query with parameters, including LIMIT and OFFSET
+        // Stored trades are served as is, trades of the days without any are derived from mbp
         let cursor = sqlx::query(
             r#"
+            WITH stored AS (
+                SELECT t.instrument_id, t.ts_event, t.price, t.size, t.action, t.side, t.flags, t.ts_recv, t.ts_in_delta, t.sequence, i.ticker
+                FROM trade t
+                INNER JOIN instrument i ON t.instrument_id = i.id
+                WHERE t.ts_recv BETWEEN $1 AND $2
+                AND i.ticker = ANY($3)
+                AND ($4::bigint IS NULL OR t.ingest_id IS NULL
+                    OR t.ingest_id IN (SELECT ingest_id FROM data_version WHERE seq <= $4))
+            ),
+            -- Days of the range with stored trades, whole days so the choice doesn't depend on the range
+            stored_days AS (
+                SELECT DISTINCT t.instrument_id, t.ts_recv / 86400000000000 AS day
+                FROM trade t
+                INNER JOIN instrument i ON t.instrument_id = i.id
+                WHERE t.ts_recv BETWEEN ($1 / 86400000000000) * 86400000000000
+                    AND ($2 / 86400000000000 + 1) * 86400000000000 - 1
+                AND i.ticker = ANY($3)
+                AND ($4::bigint IS NULL OR t.ingest_id IS NULL
+                    OR t.ingest_id IN (SELECT ingest_id FROM data_version WHERE seq <= $4))
+            )
+            SELECT * FROM stored
+            UNION ALL
             SELECT m.instrument_id, m.ts_event, m.price, m.size, m.action, m.side, m.flags, m.ts_recv, m.ts_in_delta, m.sequence, i.ticker
             FROM mbp m
             INNER JOIN instrument i ON m.instrument_id = i.id
-            LEFT JOIN bid_ask b ON m.id = b.mbp_id AND b.depth = 0
             WHERE m.ts_recv BETWEEN $1 AND $2
             AND i.ticker = ANY($3)
             AND m.action = 84  -- Filter only trades where action is 'T' (ASCII 84)
-            ORDER BY m.ts_event
+            AND NOT EXISTS (SELECT 1 FROM stored_days d WHERE d.instrument_id = m.instrument_id AND d.day = m.ts_recv / 86400000000000)
+            AND (m.superseded_by IS NULL OR m.superseded_by IN (SELECT id FROM data_version WHERE seq > $4))
+            AND ($4::bigint IS NULL OR m.ingest_id IS NULL
+                OR m.ingest_id IN (SELECT ingest_id FROM data_version WHERE seq <= $4))
+            ORDER BY ts_event
             "#)
             .bind(params.start_ts)
             .bind(params.end_ts - 1)
             .bind(symbol_array)
+            .bind(version)
             .fetch(pool);
 
         Ok(cursor)
@@ -245,17 +382,57 @@ impl RecordsQuery for BboMsg {
         let _ = params.interval_adjust_ts_start()?;
         let _ = params.interval_adjust_ts_end()?; 
         let interval_ns = params.schema_interval()?;
+        let version = params.as_of(pool).await?;
         let symbol_array: Vec<String> = params.symbols.iter().map(|s| s.clone()).collect(); // Ownership fix
 
         info!(
-            "Retrieving {:?} records for symbols: {:?} start: {:?} end: {:?} ",
-            params.schema, params.symbols, params.start_ts, params.end_ts
+            "Retrieving {:?} records for symbols: {:?} start: {:?} end: {:?} as_of {:?}",
+            params.schema, params.symbols, params.start_ts, params.end_ts, version
         );
 
         // Construct the SQL query with a join and additional filtering by symbols
         let cursor = sqlx::query(
             r#"
-            WITH ordered_data AS (
+            WITH stored_interval AS (
+                -- Longest stored interval dividing the requested one, per instrument and interval
+                SELECT q.instrument_id, (q.ts_recv - 1) / $3 AS bucket, MAX(q.interval_ns) AS interval_ns
+                FROM bbo q
+                INNER JOIN instrument i ON q.instrument_id = i.id
+                WHERE $3 % q.interval_ns = 0
+                AND q.ts_recv BETWEEN ($1 - $3 + 1) AND ($2 - $3)
+                AND i.ticker = ANY($4)
+                AND ($5::bigint IS NULL OR q.ingest_id IS NULL
+                    OR q.ingest_id IN (SELECT ingest_id FROM data_version WHERE seq <= $5))
+                GROUP BY q.instrument_id, (q.ts_recv - 1) / $3
+            ),
+            -- The last stored quote of each interval, stamped with the end of it
+            stored AS (
+                SELECT DISTINCT ON (q.instrument_id, (q.ts_recv - 1) / $3)
+                    q.instrument_id,
+                    q.ts_event,
+                    ((q.ts_recv - 1) / $3 + 1) * $3 AS ts_recv,
+                    q.bid_px,
+                    q.ask_px,
+                    q.bid_sz,
+                    q.ask_sz,
+                    q.bid_ct,
+                    q.ask_ct,
+                    q.price,
+                    q.size,
+                    q.side,
+                    q.flags,
+                    q.sequence,
+                    i.ticker
+                FROM bbo q
+                INNER JOIN stored_interval s ON q.instrument_id = s.instrument_id AND q.interval_ns = s.interval_ns
+                    AND (q.ts_recv - 1) / $3 = s.bucket
+                INNER JOIN instrument i ON q.instrument_id = i.id
+                WHERE q.ts_recv BETWEEN ($1 - $3 + 1) AND ($2 - $3)
+                AND ($5::bigint IS NULL OR q.ingest_id IS NULL
+                    OR q.ingest_id IN (SELECT ingest_id FROM data_version WHERE seq <= $5))
+                ORDER BY q.instrument_id, (q.ts_recv - 1) / $3, q.ts_recv DESC
+            ),
+            ordered_data AS (
                 SELECT
                     m.id,
                     m.instrument_id,
@@ -277,9 +454,13 @@ impl RecordsQuery for BboMsg {
                     row_number() OVER (PARTITION BY m.instrument_id, floor((m.ts_recv - 1) / $3) * $3 ORDER BY m.ts_recv DESC, m.ctid DESC) AS last_row
                 FROM mbp m
                 INNER JOIN instrument i ON m.instrument_id = i.id
-                LEFT JOIN bid_ask b ON m.id = b.mbp_id AND b.depth = 0
+                LEFT JOIN bid_ask b ON m.id = b.mbp_id AND m.ts_recv = b.ts_recv AND b.depth = 0
+                    AND b.ts_recv BETWEEN ($1 - 86400000000000) AND $2 -- Prunes bid_ask partitions as well
                 WHERE m.ts_recv BETWEEN ($1 - 86400000000000) AND $2
                 AND i.ticker = ANY($4)
+                AND (m.superseded_by IS NULL OR m.superseded_by IN (SELECT id FROM data_version WHERE seq > $5))
+                AND ($5::bigint IS NULL OR m.ingest_id IS NULL
+                    OR m.ingest_id IN (SELECT ingest_id FROM data_version WHERE seq <= $5))
             ),
             -- Subquery to get the last trade event
             trade_data AS (
@@ -350,8 +531,9 @@ impl RecordsQuery for BboMsg {
                     f.last_trade_flags AS flags,
                     f.last_trade_sequence AS sequence
                 FROM filled_ts_event f
-            )
-            SELECT
+            ),
+            derived AS (
+                SELECT
                 fp.instrument_id,
                 fp.ts_event,
                 fp.ts_recv,
@@ -370,12 +552,19 @@ impl RecordsQuery for BboMsg {
             FROM filled_price_size fp
             INNER JOIN instrument i ON fp.instrument_id = i.id
             WHERE fp.ts_recv BETWEEN $1 AND ($2 - $3)
-            ORDER BY fp.ts_recv;
+            AND NOT EXISTS (SELECT 1 FROM stored s WHERE s.instrument_id = fp.instrument_id AND s.ts_recv = fp.ts_recv)
+            )
+            -- Intervals with stored quotes are sampled from those, the others are derived from mbp
+            SELECT * FROM stored
+            UNION ALL
+            SELECT * FROM derived
+            ORDER BY ts_recv;
             "#)
             .bind(params.start_ts)
             .bind(params.end_ts)
             .bind(interval_ns)
             .bind(symbol_array)
+            .bind(version)
             .fetch(pool);
 
         Ok(cursor)
@@ -421,61 +610,110 @@ impl RecordsQuery for OhlcvMsg {
         let _ = params.interval_adjust_ts_start()?;
         let _ = params.interval_adjust_ts_end()?; 
         let interval_ns = params.schema_interval()?;
+        let version = params.as_of(pool).await?;
         let symbol_array: Vec<String> = params.symbols.iter().map(|s| s.clone()).collect(); // Ownership fix
 
+        // Longest rollup the bars can be built from, none below a second
+        let rollup_ns = ROLLUP_INTERVALS.iter().rev().find(|i| interval_ns % **i == 0).copied();
+
         info!(
-            "Retrieving {:?} records for symbols: {:?} start: {:?} end: {:?} ",
-            params.schema, params.symbols, params.start_ts, params.end_ts
+            "Retrieving {:?} records for symbols: {:?} start: {:?} end: {:?} interval {:?} as_of {:?}",
+            params.schema, params.symbols, params.start_ts, params.end_ts, interval_ns, version
         );
 
         let cursor = sqlx::query(
         r#"
-        WITH ordered_data AS (
+        WITH stored_interval AS (
+          -- Longest stored interval dividing the bars, per instrument and bar
+          SELECT o.instrument_id, o.ts_event / $3 AS bucket, MAX(o.interval_ns) AS interval_ns
+          FROM ohlcv o
+          INNER JOIN instrument i ON o.instrument_id = i.id
+          WHERE $3 % o.interval_ns = 0
+          AND o.ts_event BETWEEN $1 AND ($2 - 1)
+          AND i.ticker = ANY($4)
+          AND ($5::bigint IS NULL OR o.ingest_id IS NULL
+              OR o.ingest_id IN (SELECT ingest_id FROM data_version WHERE seq <= $5))
+          GROUP BY o.instrument_id, o.ts_event / $3
+        ),
+        stored AS (
+          SELECT
+            o.instrument_id,
+            (o.ts_event / $3) * $3 AS ts_event,
+            (array_agg(o.open ORDER BY o.ts_event))[1] AS open,
+            (array_agg(o.close ORDER BY o.ts_event DESC))[1] AS close,
+            MIN(o.low) AS low,
+            MAX(o.high) AS high,
+            SUM(o.volume)::bigint AS volume,
+            i.ticker
+          FROM ohlcv o
+          INNER JOIN stored_interval s ON o.instrument_id = s.instrument_id AND o.interval_ns = s.interval_ns
+            AND o.ts_event / $3 = s.bucket
+          INNER JOIN instrument i ON o.instrument_id = i.id
+          WHERE o.ts_event BETWEEN $1 AND ($2 - 1)
+          AND ($5::bigint IS NULL OR o.ingest_id IS NULL
+              OR o.ingest_id IN (SELECT ingest_id FROM data_version WHERE seq <= $5))
+          GROUP BY o.instrument_id, o.ts_event / $3, i.ticker
+        ),
+        -- Bars of other intervals are combined from the rollup bars dividing them
+        rollup AS (
+          SELECT
+            r.instrument_id,
+            (r.ts_event / $3) * $3 AS ts_event,
+            (array_agg(r.open ORDER BY r.ts_event))[1] AS open,
+            (array_agg(r.close ORDER BY r.ts_event DESC))[1] AS close,
+            MIN(r.low) AS low,
+            MAX(r.high) AS high,
+            SUM(r.volume)::bigint AS volume,
+            i.ticker
+          FROM ohlcv_rollup r
+          INNER JOIN instrument i ON r.instrument_id = i.id
+          WHERE $5::bigint IS NULL
+          AND r.interval_ns = $6
+          AND r.ts_event BETWEEN $1 AND ($2 - 1)
+          AND i.ticker = ANY($4)
+          AND NOT EXISTS (SELECT 1 FROM stored_interval s WHERE s.instrument_id = r.instrument_id AND s.bucket = r.ts_event / $3)
+          GROUP BY r.instrument_id, r.ts_event / $3, i.ticker
+        ),
+        -- Rollups only hold the current bars of whole seconds, older versions and shorter
+        -- intervals are aggregated from the trades
+        derived AS (
           SELECT
             m.instrument_id,
-            m.ts_recv,
-            m.price,
-            m.size,
-            row_number() OVER (PARTITION BY m.instrument_id, floor(m.ts_recv / $3) * $3 ORDER BY m.ts_recv ASC,  m.ctid ASC) AS first_row,
-            row_number() OVER (PARTITION BY m.instrument_id, floor(m.ts_recv / $3) * $3 ORDER BY m.ts_recv DESC, m.ctid DESC) AS last_row
+            (m.ts_recv / $3) * $3 AS ts_event,
+            (array_agg(m.price ORDER BY m.ts_recv, m.id))[1] AS open,
+            (array_agg(m.price ORDER BY m.ts_recv DESC, m.id DESC))[1] AS close,
+            MIN(m.price) AS low,
+            MAX(m.price) AS high,
+            SUM(m.size)::bigint AS volume,
+            i.ticker
           FROM mbp m
           INNER JOIN instrument i ON m.instrument_id = i.id
-          WHERE m.ts_recv BETWEEN $1 AND $2
+          WHERE ($5::bigint IS NOT NULL OR $6::bigint IS NULL)
+          AND m.action = 84 -- Trades only, 'T' in ASCII
+          AND m.ts_recv BETWEEN $1 AND ($2 - 1)
           AND i.ticker = ANY($4)
-          AND m.action = 84  -- Filter only trades where action is 'T' (ASCII 84)
-        ),
-        aggregated_data AS (
-          SELECT
-            instrument_id,
-            floor(ts_recv / $3) * $3 AS ts_event, -- Maintain nanoseconds
-            MIN(price) FILTER (WHERE first_row = 1) AS open,
-            MIN(price) FILTER (WHERE last_row = 1) AS close,
-            MIN(price) AS low,
-            MAX(price) AS high,
-            SUM(size) AS volume
-          FROM ordered_data
-          GROUP BY
-            instrument_id,
-            floor(ts_recv / $3) * $3
+          AND NOT EXISTS (SELECT 1 FROM stored_interval s WHERE s.instrument_id = m.instrument_id AND s.bucket = m.ts_recv / $3)
+          AND (m.superseded_by IS NULL OR m.superseded_by IN (SELECT id FROM data_version WHERE seq > $5))
+          AND ($5::bigint IS NULL OR m.ingest_id IS NULL
+              OR m.ingest_id IN (SELECT ingest_id FROM data_version WHERE seq <= $5))
+          GROUP BY m.instrument_id, m.ts_recv / $3, i.ticker
         )
-        SELECT
-          a.instrument_id,
-          CAST(a.ts_event AS BIGINT), -- Keep as nanoseconds
-          a.open,
-          a.close,
-          a.low,
-          a.high,
-          a.volume,
-          i.ticker
-        FROM aggregated_data a
-        INNER JOIN instrument i ON a.instrument_id = i.id
-        ORDER BY a.ts_event
+        -- Bars with stored bars in them are combined from those alone, the others from the bars rolled
+        -- up from mbp trades
+        SELECT * FROM stored
+        UNION ALL
+        SELECT * FROM rollup
+        UNION ALL
+        SELECT * FROM derived
+        ORDER BY ts_event
         "#
         )
         .bind(params.start_ts)
         .bind(params.end_ts)
         .bind(interval_ns)
         .bind(symbol_array)
+        .bind(version)
+        .bind(rollup_ns)
         .fetch(pool);
 
         Ok(cursor)
@@ -506,6 +744,7 @@ impl RecordsQuery for RecordEnum {
         params: RetrieveParams,
     ) -> Result<Pin<Box<dyn Stream<Item = std::result::Result<sqlx::postgres::PgRow, sqlx::Error>> + Send>>> {
 
+        // Mbp10 records are served as JSON by `Mbp10Msg`, mbn has no type to encode them into.
         match RType::from(params.rtype().unwrap()) {
             RType::Mbp1 => Ok(Mbp1Msg::retrieve_query(pool, params).await?),
             RType::Trade => Ok(TradeMsg::retrieve_query(pool, params).await?),
@@ -538,7 +777,11 @@ mod test {
     use mbn::symbols::Instrument;
     use serial_test::serial;
     use mbn::symbols::Vendors;
-    use crate::database::market_data::create::RecordInsertQueries;
+    use crate::database::market_data::create::{IngestMode, InsertBatch, RecordInsertQueries, supersede_mbp_range};
+    use crate::database::market_data::rollup::refresh_queued_rollups;
+    use crate::database::ingest::IngestSession;
+    use crate::database::versions::VersionKind;
+    use crate::test_utils::{delete_instrument, mbp1};
     use sqlx::{PgPool, Postgres, Transaction};
     use futures::stream::StreamExt;
     use tracing::error;
@@ -592,6 +835,9 @@ mod test {
             start_ts: 1704209103644092563,
             end_ts: 1704209903644092567,
             schema: String::from("mbp-1"),
+            as_of_version: None,
+            as_of_time: None,
+            interval: None,
         };
 
         // Test
@@ -606,6 +852,9 @@ mod test {
             start_ts: 1704209103644092563,
             end_ts: 1704209903644092567,
             schema: String::from("tbbo"),
+            as_of_version: None,
+            as_of_time: None,
+            interval: None,
         };
 
         // Test
@@ -614,6 +863,43 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_retrieve_params_interval() -> anyhow::Result<()> {
+        let mut params = RetrieveParams {
+            symbols: vec!["AAPL".to_string()],
+            start_ts: 1704209103644092563,
+            end_ts: 1704209903644092567,
+            schema: String::from("ohlcv-1s"),
+            as_of_version: None,
+            as_of_time: None,
+            interval: Some(Interval::Duration("15m".to_string())),
+        };
+
+        // Test
+        assert_eq!(params.schema_interval()?, 900_000_000_000);
+        assert_eq!(params.metadata_schema()?, Schema::Ohlcv1S);
+        params.interval_adjust_ts_start()?;
+        params.interval_adjust_ts_end()?;
+        assert_eq!((params.start_ts, params.end_ts), (1704208500000000000, 1704210300000000000));
+
+        params.interval = Some(Interval::Nanos(60_000_000_000));
+        assert_eq!(params.metadata_schema()?, Schema::Ohlcv1M);
+
+        let intervals: Vec<Interval> = serde_json::from_str(r#"[5000000000, "5s", "4h", "1w"]"#)?;
+        let nanos: Vec<i64> = intervals.iter().map(|i| i.nanos()).collect::<Result<_>>()?;
+        assert_eq!(nanos, vec![5_000_000_000, 5_000_000_000, 14_400_000_000_000, 604_800_000_000_000]);
+
+        for invalid in ["", "15", "m", "-5s", "0s", "5y"] {
+            assert!(Interval::Duration(invalid.to_string()).nanos().is_err());
+        }
+        assert!(Interval::Nanos(0).nanos().is_err());
+
+        params.schema = String::from("mbp-1");
+        assert!(params.schema_interval().is_err());
+
+        Ok(())
+    }
+
     #[test]
     fn test_retrieve_params_rtype() -> anyhow::Result<()> {
         let params = RetrieveParams {
@@ -621,6 +907,9 @@ mod test {
             start_ts: 1728878401000000000,
             end_ts: 1728878460000000000,
             schema: String::from("ohlcv-1h"),
+            as_of_version: None,
+            as_of_time: None,
+            interval: None,
         };
 
         // Test
@@ -703,6 +992,9 @@ mod test {
             start_ts: 1704209103644092563,
             end_ts: 1704209903644092567,
             schema: String::from("mbp-1"),
+            as_of_version: None,
+            as_of_time: None,
+            interval: None,
         };
 
         let mut cursor =
@@ -817,6 +1109,9 @@ mod test {
             start_ts: 1704209103644092563,
             end_ts: 1704209903644092567,
             schema: String::from("tbbo"),
+            as_of_version: None,
+            as_of_time: None,
+            interval: None,
         };
 
         let mut cursor =
@@ -932,6 +1227,9 @@ mod test {
             start_ts: 1704209103644092563,
             end_ts: 1704209903644092567,
             schema: String::from("trade"),
+            as_of_version: None,
+            as_of_time: None,
+            interval: None,
         };
 
 
@@ -1049,6 +1347,9 @@ mod test {
             start_ts: 1704209103644092563,
             end_ts: 1704209903644092567,
             schema: String::from("bbo-1s"),
+            as_of_version: None,
+            as_of_time: None,
+            interval: None,
         };
 
         let mut cursor =
@@ -1187,6 +1488,9 @@ mod test {
             start_ts: 1704209103644092562,
             end_ts: 1704295503654092563,
             schema: String::from("ohlcv-1d"),
+            as_of_version: None,
+            as_of_time: None,
+            interval: None,
         };
         
         let mut cursor =
@@ -1229,4 +1533,422 @@ mod test {
         let _ = transaction.commit().await;
         Ok(())
     }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_retrieve_stored_ohlcv() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+
+        let instrument_id = create_instrument(&pool)
+            .await
+            .expect("Error creating instrument.");
+
+        // Mock data, mbp trades in the stored minute and the one after it
+        let mut transaction = pool.begin().await?;
+        let trade = |ts: u64| mbp1(instrument_id, ts, 500, Action::Trade);
+        insert_records(&mut transaction, vec![trade(1704209103644092562), trade(1704209163644092562)]).await?;
+
+        let bar = OhlcvMsg {
+            hd: { RecordHeader::new::<OhlcvMsg>(instrument_id as u32, 1704209100000000000) },
+            open: 100,
+            high: 300,
+            low: 50,
+            close: 200,
+            volume: 42,
+        };
+        let mut insert_batch =
+            InsertBatch::new(IngestMode::Strict, None).with_interval(Some(60_000_000_000));
+        insert_batch.process_ohlcv(&bar).await?;
+        insert_batch.execute(&mut transaction).await?;
+        transaction.commit().await?;
+
+        // Test
+        let query_params = RetrieveParams {
+            symbols: vec!["AAPL".to_string()],
+            start_ts: 1704209100000000000,
+            end_ts: 1704209220000000000,
+            schema: String::from("ohlcv-1m"),
+            as_of_version: None,
+            as_of_time: None,
+            interval: None,
+        };
+
+        let mut cursor = OhlcvMsg::retrieve_query(&pool, query_params)
+            .await
+            .expect("Error on retrieve records.");
+
+        let mut query: Vec<OhlcvMsg> = vec![];
+        while let Some(row_result) = cursor.next().await {
+            query.push(OhlcvMsg::from_row(&row_result?)?);
+        }
+
+        // Validate, the stored bar replaces the trades of its minute only
+        let derived = OhlcvMsg {
+            hd: { RecordHeader::new::<OhlcvMsg>(instrument_id as u32, 1704209160000000000) },
+            open: 500,
+            high: 500,
+            low: 500,
+            close: 500,
+            volume: 1,
+        };
+        assert_eq!(query, vec![bar, derived]);
+
+        // Bars of a multiple of the stored interval are combined from the stored bars
+        let next_bar = OhlcvMsg {
+            hd: { RecordHeader::new::<OhlcvMsg>(instrument_id as u32, 1704209160000000000) },
+            open: 200,
+            high: 400,
+            low: 150,
+            close: 250,
+            volume: 8,
+        };
+        let mut insert_batch =
+            InsertBatch::new(IngestMode::Strict, None).with_interval(Some(60_000_000_000));
+        insert_batch.process_ohlcv(&next_bar).await?;
+        let mut transaction = pool.begin().await?;
+        insert_batch.execute(&mut transaction).await?;
+        transaction.commit().await?;
+
+        let query_params = RetrieveParams {
+            symbols: vec!["AAPL".to_string()],
+            start_ts: 1704209100000000000,
+            end_ts: 1704209400000000000,
+            schema: String::from("ohlcv-1m"),
+            as_of_version: None,
+            as_of_time: None,
+            interval: Some(Interval::Duration("5m".to_string())),
+        };
+
+        let mut cursor = OhlcvMsg::retrieve_query(&pool, query_params)
+            .await
+            .expect("Error on retrieve records.");
+
+        let mut query: Vec<OhlcvMsg> = vec![];
+        while let Some(row_result) = cursor.next().await {
+            query.push(OhlcvMsg::from_row(&row_result?)?);
+        }
+
+        let combined = OhlcvMsg {
+            hd: { RecordHeader::new::<OhlcvMsg>(instrument_id as u32, 1704209100000000000) },
+            open: 100,
+            high: 400,
+            low: 50,
+            close: 250,
+            volume: 50,
+        };
+        assert_eq!(query, vec![combined]);
+
+        // Cleanup
+        delete_instrument(&pool, instrument_id).await?;
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_retrieve_stored_bbo_interval() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+
+        let instrument_id = create_instrument(&pool)
+            .await
+            .expect("Error creating instrument.");
+
+        // Mock data, two 1s quotes of the same 5s interval
+        let quote = |ts_recv: u64, bid_px: i64| BboMsg {
+            hd: { RecordHeader::new::<BboMsg>(instrument_id as u32, ts_recv - 500_000_000) },
+            price: 6770,
+            size: 1,
+            side: Side::Bid as c_char,
+            flags: 0,
+            ts_recv,
+            sequence: 739763,
+            levels: [BidAskPair {
+                bid_px,
+                ask_px: bid_px + 2,
+                bid_sz: 1,
+                ask_sz: 1,
+                bid_ct: 10,
+                ask_ct: 20,
+            }],
+        };
+        let mut insert_batch =
+            InsertBatch::new(IngestMode::Strict, None).with_interval(Some(1_000_000_000));
+        insert_batch.process_bbo(&quote(1704209101000000000, 100)).await?;
+        insert_batch.process_bbo(&quote(1704209103000000000, 200)).await?;
+        let mut transaction = pool.begin().await?;
+        insert_batch.execute(&mut transaction).await?;
+
+        // Mbp trades in the stored interval and the one after it
+        let trade = |ts: u64| mbp1(instrument_id, ts, 500, Action::Trade);
+        insert_records(&mut transaction, vec![trade(1704209102000000000), trade(1704209107000000000)]).await?;
+        transaction.commit().await?;
+
+        // Test
+        let query_params = RetrieveParams {
+            symbols: vec!["AAPL".to_string()],
+            start_ts: 1704209100000000000,
+            end_ts: 1704209115000000000,
+            schema: String::from("bbo-1s"),
+            as_of_version: None,
+            as_of_time: None,
+            interval: Some(Interval::Duration("5s".to_string())),
+        };
+
+        let mut cursor = BboMsg::retrieve_query(&pool, query_params)
+            .await
+            .expect("Error on retrieve records.");
+
+        let mut query: Vec<BboMsg> = vec![];
+        while let Some(row_result) = cursor.next().await {
+            query.push(BboMsg::from_row(&row_result?)?);
+        }
+
+        // Validate, the last stored quote of its interval stamped with the end, the next derived
+        let mut expected = quote(1704209103000000000, 200);
+        expected.ts_recv = 1704209105000000000;
+        assert_eq!(query.len(), 2);
+        assert_eq!(query[0], expected);
+        assert_eq!(query[1].ts_recv, 1704209110000000000);
+        assert_eq!(query[1].levels[0].bid_px, 1);
+
+        // Cleanup
+        delete_instrument(&pool, instrument_id).await?;
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_retrieve_stored_trade() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+
+        let instrument_id = create_instrument(&pool)
+            .await
+            .expect("Error creating instrument.");
+
+        // Mock data
+        let trade = TradeMsg {
+            hd: { RecordHeader::new::<TradeMsg>(instrument_id as u32, 1704209103644092562) },
+            price: 6770,
+            size: 1,
+            action: Action::Trade as c_char,
+            side: Side::Ask as c_char,
+            flags: 0,
+            depth: 0,
+            ts_recv: 1704209103644092562,
+            ts_in_delta: 17493,
+            sequence: 739763,
+        };
+        let mut insert_batch = InsertBatch::new(IngestMode::Strict, None);
+        insert_batch.process_trade(&trade).await?;
+        let mut transaction = pool.begin().await?;
+        insert_batch.execute(&mut transaction).await?;
+
+        // Mbp trades later the same day and on the next one
+        let mbp_trade = |ts: u64| mbp1(instrument_id, ts, 500, Action::Trade);
+        insert_records(&mut transaction, vec![mbp_trade(1704209203644092562), mbp_trade(1704295603644092562)]).await?;
+        transaction.commit().await?;
+
+        // Test
+        let query_params = RetrieveParams {
+            symbols: vec!["AAPL".to_string()],
+            start_ts: 1704209103644092562,
+            end_ts: 1704295603644092563,
+            schema: String::from("trade"),
+            as_of_version: None,
+            as_of_time: None,
+            interval: None,
+        };
+
+        let mut cursor = TradeMsg::retrieve_query(&pool, query_params)
+            .await
+            .expect("Error on retrieve records.");
+
+        let mut query: Vec<TradeMsg> = vec![];
+        while let Some(row_result) = cursor.next().await {
+            query.push(TradeMsg::from_row(&row_result?)?);
+        }
+
+        // Validate, the stored trades replace the mbp ones of their day only
+        let derived = TradeMsg {
+            hd: { RecordHeader::new::<TradeMsg>(instrument_id as u32, 1704295603644092562) },
+            price: 500,
+            size: 1,
+            action: Action::Trade as c_char,
+            side: Side::Bid as c_char,
+            flags: 0,
+            depth: 0,
+            ts_recv: 1704295603644092562,
+            ts_in_delta: 17493,
+            sequence: 739763,
+        };
+        assert_eq!(query, vec![trade, derived]);
+
+        // Cleanup
+        delete_instrument(&pool, instrument_id).await?;
+        Ok(())
+    }
+
+    fn as_of_params(schema: &str, as_of_version: Option<i32>) -> RetrieveParams {
+        RetrieveParams {
+            symbols: vec!["AAPL".to_string()],
+            start_ts: 1704209100000000000,
+            end_ts: 1704209160000000000,
+            schema: String::from(schema),
+            as_of_version,
+            as_of_time: None,
+            interval: None,
+        }
+    }
+
+    async fn insert_trade(pool: &PgPool, instrument_id: i32, ingest_id: i32, price: i64, version: Option<i32>) -> anyhow::Result<i32> {
+        let trade = mbp1(instrument_id, 1704209103644092562, price, Action::Trade);
+
+        // A correction supersedes the rows it replaces in the same transaction
+        let mut transaction = pool.begin().await?;
+        let version = match version {
+            Some(version) => version,
+            None => DataVersion::create(&mut transaction, VersionKind::Replace, Some(ingest_id)).await?,
+        };
+        supersede_mbp_range(
+            &mut transaction,
+            version,
+            &[instrument_id],
+            1704209100000000000,
+            1704209160000000000,
+        )
+        .await?;
+        let mut insert_batch = InsertBatch::new(IngestMode::Strict, Some(ingest_id));
+        insert_batch.process(&trade).await?;
+        insert_batch.execute(&mut transaction).await?;
+        DataVersion::commit(&mut transaction, version).await?;
+        transaction.commit().await?;
+        refresh_queued_rollups(pool).await?;
+
+        Ok(version)
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_retrieve_as_of_version() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+
+        let instrument_id = create_instrument(&pool)
+            .await
+            .expect("Error creating instrument.");
+
+        // Mock data, a trade and its correction
+        let original = IngestSession::create(&pool, "original.bin", IngestMode::Strict, false).await?;
+        let first = DataVersion::create(&pool, VersionKind::Ingest, Some(original)).await?;
+        insert_trade(&pool, instrument_id, original, 100, Some(first)).await?;
+        let correction = IngestSession::create(&pool, "correction.bin", IngestMode::Replace, false).await?;
+        let second = insert_trade(&pool, instrument_id, correction, 200, None).await?;
+
+        // Test
+        let mut prices = Vec::new();
+        for version in [Some(first), Some(second), None] {
+            let mut cursor = Mbp1Msg::retrieve_query(&pool, as_of_params("mbp-1", version)).await?;
+            let mut query: Vec<i64> = vec![];
+            while let Some(row_result) = cursor.next().await {
+                query.push(Mbp1Msg::from_row(&row_result?)?.price);
+            }
+            prices.push(query);
+        }
+
+        let mut bars = Vec::new();
+        for version in [Some(first), None] {
+            let mut cursor = OhlcvMsg::retrieve_query(&pool, as_of_params("ohlcv-1m", version)).await?;
+            let mut query: Vec<i64> = vec![];
+            while let Some(row_result) = cursor.next().await {
+                query.push(OhlcvMsg::from_row(&row_result?)?.close);
+            }
+            bars.push(query);
+        }
+
+        // Validate
+        assert_eq!(prices, vec![vec![100], vec![200], vec![200]]);
+        assert_eq!(bars, vec![vec![100], vec![200]]);
+
+        // Cleanup
+        delete_instrument(&pool, instrument_id).await?;
+        sqlx::query("DELETE FROM data_version WHERE id IN ($1, $2)")
+            .bind(first)
+            .bind(second)
+            .execute(&pool)
+            .await?;
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_retrieve_ohlcv_interval() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+
+        let instrument_id = create_instrument(&pool)
+            .await
+            .expect("Error creating instrument.");
+
+        // Mock data, two trades in the first 15m bar and one in the second
+        let trade = |ts_recv: u64, price: i64| mbp1(instrument_id, ts_recv, price, Action::Trade);
+        let records = vec![
+            trade(1704209103644092562, 500),
+            trade(1704209104644092562, 6770),
+            trade(1704209703644092562, 6870),
+        ];
+
+        let mut transaction = pool.begin().await?;
+        insert_records(&mut transaction, records).await?;
+        transaction.commit().await?;
+
+        // Test
+        let mut bars = Vec::new();
+        for interval in [Interval::Duration("15m".to_string()), Interval::Nanos(500_000_000)] {
+            let query_params = RetrieveParams {
+                symbols: vec!["AAPL".to_string()],
+                start_ts: 1704209103644092562,
+                end_ts: 1704209703644092563,
+                schema: String::from("ohlcv-1s"),
+                as_of_version: None,
+                as_of_time: None,
+                interval: Some(interval),
+            };
+            let mut cursor = OhlcvMsg::retrieve_query(&pool, query_params).await?;
+            let mut query: Vec<(u64, i64, i64, u64)> = vec![];
+            while let Some(row_result) = cursor.next().await {
+                let bar = OhlcvMsg::from_row(&row_result?)?;
+                query.push((bar.hd.ts_event, bar.open, bar.close, bar.volume));
+            }
+            bars.push(query);
+        }
+
+        // Validate
+        assert_eq!(
+            bars[0],
+            vec![
+                (1704208500000000000, 500, 6770, 2),
+                (1704209400000000000, 6870, 6870, 1)
+            ]
+        );
+        assert_eq!(
+            bars[1],
+            vec![
+                (1704209103500000000, 500, 500, 1),
+                (1704209104500000000, 6770, 6770, 1),
+                (1704209703500000000, 6870, 6870, 1)
+            ]
+        );
+
+        // Cleanup
+        delete_instrument(&pool, instrument_id).await?;
+
+        Ok(())
+    }
 }
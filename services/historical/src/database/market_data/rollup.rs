@@ -0,0 +1,400 @@
+use crate::Result;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::collections::BTreeSet;
+use tracing::info;
+
+/// Bar intervals kept in `ohlcv_rollup`, each built from the one before it.
+pub const ROLLUP_INTERVALS: [i64; 4] = [
+    1_000_000_000,      // 1 second
+    60_000_000_000,     // 1 minute
+    3_600_000_000_000,  // 1 hour
+    86_400_000_000_000, // 1 day
+];
+
+/// Class of the per instrument advisory locks held while bars are recomputed, so concurrent
+/// refreshes see each other's bars before writing a shared one.
+const ROLLUP_LOCK: i32 = 0x6f68_6c63;
+
+/// Rows the bars of `interval` are aggregated from: the mbp trades for 1s bars, otherwise the
+/// bars of the previous interval. `seq` orders rows with the same `ts`.
+fn rollup_source(interval: i64) -> String {
+    match ROLLUP_INTERVALS.iter().position(|i| *i == interval) {
+        Some(0) | None => r#"
+            SELECT instrument_id, ts_recv AS ts, id AS seq, price AS open, price AS close,
+                   price AS low, price AS high, size::bigint AS volume
+            FROM mbp
+            WHERE action = 84 -- Trades only, 'T' in ASCII
+            AND superseded_by IS NULL
+            "#
+        .to_string(),
+        Some(level) => format!(
+            r#"
+            SELECT instrument_id, ts_event AS ts, 0::bigint AS seq, open, close, low, high, volume
+            FROM ohlcv_rollup
+            WHERE interval_ns = {}
+            "#,
+            ROLLUP_INTERVALS[level - 1]
+        ),
+    }
+}
+
+async fn lock_instruments(
+    tx: &mut Transaction<'_, Postgres>,
+    instrument_ids: &BTreeSet<i32>,
+) -> Result<()> {
+    // Always in id order, so writers sharing instruments cannot deadlock
+    for id in instrument_ids {
+        sqlx::query("SELECT pg_advisory_xact_lock($1, $2)")
+            .bind(ROLLUP_LOCK)
+            .bind(*id)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Recomputes every bar holding one of the given trades, `(instrument_id, ts_recv)`, from the
+/// rows currently in the transaction. Bars left without trades are removed.
+pub async fn refresh_rollups(
+    tx: &mut Transaction<'_, Postgres>,
+    trades: &[(i32, i64)],
+) -> Result<()> {
+    if trades.is_empty() {
+        return Ok(());
+    }
+    lock_instruments(tx, &trades.iter().map(|(id, _)| *id).collect()).await?;
+
+    let mut bars: BTreeSet<(i32, i64)> = trades.iter().copied().collect();
+    for interval in ROLLUP_INTERVALS {
+        bars = bars
+            .into_iter()
+            .map(|(id, ts)| (id, ts - ts.rem_euclid(interval)))
+            .collect();
+        let (instrument_ids, starts): (Vec<i32>, Vec<i64>) = bars.iter().copied().unzip();
+
+        sqlx::query(
+            r#"
+            DELETE FROM ohlcv_rollup r
+            USING UNNEST($1::int[], $2::bigint[]) AS t(instrument_id, ts_event)
+            WHERE r.interval_ns = $3 AND r.instrument_id = t.instrument_id AND r.ts_event = t.ts_event
+            "#,
+        )
+        .bind(&instrument_ids)
+        .bind(&starts)
+        .bind(interval)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(&format!(
+            r#"
+            INSERT INTO ohlcv_rollup (instrument_id, interval_ns, ts_event, open, close, low, high, volume)
+            SELECT t.instrument_id, $3, t.ts_event,
+                   (array_agg(s.open ORDER BY s.ts, s.seq))[1],
+                   (array_agg(s.close ORDER BY s.ts DESC, s.seq DESC))[1],
+                   MIN(s.low), MAX(s.high), SUM(s.volume)::bigint
+            FROM UNNEST($1::int[], $2::bigint[]) AS t(instrument_id, ts_event)
+            INNER JOIN ({}) s ON s.instrument_id = t.instrument_id
+                AND s.ts >= t.ts_event AND s.ts < t.ts_event + $3
+            GROUP BY t.instrument_id, t.ts_event
+            "#,
+            rollup_source(interval)
+        ))
+        .bind(&instrument_ids)
+        .bind(&starts)
+        .bind(interval)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Queues the seconds of the given trades, `(instrument_id, ts_recv)`, for
+/// `refresh_queued_rollups`. Takes no lock, so writers can queue trades of the same instruments
+/// concurrently.
+pub async fn queue_rollups(
+    tx: &mut Transaction<'_, Postgres>,
+    trades: &[(i32, i64)],
+) -> Result<()> {
+    let seconds: BTreeSet<(i32, i64)> = trades
+        .iter()
+        .map(|(id, ts)| (*id, ts - ts.rem_euclid(ROLLUP_INTERVALS[0])))
+        .collect();
+    if seconds.is_empty() {
+        return Ok(());
+    }
+    let (instrument_ids, starts): (Vec<i32>, Vec<i64>) = seconds.into_iter().unzip();
+
+    sqlx::query(
+        "INSERT INTO ohlcv_rollup_queue (instrument_id, ts_event) SELECT * FROM UNNEST($1::int[], $2::bigint[])",
+    )
+    .bind(&instrument_ids)
+    .bind(&starts)
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Recomputes the bars of every queued second from the committed rows and empties the queue.
+/// Keys queued by transactions still running are left for the next call. Returns the number
+/// of seconds refreshed.
+pub async fn refresh_queued_rollups(pool: &PgPool) -> Result<usize> {
+    let mut tx = pool.begin().await?;
+    let queued: Vec<(i32, i64)> =
+        sqlx::query_as("DELETE FROM ohlcv_rollup_queue RETURNING instrument_id, ts_event")
+            .fetch_all(&mut tx)
+            .await?;
+    let seconds: Vec<(i32, i64)> = queued
+        .into_iter()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    refresh_rollups(&mut tx, &seconds).await?;
+    tx.commit().await?;
+
+    Ok(seconds.len())
+}
+
+/// Rebuilds every bar of the instruments in `[start, end)`, widened to whole days. Returns the
+/// number of bars written.
+pub async fn rebuild_rollups(
+    tx: &mut Transaction<'_, Postgres>,
+    instrument_ids: &[i32],
+    start: i64,
+    end: i64,
+) -> Result<u64> {
+    lock_instruments(tx, &instrument_ids.iter().copied().collect()).await?;
+
+    let day = ROLLUP_INTERVALS[ROLLUP_INTERVALS.len() - 1];
+    let start = start - start.rem_euclid(day);
+    let end = end + (day - end.rem_euclid(day)) % day;
+
+    let mut written = 0;
+    for interval in ROLLUP_INTERVALS {
+        sqlx::query(
+            r#"
+            DELETE FROM ohlcv_rollup
+            WHERE interval_ns = $1 AND instrument_id = ANY($2) AND ts_event >= $3 AND ts_event < $4
+            "#,
+        )
+        .bind(interval)
+        .bind(instrument_ids)
+        .bind(start)
+        .bind(end)
+        .execute(&mut *tx)
+        .await?;
+
+        let result = sqlx::query(&format!(
+            r#"
+            INSERT INTO ohlcv_rollup (instrument_id, interval_ns, ts_event, open, close, low, high, volume)
+            SELECT s.instrument_id, $1, (s.ts / $1) * $1,
+                   (array_agg(s.open ORDER BY s.ts, s.seq))[1],
+                   (array_agg(s.close ORDER BY s.ts DESC, s.seq DESC))[1],
+                   MIN(s.low), MAX(s.high), SUM(s.volume)::bigint
+            FROM ({}) s
+            WHERE s.instrument_id = ANY($2) AND s.ts >= $3 AND s.ts < $4
+            GROUP BY s.instrument_id, s.ts / $1
+            "#,
+            rollup_source(interval)
+        ))
+        .bind(interval)
+        .bind(instrument_ids)
+        .bind(start)
+        .bind(end)
+        .execute(&mut *tx)
+        .await?;
+        written += result.rows_affected();
+    }
+
+    info!(
+        "Rebuilt {} rollup bars of {} instruments from {} to {}",
+        written,
+        instrument_ids.len(),
+        start,
+        end
+    );
+    Ok(written)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::init::init_db;
+    use crate::database::market_data::create::{IngestMode, InsertBatch};
+    use crate::test_utils::{create_instrument, delete_instrument, mbp1};
+    use mbn::enums::Action;
+    use mbn::records::Mbp1Msg;
+    use serial_test::serial;
+    use sqlx::PgPool;
+
+    fn trade(id: i32, ts: i64, price: i64, size: u32) -> Mbp1Msg {
+        Mbp1Msg {
+            size,
+            ..mbp1(id, ts as u64, price, Action::Trade)
+        }
+    }
+
+    async fn insert(pool: &PgPool, records: &[Mbp1Msg]) -> anyhow::Result<()> {
+        let mut tx = pool.begin().await?;
+        let mut batch = InsertBatch::new(IngestMode::Strict, None);
+        for record in records {
+            batch.process(record).await?;
+        }
+        batch.execute(&mut tx).await?;
+        tx.commit().await?;
+        refresh_queued_rollups(pool).await?;
+        Ok(())
+    }
+
+    async fn bars(
+        pool: &PgPool,
+        id: i32,
+        interval: i64,
+    ) -> anyhow::Result<Vec<(i64, i64, i64, i64, i64, i64)>> {
+        let bars = sqlx::query_as(
+            r#"
+            SELECT ts_event, open, close, low, high, volume
+            FROM ohlcv_rollup
+            WHERE instrument_id = $1 AND interval_ns = $2
+            ORDER BY ts_event
+            "#,
+        )
+        .bind(id)
+        .bind(interval)
+        .fetch_all(pool)
+        .await?;
+        Ok(bars)
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_refresh_rollups() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let id = create_instrument(&pool, "AAPL").await?;
+        let second = 1704209103000000000; // 2024-01-02 15:25:03
+
+        // Test
+        insert(
+            &pool,
+            &[
+                trade(id, second + 10, 100, 1),
+                trade(id, second + 20, 105, 2),
+                trade(id, second + 1_000_000_000, 95, 3),
+            ],
+        )
+        .await?;
+        // A later batch with an earlier trade in the first bar
+        insert(&pool, &[trade(id, second + 5, 110, 4)]).await?;
+
+        // Validate
+        assert_eq!(
+            bars(&pool, id, 1_000_000_000).await?,
+            vec![
+                (second, 110, 105, 100, 110, 7),
+                (second + 1_000_000_000, 95, 95, 95, 95, 3)
+            ]
+        );
+        assert_eq!(
+            bars(&pool, id, 60_000_000_000).await?,
+            vec![(1704209100000000000, 110, 95, 95, 110, 10)]
+        );
+        assert_eq!(
+            bars(&pool, id, 86_400_000_000_000).await?,
+            vec![(1704153600000000000, 110, 95, 95, 110, 10)]
+        );
+
+        // Cleanup
+        delete_instrument(&pool, id).await?;
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_refresh_queued_rollups() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let id = create_instrument(&pool, "AAPL").await?;
+        let second = 1704209103000000000;
+
+        // Two writers of the same instrument, one committed and one still open
+        let mut committed = pool.begin().await?;
+        let mut batch = InsertBatch::new(IngestMode::Strict, None);
+        batch.process(&trade(id, second + 20, 105, 2)).await?;
+        batch.execute(&mut committed).await?;
+        committed.commit().await?;
+
+        let mut open = pool.begin().await?;
+        let mut batch = InsertBatch::new(IngestMode::Strict, None);
+        batch.process(&trade(id, second + 10, 100, 1)).await?;
+        batch.execute(&mut open).await?;
+
+        // Test
+        assert!(bars(&pool, id, 1_000_000_000).await?.is_empty());
+        let refreshed = refresh_queued_rollups(&pool).await?;
+        open.commit().await?;
+
+        // Validate
+        assert_eq!(refreshed, 1);
+        assert_eq!(
+            bars(&pool, id, 1_000_000_000).await?,
+            vec![(second, 105, 105, 105, 105, 2)]
+        );
+
+        // The open writer's trade is refreshed by the next call
+        assert_eq!(refresh_queued_rollups(&pool).await?, 1);
+        assert_eq!(
+            bars(&pool, id, 1_000_000_000).await?,
+            vec![(second, 100, 105, 100, 105, 3)]
+        );
+        assert_eq!(refresh_queued_rollups(&pool).await?, 0);
+
+        // Cleanup
+        delete_instrument(&pool, id).await?;
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_rebuild_rollups() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let id = create_instrument(&pool, "AAPL").await?;
+        let second = 1704209103000000000;
+        insert(
+            &pool,
+            &[
+                trade(id, second, 100, 1),
+                trade(id, second + 60_000_000_000, 90, 1),
+            ],
+        )
+        .await?;
+        let expected = bars(&pool, id, 60_000_000_000).await?;
+        sqlx::query("DELETE FROM ohlcv_rollup WHERE instrument_id = $1")
+            .bind(id)
+            .execute(&pool)
+            .await?;
+
+        // Test
+        let mut tx = pool.begin().await?;
+        let written = rebuild_rollups(&mut tx, &[id], second, second + 1).await?;
+        tx.commit().await?;
+
+        // Validate
+        assert_eq!(written, 2 + 2 + 1 + 1);
+        assert_eq!(bars(&pool, id, 60_000_000_000).await?, expected);
+        assert_eq!(expected.len(), 2);
+
+        // Cleanup
+        delete_instrument(&pool, id).await?;
+
+        Ok(())
+    }
+}
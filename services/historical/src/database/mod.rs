@@ -1,4 +1,10 @@
+pub mod ingest;
 pub mod init;
+pub mod jobs;
 pub mod market_data;
+pub mod partitions;
+pub mod retention;
+pub mod stats;
 pub mod symbols;
 pub mod utils;
+pub mod versions;
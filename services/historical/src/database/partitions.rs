@@ -0,0 +1,431 @@
+use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::collections::BTreeSet;
+use std::env;
+use std::str::FromStr;
+use tracing::info;
+
+pub(crate) const NANOS_PER_DAY: i64 = 86_400_000_000_000;
+
+/// Advisory lock taken while partitions are created, so concurrent writers create each range once.
+const PARTITION_LOCK: i64 = 0x6d62_705f_7061_7274;
+
+/// Width of new `mbp`/`bid_ask` partitions, set with `HISTORICAL_PARTITION_INTERVAL`. Partitions
+/// that already exist keep their range, new ones are clipped to fit around them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PartitionInterval {
+    #[default]
+    Month,
+    Day,
+}
+
+impl PartitionInterval {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PartitionInterval::Month => "month",
+            PartitionInterval::Day => "day",
+        }
+    }
+
+    pub fn from_env() -> Result<Self> {
+        match env::var("HISTORICAL_PARTITION_INTERVAL") {
+            Ok(interval) if !interval.is_empty() => PartitionInterval::from_str(&interval),
+            _ => Ok(PartitionInterval::default()),
+        }
+    }
+
+    /// UTC range `[start, end)` in nanoseconds of the partition holding `ts`.
+    pub fn bounds(&self, ts: i64) -> (i64, i64) {
+        let day = ts.div_euclid(NANOS_PER_DAY);
+        match self {
+            PartitionInterval::Day => (day * NANOS_PER_DAY, (day + 1) * NANOS_PER_DAY),
+            PartitionInterval::Month => {
+                let (year, month, _) = civil_from_days(day);
+                let (next_year, next_month) = if month == 12 {
+                    (year + 1, 1)
+                } else {
+                    (year, month + 1)
+                };
+                (
+                    days_from_civil(year, month, 1) * NANOS_PER_DAY,
+                    days_from_civil(next_year, next_month, 1) * NANOS_PER_DAY,
+                )
+            }
+        }
+    }
+}
+
+impl FromStr for PartitionInterval {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "month" => Ok(PartitionInterval::Month),
+            "day" => Ok(PartitionInterval::Day),
+            _ => Err(crate::error!(
+                CustomError,
+                "Invalid partition interval: {}",
+                s
+            )),
+        }
+    }
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date.
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Date of the given days since 1970-01-01.
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Suffix of the partition tables starting at `start`, e.g. `p20240101`.
+fn partition_name(start: i64) -> String {
+    let (year, month, day) = civil_from_days(start.div_euclid(NANOS_PER_DAY));
+    format!("p{:04}{:02}{:02}", year, month, day)
+}
+
+/// A `ts_recv` range of `mbp` and `bid_ask`, stored in the tables `mbp_<name>` and
+/// `bid_ask_<name>`. Detached partitions keep their rows but are no longer queried.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Partition {
+    pub name: String,
+    pub range_start: i64, // Inclusive
+    pub range_end: i64,   // Exclusive
+    pub attached: bool,
+    pub created_at: i64, // UNIX nanoseconds
+}
+
+const PARTITION_COLUMNS: &str = r#"
+    name, range_start, range_end, attached,
+    CAST(EXTRACT(EPOCH FROM created_at) * 1000000000 AS BIGINT) AS created_at
+"#;
+
+impl Partition {
+    pub async fn list(pool: &PgPool) -> Result<Vec<Partition>> {
+        let partitions: Vec<Partition> = sqlx::query_as(&format!(
+            "SELECT {} FROM mbp_partition ORDER BY range_start",
+            PARTITION_COLUMNS
+        ))
+        .fetch_all(pool)
+        .await?;
+
+        Ok(partitions)
+    }
+
+    async fn get_for_update(
+        tx: &mut Transaction<'_, Postgres>,
+        name: &str,
+    ) -> Result<Option<Partition>> {
+        let partition: Option<Partition> = sqlx::query_as(&format!(
+            "SELECT {} FROM mbp_partition WHERE name = $1 FOR UPDATE",
+            PARTITION_COLUMNS
+        ))
+        .bind(name)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        Ok(partition)
+    }
+
+    /// Attaches a detached partition again, validating its rows against the range.
+    pub async fn attach(pool: &PgPool, name: &str) -> Result<Partition> {
+        let mut tx = pool.begin().await?;
+        let partition = match Self::get_for_update(&mut tx, name).await? {
+            Some(partition) if !partition.attached => partition,
+            Some(_) => {
+                return Err(crate::error!(
+                    CustomError,
+                    "Partition {} is attached.",
+                    name
+                ))
+            }
+            None => return Err(crate::error!(CustomError, "Unknown partition: {}", name)),
+        };
+
+        // mbp first, the bid_ask foreign key is checked against it
+        for table in ["mbp", "bid_ask"] {
+            sqlx::query(&format!(
+                "ALTER TABLE {0} ATTACH PARTITION {0}_{1} FOR VALUES FROM ({2}) TO ({3})",
+                table, partition.name, partition.range_start, partition.range_end
+            ))
+            .execute(&mut *tx)
+            .await?;
+        }
+        sqlx::query("UPDATE mbp_partition SET attached = TRUE WHERE name = $1")
+            .bind(name)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        info!("Attached partition {}", name);
+        Ok(Partition {
+            attached: true,
+            ..partition
+        })
+    }
+
+    /// Detaches a partition, keeping its tables.
+    pub async fn detach(pool: &PgPool, name: &str) -> Result<Partition> {
+        let mut tx = pool.begin().await?;
+        let partition = match Self::get_for_update(&mut tx, name).await? {
+            Some(partition) if partition.attached => partition,
+            Some(_) => {
+                return Err(crate::error!(
+                    CustomError,
+                    "Partition {} is detached.",
+                    name
+                ))
+            }
+            None => return Err(crate::error!(CustomError, "Unknown partition: {}", name)),
+        };
+
+        // bid_ask first, and without the foreign key it keeps once detached, so the mbp
+        // partition holding the rows it references can be detached as well
+        sqlx::query(&format!(
+            "ALTER TABLE bid_ask DETACH PARTITION bid_ask_{}",
+            partition.name
+        ))
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(&format!(
+            "ALTER TABLE bid_ask_{} DROP CONSTRAINT IF EXISTS fk_mbp_bid_ask",
+            partition.name
+        ))
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(&format!(
+            "ALTER TABLE mbp DETACH PARTITION mbp_{}",
+            partition.name
+        ))
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("UPDATE mbp_partition SET attached = FALSE WHERE name = $1")
+            .bind(name)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        info!("Detached partition {}", name);
+        Ok(Partition {
+            attached: false,
+            ..partition
+        })
+    }
+}
+
+/// `(range_start, range_end, attached)` of every partition, ordered by start.
+async fn partition_ranges(tx: &mut Transaction<'_, Postgres>) -> Result<Vec<(i64, i64, bool)>> {
+    let ranges: Vec<(i64, i64, bool)> = sqlx::query_as(
+        "SELECT range_start, range_end, attached FROM mbp_partition ORDER BY range_start",
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    Ok(ranges)
+}
+
+/// Days, as their first nanosecond, not covered by any partition. Partition bounds are whole
+/// days, so a day is either fully covered or not at all.
+fn missing_days(days: &BTreeSet<i64>, ranges: &[(i64, i64, bool)]) -> Result<Vec<i64>> {
+    let mut missing = Vec::new();
+    for day in days {
+        match ranges
+            .iter()
+            .find(|(start, end, _)| start <= day && day < end)
+        {
+            Some((start, _, false)) => {
+                return Err(crate::error!(
+                    CustomError,
+                    "Partition {} is detached, attach it to load records into its range.",
+                    partition_name(*start)
+                ));
+            }
+            Some(_) => {}
+            None => missing.push(*day),
+        }
+    }
+    Ok(missing)
+}
+
+/// Makes sure rows with the given `ts_recv` values have a partition to go to, creating the
+/// missing ones with `interval`, clipped to the gaps between existing partitions.
+pub async fn ensure_partitions(
+    tx: &mut Transaction<'_, Postgres>,
+    timestamps: &[i64],
+    interval: PartitionInterval,
+) -> Result<()> {
+    let days: BTreeSet<i64> = timestamps
+        .iter()
+        .map(|ts| ts.div_euclid(NANOS_PER_DAY) * NANOS_PER_DAY)
+        .collect();
+    if days.is_empty() || missing_days(&days, &partition_ranges(tx).await?)?.is_empty() {
+        return Ok(());
+    }
+
+    // Check again under the lock, another writer may have created them meanwhile
+    sqlx::query("SELECT pg_advisory_xact_lock($1)")
+        .bind(PARTITION_LOCK)
+        .execute(&mut *tx)
+        .await?;
+    let mut ranges = partition_ranges(tx).await?;
+
+    for day in missing_days(&days, &ranges)? {
+        if ranges
+            .iter()
+            .any(|(start, end, _)| *start <= day && day < *end)
+        {
+            continue; // Created for an earlier day of this batch
+        }
+
+        let (mut start, mut end) = interval.bounds(day);
+        for (range_start, range_end, _) in &ranges {
+            if *range_end <= day {
+                start = start.max(*range_end);
+            } else if *range_start > day {
+                end = end.min(*range_start);
+            }
+        }
+
+        let name = partition_name(start);
+        for table in ["mbp", "bid_ask"] {
+            sqlx::query(&format!(
+                "CREATE TABLE {0}_{1} PARTITION OF {0} FOR VALUES FROM ({2}) TO ({3})",
+                table, name, start, end
+            ))
+            .execute(&mut *tx)
+            .await?;
+        }
+        sqlx::query("INSERT INTO mbp_partition (name, range_start, range_end) VALUES ($1, $2, $3)")
+            .bind(&name)
+            .bind(start)
+            .bind(end)
+            .execute(&mut *tx)
+            .await?;
+
+        info!("Created partition {} for [{}, {})", name, start, end);
+        ranges.push((start, end, true));
+    }
+
+    Ok(())
+}
+
+/// Creates the partition of `now` and the `ahead` following ones, so loads of live data never
+/// wait on partition creation.
+pub async fn create_future_partitions(
+    pool: &PgPool,
+    now: i64,
+    interval: PartitionInterval,
+    ahead: u32,
+) -> Result<()> {
+    let mut starts = Vec::new();
+    let mut ts = now;
+    for _ in 0..=ahead {
+        let (start, end) = interval.bounds(ts);
+        starts.push(start);
+        ts = end;
+    }
+
+    let mut tx = pool.begin().await?;
+    ensure_partitions(&mut tx, &starts, interval).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::init::init_db;
+    use serial_test::serial;
+
+    #[test]
+    fn test_partition_bounds() {
+        // 2024-02-29 12:00 UTC
+        let ts = 1709208000000000000;
+
+        // Test
+        let month = PartitionInterval::Month.bounds(ts);
+        let day = PartitionInterval::Day.bounds(ts);
+
+        // Validate
+        assert_eq!(month, (1706745600000000000, 1709251200000000000)); // Feb 2024
+        assert_eq!(day, (1709164800000000000, 1709251200000000000));
+        assert_eq!(partition_name(month.0), "p20240201");
+        assert_eq!(partition_name(day.0), "p20240229");
+        assert_eq!(
+            PartitionInterval::Month.bounds(1733011200000000000), // 2024-12-01
+            (1733011200000000000, 1735689600000000000)
+        );
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_ensure_partitions() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        // 1990-01-15 and 1990-01-20, before any loaded data
+        let timestamps = [632361600000000000, 632793600000000000];
+
+        // Test
+        let mut tx = pool.begin().await?;
+        ensure_partitions(&mut tx, &timestamps, PartitionInterval::Day).await?;
+        ensure_partitions(&mut tx, &timestamps, PartitionInterval::Day).await?;
+        tx.commit().await?;
+        let detached = Partition::detach(&pool, "p19900115").await?;
+        let mut tx = pool.begin().await?;
+        let result = ensure_partitions(&mut tx, &timestamps, PartitionInterval::Day).await;
+        tx.rollback().await?;
+        let attached = Partition::attach(&pool, "p19900115").await?;
+
+        // Validate
+        let created: Vec<Partition> = Partition::list(&pool)
+            .await?
+            .into_iter()
+            .filter(|p| p.name.starts_with("p1990"))
+            .collect();
+        assert_eq!(created.len(), 2);
+        assert_eq!(created[0].range_start, 632361600000000000);
+        assert_eq!(created[0].range_end, 632361600000000000 + NANOS_PER_DAY);
+        assert!(!detached.attached);
+        assert!(result.is_err());
+        assert!(attached.attached);
+
+        // Cleanup, referenced mbp partitions can only be dropped once detached
+        for partition in created {
+            Partition::detach(&pool, &partition.name).await?;
+            for table in ["bid_ask", "mbp"] {
+                sqlx::query(&format!("DROP TABLE {}_{}", table, partition.name))
+                    .execute(&pool)
+                    .await?;
+            }
+        }
+        sqlx::query("DELETE FROM mbp_partition WHERE name LIKE 'p1990%'")
+            .execute(&pool)
+            .await?;
+
+        Ok(())
+    }
+}
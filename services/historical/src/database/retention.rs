@@ -0,0 +1,508 @@
+use crate::database::partitions::{civil_from_days, days_from_civil, NANOS_PER_DAY};
+use crate::{Error, Result};
+use futures::stream::{Stream, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Row, Transaction};
+use std::pin::Pin;
+use std::str::FromStr;
+use tracing::info;
+
+/// Start of the month `months` before the month of `now`, rows received earlier are expired.
+/// Whole months are kept so archives line up with the monthly partitions.
+pub fn retention_cutoff(now: i64, months: i32) -> i64 {
+    let (year, month, _) = civil_from_days(now.div_euclid(NANOS_PER_DAY));
+    let index = year * 12 + month as i64 - 1 - months as i64;
+    days_from_civil(index.div_euclid(12), index.rem_euclid(12) as u32 + 1, 1) * NANOS_PER_DAY
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RetentionPolicyParams {
+    pub instrument_id: Option<i32>,
+    pub dataset: Option<String>,
+    pub retention_months: i32,
+}
+
+/// Months of mbp data kept for an instrument, or for every instrument of a dataset.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, sqlx::FromRow)]
+pub struct RetentionPolicy {
+    pub id: i32,
+    pub instrument_id: Option<i32>,
+    pub dataset: Option<String>,
+    pub retention_months: i32,
+    pub created_at: i64, // UNIX nanoseconds
+    pub updated_at: i64, // UNIX nanoseconds
+}
+
+const POLICY_COLUMNS: &str = r#"
+    id, instrument_id, dataset, retention_months,
+    CAST(EXTRACT(EPOCH FROM created_at) * 1000000000 AS BIGINT) AS created_at,
+    CAST(EXTRACT(EPOCH FROM updated_at) * 1000000000 AS BIGINT) AS updated_at
+"#;
+
+impl RetentionPolicy {
+    /// Creates the policy of the instrument or dataset, or updates its retention if it exists.
+    pub async fn set(pool: &PgPool, params: &RetentionPolicyParams) -> Result<RetentionPolicy> {
+        let conflict = match (params.instrument_id, params.dataset.as_deref()) {
+            (Some(_), None) => "instrument_id",
+            (None, Some(dataset)) if !dataset.is_empty() => "dataset",
+            _ => {
+                return Err(crate::error!(
+                    CustomError,
+                    "A retention policy needs either an instrument_id or a dataset."
+                ))
+            }
+        };
+        if params.retention_months <= 0 {
+            return Err(crate::error!(
+                CustomError,
+                "retention_months must be positive."
+            ));
+        }
+
+        let policy: RetentionPolicy = sqlx::query_as(&format!(
+            r#"
+            INSERT INTO retention_policy (instrument_id, dataset, retention_months)
+            VALUES ($1, $2, $3)
+            ON CONFLICT ({}) DO UPDATE
+            SET retention_months = EXCLUDED.retention_months, updated_at = CURRENT_TIMESTAMP
+            RETURNING {}
+            "#,
+            conflict, POLICY_COLUMNS
+        ))
+        .bind(params.instrument_id)
+        .bind(params.dataset.as_deref())
+        .bind(params.retention_months)
+        .fetch_one(pool)
+        .await?;
+
+        info!("Set retention policy {}", policy.id);
+        Ok(policy)
+    }
+
+    pub async fn list(pool: &PgPool) -> Result<Vec<RetentionPolicy>> {
+        let policies: Vec<RetentionPolicy> = sqlx::query_as(&format!(
+            "SELECT {} FROM retention_policy ORDER BY id",
+            POLICY_COLUMNS
+        ))
+        .fetch_all(pool)
+        .await?;
+
+        Ok(policies)
+    }
+
+    /// Returns whether the policy existed.
+    pub async fn delete(pool: &PgPool, id: i32) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM retention_policy WHERE id = $1")
+            .bind(id)
+            .execute(pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveStatus {
+    Exporting,
+    Verified,
+    Deleted,
+    Failed,
+}
+
+impl ArchiveStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ArchiveStatus::Exporting => "exporting",
+            ArchiveStatus::Verified => "verified",
+            ArchiveStatus::Deleted => "deleted",
+            ArchiveStatus::Failed => "failed",
+        }
+    }
+}
+
+impl FromStr for ArchiveStatus {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "exporting" => Ok(ArchiveStatus::Exporting),
+            "verified" => Ok(ArchiveStatus::Verified),
+            "deleted" => Ok(ArchiveStatus::Deleted),
+            "failed" => Ok(ArchiveStatus::Failed),
+            _ => Err(crate::error!(CustomError, "Invalid archive status: {}", s)),
+        }
+    }
+}
+
+/// An expired `ts_recv` range of one instrument exported to an mbn file, with the deeper levels
+/// of its mbp-10 rows in a JSON lines file next to it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetentionArchive {
+    pub id: i32,
+    pub policy_id: Option<i32>,
+    pub instrument_id: i32,
+    pub ticker: String,
+    pub range_start: i64, // Inclusive
+    pub range_end: i64,   // Exclusive
+    pub file_path: String,
+    pub levels_path: Option<String>,
+    pub records: i64,
+    pub status: ArchiveStatus,
+    pub error: Option<String>,
+    pub created_at: i64, // UNIX nanoseconds
+    pub updated_at: i64, // UNIX nanoseconds
+}
+
+impl sqlx::FromRow<'_, sqlx::postgres::PgRow> for RetentionArchive {
+    fn from_row(row: &sqlx::postgres::PgRow) -> std::result::Result<Self, sqlx::Error> {
+        let status: String = row.try_get("status")?;
+
+        Ok(RetentionArchive {
+            id: row.try_get("id")?,
+            policy_id: row.try_get("policy_id")?,
+            instrument_id: row.try_get("instrument_id")?,
+            ticker: row.try_get("ticker")?,
+            range_start: row.try_get("range_start")?,
+            range_end: row.try_get("range_end")?,
+            file_path: row.try_get("file_path")?,
+            levels_path: row.try_get("levels_path")?,
+            records: row.try_get("records")?,
+            status: ArchiveStatus::from_str(&status).map_err(|e| sqlx::Error::Decode(e.into()))?,
+            error: row.try_get("error")?,
+            created_at: row.try_get("created_at")?,
+            updated_at: row.try_get("updated_at")?,
+        })
+    }
+}
+
+const ARCHIVE_COLUMNS: &str = r#"
+    id, policy_id, instrument_id, ticker, range_start, range_end, file_path, levels_path, records,
+    status, error,
+    CAST(EXTRACT(EPOCH FROM created_at) * 1000000000 AS BIGINT) AS created_at,
+    CAST(EXTRACT(EPOCH FROM updated_at) * 1000000000 AS BIGINT) AS updated_at
+"#;
+
+impl RetentionArchive {
+    pub async fn create(
+        pool: &PgPool,
+        target: &RetentionStatus,
+        range_start: i64,
+        range_end: i64,
+        file_path: &str,
+    ) -> Result<i32> {
+        let id: i32 = sqlx::query_scalar(
+            r#"
+            INSERT INTO retention_archive (policy_id, instrument_id, ticker, range_start, range_end, file_path)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id
+            "#,
+        )
+        .bind(target.policy_id)
+        .bind(target.instrument_id)
+        .bind(&target.ticker)
+        .bind(range_start)
+        .bind(range_end)
+        .bind(file_path)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(id)
+    }
+
+    pub async fn update<'c, E>(
+        executor: E,
+        id: i32,
+        status: ArchiveStatus,
+        records: i64,
+        error: Option<&str>,
+    ) -> Result<()>
+    where
+        E: sqlx::Executor<'c, Database = Postgres>,
+    {
+        sqlx::query(
+            r#"
+            UPDATE retention_archive
+            SET status = $2, records = $3, error = $4, updated_at = CURRENT_TIMESTAMP
+            WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .bind(status.as_str())
+        .bind(records)
+        .bind(error)
+        .execute(executor)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn set_levels_path(pool: &PgPool, id: i32, levels_path: &str) -> Result<()> {
+        sqlx::query("UPDATE retention_archive SET levels_path = $2 WHERE id = $1")
+            .bind(id)
+            .bind(levels_path)
+            .execute(pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Archives of one instrument, or of every instrument when `None`, newest first.
+    pub async fn list(pool: &PgPool, instrument_id: Option<i32>) -> Result<Vec<RetentionArchive>> {
+        let archives: Vec<RetentionArchive> = sqlx::query_as(&format!(
+            "SELECT {} FROM retention_archive WHERE $1::int IS NULL OR instrument_id = $1 ORDER BY id DESC",
+            ARCHIVE_COLUMNS
+        ))
+        .bind(instrument_id)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(archives)
+    }
+}
+
+/// Retention state of an instrument covered by a policy.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, sqlx::FromRow)]
+pub struct RetentionStatus {
+    pub instrument_id: i32,
+    pub ticker: String,
+    pub dataset: Option<String>,
+    pub policy_id: i32,
+    pub retention_months: i32,
+    /// Rows received before this are expired.
+    #[sqlx(default)]
+    pub cutoff: i64,
+    pub oldest_ts_recv: Option<i64>,
+    /// End of the latest archived range whose rows were deleted.
+    pub archived_through: Option<i64>,
+    pub archived_records: i64,
+    /// Error of the latest archive, if it failed.
+    pub last_error: Option<String>,
+}
+
+impl RetentionStatus {
+    /// Whether the instrument still has expired rows.
+    pub fn expired(&self) -> bool {
+        self.oldest_ts_recv.is_some_and(|ts| ts < self.cutoff)
+    }
+
+    /// Instruments with a policy of their own or through their dataset, the instrument's taking
+    /// precedence.
+    pub async fn list(pool: &PgPool, now: i64) -> Result<Vec<RetentionStatus>> {
+        let mut statuses: Vec<RetentionStatus> = sqlx::query_as(
+            r#"
+            SELECT i.id AS instrument_id, i.ticker, i.dataset, p.id AS policy_id, p.retention_months,
+                (SELECT MIN(ts_recv) FROM mbp WHERE instrument_id = i.id) AS oldest_ts_recv,
+                (SELECT MAX(range_end) FROM retention_archive
+                    WHERE instrument_id = i.id AND status = 'deleted') AS archived_through,
+                (SELECT COALESCE(SUM(records), 0)::bigint FROM retention_archive
+                    WHERE instrument_id = i.id AND status = 'deleted') AS archived_records,
+                (SELECT error FROM retention_archive
+                    WHERE instrument_id = i.id ORDER BY id DESC LIMIT 1) AS last_error
+            FROM instrument i
+            INNER JOIN LATERAL (
+                SELECT id, retention_months
+                FROM retention_policy
+                WHERE instrument_id = i.id OR (instrument_id IS NULL AND dataset = i.dataset)
+                ORDER BY instrument_id IS NULL
+                LIMIT 1
+            ) p ON TRUE
+            ORDER BY i.id
+            "#,
+        )
+        .fetch_all(pool)
+        .await?;
+
+        for status in &mut statuses {
+            status.cutoff = retention_cutoff(now, status.retention_months);
+        }
+        Ok(statuses)
+    }
+}
+
+/// Earliest `ts_recv` of the instrument's mbp rows received before `cutoff`.
+pub async fn oldest_expired(pool: &PgPool, instrument_id: i32, cutoff: i64) -> Result<Option<i64>> {
+    let oldest: Option<i64> = sqlx::query_scalar(
+        "SELECT MIN(ts_recv) FROM mbp WHERE instrument_id = $1 AND ts_recv < $2",
+    )
+    .bind(instrument_id)
+    .bind(cutoff)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(oldest)
+}
+
+/// Mbp rows of an instrument in `[start, end)` in the order they are archived, read with
+/// `Mbp10Msg::from_row`. `discriminator` and `depth_count`, the number of stored levels, tell
+/// MBP-1 rows from MBP-10 ones.
+pub fn archive_query(
+    pool: &PgPool,
+    instrument_id: i32,
+    start: i64,
+    end: i64,
+) -> Pin<Box<dyn Stream<Item = std::result::Result<sqlx::postgres::PgRow, sqlx::Error>> + Send + '_>>
+{
+    sqlx::query(
+        r#"
+        SELECT m.instrument_id, m.ts_event, m.price, m.size, m.action, m.side, m.flags, m.ts_recv, m.ts_in_delta, m.sequence, m.discriminator,
+               COALESCE(cardinality(b.depths), 0) AS depth_count,
+               b.depths, b.bid_px, b.ask_px, b.bid_sz, b.ask_sz, b.bid_ct, b.ask_ct
+        FROM mbp m
+        CROSS JOIN LATERAL (
+            SELECT array_agg(depth ORDER BY depth) AS depths,
+                   array_agg(bid_px ORDER BY depth) AS bid_px,
+                   array_agg(ask_px ORDER BY depth) AS ask_px,
+                   array_agg(bid_sz ORDER BY depth) AS bid_sz,
+                   array_agg(ask_sz ORDER BY depth) AS ask_sz,
+                   array_agg(bid_ct ORDER BY depth) AS bid_ct,
+                   array_agg(ask_ct ORDER BY depth) AS ask_ct
+            FROM bid_ask
+            WHERE mbp_id = m.id AND ts_recv = m.ts_recv AND ts_recv >= $2 AND ts_recv < $3
+        ) b
+        WHERE m.instrument_id = $1 AND m.ts_recv >= $2 AND m.ts_recv < $3
+        AND m.superseded_by IS NULL
+        ORDER BY m.ts_recv, m.id
+        "#,
+    )
+    .bind(instrument_id)
+    .bind(start)
+    .bind(end)
+    .fetch(pool)
+}
+
+/// Order-independent digest of archived mbp rows: their count and the wrapping sum of their
+/// dedup keys. The export, the archive file and the deleted rows are each folded into one as they
+/// are read, so they are compared without keeping their keys.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ArchiveDigest {
+    pub records: u64,
+    pub key_sum: u64,
+}
+
+impl ArchiveDigest {
+    pub fn add(&mut self, dedup_key: i64) {
+        self.records += 1;
+        self.key_sum = self.key_sum.wrapping_add(dedup_key as u64);
+    }
+
+    /// Swaps a folded key for another, without changing the count.
+    pub fn replace(&mut self, old_key: i64, new_key: i64) {
+        self.key_sum = self
+            .key_sum
+            .wrapping_sub(old_key as u64)
+            .wrapping_add(new_key as u64);
+    }
+}
+
+/// Deletes the instrument's mbp rows in `[start, end)`, their levels cascade. Superseded rows
+/// go as well, only the current ones are archived and folded into the returned digest.
+pub async fn delete_range(
+    tx: &mut Transaction<'_, Postgres>,
+    instrument_id: i32,
+    start: i64,
+    end: i64,
+) -> Result<ArchiveDigest> {
+    let mut keys = sqlx::query_scalar::<_, i64>(
+        r#"
+        WITH deleted AS (
+            DELETE FROM mbp WHERE instrument_id = $1 AND ts_recv >= $2 AND ts_recv < $3
+            RETURNING dedup_key, superseded_by
+        )
+        SELECT dedup_key FROM deleted WHERE superseded_by IS NULL
+        "#,
+    )
+    .bind(instrument_id)
+    .bind(start)
+    .bind(end)
+    .fetch(tx);
+
+    let mut digest = ArchiveDigest::default();
+    while let Some(key) = keys.try_next().await? {
+        digest.add(key);
+    }
+
+    Ok(digest)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::init::init_db;
+    use crate::test_utils::{create_instrument, delete_instrument};
+    use serial_test::serial;
+
+    #[test]
+    fn test_retention_cutoff() {
+        // 2024-02-29 12:00 UTC
+        let now = 1709208000000000000;
+
+        // Test
+        let one = retention_cutoff(now, 1);
+        let eighteen = retention_cutoff(now, 18);
+
+        // Validate
+        assert_eq!(one, 1704067200000000000); // 2024-01-01
+        assert_eq!(eighteen, 1659312000000000000); // 2022-08-01
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_retention_status() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let aapl = create_instrument(&pool, "AAPL").await?;
+        let tsla = create_instrument(&pool, "TSLA").await?;
+
+        // Test
+        let dataset = RetentionPolicy::set(
+            &pool,
+            &RetentionPolicyParams {
+                dataset: Some("GLBX.MDP3".to_string()),
+                retention_months: 18,
+                ..Default::default()
+            },
+        )
+        .await?;
+        let instrument = RetentionPolicy::set(
+            &pool,
+            &RetentionPolicyParams {
+                instrument_id: Some(tsla),
+                retention_months: 6,
+                ..Default::default()
+            },
+        )
+        .await?;
+        let updated = RetentionPolicy::set(
+            &pool,
+            &RetentionPolicyParams {
+                instrument_id: Some(tsla),
+                retention_months: 3,
+                ..Default::default()
+            },
+        )
+        .await?;
+        let invalid = RetentionPolicy::set(&pool, &RetentionPolicyParams::default()).await;
+        let statuses = RetentionStatus::list(&pool, 1709208000000000000).await?;
+
+        // Validate
+        assert_eq!(instrument.id, updated.id);
+        assert!(invalid.is_err());
+        let aapl_status = statuses.iter().find(|s| s.instrument_id == aapl).unwrap();
+        let tsla_status = statuses.iter().find(|s| s.instrument_id == tsla).unwrap();
+        assert_eq!(aapl_status.policy_id, dataset.id);
+        assert_eq!(aapl_status.retention_months, 18);
+        assert_eq!(tsla_status.policy_id, instrument.id);
+        assert_eq!(tsla_status.retention_months, 3);
+        assert_eq!(tsla_status.cutoff, retention_cutoff(1709208000000000000, 3));
+        assert!(!tsla_status.expired());
+
+        // Cleanup
+        RetentionPolicy::delete(&pool, dataset.id).await?;
+        delete_instrument(&pool, aapl).await?;
+        delete_instrument(&pool, tsla).await?;
+
+        Ok(())
+    }
+}
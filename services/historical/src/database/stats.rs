@@ -0,0 +1,288 @@
+use crate::database::partitions::{civil_from_days, NANOS_PER_DAY};
+use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Row};
+
+/// Stored data of an instrument. Counts and ranges cover the current mbp rows, the rows
+/// superseded by corrections are counted apart. Row counts and sizes are estimates from the
+/// planner statistics of each partition, as of its last `ANALYZE`, while ranges and days are
+/// exact.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, sqlx::FromRow)]
+pub struct InstrumentStats {
+    pub instrument_id: i32,
+    pub ticker: String,
+    pub mbp_rows: i64,
+    pub superseded_rows: i64,
+    pub bid_ask_rows: i64,
+    pub min_ts_event: Option<i64>,
+    pub max_ts_event: Option<i64>,
+    pub min_ts_recv: Option<i64>,
+    pub max_ts_recv: Option<i64>,
+    pub days: i64, // UTC days with at least one current row
+    /// Share of the `mbp` and `bid_ask` partitions, in bytes, by row count.
+    pub approx_size_bytes: i64,
+    pub last_ingest: Option<i64>, // UNIX nanoseconds, completion of its last ingest session
+}
+
+/// Current mbp rows of an instrument received on a UTC day.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DayCoverage {
+    pub date: String, // YYYY-MM-DD
+    pub day_start: i64,
+    pub rows: i64,
+    pub first_ts_recv: Option<i64>,
+    pub last_ts_recv: Option<i64>,
+}
+
+impl InstrumentStats {
+    /// Stats of every instrument, including the ones without data. Nothing is scanned: counts
+    /// come from `pg_class` and `pg_stats`, ranges from the `(instrument_id, ts_recv)` and
+    /// `(instrument_id, ts_event)` indexes, with one lookup per day with data for `days`, and
+    /// the last ingest from the sessions recorded against the instrument.
+    pub async fn list(pool: &PgPool) -> Result<Vec<InstrumentStats>> {
+        // The lookups are costed high enough to be JIT compiled, which takes far longer than them
+        let mut tx = pool.begin().await?;
+        sqlx::query("SET LOCAL jit = off").execute(&mut tx).await?;
+
+        let stats: Vec<InstrumentStats> = sqlx::query_as(
+            r#"
+            WITH RECURSIVE day_starts AS (
+                SELECT i.id AS instrument_id,
+                    (SELECT MIN(ts_recv) FROM mbp
+                     WHERE instrument_id = i.id AND superseded_by IS NULL) AS ts_recv
+                FROM instrument i
+                UNION ALL
+                -- First current row of the next day with one
+                SELECT d.instrument_id,
+                    (SELECT MIN(ts_recv) FROM mbp
+                     WHERE instrument_id = d.instrument_id AND superseded_by IS NULL
+                     AND ts_recv >= (d.ts_recv / $1 + 1) * $1)
+                FROM day_starts d
+                WHERE d.ts_recv IS NOT NULL
+            ),
+            days AS (
+                SELECT instrument_id, COUNT(ts_recv) AS days
+                FROM day_starts
+                GROUP BY instrument_id
+            ),
+            partitions AS (
+                SELECT c.relname, n.nspname, i.inhparent = 'mbp'::regclass AS is_mbp,
+                    GREATEST(c.reltuples, 0)::float8 AS reltuples,
+                    pg_total_relation_size(c.oid)::float8 AS bytes
+                FROM pg_inherits i
+                INNER JOIN pg_class c ON c.oid = i.inhrelid
+                INNER JOIN pg_namespace n ON n.oid = c.relnamespace
+                WHERE i.inhparent IN ('mbp'::regclass, 'bid_ask'::regclass)
+            ),
+            totals AS (
+                SELECT
+                    SUM(reltuples) FILTER (WHERE is_mbp) AS mbp_rows,
+                    SUM(reltuples) FILTER (WHERE NOT is_mbp) AS bid_ask_rows,
+                    SUM(bytes) FILTER (WHERE NOT is_mbp) AS bid_ask_bytes
+                FROM partitions
+            ),
+            -- Statistics of each mbp partition: the most common instrument ids with their
+            -- frequencies, the number of distinct ids and the share of current rows
+            partition_stats AS (
+                SELECT p.reltuples, p.bytes,
+                    COALESCE(s.most_common_vals::text::int[], '{}') AS ids,
+                    COALESCE(s.most_common_freqs, '{}') AS freqs,
+                    CASE WHEN s.n_distinct < 0 THEN -s.n_distinct * p.reltuples
+                        ELSE s.n_distinct END AS distinct_ids,
+                    COALESCE(c.null_frac, 1) AS current_frac
+                FROM partitions p
+                LEFT JOIN pg_stats s ON s.schemaname = p.nspname AND s.tablename = p.relname
+                    AND s.attname = 'instrument_id'
+                LEFT JOIN pg_stats c ON c.schemaname = p.nspname AND c.tablename = p.relname
+                    AND c.attname = 'superseded_by'
+                WHERE p.is_mbp
+            ),
+            -- Rows of an instrument in each partition, estimated the way the planner does
+            estimates AS (
+                SELECT i.id AS instrument_id, p.bytes, p.current_frac,
+                    p.reltuples * COALESCE(
+                        p.freqs[array_position(p.ids, i.id)],
+                        (1 - (SELECT COALESCE(SUM(f), 0) FROM unnest(p.freqs) f))
+                            / NULLIF(p.distinct_ids - cardinality(p.ids), 0),
+                        0) AS rows
+                FROM instrument i
+                CROSS JOIN partition_stats p
+            ),
+            rows AS (
+                SELECT instrument_id,
+                    SUM(rows * current_frac) AS mbp_rows,
+                    SUM(rows * (1 - current_frac)) AS superseded_rows,
+                    SUM(rows) AS all_rows,
+                    SUM(bytes * rows / NULLIF((SELECT mbp_rows FROM totals), 0)) AS mbp_bytes
+                FROM estimates
+                GROUP BY instrument_id
+            )
+            SELECT
+                i.id AS instrument_id,
+                i.ticker,
+                CAST(ROUND(COALESCE(r.mbp_rows, 0)) AS BIGINT) AS mbp_rows,
+                CAST(ROUND(COALESCE(r.superseded_rows, 0)) AS BIGINT) AS superseded_rows,
+                -- Levels per mbp row are taken to be the same for every instrument
+                CAST(ROUND(COALESCE(
+                    r.all_rows * t.bid_ask_rows / NULLIF(t.mbp_rows, 0), 0)) AS BIGINT) AS bid_ask_rows,
+                (SELECT MIN(ts_event) FROM mbp
+                 WHERE instrument_id = i.id AND superseded_by IS NULL) AS min_ts_event,
+                (SELECT MAX(ts_event) FROM mbp
+                 WHERE instrument_id = i.id AND superseded_by IS NULL) AS max_ts_event,
+                (SELECT MIN(ts_recv) FROM mbp
+                 WHERE instrument_id = i.id AND superseded_by IS NULL) AS min_ts_recv,
+                (SELECT MAX(ts_recv) FROM mbp
+                 WHERE instrument_id = i.id AND superseded_by IS NULL) AS max_ts_recv,
+                COALESCE(d.days, 0) AS days,
+                CAST(ROUND(COALESCE(r.mbp_bytes, 0)
+                    + COALESCE(t.bid_ask_bytes * r.all_rows / NULLIF(t.mbp_rows, 0), 0)
+                ) AS BIGINT) AS approx_size_bytes,
+                (SELECT CAST(EXTRACT(EPOCH FROM MAX(s.completed_at)) * 1000000000 AS BIGINT)
+                 FROM ingest_session_instrument si
+                 INNER JOIN ingest_session s ON s.id = si.ingest_id
+                 WHERE si.instrument_id = i.id AND s.status = 'completed') AS last_ingest
+            FROM instrument i
+            LEFT JOIN rows r ON r.instrument_id = i.id
+            LEFT JOIN days d ON d.instrument_id = i.id
+            CROSS JOIN totals t
+            ORDER BY i.ticker
+            "#,
+        )
+        .bind(NANOS_PER_DAY)
+        .fetch_all(&mut tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(stats)
+    }
+}
+
+/// Coverage of an instrument for every day from its first to its last current mbp row, days
+/// without rows included, oldest first. Empty when the instrument has no data.
+pub async fn day_coverage(pool: &PgPool, ticker: &str) -> Result<Vec<DayCoverage>> {
+    let instrument_id: i32 = sqlx::query_scalar("SELECT id FROM instrument WHERE ticker = $1")
+        .bind(ticker)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| crate::error!(CustomError, "Unknown symbol: {}", ticker))?;
+
+    let rows = sqlx::query(
+        r#"
+        WITH days AS (
+            SELECT ts_recv / $2 AS day, COUNT(*) AS rows, MIN(ts_recv) AS first_ts_recv,
+                MAX(ts_recv) AS last_ts_recv
+            FROM mbp
+            WHERE instrument_id = $1 AND superseded_by IS NULL
+            GROUP BY ts_recv / $2
+        )
+        SELECT
+            calendar.day,
+            COALESCE(d.rows, 0) AS rows,
+            d.first_ts_recv,
+            d.last_ts_recv
+        FROM generate_series(
+            (SELECT MIN(day) FROM days),
+            (SELECT MAX(day) FROM days)
+        ) AS calendar(day)
+        LEFT JOIN days d ON d.day = calendar.day
+        ORDER BY calendar.day
+        "#,
+    )
+    .bind(instrument_id)
+    .bind(NANOS_PER_DAY)
+    .fetch_all(pool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            let day: i64 = row.try_get("day")?;
+            let (year, month, date) = civil_from_days(day);
+
+            Ok(DayCoverage {
+                date: format!("{:04}-{:02}-{:02}", year, month, date),
+                day_start: day * NANOS_PER_DAY,
+                rows: row.try_get("rows")?,
+                first_ts_recv: row.try_get("first_ts_recv")?,
+                last_ts_recv: row.try_get("last_ts_recv")?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::ingest::{IngestSession, IngestStatus};
+    use crate::database::init::init_db;
+    use crate::database::market_data::create::{IngestMode, InsertBatch};
+    use crate::test_utils::{create_instrument, delete_instrument, mbp1};
+    use mbn::enums::Action;
+    use serial_test::serial;
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_stats_and_coverage() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+
+        // Setup
+        let instrument_id = create_instrument(&pool, "AAPL").await?;
+
+        // Rows on the first and third day, none on the second
+        let first: i64 = 1704153600000000000;
+        let ingest_id = IngestSession::create(&pool, "create", IngestMode::Strict, false).await?;
+        let mut insert_batch = InsertBatch::new(IngestMode::Strict, Some(ingest_id));
+        for ts in [first + 1, first + 2, first + 2 * NANOS_PER_DAY + 1] {
+            insert_batch
+                .process(&mbp1(instrument_id, ts as u64, 100, Action::Trade))
+                .await?;
+        }
+        let mut transaction = pool.begin().await?;
+        insert_batch.execute(&mut transaction).await?;
+        IngestSession::record_instruments(&mut transaction, ingest_id, &[instrument_id]).await?;
+        transaction.commit().await?;
+        IngestSession::update_status(&pool, ingest_id, IngestStatus::Completed).await?;
+        let session = IngestSession::get(&pool, ingest_id).await?.unwrap();
+        // Counts are estimated from the statistics, which cover every row of tables this small
+        sqlx::query("ANALYZE mbp, bid_ask").execute(&pool).await?;
+
+        // Test
+        let stats = InstrumentStats::list(&pool).await?;
+        let coverage = day_coverage(&pool, "AAPL").await?;
+
+        // Validate
+        let aapl = stats
+            .iter()
+            .find(|s| s.instrument_id == instrument_id)
+            .expect("Instrument missing from stats.");
+        assert_eq!(aapl.mbp_rows, 3);
+        assert_eq!(aapl.bid_ask_rows, 3);
+        assert_eq!(aapl.superseded_rows, 0);
+        assert_eq!(aapl.days, 2);
+        assert_eq!(aapl.min_ts_recv, Some(first + 1));
+        assert_eq!(aapl.max_ts_recv, Some(first + 2 * NANOS_PER_DAY + 1));
+        assert_eq!(aapl.last_ingest, session.completed_at);
+        assert!(aapl.approx_size_bytes > 0);
+
+        let rows: Vec<(String, i64)> = coverage.iter().map(|d| (d.date.clone(), d.rows)).collect();
+        assert_eq!(
+            rows,
+            vec![
+                ("2024-01-02".to_string(), 2),
+                ("2024-01-03".to_string(), 0),
+                ("2024-01-04".to_string(), 1)
+            ]
+        );
+        assert!(day_coverage(&pool, "MISSING").await.is_err());
+
+        // Cleanup
+        delete_instrument(&pool, instrument_id).await?;
+        sqlx::query("DELETE FROM ingest_session WHERE id = $1")
+            .bind(ingest_id)
+            .execute(&pool)
+            .await?;
+
+        Ok(())
+    }
+}
@@ -2,6 +2,7 @@ use crate::Result;
 use async_trait::async_trait;
 use mbn::symbols::{Instrument, SymbolMap};
 use sqlx::{PgPool, Postgres, Row, Transaction};
+use std::collections::HashMap;
 use tracing::info;
 struct InstrumentWrapper(Instrument);
 
@@ -201,11 +202,101 @@ pub async fn query_symbols_map(pool: &PgPool, tickers: &Vec<String>) -> Result<S
     Ok(map)
 }
 
+/// Instrument ids keyed by ticker, used to resolve symbols in tabular uploads.
+pub async fn query_instrument_ids(pool: &PgPool) -> Result<HashMap<String, u32>> {
+    let rows: Vec<(i32, String)> = sqlx::query_as("SELECT id, ticker FROM instrument")
+        .fetch_all(pool)
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(id, ticker)| (ticker, id as u32))
+        .collect())
+}
+
+/// Recomputes `first_available` and `last_available` from the stored records, for the given
+/// instruments or every instrument when `None`. Instruments without records are reset to 0.
+pub async fn refresh_availability(
+    tx: &mut Transaction<'_, Postgres>,
+    instrument_ids: Option<&[i32]>,
+) -> Result<u64> {
+    // Each table is bounded by the timestamp it is indexed and retrieved by
+    let result = sqlx::query(
+        r#"
+        UPDATE instrument i
+        SET first_available = COALESCE(LEAST(
+                (SELECT MIN(ts_event) FROM mbp WHERE instrument_id = i.id AND superseded_by IS NULL),
+                (SELECT MIN(ts_recv) FROM trade WHERE instrument_id = i.id),
+                (SELECT MIN(ts_event) FROM ohlcv WHERE instrument_id = i.id),
+                (SELECT MIN(ts_recv) FROM bbo WHERE instrument_id = i.id),
+                (SELECT MIN(ts_recv) FROM mbo WHERE instrument_id = i.id)
+            ), 0),
+            last_available = COALESCE(GREATEST(
+                (SELECT MAX(ts_event) FROM mbp WHERE instrument_id = i.id AND superseded_by IS NULL),
+                (SELECT MAX(ts_recv) FROM trade WHERE instrument_id = i.id),
+                (SELECT MAX(ts_event) FROM ohlcv WHERE instrument_id = i.id),
+                (SELECT MAX(ts_recv) FROM bbo WHERE instrument_id = i.id),
+                (SELECT MAX(ts_recv) FROM mbo WHERE instrument_id = i.id)
+            ), 0)
+        WHERE $1::int[] IS NULL OR i.id = ANY($1)
+        "#,
+    )
+    .bind(instrument_ids)
+    .execute(tx)
+    .await?;
+
+    info!(
+        "Refreshed availability of {} instruments",
+        result.rows_affected()
+    );
+    Ok(result.rows_affected())
+}
+
+/// Creates the tickers missing from the `instrument` table and returns the ids of all of them.
+pub async fn create_missing_instruments(
+    pool: &PgPool,
+    tickers: &[String],
+    vendor: Option<&str>,
+    dataset: Option<&str>,
+    stype: Option<&str>,
+) -> Result<HashMap<String, u32>> {
+    let mut tx = pool.begin().await?;
+    let result = sqlx::query(
+        r#"
+        INSERT INTO instrument (ticker, name, vendor, dataset, stype)
+        SELECT ticker, ticker, COALESCE($2, 'unknown'), $3, $4 FROM UNNEST($1::text[]) AS ticker
+        ON CONFLICT (ticker) DO NOTHING
+        "#,
+    )
+    .bind(tickers)
+    .bind(vendor)
+    .bind(dataset)
+    .bind(stype)
+    .execute(&mut tx)
+    .await?;
+    info!("Created {} instruments", result.rows_affected());
+
+    let rows: Vec<(i32, String)> =
+        sqlx::query_as("SELECT id, ticker FROM instrument WHERE ticker = ANY($1)")
+            .bind(tickers)
+            .fetch_all(&mut tx)
+            .await?;
+    tx.commit().await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|(id, ticker)| (ticker, id as u32))
+        .collect())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::database::init::init_db;
+    use crate::database::market_data::create::{IngestMode, InsertBatch};
+    use crate::test_utils::{create_instrument, delete_instrument, mbp1};
     use dotenv;
+    use mbn::enums::Action;
     use mbn::symbols::SymbolMap;
     use mbn::symbols::Vendors;
     use serial_test::serial;
@@ -280,6 +371,78 @@ mod test {
         Ok(())
     }
 
+    #[sqlx::test]
+    #[serial]
+    async fn test_create_missing_instruments() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let existing = create_instrument(&pool, "AAPL").await?;
+        let tickers = vec!["AAPL".to_string(), "AAPL1".to_string()];
+
+        // Test
+        let ids = create_missing_instruments(
+            &pool,
+            &tickers,
+            Some("databento"),
+            Some("GLBX.MDP3"),
+            Some("continuous"),
+        )
+        .await?;
+
+        // Validate
+        assert_eq!(2, ids.len());
+        assert_eq!(ids["AAPL"], existing as u32);
+        let vendor: String = sqlx::query_scalar("SELECT vendor FROM instrument WHERE ticker = $1")
+            .bind("AAPL1")
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(vendor, "databento");
+
+        // Cleanup
+        for id in ids.values() {
+            delete_instrument(&pool, *id as i32).await?;
+        }
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[serial]
+    async fn test_refresh_availability() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let id = create_instrument(&pool, "AAPL").await?;
+
+        let mbp_1 = mbp1(id, 1704209103644092564, 6770, Action::Add);
+        let mbp_2 = mbp1(id, 1704209103644092566, 6770, Action::Add);
+
+        let mut transaction = pool.begin().await?;
+        let mut batch = InsertBatch::new(IngestMode::Strict, None);
+        batch.process(&mbp_1).await?;
+        batch.process(&mbp_2).await?;
+        batch.execute(&mut transaction).await?;
+        transaction.commit().await?;
+
+        // Test
+        let mut transaction = pool.begin().await?;
+        refresh_availability(&mut transaction, Some(&[id])).await?;
+        transaction.commit().await?;
+
+        // Validate
+        let (first, last): (i64, i64) =
+            sqlx::query_as("SELECT first_available, last_available FROM instrument WHERE id = $1")
+                .bind(id)
+                .fetch_one(&pool)
+                .await?;
+        assert_eq!(first, 1704209103644092564);
+        assert_eq!(last, 1704209103644092566);
+
+        // Cleanup
+        delete_instrument(&pool, id).await?;
+
+        Ok(())
+    }
+
     #[sqlx::test]
     #[serial]
     async fn test_get_symbol_map_partial() -> anyhow::Result<()> {
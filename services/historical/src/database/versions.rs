@@ -0,0 +1,245 @@
+use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Row, Transaction};
+use std::str::FromStr;
+
+/// Change that created a dataset version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VersionKind {
+    /// An ingest session completed, adding its rows.
+    Ingest,
+    /// A replace upload superseded a range and added the corrected rows.
+    Replace,
+    /// A range deletion superseded the rows of the range.
+    Delete,
+}
+
+impl VersionKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VersionKind::Ingest => "ingest",
+            VersionKind::Replace => "replace",
+            VersionKind::Delete => "delete",
+        }
+    }
+}
+
+impl FromStr for VersionKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ingest" => Ok(VersionKind::Ingest),
+            "replace" => Ok(VersionKind::Replace),
+            "delete" => Ok(VersionKind::Delete),
+            _ => Err(crate::error!(CustomError, "Invalid version kind: {}", s)),
+        }
+    }
+}
+
+/// A state of the market data. Versions are ordered by `seq`, taken when the change commits:
+/// version `n` holds the rows of every session whose version committed up to `n`, minus the mbp
+/// rows superseded by a version committed up to `n`. Rows without a session predate versions
+/// and belong to all of them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DataVersion {
+    pub id: i32,
+    pub kind: VersionKind,
+    pub ingest_id: Option<i32>,
+    pub created_at: i64,           // UNIX nanoseconds
+    pub seq: Option<i64>,          // None until the change commits
+    pub committed_at: Option<i64>, // UNIX nanoseconds
+}
+
+impl sqlx::FromRow<'_, sqlx::postgres::PgRow> for DataVersion {
+    fn from_row(row: &sqlx::postgres::PgRow) -> std::result::Result<Self, sqlx::Error> {
+        let kind: String = row.try_get("kind")?;
+
+        Ok(DataVersion {
+            id: row.try_get("id")?,
+            kind: VersionKind::from_str(&kind).map_err(|e| sqlx::Error::Decode(e.into()))?,
+            ingest_id: row.try_get("ingest_id")?,
+            created_at: row.try_get("created_at")?,
+            seq: row.try_get("seq")?,
+            committed_at: row.try_get("committed_at")?,
+        })
+    }
+}
+
+const VERSION_COLUMNS: &str = r#"
+    id, kind, ingest_id, seq,
+    CAST(EXTRACT(EPOCH FROM created_at) * 1000000000 AS BIGINT) AS created_at,
+    CAST(EXTRACT(EPOCH FROM committed_at) * 1000000000 AS BIGINT) AS committed_at
+"#;
+
+/// Advisory lock held from taking a commit sequence to the commit, so versions commit in
+/// sequence order.
+const COMMIT_LOCK: i64 = 0x6461_7461_5f76_6572;
+
+impl DataVersion {
+    /// Creates a version and returns its id. The version belongs to the transaction's change and
+    /// is only ordered once `commit` is called. A session has a single version, creating it
+    /// again returns the existing one.
+    pub async fn create<'c, E>(
+        executor: E,
+        kind: VersionKind,
+        ingest_id: Option<i32>,
+    ) -> Result<i32>
+    where
+        E: sqlx::Executor<'c, Database = Postgres>,
+    {
+        let id: i32 = sqlx::query_scalar(
+            r#"
+            INSERT INTO data_version (kind, ingest_id)
+            VALUES ($1, $2)
+            ON CONFLICT (ingest_id) DO UPDATE SET ingest_id = EXCLUDED.ingest_id
+            RETURNING id
+            "#,
+        )
+        .bind(kind.as_str())
+        .bind(ingest_id)
+        .fetch_one(executor)
+        .await?;
+
+        Ok(id)
+    }
+
+    /// Takes the next commit sequence and time for a version, right before its transaction
+    /// commits. The lock taken is held until then, so a version never becomes visible after a
+    /// later one and versions served as of a sequence or time do not change. Returns the
+    /// sequence, the existing one if the version was already committed.
+    pub async fn commit(tx: &mut Transaction<'_, Postgres>, id: i32) -> Result<i64> {
+        sqlx::query("SELECT pg_advisory_xact_lock($1)")
+            .bind(COMMIT_LOCK)
+            .execute(&mut *tx)
+            .await?;
+
+        let seq: i64 = sqlx::query_scalar(
+            r#"
+            UPDATE data_version
+            SET seq = COALESCE(seq, nextval('data_version_seq')),
+                committed_at = COALESCE(committed_at, clock_timestamp())
+            WHERE id = $1
+            RETURNING seq
+            "#,
+        )
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        Ok(seq)
+    }
+
+    /// Committed versions, newest first.
+    pub async fn list(pool: &PgPool) -> Result<Vec<DataVersion>> {
+        let versions: Vec<DataVersion> = sqlx::query_as(&format!(
+            "SELECT {} FROM data_version WHERE seq IS NOT NULL ORDER BY seq DESC",
+            VERSION_COLUMNS
+        ))
+        .fetch_all(pool)
+        .await?;
+
+        Ok(versions)
+    }
+
+    /// Commit sequence of a version.
+    pub async fn seq(pool: &PgPool, id: i32) -> Result<i64> {
+        let seq: Option<i64> =
+            sqlx::query_scalar("SELECT seq FROM data_version WHERE id = $1 AND seq IS NOT NULL")
+                .bind(id)
+                .fetch_optional(pool)
+                .await?;
+
+        seq.ok_or_else(|| crate::error!(CustomError, "Unknown version: {}", id))
+    }
+
+    /// Sequence of the latest version committed at or before `time`, in UNIX nanoseconds. 0
+    /// when there is none, which holds only the rows predating versions.
+    pub async fn as_of_time(pool: &PgPool, time: i64) -> Result<i64> {
+        let seq: Option<i64> = sqlx::query_scalar(
+            r#"
+            SELECT MAX(seq) FROM data_version
+            WHERE CAST(EXTRACT(EPOCH FROM committed_at) * 1000000000 AS BIGINT) <= $1
+            "#,
+        )
+        .bind(time)
+        .fetch_one(pool)
+        .await?;
+
+        Ok(seq.unwrap_or(0))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::ingest::IngestSession;
+    use crate::database::init::init_db;
+    use crate::database::market_data::create::IngestMode;
+    use serial_test::serial;
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_data_version() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let ingest_id =
+            IngestSession::create(&pool, "test_file.bin", IngestMode::Strict, false).await?;
+
+        // Test
+        // A replacement started first but committed last
+        let mut replace_tx = pool.begin().await?;
+        let first = DataVersion::create(&mut replace_tx, VersionKind::Replace, None).await?;
+
+        let mut tx = pool.begin().await?;
+        let second = DataVersion::create(&mut tx, VersionKind::Ingest, Some(ingest_id)).await?;
+        let again = DataVersion::create(&mut tx, VersionKind::Ingest, Some(ingest_id)).await?;
+        DataVersion::commit(&mut tx, second).await?;
+        tx.commit().await?;
+        let before_replace = DataVersion::list(&pool).await?;
+
+        DataVersion::commit(&mut replace_tx, first).await?;
+        replace_tx.commit().await?;
+        let versions = DataVersion::list(&pool).await?;
+
+        // Validate
+        assert_eq!(second, again);
+        assert!(second > first);
+        assert_eq!(before_replace[0].id, second);
+        assert_eq!(versions[0].id, first);
+        assert_eq!(versions[0].kind, VersionKind::Replace);
+        assert_eq!(versions[1].ingest_id, Some(ingest_id));
+        assert!(versions[0].seq > versions[1].seq);
+
+        // Answers served before the replacement committed stay the same
+        let committed_at = versions[1].committed_at.unwrap();
+        assert_eq!(
+            DataVersion::as_of_time(&pool, committed_at).await?,
+            versions[1].seq.unwrap()
+        );
+        assert_eq!(
+            DataVersion::as_of_time(&pool, versions[0].committed_at.unwrap()).await?,
+            versions[0].seq.unwrap()
+        );
+        assert_eq!(
+            DataVersion::seq(&pool, first).await?,
+            versions[0].seq.unwrap()
+        );
+        assert_eq!(DataVersion::as_of_time(&pool, 0).await?, 0);
+
+        // Cleanup
+        sqlx::query("DELETE FROM data_version WHERE id IN ($1, $2)")
+            .bind(first)
+            .bind(second)
+            .execute(&pool)
+            .await?;
+        sqlx::query("DELETE FROM ingest_session WHERE id = $1")
+            .bind(ingest_id)
+            .execute(&pool)
+            .await?;
+
+        Ok(())
+    }
+}
@@ -20,6 +20,12 @@ pub enum Error {
     GeneralError(#[from] Box<dyn std::error::Error + Send + Sync + 'static>),
     #[error("MBN error: {0}")]
     MbnError(#[from] mbn::error::Error),
+    #[error("Csv error: {0}")]
+    CsvError(#[from] csv::Error),
+    #[error("Parquet error: {0}")]
+    ParquetError(#[from] parquet::errors::ParquetError),
+    #[error("Arrow error: {0}")]
+    ArrowError(#[from] arrow_schema::ArrowError),
     #[error("Custom error: {0}")]
     CustomError(String),
     // #[error("Stream error")]
@@ -42,6 +48,9 @@ impl Into<ApiResponse<String>> for Error {
             Error::EnvVarError(ref msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg.to_string()),
             Error::GeneralError(ref msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg.to_string()),
             Error::MbnError(ref msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg.to_string()),
+            Error::CsvError(ref msg) => (StatusCode::BAD_REQUEST, msg.to_string()),
+            Error::ParquetError(ref msg) => (StatusCode::BAD_REQUEST, msg.to_string()),
+            Error::ArrowError(ref msg) => (StatusCode::BAD_REQUEST, msg.to_string()),
             Error::CustomError(ref msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg.to_string()),
             // Error::StreamError(_) => panic!("StreamError should not be converted to ApiResponse"),
             // Error::StreamError(ref msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
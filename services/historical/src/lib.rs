@@ -4,5 +4,7 @@ pub mod logger;
 pub mod response;
 pub mod router;
 pub mod services;
+#[cfg(test)]
+pub mod test_utils;
 
 pub use self::error::{Error, Result};
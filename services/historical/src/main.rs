@@ -2,6 +2,10 @@ use dotenv::dotenv;
 use historical::database::init::init_db;
 use historical::logger::system_logger;
 use historical::router::router;
+use historical::services::admin::maintain_partitions;
+use historical::services::market_data::jobs::recover_interrupted;
+use historical::services::market_data::watcher::{watch_folder, WatcherConfig};
+use historical::services::retention::{enforce_retention, RetentionConfig};
 use historical::Result;
 use std::env;
 use std::net::SocketAddr;
@@ -17,6 +21,29 @@ async fn main() -> Result<()> {
     // Initialize the database and obtain a connection pool
     let pool = init_db().await.expect("Error on market_data pool.");
 
+    // Fail jobs and roll back uploads interrupted by the last shutdown
+    recover_interrupted(&pool)
+        .await
+        .expect("Error recovering interrupted ingest jobs.");
+
+    // Create upcoming mbp partitions ahead of the data
+    let partition_pool = pool.clone();
+    tokio::spawn(async move {
+        if let Err(e) = maintain_partitions(partition_pool).await {
+            tracing::error!("Partition maintenance stopped: {:?}", e);
+        }
+    });
+
+    // Load files dropped into the watched folder, if one is configured
+    if let Some(config) = WatcherConfig::from_env().expect("Invalid drop folder configuration.") {
+        tokio::spawn(watch_folder(pool.clone(), config));
+    }
+
+    // Archive and delete expired data, if an archive folder is configured
+    if let Some(config) = RetentionConfig::from_env().expect("Invalid retention configuration.") {
+        tokio::spawn(enforce_retention(pool.clone(), config));
+    }
+
     // Initialize the Axum routing service
     let app = router(pool);
 
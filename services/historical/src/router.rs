@@ -1,4 +1,7 @@
-use crate::services::{market_data::market_data_service, symbols::instrument_service};
+use crate::services::{
+    admin::admin_service, market_data::market_data_service, retention::retention_service,
+    stats::stats_service, symbols::instrument_service,
+};
 use axum::{extract::Extension, Router};
 use dotenv::dotenv;
 use sqlx::PgPool;
@@ -14,6 +17,12 @@ pub fn router(pool: PgPool) -> Router {
                 "/instruments",
                 instrument_service().layer(Extension(pool.clone())),
             )
-            .nest("/mbp", market_data_service().layer(Extension(pool.clone()))),
+            .nest("/mbp", market_data_service().layer(Extension(pool.clone())))
+            .nest("/admin", admin_service().layer(Extension(pool.clone())))
+            .nest(
+                "/retention",
+                retention_service().layer(Extension(pool.clone())),
+            )
+            .nest("/stats", stats_service().layer(Extension(pool.clone()))),
     )
 }
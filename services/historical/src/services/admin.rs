@@ -0,0 +1,111 @@
+use crate::database::partitions::{create_future_partitions, Partition, PartitionInterval};
+use crate::error::Result;
+use crate::response::ApiResponse;
+use crate::Error;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::{get, post};
+use axum::{Extension, Json, Router};
+use sqlx::PgPool;
+use std::env;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info};
+
+/// How often upcoming partitions are checked.
+const PARTITION_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+// Service
+pub fn admin_service() -> Router {
+    Router::new()
+        .route("/partitions/list", get(list_partitions))
+        .route("/partitions/attach", post(attach_partition))
+        .route("/partitions/detach", post(detach_partition))
+}
+
+// Handlers
+pub async fn list_partitions(Extension(pool): Extension<PgPool>) -> Result<impl IntoResponse> {
+    info!("Handling request to list partitions");
+
+    match Partition::list(&pool).await {
+        Ok(partitions) => Ok(ApiResponse::new(
+            "success",
+            &format!("Found {} partitions", partitions.len()),
+            StatusCode::OK,
+            partitions,
+        )),
+        Err(e) => {
+            error!("Failed to list partitions: {:?}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Attaches a detached partition, e.g. `"p20240101"`, so its rows are queried again.
+pub async fn attach_partition(
+    Extension(pool): Extension<PgPool>,
+    Json(name): Json<String>,
+) -> Result<impl IntoResponse> {
+    info!("Handling request to attach partition {}", name);
+
+    match Partition::attach(&pool, &name).await {
+        Ok(partition) => Ok(ApiResponse::new(
+            "success",
+            &format!("Attached partition {}", name),
+            StatusCode::OK,
+            partition,
+        )),
+        Err(e) => {
+            error!("Failed to attach partition: {:?}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Detaches a partition. Its tables are kept and can be attached again.
+pub async fn detach_partition(
+    Extension(pool): Extension<PgPool>,
+    Json(name): Json<String>,
+) -> Result<impl IntoResponse> {
+    info!("Handling request to detach partition {}", name);
+
+    match Partition::detach(&pool, &name).await {
+        Ok(partition) => Ok(ApiResponse::new(
+            "success",
+            &format!("Detached partition {}", name),
+            StatusCode::OK,
+            partition,
+        )),
+        Err(e) => {
+            error!("Failed to detach partition: {:?}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Keeps the current and the next `HISTORICAL_PARTITION_AHEAD` (default 2) partitions created
+/// until the service stops.
+pub async fn maintain_partitions(pool: PgPool) -> Result<()> {
+    let interval = PartitionInterval::from_env()?;
+    let ahead: u32 = match env::var("HISTORICAL_PARTITION_AHEAD") {
+        Ok(value) => value.parse().map_err(|_| {
+            crate::error!(
+                CustomError,
+                "HISTORICAL_PARTITION_AHEAD is not a valid integer."
+            )
+        })?,
+        Err(_) => 2,
+    };
+    info!("Creating {} partitions {} ahead", interval.as_str(), ahead);
+
+    let mut ticker = tokio::time::interval(PARTITION_CHECK_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as i64;
+        if let Err(e) = create_future_partitions(&pool, now, interval, ahead).await {
+            error!("Error creating upcoming partitions: {:?}", e);
+        }
+    }
+}
@@ -1,15 +1,36 @@
+pub mod dbn;
+pub mod delete;
+pub mod jobs;
 pub mod load;
+pub mod mbo;
+pub mod mbp10;
+pub mod order_book;
 pub mod record_loader;
 pub mod record_retriever;
+pub mod record_source;
+pub mod record_validator;
 pub mod retrieve;
+pub mod rollup;
+pub mod versions;
+pub mod watcher;
 
 // pub mod streamer;
 // pub mod test_load;
 
-use crate::services::market_data::load::{bulk_upload, create_record};
+use crate::services::market_data::delete::delete_records;
+use crate::services::market_data::jobs::{
+    cancel_job, create_batch_job, create_job, get_job, get_job_summary, list_jobs,
+};
+use crate::services::market_data::load::{
+    bulk_upload, create_record, import_upload, resume_upload, stream_upload,
+};
+use crate::services::market_data::mbo::{book_state, create_mbo, replay};
+use crate::services::market_data::mbp10::{create_mbp10, get_mbp10};
 use crate::services::market_data::retrieve::get_records;
+use crate::services::market_data::rollup::rebuild_rollup_bars;
+use crate::services::market_data::versions::list_versions;
 use axum::{
-    routing::{get, post},
+    routing::{delete, get, post},
     Router,
 };
 
@@ -18,5 +39,22 @@ pub fn market_data_service() -> Router {
     Router::new()
         .route("/create", post(create_record))
         .route("/get", get(get_records))
+        .route("/stream_upload", post(stream_upload))
         .route("/bulk_upload", post(bulk_upload))
+        .route("/import", post(import_upload))
+        .route("/resume", post(resume_upload))
+        .route("/delete", delete(delete_records))
+        .route("/jobs/create", post(create_job))
+        .route("/jobs/batch", post(create_batch_job))
+        .route("/jobs/get", get(get_job))
+        .route("/jobs/summary", get(get_job_summary))
+        .route("/jobs/list", get(list_jobs))
+        .route("/jobs/cancel", post(cancel_job))
+        .route("/mbo/create", post(create_mbo))
+        .route("/mbo/replay", get(replay))
+        .route("/mbo/book", get(book_state))
+        .route("/mbp10/create", post(create_mbp10))
+        .route("/mbp10/get", get(get_mbp10))
+        .route("/rollups/rebuild", post(rebuild_rollup_bars))
+        .route("/versions/list", get(list_versions))
 }
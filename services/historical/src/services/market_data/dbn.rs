@@ -0,0 +1,575 @@
+use crate::database::partitions::{civil_from_days, NANOS_PER_DAY};
+use crate::database::symbols::{create_missing_instruments, query_instrument_ids};
+use crate::services::market_data::load::LoadParams;
+use crate::services::market_data::record_source::{decompress, RecordSource};
+use crate::{Error, Result};
+use mbn::record_enum::RecordEnum;
+use mbn::records::{BboMsg, BidAskPair, Mbp1Msg, OhlcvMsg, RecordHeader, TradeMsg};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read};
+use std::os::raw::c_char;
+use std::path::Path;
+
+const DBN_MAGIC: &[u8; 3] = b"DBN";
+
+/// Bytes of the metadata fields before the schema definition, whatever the version.
+const FIXED_METADATA_LEN: usize = 100;
+const DATASET_CSTR_LEN: usize = 16;
+/// Symbol length of version 1 files, later versions record theirs in the metadata.
+const V1_SYMBOL_CSTR_LEN: usize = 22;
+
+/// Schema id of TBBO files, whose records share the MBP-1 rtype.
+const SCHEMA_TBBO: u16 = 3;
+
+/// Bytes of the header every record starts with.
+const RECORD_HEADER_LEN: usize = 16;
+
+const RTYPE_MBP0: u8 = 0x00;
+const RTYPE_MBP1: u8 = 0x01;
+const RTYPE_OHLCV_1S: u8 = 0x20;
+const RTYPE_OHLCV_1D: u8 = 0x23;
+const RTYPE_BBO_1S: u8 = 0xC3;
+const RTYPE_BBO_1M: u8 = 0xC4;
+
+/// Little-endian fields of a DBN buffer, read in order.
+struct Fields<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Fields { buf, pos: 0 }
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let bytes = self.bytes(N)?;
+        Ok(bytes.try_into().expect("slice of N bytes"))
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or_else(|| crate::error!(CustomError, "Truncated DBN data"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_le_bytes(self.take()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    fn i64(&mut self) -> Result<i64> {
+        Ok(i64::from_le_bytes(self.take()?))
+    }
+
+    /// A NUL-padded string of `len` bytes.
+    fn cstr(&mut self, len: usize) -> Result<String> {
+        let bytes = self.bytes(len)?;
+        let end = bytes.iter().position(|b| *b == 0).unwrap_or(len);
+        Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+    }
+
+    fn skip(&mut self, len: usize) -> Result<()> {
+        self.bytes(len).map(|_| ())
+    }
+
+    fn levels(&mut self) -> Result<[BidAskPair; 1]> {
+        Ok([BidAskPair {
+            bid_px: self.i64()?,
+            ask_px: self.i64()?,
+            bid_sz: self.u32()?,
+            ask_sz: self.u32()?,
+            bid_ct: self.u32()?,
+            ask_ct: self.u32()?,
+        }])
+    }
+}
+
+/// Raw symbol of a file's instrument id over `[start_date, end_date)`, dates as YYYYMMDD.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SymbolInterval {
+    start_date: u32,
+    end_date: u32,
+    raw_symbol: String,
+}
+
+/// Metadata heading a Databento DBN file, versions 1 to 3.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbnMetadata {
+    pub version: u8,
+    pub dataset: String,
+    /// `None` for files mixing schemas.
+    pub schema: Option<u16>,
+    mappings: HashMap<u32, Vec<SymbolInterval>>,
+}
+
+impl DbnMetadata {
+    /// Reads the metadata, leaving `reader` at the first record.
+    pub fn decode(reader: &mut impl Read) -> Result<Self> {
+        let mut prelude = [0u8; 8];
+        reader.read_exact(&mut prelude)?;
+        if &prelude[..3] != DBN_MAGIC {
+            return Err(crate::error!(CustomError, "Not a DBN file"));
+        }
+        let version = prelude[3];
+        if !(1..=3).contains(&version) {
+            return Err(crate::error!(
+                CustomError,
+                "Unsupported DBN version {}",
+                version
+            ));
+        }
+        let length = u32::from_le_bytes(prelude[4..].try_into().expect("4 bytes"));
+        let mut buf = vec![0u8; length as usize];
+        reader.read_exact(&mut buf)?;
+
+        let mut fields = Fields::new(&buf);
+        let dataset = fields.cstr(DATASET_CSTR_LEN)?;
+        let schema = Some(fields.u16()?).filter(|schema| *schema != u16::MAX);
+        fields.skip(8 + 8 + 8)?; // start, end, limit
+        if version == 1 {
+            fields.skip(8)?; // record_count
+        }
+        fields.skip(3)?; // stype_in, stype_out, ts_out
+        let symbol_cstr_len = if version == 1 {
+            V1_SYMBOL_CSTR_LEN
+        } else {
+            fields.u16()? as usize
+        };
+        fields.pos = FIXED_METADATA_LEN;
+
+        let schema_definition_length = fields.u32()?;
+        fields.skip(schema_definition_length as usize)?;
+
+        // symbols, partial and not_found
+        for _ in 0..3 {
+            let count = fields.u32()?;
+            fields.skip(count as usize * symbol_cstr_len)?;
+        }
+
+        // Mappings from the requested symbols to the instrument ids of the records
+        let mut mappings: HashMap<u32, Vec<SymbolInterval>> = HashMap::new();
+        for _ in 0..fields.u32()? {
+            let raw_symbol = fields.cstr(symbol_cstr_len)?;
+            for _ in 0..fields.u32()? {
+                let start_date = fields.u32()?;
+                let end_date = fields.u32()?;
+                let symbol = fields.cstr(symbol_cstr_len)?;
+                // Only mappings to instrument ids identify the records
+                if let Ok(instrument_id) = symbol.parse::<u32>() {
+                    mappings
+                        .entry(instrument_id)
+                        .or_default()
+                        .push(SymbolInterval {
+                            start_date,
+                            end_date,
+                            raw_symbol: raw_symbol.clone(),
+                        });
+                }
+            }
+        }
+
+        Ok(DbnMetadata {
+            version,
+            dataset,
+            schema,
+            mappings,
+        })
+    }
+
+    /// Every raw symbol the records map to.
+    pub fn raw_symbols(&self) -> Vec<String> {
+        let mut symbols: Vec<String> = self
+            .mappings
+            .values()
+            .flatten()
+            .map(|interval| interval.raw_symbol.clone())
+            .collect();
+        symbols.sort();
+        symbols.dedup();
+        symbols
+    }
+
+    /// Raw symbol of the file's instrument id on the UTC date of `ts_event`.
+    fn raw_symbol(&self, instrument_id: u32, ts_event: u64) -> Option<&str> {
+        let (year, month, day) = civil_from_days(ts_event as i64 / NANOS_PER_DAY);
+        let date = year as u32 * 10_000 + month * 100 + day;
+
+        self.mappings
+            .get(&instrument_id)?
+            .iter()
+            .find(|interval| interval.start_date <= date && date < interval.end_date)
+            .map(|interval| interval.raw_symbol.as_str())
+    }
+}
+
+/// Databento DBN records, mapped to the mbn record of the same schema. MBP-1, TBBO, trades, OHLCV
+/// and BBO are read, other record types fail the upload. Instrument ids are resolved to symbols
+/// through the file's mappings, then to the server's ids through the `instrument` table.
+pub struct DbnSource {
+    reader: Box<dyn Read + Send>,
+    metadata: DbnMetadata,
+    instruments: HashMap<String, u32>,
+}
+
+impl DbnSource {
+    fn instrument_id(&self, instrument_id: u32, ts_event: u64) -> Result<u32> {
+        let symbol = self
+            .metadata
+            .raw_symbol(instrument_id, ts_event)
+            .ok_or_else(|| {
+                crate::error!(
+                    CustomError,
+                    "Instrument id {} is not in the file's symbol mappings",
+                    instrument_id
+                )
+            })?;
+
+        self.instruments
+            .get(symbol)
+            .copied()
+            .ok_or_else(|| crate::error!(CustomError, "Unknown symbol: {}", symbol))
+    }
+
+    fn decode_record(&self, buf: &[u8]) -> Result<RecordEnum> {
+        let mut fields = Fields::new(buf);
+        fields.skip(1)?; // length
+        let rtype = fields.u8()?;
+        fields.skip(2)?; // publisher_id
+        let file_instrument_id = fields.u32()?;
+        let ts_event = fields.u64()?;
+        let instrument_id = self.instrument_id(file_instrument_id, ts_event)?;
+
+        let record = match rtype {
+            RTYPE_MBP1 => {
+                let msg = Mbp1Msg {
+                    hd: RecordHeader::new::<Mbp1Msg>(instrument_id, ts_event),
+                    price: fields.i64()?,
+                    size: fields.u32()?,
+                    action: fields.u8()? as c_char,
+                    side: fields.u8()? as c_char,
+                    flags: fields.u8()?,
+                    depth: fields.u8()?,
+                    ts_recv: fields.u64()?,
+                    ts_in_delta: fields.i32()?,
+                    sequence: fields.u32()?,
+                    discriminator: 0,
+                    levels: fields.levels()?,
+                };
+                if self.metadata.schema == Some(SCHEMA_TBBO) {
+                    RecordEnum::Tbbo(msg)
+                } else {
+                    RecordEnum::Mbp1(msg)
+                }
+            }
+            RTYPE_MBP0 => RecordEnum::Trade(TradeMsg {
+                hd: RecordHeader::new::<TradeMsg>(instrument_id, ts_event),
+                price: fields.i64()?,
+                size: fields.u32()?,
+                action: fields.u8()? as c_char,
+                side: fields.u8()? as c_char,
+                flags: fields.u8()?,
+                depth: fields.u8()?,
+                ts_recv: fields.u64()?,
+                ts_in_delta: fields.i32()?,
+                sequence: fields.u32()?,
+            }),
+            RTYPE_OHLCV_1S..=RTYPE_OHLCV_1D => RecordEnum::Ohlcv(OhlcvMsg {
+                hd: RecordHeader::new::<OhlcvMsg>(instrument_id, ts_event),
+                open: fields.i64()?,
+                high: fields.i64()?,
+                low: fields.i64()?,
+                close: fields.i64()?,
+                volume: fields.u64()?,
+            }),
+            RTYPE_BBO_1S | RTYPE_BBO_1M => {
+                let price = fields.i64()?;
+                let size = fields.u32()?;
+                fields.skip(1)?;
+                let side = fields.u8()? as c_char;
+                let flags = fields.u8()?;
+                fields.skip(1)?;
+                let ts_recv = fields.u64()?;
+                fields.skip(4)?;
+                RecordEnum::Bbo(BboMsg {
+                    hd: RecordHeader::new::<BboMsg>(instrument_id, ts_event),
+                    price,
+                    size,
+                    side,
+                    flags,
+                    ts_recv,
+                    sequence: fields.u32()?,
+                    levels: fields.levels()?,
+                })
+            }
+            _ => {
+                return Err(crate::error!(
+                    CustomError,
+                    "Unsupported DBN record type 0x{:02X}",
+                    rtype
+                ))
+            }
+        };
+
+        Ok(record)
+    }
+}
+
+impl RecordSource for DbnSource {
+    fn read_records(mut self: Box<Self>, emit: &mut dyn FnMut(Result<RecordEnum>) -> bool) {
+        loop {
+            // The first byte of a record is its length in 4-byte words, header included
+            let mut length = [0u8; 1];
+            match self.reader.read_exact(&mut length) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return,
+                Err(e) => {
+                    emit(Err(e.into()));
+                    return;
+                }
+            }
+
+            let record_len = length[0] as usize * 4;
+            if record_len < RECORD_HEADER_LEN {
+                emit(Err(crate::error!(
+                    CustomError,
+                    "DBN record of {} bytes is shorter than its header",
+                    record_len
+                )));
+                return;
+            }
+
+            let mut buf = vec![0u8; record_len];
+            buf[0] = length[0];
+            let record_result = self
+                .reader
+                .read_exact(&mut buf[1..])
+                .map_err(Error::from)
+                .and_then(|_| self.decode_record(&buf));
+            let failed = record_result.is_err();
+            if !emit(record_result) || failed {
+                return;
+            }
+        }
+    }
+}
+
+/// Opens a DBN file, decompressing it when it is zstd or gzip compressed. With
+/// `create_instruments`, the symbols of its mappings missing from the server are created.
+pub async fn open_dbn(
+    pool: &PgPool,
+    path: &Path,
+    params: &LoadParams,
+) -> Result<Box<dyn RecordSource>> {
+    let file = File::open(path)?;
+    let (reader, metadata) = tokio::task::spawn_blocking(move || -> Result<_> {
+        let mut reader = decompress(BufReader::new(file))?;
+        let metadata = DbnMetadata::decode(&mut reader)?;
+        Ok((reader, metadata))
+    })
+    .await
+    .map_err(|e| crate::error!(CustomError, "Metadata task failed: {}", e))??;
+
+    let instruments = if params.create_instruments {
+        create_missing_instruments(
+            pool,
+            &metadata.raw_symbols(),
+            params.vendor.as_deref(),
+            params
+                .dataset
+                .as_deref()
+                .or(Some(metadata.dataset.as_str())),
+            params.stype.as_deref(),
+        )
+        .await?
+    } else {
+        query_instrument_ids(pool).await?
+    };
+
+    Ok(Box::new(DbnSource {
+        reader,
+        metadata,
+        instruments,
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mbn::enums::{Action, Side};
+    use std::io::Cursor;
+
+    const SYMBOL_CSTR_LEN: usize = 71;
+
+    fn cstr(value: &str, len: usize) -> Vec<u8> {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.resize(len, 0);
+        bytes
+    }
+
+    /// A version 2 file whose instrument id 42 is ESH4 in January 2024.
+    fn dbn_file(schema: u16, records: &[Vec<u8>]) -> Vec<u8> {
+        let mut metadata = cstr("GLBX.MDP3", DATASET_CSTR_LEN);
+        metadata.extend(schema.to_le_bytes());
+        metadata.extend([0u8; 24]); // start, end, limit
+        metadata.extend([0u8, 0, 0]); // stype_in, stype_out, ts_out
+        metadata.extend((SYMBOL_CSTR_LEN as u16).to_le_bytes());
+        metadata.resize(FIXED_METADATA_LEN, 0);
+        metadata.extend(0u32.to_le_bytes()); // schema definition
+        metadata.extend([0u8; 12]); // symbols, partial, not_found
+        metadata.extend(1u32.to_le_bytes());
+        metadata.extend(cstr("ESH4", SYMBOL_CSTR_LEN));
+        metadata.extend(1u32.to_le_bytes());
+        metadata.extend(20240101u32.to_le_bytes());
+        metadata.extend(20240201u32.to_le_bytes());
+        metadata.extend(cstr("42", SYMBOL_CSTR_LEN));
+
+        let mut file = b"DBN\x02".to_vec();
+        file.extend((metadata.len() as u32).to_le_bytes());
+        file.extend(metadata);
+        for record in records {
+            file.extend(record);
+        }
+        file
+    }
+
+    fn record(rtype: u8, instrument_id: u32, ts_event: u64, body: &[u8]) -> Vec<u8> {
+        let mut record = vec![((16 + body.len()) / 4) as u8, rtype];
+        record.extend(1u16.to_le_bytes());
+        record.extend(instrument_id.to_le_bytes());
+        record.extend(ts_event.to_le_bytes());
+        record.extend(body);
+        record
+    }
+
+    fn mbp1_body(price: i64, ts_recv: u64) -> Vec<u8> {
+        let mut body = price.to_le_bytes().to_vec();
+        body.extend(5u32.to_le_bytes());
+        body.extend([Action::Trade as u8, Side::Bid as u8, 0, 0]);
+        body.extend(ts_recv.to_le_bytes());
+        body.extend(17493i32.to_le_bytes());
+        body.extend(739763u32.to_le_bytes());
+        for px in [price - 1, price + 1] {
+            body.extend(px.to_le_bytes());
+        }
+        for value in [10u32, 20, 1, 2] {
+            body.extend(value.to_le_bytes());
+        }
+        body
+    }
+
+    fn read_dbn(file: Vec<u8>) -> Vec<Result<RecordEnum>> {
+        let mut reader = Cursor::new(file);
+        let metadata = DbnMetadata::decode(&mut reader).unwrap();
+        let source = Box::new(DbnSource {
+            reader: Box::new(reader),
+            metadata,
+            instruments: HashMap::from([("ESH4".to_string(), 7)]),
+        });
+
+        let mut records = Vec::new();
+        source.read_records(&mut |record| {
+            records.push(record);
+            true
+        });
+        records
+    }
+
+    #[test]
+    fn test_dbn_mbp1() {
+        let ts: u64 = 1704209103644092564;
+        let file = dbn_file(1, &[record(RTYPE_MBP1, 42, ts, &mbp1_body(6770, ts + 1))]);
+
+        // Test
+        let records = read_dbn(file);
+
+        // Validate
+        assert_eq!(records.len(), 1);
+        match records[0].as_ref().unwrap() {
+            RecordEnum::Mbp1(msg) => {
+                assert_eq!(msg.hd.instrument_id, 7);
+                assert_eq!(msg.hd.ts_event, ts);
+                assert_eq!(msg.ts_recv, ts + 1);
+                assert_eq!(msg.price, 6770);
+                assert_eq!(msg.size, 5);
+                assert_eq!(msg.action, Action::Trade as c_char);
+                assert_eq!(msg.sequence, 739763);
+                assert_eq!(msg.levels[0].bid_px, 6769);
+                assert_eq!(msg.levels[0].ask_px, 6771);
+                assert_eq!(msg.levels[0].ask_ct, 2);
+            }
+            record => panic!("Unexpected record {:?}", record),
+        }
+    }
+
+    #[test]
+    fn test_dbn_unmapped_and_unsupported() {
+        let ts: u64 = 1704209103644092564;
+        let february = ts + 31 * NANOS_PER_DAY as u64;
+
+        for (rtype, ts_event, message) in [
+            (RTYPE_MBP1, february, "not in the file's symbol mappings"),
+            (0xA0, ts, "Unsupported DBN record type 0xA0"),
+        ] {
+            let file = dbn_file(
+                1,
+                &[record(rtype, 42, ts_event, &mbp1_body(6770, ts_event))],
+            );
+
+            // Test
+            let records = read_dbn(file);
+
+            // Validate
+            assert_eq!(records.len(), 1);
+            let error = records[0].as_ref().unwrap_err().to_string();
+            assert!(error.contains(message), "{}", error);
+        }
+    }
+
+    #[test]
+    fn test_dbn_corrupt_length() {
+        let ts: u64 = 1704209103644092564;
+        let valid = record(RTYPE_MBP1, 42, ts, &mbp1_body(6770, ts + 1));
+        let mut truncated = valid.clone();
+        truncated.truncate(20);
+
+        for (records, message) in [
+            (vec![vec![0u8; 4]], "shorter than its header"),
+            (
+                vec![vec![2u8, RTYPE_MBP1, 0, 0, 0, 0, 0, 0]],
+                "shorter than its header",
+            ),
+            (vec![truncated], "failed to fill whole buffer"),
+        ] {
+            let file = dbn_file(1, &records);
+
+            // Test
+            let records = read_dbn(file);
+
+            // Validate
+            assert_eq!(records.len(), 1);
+            let error = records[0].as_ref().unwrap_err().to_string();
+            assert!(error.contains(message), "{}", error);
+        }
+    }
+}
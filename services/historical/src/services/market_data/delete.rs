@@ -0,0 +1,80 @@
+use crate::database::market_data::create::supersede_mbp_range;
+use crate::database::symbols::query_instrument_ids;
+use crate::database::versions::{DataVersion, VersionKind};
+use crate::error::Result;
+use crate::response::ApiResponse;
+use crate::Error;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tracing::{error, info};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeleteParams {
+    pub symbols: Vec<String>,
+    /// `ts_recv` range `[start_ts, end_ts)` of the deleted records.
+    pub start_ts: i64,
+    pub end_ts: i64,
+}
+
+// Handlers
+/// Deletes the mbp records, and their book levels, of the instruments in a `ts_recv` range. The
+/// rest of each instrument's history is kept, and the deleted records stay readable as of the
+/// versions before the deletion.
+pub async fn delete_records(
+    Extension(pool): Extension<PgPool>,
+    Json(params): Json<DeleteParams>,
+) -> Result<impl IntoResponse> {
+    info!("Handling request to delete records {:?}", params);
+
+    match delete(&pool, &params).await {
+        Ok(deleted) => Ok(ApiResponse::new(
+            "success",
+            &format!("Successfully deleted {} records", deleted),
+            StatusCode::OK,
+            deleted,
+        )),
+        Err(e) => {
+            error!("Failed to delete records: {:?}", e);
+            Err(e)
+        }
+    }
+}
+
+async fn delete(pool: &PgPool, params: &DeleteParams) -> Result<u64> {
+    if params.symbols.is_empty() {
+        return Err(crate::error!(
+            CustomError,
+            "No symbols to delete records of."
+        ));
+    }
+    if params.start_ts >= params.end_ts {
+        return Err(crate::error!(
+            CustomError,
+            "start_ts must be before end_ts."
+        ));
+    }
+
+    let instrument_ids = query_instrument_ids(pool).await?;
+    let ids: Vec<i32> = params
+        .symbols
+        .iter()
+        .map(|symbol| {
+            instrument_ids
+                .get(symbol)
+                .map(|id| *id as i32)
+                .ok_or_else(|| crate::error!(CustomError, "Unknown symbol: {}", symbol))
+        })
+        .collect::<Result<_>>()?;
+
+    let mut tx = pool.begin().await?;
+    let version = DataVersion::create(&mut tx, VersionKind::Delete, None).await?;
+    let deleted =
+        supersede_mbp_range(&mut tx, version, &ids, params.start_ts, params.end_ts).await?;
+    DataVersion::commit(&mut tx, version).await?;
+    tx.commit().await?;
+
+    Ok(deleted)
+}
@@ -0,0 +1,656 @@
+use crate::database::ingest::{IngestSession, IngestStatus, LoadProgress};
+use crate::database::jobs::{FileStatus, IngestJob, JobFile, JobStatus, ManifestEntry};
+use crate::database::market_data::create::rollback_all_batches;
+use crate::database::market_data::rollup::refresh_queued_rollups;
+use crate::response::ApiResponse;
+use crate::services::market_data::load::{check_file, data_path, LoadParams};
+use crate::services::market_data::record_loader::RecordLoader;
+use crate::services::market_data::record_source::{open_source, ColumnMap};
+use crate::{Error, Result};
+use axum::extract::Query;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use futures::stream::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tracing::{error, info};
+
+/// How often a running job writes its progress and checks for cancellation.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Files of a batch job, e.g. `{"glob": "2024/*.bin.zst"}`. Paths are relative to the data
+/// directory, directory entries and glob matches are loaded in path order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchSource {
+    Directory(String),
+    Glob(String),
+    Manifest(Vec<ManifestEntry>),
+}
+
+impl BatchSource {
+    pub fn describe(&self) -> String {
+        match self {
+            BatchSource::Directory(directory) => format!("directory {}", directory),
+            BatchSource::Glob(pattern) => format!("glob {}", pattern),
+            BatchSource::Manifest(entries) => format!("manifest of {} files", entries.len()),
+        }
+    }
+
+    /// Files to load, in order, with their paths resolved.
+    pub fn entries(&self) -> Result<Vec<ManifestEntry>> {
+        let mut paths: Vec<PathBuf> = match self {
+            BatchSource::Directory(directory) => std::fs::read_dir(data_path(directory))?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<std::io::Result<Vec<_>>>()?
+                .into_iter()
+                .filter(|path| path.is_file())
+                .collect(),
+            BatchSource::Glob(pattern) => {
+                let pattern = data_path(pattern).display().to_string();
+                glob::glob(&pattern)
+                    .map_err(|e| crate::error!(CustomError, "Invalid glob {}: {}", pattern, e))?
+                    .filter_map(|path| path.ok())
+                    .filter(|path| path.is_file())
+                    .collect()
+            }
+            BatchSource::Manifest(entries) => {
+                return Ok(entries
+                    .iter()
+                    .map(|entry| ManifestEntry {
+                        file_path: data_path(&entry.file_path).display().to_string(),
+                        ..entry.clone()
+                    })
+                    .collect());
+            }
+        };
+        paths.sort();
+
+        Ok(paths
+            .into_iter()
+            .map(|path| ManifestEntry {
+                file_path: path.display().to_string(),
+                ..Default::default()
+            })
+            .collect())
+    }
+}
+
+/// A batch job with the paths of its files grouped by outcome.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobSummary {
+    pub job: IngestJob,
+    pub loaded: Vec<String>,
+    pub skipped: Vec<String>,
+    pub failed: Vec<String>,
+    pub pending: Vec<String>,
+    pub files: Vec<JobFile>,
+}
+
+impl JobSummary {
+    pub fn new(job: IngestJob, files: Vec<JobFile>) -> Self {
+        let paths = |status: &[FileStatus]| -> Vec<String> {
+            files
+                .iter()
+                .filter(|file| status.contains(&file.status))
+                .map(|file| file.file_path.clone())
+                .collect()
+        };
+
+        JobSummary {
+            job,
+            loaded: paths(&[FileStatus::Loaded]),
+            skipped: paths(&[FileStatus::Skipped]),
+            failed: paths(&[FileStatus::Failed]),
+            pending: paths(&[FileStatus::Pending, FileStatus::Running]),
+            files,
+        }
+    }
+}
+
+// Handlers
+pub async fn create_job(
+    Extension(pool): Extension<PgPool>,
+    Query(params): Query<LoadParams>,
+    Json(file_path): Json<String>,
+) -> Result<impl IntoResponse> {
+    let path = check_file(file_path)?;
+    info!(
+        "Handling request to create ingest job for {}",
+        path.display()
+    );
+
+    let id = IngestJob::create(&pool, &path.display().to_string(), params.mode).await?;
+    tokio::spawn(run_job(pool, id, path, params));
+
+    Ok(ApiResponse::new(
+        "success",
+        &format!("Successfully created ingest job with id {}", id),
+        StatusCode::OK,
+        id,
+    ))
+}
+
+/// Queues a job that loads every file of a directory, glob or manifest.
+pub async fn create_batch_job(
+    Extension(pool): Extension<PgPool>,
+    Query(params): Query<LoadParams>,
+    Json(source): Json<BatchSource>,
+) -> Result<impl IntoResponse> {
+    info!(
+        "Handling request to create ingest job for {}",
+        source.describe()
+    );
+
+    let entries = source.entries()?;
+    if entries.is_empty() {
+        error!("No files found for {}", source.describe());
+        return Err(crate::error!(
+            CustomError,
+            "No files found for {}",
+            source.describe()
+        ));
+    }
+
+    let id = IngestJob::create(&pool, &source.describe(), params.mode).await?;
+    JobFile::create_all(&pool, id, &entries).await?;
+    tokio::spawn(run_batch_job(pool, id, params));
+
+    Ok(ApiResponse::new(
+        "success",
+        &format!(
+            "Successfully created ingest job with id {} for {} files",
+            id,
+            entries.len()
+        ),
+        StatusCode::OK,
+        id,
+    ))
+}
+
+pub async fn get_job_summary(
+    Extension(pool): Extension<PgPool>,
+    Json(id): Json<i32>,
+) -> Result<impl IntoResponse> {
+    info!("Handling request to get the summary of ingest job {}", id);
+
+    let Some(job) = IngestJob::get(&pool, id).await? else {
+        return Ok(ApiResponse::new(
+            "success",
+            &format!("No ingest job found with id {}", id),
+            StatusCode::NOT_FOUND,
+            None,
+        ));
+    };
+    let files = JobFile::list(&pool, id).await?;
+
+    Ok(ApiResponse::new(
+        "success",
+        &format!("Successfully retrieved summary of ingest job {}", id),
+        StatusCode::OK,
+        Some(JobSummary::new(job, files)),
+    ))
+}
+
+pub async fn get_job(
+    Extension(pool): Extension<PgPool>,
+    Json(id): Json<i32>,
+) -> Result<impl IntoResponse> {
+    info!("Handling request to get ingest job {}", id);
+
+    match IngestJob::get(&pool, id).await {
+        Ok(Some(job)) => Ok(ApiResponse::new(
+            "success",
+            &format!("Successfully retrieved ingest job {}", id),
+            StatusCode::OK,
+            Some(job),
+        )),
+        Ok(None) => Ok(ApiResponse::new(
+            "success",
+            &format!("No ingest job found with id {}", id),
+            StatusCode::NOT_FOUND,
+            None,
+        )),
+        Err(e) => {
+            error!("Failed to retrieve ingest job: {:?}", e);
+            Err(e)
+        }
+    }
+}
+
+pub async fn list_jobs(Extension(pool): Extension<PgPool>) -> Result<impl IntoResponse> {
+    info!("Handling request to list ingest jobs");
+
+    match IngestJob::list(&pool).await {
+        Ok(jobs) => Ok(ApiResponse::new(
+            "success",
+            "Successfully retrieved list of ingest jobs.",
+            StatusCode::OK,
+            jobs,
+        )),
+        Err(e) => {
+            error!("Failed to retrieve ingest job list: {:?}", e);
+            Err(e)
+        }
+    }
+}
+
+pub async fn cancel_job(
+    Extension(pool): Extension<PgPool>,
+    Json(id): Json<i32>,
+) -> Result<impl IntoResponse> {
+    info!("Handling request to cancel ingest job {}", id);
+
+    match IngestJob::request_cancel(&pool, id).await {
+        Ok(true) => Ok(ApiResponse::new(
+            "success",
+            &format!("Cancellation requested for ingest job {}", id),
+            StatusCode::OK,
+            "".to_string(),
+        )),
+        Ok(false) => Ok(ApiResponse::new(
+            "failed",
+            &format!("Ingest job {} is not queued or running", id),
+            StatusCode::CONFLICT,
+            "".to_string(),
+        )),
+        Err(e) => {
+            error!("Failed to cancel ingest job: {:?}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Drives a bulk upload to completion, persisting its progress to `ingest_job`.
+pub async fn run_job(pool: PgPool, job_id: i32, path: PathBuf, params: LoadParams) {
+    if let Err(e) = execute_job(&pool, job_id, &path, &params).await {
+        error!("Ingest job {} failed: {:?}", job_id, e);
+        let _ = IngestJob::finish(&pool, job_id, JobStatus::Failed, &[e.to_string()]).await;
+    }
+}
+
+async fn execute_job(pool: &PgPool, job_id: i32, path: &Path, params: &LoadParams) -> Result<()> {
+    let outcome = drive_load(pool, job_id, path, params, None, &LoadProgress::default()).await?;
+
+    let status = if outcome.completed {
+        JobStatus::Completed
+    } else if outcome.cancelled {
+        JobStatus::Cancelled
+    } else {
+        JobStatus::Failed
+    };
+    IngestJob::finish(pool, job_id, status, &[]).await?;
+
+    Ok(())
+}
+
+/// Result of loading one file under a job.
+struct LoadOutcome {
+    ingest_id: i32,
+    progress: LoadProgress,
+    completed: bool,
+    cancelled: bool,
+}
+
+/// Loads `path`, writing the job's progress as `previous` plus the running load's totals. The
+/// load fails if the file does not hold `expected_records` records.
+async fn drive_load(
+    pool: &PgPool,
+    job_id: i32,
+    path: &Path,
+    params: &LoadParams,
+    expected_records: Option<i64>,
+    previous: &LoadProgress,
+) -> Result<LoadOutcome> {
+    let decoder = open_source(pool, path, params, ColumnMap::new()).await?;
+    let source = path.display().to_string();
+    let loader = RecordLoader::new(20_000, pool.clone(), params, &source)
+        .await?
+        .with_expected_records(expected_records.map(|expected| expected as u64));
+    IngestJob::start(pool, job_id, loader.ingest_id()).await?;
+    info!(
+        "Ingest job {} started with session {}",
+        job_id,
+        loader.ingest_id()
+    );
+
+    let ingest_id = loader.ingest_id();
+    let progress = loader.progress();
+    let cancelled = loader.cancel_handle();
+    let mut stream = loader.process_records(decoder).await;
+    let mut ticker = tokio::time::interval(PROGRESS_INTERVAL);
+
+    // The loader reports through the shared progress, so the streamed chunks are only drained
+    loop {
+        tokio::select! {
+            chunk = stream.next() => {
+                if chunk.is_none() {
+                    break;
+                }
+            }
+            _ = ticker.tick() => {
+                let snapshot = previous.merged(&progress.lock().unwrap());
+                if IngestJob::update_progress(pool, job_id, &snapshot).await? {
+                    cancelled.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    let snapshot = progress.lock().unwrap().clone();
+    IngestJob::update_progress(pool, job_id, &previous.merged(&snapshot)).await?;
+
+    // A cancel that arrives after the last record no longer stops the load
+    let completed = IngestSession::get(pool, ingest_id)
+        .await?
+        .is_some_and(|session| session.status == IngestStatus::Completed);
+
+    Ok(LoadOutcome {
+        ingest_id,
+        progress: snapshot,
+        completed,
+        cancelled: cancelled.load(Ordering::Relaxed),
+    })
+}
+
+/// Loads the files of a batch job in order. A failed file does not stop the files after it.
+pub async fn run_batch_job(pool: PgPool, job_id: i32, params: LoadParams) {
+    if let Err(e) = execute_batch_job(&pool, job_id, &params).await {
+        error!("Ingest job {} failed: {:?}", job_id, e);
+        let _ = IngestJob::finish(&pool, job_id, JobStatus::Failed, &[e.to_string()]).await;
+    }
+}
+
+async fn execute_batch_job(pool: &PgPool, job_id: i32, params: &LoadParams) -> Result<()> {
+    let mut totals = LoadProgress::default();
+    let mut errors = Vec::new();
+
+    for file in JobFile::list(pool, job_id).await? {
+        if IngestJob::update_progress(pool, job_id, &totals).await? {
+            IngestJob::finish(pool, job_id, JobStatus::Cancelled, &errors).await?;
+            return Ok(());
+        }
+
+        let path = PathBuf::from(&file.file_path);
+        if !path.is_file() {
+            fail_file(pool, &file, None, "Path is not a file.", &mut errors).await?;
+            continue;
+        }
+
+        let checksum = file_checksum(path.clone()).await?;
+        if let Some(expected) = &file.expected_checksum {
+            if *expected != checksum {
+                let message = format!("Checksum mismatch: expected {}, got {}", expected, checksum);
+                fail_file(pool, &file, None, &message, &mut errors).await?;
+                continue;
+            }
+        }
+
+        if JobFile::checksum_loaded(pool, &checksum).await? {
+            info!("Skipping {}, already ingested", file.file_path);
+            JobFile::finish(pool, file.id, FileStatus::Skipped, None, 0, None).await?;
+            continue;
+        }
+
+        JobFile::start(pool, file.id, &checksum).await?;
+        let outcome =
+            match drive_load(pool, job_id, &path, params, file.expected_records, &totals).await {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    fail_file(pool, &file, None, &e.to_string(), &mut errors).await?;
+                    continue;
+                }
+            };
+        totals = totals.merged(&outcome.progress);
+
+        if !outcome.completed {
+            let message = match outcome.progress.errors.last() {
+                Some(error) => error.clone(),
+                None if outcome.cancelled => "Cancelled.".to_string(),
+                None => "Load failed.".to_string(),
+            };
+            fail_file(pool, &file, Some(outcome.ingest_id), &message, &mut errors).await?;
+
+            if outcome.cancelled {
+                IngestJob::finish(pool, job_id, JobStatus::Cancelled, &errors).await?;
+                return Ok(());
+            }
+            continue;
+        }
+
+        JobFile::finish(
+            pool,
+            file.id,
+            FileStatus::Loaded,
+            Some(outcome.ingest_id),
+            outcome.progress.records_committed as i64,
+            None,
+        )
+        .await?;
+    }
+
+    IngestJob::update_progress(pool, job_id, &totals).await?;
+    let status = if errors.is_empty() {
+        JobStatus::Completed
+    } else {
+        JobStatus::Failed
+    };
+    IngestJob::finish(pool, job_id, status, &errors).await?;
+
+    Ok(())
+}
+
+async fn fail_file(
+    pool: &PgPool,
+    file: &JobFile,
+    ingest_id: Option<i32>,
+    message: &str,
+    errors: &mut Vec<String>,
+) -> Result<()> {
+    error!("Failed to load {}: {}", file.file_path, message);
+    errors.push(format!("{}: {}", file.file_path, message));
+    JobFile::finish(
+        pool,
+        file.id,
+        FileStatus::Failed,
+        ingest_id,
+        0,
+        Some(message),
+    )
+    .await
+}
+
+/// Hex encoded sha256 of the file, read on a blocking thread.
+async fn file_checksum(path: PathBuf) -> Result<String> {
+    tokio::task::spawn_blocking(move || {
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut File::open(&path)?, &mut hasher)?;
+        let checksum = hasher.finalize();
+        Ok(checksum
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect())
+    })
+    .await
+    .map_err(|e| crate::error!(CustomError, "Checksum task failed: {}", e))?
+}
+
+/// Fails jobs and rolls back sessions left unfinished by a previous run of the service.
+/// Resumable sessions keep their committed batches so they can be resumed. Rollup bars still
+/// queued are refreshed.
+pub async fn recover_interrupted(pool: &PgPool) -> Result<()> {
+    for job in IngestJob::fail_interrupted(pool).await? {
+        info!("Ingest job {} was interrupted by a restart", job.id);
+    }
+    JobFile::fail_interrupted(pool).await?;
+
+    for session in IngestSession::list_running(pool).await? {
+        IngestSession::update_status(pool, session.id, IngestStatus::Failed).await?;
+        if session.resumable {
+            info!(
+                "Ingest session {} was interrupted at record {}",
+                session.id, session.checkpoint_record
+            );
+            continue;
+        }
+
+        let mut tx = pool.begin().await?;
+        let deleted = rollback_all_batches(session.id, &mut tx).await?;
+        tx.commit().await?;
+        info!(
+            "Rolled back {} records from interrupted ingest session {}",
+            deleted, session.id
+        );
+    }
+
+    // Bars of trades committed before the restart but never refreshed
+    let seconds = refresh_queued_rollups(pool).await?;
+    if seconds > 0 {
+        info!("Refreshed the rollup bars of {} queued seconds", seconds);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::init::init_db;
+    use crate::database::market_data::create::IngestMode;
+    use crate::test_utils::{create_instrument, delete_instrument, mbp1};
+    use mbn::encode::RecordEncoder;
+    use mbn::enums::Action;
+    use mbn::record_ref::RecordRef;
+    use mbn::records::Mbp1Msg;
+    use serial_test::serial;
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_run_job() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let id = create_instrument(&pool, "AAPL").await?;
+
+        // Records
+        let mbp_1 = mbp1(id, 1704209103644092564, 6770, Action::Add);
+        let mbp_2 = Mbp1Msg {
+            ts_recv: 1704209103644092566,
+            ..mbp_1
+        };
+
+        let record_ref1: RecordRef = (&mbp_1).into();
+        let record_ref2: RecordRef = (&mbp_2).into();
+
+        let mut buffer = Vec::new();
+        let mut encoder = RecordEncoder::new(&mut buffer);
+        encoder
+            .encode_records(&[record_ref1, record_ref2])
+            .expect("Encoding failed");
+
+        let file = "tests/data/test_run_job.bin";
+        let path = PathBuf::from(file);
+        let _ = encoder.write_to_file(&path, false);
+
+        // Test
+        let job_id = IngestJob::create(&pool, file, IngestMode::Strict).await?;
+        run_job(pool.clone(), job_id, path.clone(), LoadParams::default()).await;
+
+        // Validate
+        let job = IngestJob::get(&pool, job_id).await?.unwrap();
+        assert_eq!(job.status, JobStatus::Completed);
+        assert_eq!(job.records_decoded, 2);
+        assert_eq!(job.records_committed, 2);
+        assert!(job.ingest_id.is_some());
+
+        // Cleanup
+        delete_instrument(&pool, id).await?;
+        sqlx::query("DELETE FROM ingest_job WHERE id = $1")
+            .bind(job_id)
+            .execute(&pool)
+            .await?;
+
+        if path.exists() {
+            std::fs::remove_file(&path).expect("Failed to delete the test file.");
+        }
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_run_batch_job() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let id = create_instrument(&pool, "AAPL").await?;
+
+        // Records
+        let mbp_1 = mbp1(id, 1704209103644092564, 6770, Action::Add);
+        let record_ref: RecordRef = (&mbp_1).into();
+
+        let mut buffer = Vec::new();
+        let mut encoder = RecordEncoder::new(&mut buffer);
+        encoder
+            .encode_records(&[record_ref])
+            .expect("Encoding failed");
+
+        let file = "tests/data/test_run_batch_job.bin";
+        let path = PathBuf::from(file);
+        let _ = encoder.write_to_file(&path, false);
+
+        // The same file twice, then with a checksum it does not match
+        let source = BatchSource::Manifest(vec![
+            ManifestEntry {
+                file_path: file.to_string(),
+                expected_records: Some(1),
+                checksum: None,
+            },
+            ManifestEntry {
+                file_path: file.to_string(),
+                ..Default::default()
+            },
+            ManifestEntry {
+                file_path: file.to_string(),
+                checksum: Some("0".repeat(64)),
+                ..Default::default()
+            },
+        ]);
+
+        // Test
+        let job_id = IngestJob::create(&pool, &source.describe(), IngestMode::Strict).await?;
+        JobFile::create_all(&pool, job_id, &source.entries()?).await?;
+        run_batch_job(pool.clone(), job_id, LoadParams::default()).await;
+
+        // Validate
+        let summary = JobSummary::new(
+            IngestJob::get(&pool, job_id).await?.unwrap(),
+            JobFile::list(&pool, job_id).await?,
+        );
+        assert_eq!(summary.job.status, JobStatus::Failed);
+        assert_eq!(summary.job.records_committed, 1);
+        assert_eq!(summary.loaded, vec![file.to_string()]);
+        assert_eq!(summary.skipped, vec![file.to_string()]);
+        assert_eq!(summary.failed, vec![file.to_string()]);
+        assert!(summary.files[0].checksum.is_some());
+
+        // Cleanup
+        delete_instrument(&pool, id).await?;
+        sqlx::query("DELETE FROM ingest_job WHERE id = $1")
+            .bind(job_id)
+            .execute(&pool)
+            .await?;
+
+        if path.exists() {
+            std::fs::remove_file(&path).expect("Failed to delete the test file.");
+        }
+
+        Ok(())
+    }
+}
@@ -1,22 +1,40 @@
+use crate::database::ingest::{IngestSession, IngestStatus};
+use crate::database::market_data::create::{IngestMode, LoaderBackend};
+use crate::database::market_data::read::schema_interval;
 use crate::services::market_data::record_loader::RecordLoader;
+use crate::services::market_data::record_source::{
+    open_source, open_with_metadata, ColumnMap, InputFormat, MbnSource, RecordSource,
+};
+use crate::services::market_data::record_validator::RecordValidator;
 use crate::{Error, Result};
+use axum::extract::{BodyStream, Query};
+use axum::http::{header, HeaderMap};
 use axum::response::IntoResponse;
 use axum::{body::StreamBody, Extension, Json};
+use bytes::Bytes;
+use futures::{Stream, TryStreamExt};
 use mbn::decode::RecordDecoder;
+use mbn::enums::Schema;
+use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
-use std::fs::File;
-use std::io::BufReader;
 use std::io::Cursor;
 use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use tokio_util::io::{StreamReader, SyncIoBridge};
 use tracing::{error, info};
 
-pub fn check_file(file_path: String) -> Result<PathBuf> {
-    let path;
+/// Location of an uploaded file or pattern, relative to the data directory outside of tests.
+pub fn data_path(file_path: &str) -> PathBuf {
     if cfg!(test) {
-        path = PathBuf::from(&file_path);
+        PathBuf::from(file_path)
     } else {
-        path = PathBuf::from("data/processed_data").join(&file_path);
+        PathBuf::from("data/processed_data").join(file_path)
     }
+}
+
+pub fn check_file(file_path: String) -> Result<PathBuf> {
+    let path = data_path(&file_path);
 
     if !path.is_file() {
         error!("Path is not a file: {}", &file_path);
@@ -25,20 +43,202 @@ pub fn check_file(file_path: String) -> Result<PathBuf> {
     Ok(path)
 }
 
+/// Query parameters shared by the upload endpoints, e.g. `?mode=skip_duplicates&backend=copy`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LoadParams {
+    #[serde(default)]
+    pub mode: IngestMode,
+    /// Keep committed batches on failure so the upload can be resumed from its checkpoint.
+    #[serde(default)]
+    pub resumable: bool,
+    #[serde(default)]
+    pub backend: LoaderBackend,
+    /// Schema of the upload, e.g. `ohlcv-1m`. Required for bar and quote records, which carry
+    /// no interval of their own.
+    #[serde(default)]
+    pub schema: Option<String>,
+    /// Batches committed concurrently, each on its own connection. Resumable uploads always use
+    /// a single writer.
+    #[serde(default)]
+    pub writers: Option<usize>,
+    /// Check the records and report data-quality issues without writing anything.
+    #[serde(default)]
+    pub validate_only: bool,
+    /// Format of uploaded files, detected from the extension when not given.
+    #[serde(default)]
+    pub format: Option<InputFormat>,
+    /// Create instruments missing from the server out of the mbn metadata's symbol map, or the
+    /// DBN metadata's mappings, and remap the upload's instrument ids to the server's.
+    #[serde(default)]
+    pub create_instruments: bool,
+    /// Vendor, dataset and stype of instruments created by `create_instruments`.
+    #[serde(default)]
+    pub vendor: Option<String>,
+    #[serde(default)]
+    pub dataset: Option<String>,
+    #[serde(default)]
+    pub stype: Option<String>,
+    /// `ts_recv` range `[replace_start, replace_end)` deleted by `mode=replace` for every
+    /// instrument in the upload. Records outside of it fail the upload.
+    #[serde(default)]
+    pub replace_start: Option<i64>,
+    #[serde(default)]
+    pub replace_end: Option<i64>,
+}
+
+/// Body of `import_upload`, a file with its column mapping.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ImportRequest {
+    pub file_path: String,
+    #[serde(default)]
+    pub columns: ColumnMap,
+}
+
+/// Parameters and column mapping of a resumable file upload, stored on its ingest session.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SessionLoad {
+    pub params: LoadParams,
+    #[serde(default)]
+    pub columns: ColumnMap,
+}
+
+impl SessionLoad {
+    fn to_json(&self) -> Result<serde_json::Value> {
+        serde_json::to_value(self).map_err(|e| Error::GeneralError(Box::new(e)))
+    }
+
+    /// Parameters a session is resumed with. Sessions that stored theirs keep them, a resume may
+    /// repeat them or leave them out but not change them.
+    fn for_resume(session: &IngestSession, params: LoadParams) -> Result<SessionLoad> {
+        let stored = match &session.load_params {
+            Some(value) => serde_json::from_value::<SessionLoad>(value.clone())
+                .map_err(|e| Error::GeneralError(Box::new(e)))?,
+            None => {
+                return Ok(SessionLoad {
+                    params,
+                    columns: ColumnMap::new(),
+                })
+            }
+        };
+
+        let requested = SessionLoad {
+            params,
+            columns: stored.columns.clone(),
+        }
+        .to_json()?;
+        let unset = SessionLoad {
+            params: LoadParams::default(),
+            columns: stored.columns.clone(),
+        }
+        .to_json()?;
+        if requested != unset && requested != stored.to_json()? {
+            error!(
+                "Resume parameters conflict with ingest session {}",
+                session.id
+            );
+            return Err(crate::error!(
+                CustomError,
+                "Ingest session {} was started with different parameters: {}",
+                session.id,
+                serde_json::to_string(&stored.params).unwrap_or_default()
+            ));
+        }
+        Ok(stored)
+    }
+}
+
+impl LoadParams {
+    pub fn interval_ns(&self) -> Result<Option<i64>> {
+        match &self.schema {
+            Some(schema) => Ok(Some(schema_interval(Schema::from_str(schema)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Range replaced by a `replace` upload, `None` for the other modes.
+    pub fn replace_range(&self) -> Result<Option<(i64, i64)>> {
+        if self.mode != IngestMode::Replace {
+            return Ok(None);
+        }
+        if self.resumable {
+            return Err(crate::error!(
+                CustomError,
+                "Replace uploads load in a single transaction and cannot be resumable."
+            ));
+        }
+        match (self.replace_start, self.replace_end) {
+            (Some(start), Some(end)) if start < end => Ok(Some((start, end))),
+            (Some(_), Some(_)) => Err(crate::error!(
+                CustomError,
+                "replace_start must be before replace_end."
+            )),
+            _ => Err(crate::error!(
+                CustomError,
+                "Replace uploads require replace_start and replace_end."
+            )),
+        }
+    }
+}
+
 // Handlers
 /// For smaller inserts, mainly using bulk_upload.
 pub async fn create_record(
     Extension(pool): Extension<PgPool>,
+    Query(params): Query<LoadParams>,
     Json(encoded_data): Json<Vec<u8>>,
 ) -> Result<impl IntoResponse> {
     info!("Handling request to create records from binary data");
 
     // Decode received binary data
     let cursor = Cursor::new(encoded_data);
-    let decoder = RecordDecoder::new(cursor);
+    let decoder: Box<dyn RecordSource> = if params.create_instruments {
+        open_with_metadata(&pool, cursor, &params).await?
+    } else {
+        Box::new(RecordDecoder::new(cursor))
+    };
+
+    // Initialize the loader
+    let loader = RecordLoader::new(1000, pool, &params, "create").await?;
+    let progress_stream = loader.process_records(decoder).await;
+
+    Ok(StreamBody::new(progress_stream))
+}
+
+/// Loads mbn bytes sent as the raw request body (`application/octet-stream`, optionally chunked).
+/// Records are decoded as the body arrives instead of buffering the whole payload. Bodies may be
+/// zstd or gzip compressed.
+pub async fn stream_upload(
+    Extension(pool): Extension<PgPool>,
+    Query(params): Query<LoadParams>,
+    headers: HeaderMap,
+    body: BodyStream,
+) -> Result<impl IntoResponse> {
+    info!("Handling request to stream records from the request body");
+
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    if content_type != "application/octet-stream" {
+        error!("Invalid content type for stream upload: {}", content_type);
+        return Err(crate::error!(
+            CustomError,
+            "Expected content type application/octet-stream, got {}",
+            content_type
+        ));
+    }
+
+    // Bridge the body into a blocking reader for the decoder, compressed bodies are detected there
+    let reader = StreamReader::new(body.map_err(std::io::Error::other));
+    let reader = SyncIoBridge::new(reader);
+    let decoder: Box<dyn RecordSource> = if params.create_instruments {
+        open_with_metadata(&pool, reader, &params).await?
+    } else {
+        Box::new(MbnSource::new(reader))
+    };
 
     // Initialize the loader
-    let loader = RecordLoader::new(1000, pool).await?;
+    let loader = RecordLoader::new(20_000, pool, &params, "stream").await?;
     let progress_stream = loader.process_records(decoder).await;
 
     Ok(StreamBody::new(progress_stream))
@@ -46,16 +246,90 @@ pub async fn create_record(
 
 pub async fn bulk_upload(
     Extension(pool): Extension<PgPool>,
+    Query(params): Query<LoadParams>,
     Json(file_path): Json<String>,
 ) -> crate::Result<impl IntoResponse> {
+    load_file(pool, params, file_path, ColumnMap::new()).await
+}
+
+/// Loads a CSV or parquet file whose columns are named differently from the record fields.
+pub async fn import_upload(
+    Extension(pool): Extension<PgPool>,
+    Query(params): Query<LoadParams>,
+    Json(request): Json<ImportRequest>,
+) -> crate::Result<impl IntoResponse> {
+    load_file(pool, params, request.file_path, request.columns).await
+}
+
+async fn load_file(
+    pool: PgPool,
+    params: LoadParams,
+    file_path: String,
+    columns: ColumnMap,
+) -> crate::Result<StreamBody<Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>>> {
     let path = check_file(file_path)?;
     info!("Preparing to stream load file: {}", &path.display());
 
-    // Initialize the decoder
-    let decoder = RecordDecoder::<BufReader<File>>::from_file(&path)?;
+    // Initialize the decoder, resumable sessions keep how the file is read for resume_upload
+    let load = SessionLoad {
+        params: params.clone(),
+        columns: columns.clone(),
+    };
+    let decoder = open_source(&pool, &path, &params, columns).await?;
+
+    if params.validate_only {
+        let validator = RecordValidator::new(20_000, pool).await?;
+        let progress_stream = validator.validate_records(decoder).await;
+        return Ok(StreamBody::new(progress_stream));
+    }
 
     // Initialize the loader
-    let loader = RecordLoader::new(20_000, pool).await?;
+    let source = path.display().to_string();
+    let loader = RecordLoader::new(20_000, pool.clone(), &params, &source).await?;
+    if params.resumable {
+        IngestSession::set_load_params(&pool, loader.ingest_id(), &load.to_json()?).await?;
+    }
+    let progress_stream = loader.process_records(decoder).await;
+
+    Ok(StreamBody::new(progress_stream))
+}
+
+/// Continues a failed resumable bulk upload from its last committed batch, reading the file with
+/// the parameters and column mapping the upload was started with.
+pub async fn resume_upload(
+    Extension(pool): Extension<PgPool>,
+    Query(params): Query<LoadParams>,
+    Json(ingest_id): Json<i32>,
+) -> crate::Result<impl IntoResponse> {
+    info!("Handling request to resume ingest session {}", ingest_id);
+
+    let session = IngestSession::get(&pool, ingest_id)
+        .await?
+        .ok_or_else(|| crate::error!(CustomError, "No ingest session with id {}", ingest_id))?;
+
+    if !session.resumable || session.status != IngestStatus::Failed {
+        error!("Ingest session {} cannot be resumed", ingest_id);
+        return Err(crate::error!(
+            CustomError,
+            "Ingest session {} is not a failed resumable upload.",
+            ingest_id
+        ));
+    }
+
+    let path = PathBuf::from(&session.source);
+    if !path.is_file() {
+        error!("Path is not a file: {}", &session.source);
+        return Err(Error::CustomError("Path is not a file.".to_string()));
+    }
+    info!(
+        "Resuming {} from record {}",
+        &session.source, session.checkpoint_record
+    );
+
+    // Reopen the file, the loader skips records up to the checkpoint
+    let load = SessionLoad::for_resume(&session, params)?;
+    let decoder = open_source(&pool, &path, &load.params, load.columns).await?;
+    let loader = RecordLoader::resume(20_000, pool, &session, &load.params).await?;
     let progress_stream = loader.process_records(decoder).await;
 
     Ok(StreamBody::new(progress_stream))
@@ -65,10 +339,13 @@ pub async fn bulk_upload(
 mod test {
     use super::*;
     use crate::database::init::init_db;
+    use crate::database::market_data::create::InsertBatch;
     use crate::database::market_data::read::RetrieveParams;
     use crate::database::symbols::InstrumentsQueries;
     use crate::response::ApiResponse;
     use crate::services::market_data::get_records;
+    use crate::services::market_data::record_validator::ValidationReport;
+    use crate::test_utils::{create_instrument, delete_instrument};
     use hyper::body::HttpBody as _;
     use mbn::encode::RecordEncoder;
     use mbn::record_ref::RecordRef;
@@ -164,10 +441,14 @@ mod test {
             .encode_records(&[record_ref1, record_ref2])
             .expect("Encoding failed");
 
-        let response = create_record(Extension(pool.clone()), Json(buffer))
-            .await
-            .expect("Error creating records.")
-            .into_response();
+        let response = create_record(
+            Extension(pool.clone()),
+            Query(LoadParams::default()),
+            Json(buffer),
+        )
+        .await
+        .expect("Error creating records.")
+        .into_response();
 
         // Validate
         let mut stream = response.into_body();
@@ -304,9 +585,13 @@ mod test {
         let _ = encoder.write_to_file(&path, false);
 
         // Test
-        let result = bulk_upload(Extension(pool.clone()), Json(file.to_string()))
-            .await
-            .into_response();
+        let result = bulk_upload(
+            Extension(pool.clone()),
+            Query(LoadParams::default()),
+            Json(file.to_string()),
+        )
+        .await
+        .into_response();
 
         // Extract the body (which is a stream)
         let mut stream = result.into_body();
@@ -349,6 +634,9 @@ mod test {
             start_ts: 1704209103644092563,
             end_ts: 1704209903644092569,
             schema: Schema::Mbp1.to_string(),
+            as_of_version: None,
+            as_of_time: None,
+            interval: None,
         };
         let response = get_records(Extension(pool.clone()), Json(params))
             .await
@@ -472,9 +760,13 @@ mod test {
         let _ = encoder.write_to_file(&path, false);
 
         // Test
-        let result = bulk_upload(Extension(pool.clone()), Json(file.to_string()))
-            .await
-            .into_response();
+        let result = bulk_upload(
+            Extension(pool.clone()),
+            Query(LoadParams::default()),
+            Json(file.to_string()),
+        )
+        .await
+        .into_response();
 
         // Extract the body (which is a stream)
         let mut stream = result.into_body();
@@ -527,4 +819,568 @@ mod test {
 
         Ok(())
     }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_bulk_upload_skip_duplicates() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let mut transaction = pool.begin().await.expect("Error settign up database.");
+
+        // Create instrument
+        let ticker = "AAPL";
+        let name = "Apple Inc.";
+        let instrument = Instrument::new(
+            None,
+            ticker,
+            name,
+            Vendors::Databento,
+            Some("continuous".to_string()),
+            Some("GLBX.MDP3".to_string()),
+            1704672000000000000,
+            1704672000000000000,
+            true,
+        );
+        let id: i32 = instrument
+            .insert_instrument(&mut transaction)
+            .await
+            .expect("Error inserting symbol.");
+        let _ = transaction.commit().await;
+
+        // Records (duplicates)
+        let mbp_1 = Mbp1Msg {
+            hd: { RecordHeader::new::<Mbp1Msg>(id as u32, 1704209103644092566) },
+            price: 6870,
+            size: 2,
+            action: 1,
+            side: 1,
+            depth: 0,
+            flags: 0,
+            ts_recv: 1704209103644092566,
+            ts_in_delta: 17493,
+            sequence: 739763,
+            discriminator: 0,
+            levels: [BidAskPair {
+                bid_px: 1,
+                ask_px: 1,
+                bid_sz: 1,
+                ask_sz: 1,
+                bid_ct: 10,
+                ask_ct: 20,
+            }],
+        };
+
+        let record_ref1: RecordRef = (&mbp_1).into();
+        let record_ref2: RecordRef = (&mbp_1).into();
+
+        let mut buffer = Vec::new();
+        let mut encoder = RecordEncoder::new(&mut buffer);
+        encoder
+            .encode_records(&[record_ref1, record_ref2])
+            .expect("Encoding failed");
+
+        let file = "tests/data/test_bulk_upload.bin";
+        let path = PathBuf::from(file);
+        let _ = encoder.write_to_file(&path, false);
+
+        // Test
+        let params = LoadParams {
+            mode: IngestMode::SkipDuplicates,
+            ..Default::default()
+        };
+        let result = bulk_upload(
+            Extension(pool.clone()),
+            Query(params),
+            Json(file.to_string()),
+        )
+        .await
+        .into_response();
+
+        // Extract the body (which is a stream)
+        let mut stream = result.into_body();
+
+        // Vectors to store success and error responses
+        let mut success_responses = Vec::new();
+        let mut error_responses = Vec::new();
+
+        // Collect streamed responses
+        while let Some(chunk) = stream.data().await {
+            match chunk {
+                Ok(bytes) => {
+                    let bytes_str = String::from_utf8_lossy(&bytes);
+
+                    match serde_json::from_str::<ApiResponse<String>>(&bytes_str) {
+                        Ok(response) => {
+                            if response.status == "success" {
+                                success_responses.push(response); // Store success response
+                            } else {
+                                error_responses.push(response); // Store error response
+                            }
+                        }
+                        Err(e) => {
+                            eprintln!("Failed to parse chunk: {:?}, raw chunk: {}", e, bytes_str);
+                        }
+                    }
+                }
+                Err(e) => {
+                    panic!("Error while reading chunk: {:?}", e);
+                }
+            }
+        }
+
+        // Validate
+        assert!(error_responses.is_empty());
+        assert!(success_responses
+            .iter()
+            .any(|r| r.message.contains("1 inserted, 1 skipped")));
+
+        // Cleanup
+        let mut transaction = pool
+            .begin()
+            .await
+            .expect("Error setting up test transaction.");
+        Instrument::delete_instrument(&mut transaction, id)
+            .await
+            .expect("Error on delete.");
+        let _ = transaction.commit().await;
+
+        // Cleanup
+        if path.exists() {
+            std::fs::remove_file(&path).expect("Failed to delete the test file.");
+        }
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_resume_upload() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let mut transaction = pool.begin().await.expect("Error settign up database.");
+
+        // Create instrument
+        let ticker = "AAPL";
+        let name = "Apple Inc.";
+        let instrument = Instrument::new(
+            None,
+            ticker,
+            name,
+            Vendors::Databento,
+            Some("continuous".to_string()),
+            Some("GLBX.MDP3".to_string()),
+            1704672000000000000,
+            1704672000000000000,
+            true,
+        );
+        let id: i32 = instrument
+            .insert_instrument(&mut transaction)
+            .await
+            .expect("Error inserting symbol.");
+        let _ = transaction.commit().await;
+
+        // Records
+        let mbp_1 = Mbp1Msg {
+            hd: { RecordHeader::new::<Mbp1Msg>(id as u32, 1704209103644092566) },
+            price: 6870,
+            size: 2,
+            action: 1,
+            side: 1,
+            depth: 0,
+            flags: 0,
+            ts_recv: 1704209103644092566,
+            ts_in_delta: 17493,
+            sequence: 739763,
+            discriminator: 0,
+            levels: [BidAskPair {
+                bid_px: 1,
+                ask_px: 1,
+                bid_sz: 1,
+                ask_sz: 1,
+                bid_ct: 10,
+                ask_ct: 20,
+            }],
+        };
+        let mbp_2 = Mbp1Msg {
+            hd: { RecordHeader::new::<Mbp1Msg>(id as u32, 1704239109644092564) },
+            price: 6870,
+            size: 2,
+            action: 1,
+            side: 1,
+            depth: 0,
+            flags: 0,
+            ts_recv: 1704209103644092566,
+            ts_in_delta: 17493,
+            sequence: 739764,
+            discriminator: 0,
+            levels: [BidAskPair {
+                bid_px: 1,
+                ask_px: 1,
+                bid_sz: 1,
+                ask_sz: 1,
+                bid_ct: 10,
+                ask_ct: 20,
+            }],
+        };
+
+        let record_ref1: RecordRef = (&mbp_1).into();
+        let record_ref2: RecordRef = (&mbp_2).into();
+
+        let mut buffer = Vec::new();
+        let mut encoder = RecordEncoder::new(&mut buffer);
+        encoder
+            .encode_records(&[record_ref1, record_ref2])
+            .expect("Encoding failed");
+
+        let file = "tests/data/test_resume_upload.bin";
+        let path = PathBuf::from(file);
+        let _ = encoder.write_to_file(&path, false);
+
+        // Failed session that committed the first record
+        let ingest_id = IngestSession::create(&pool, file, IngestMode::Strict, true).await?;
+        let mut tx = pool.begin().await?;
+        IngestSession::checkpoint(&mut tx, ingest_id, 1, 1).await?;
+        tx.commit().await?;
+        IngestSession::update_status(&pool, ingest_id, IngestStatus::Failed).await?;
+
+        // Test
+        let result = resume_upload(
+            Extension(pool.clone()),
+            Query(LoadParams::default()),
+            Json(ingest_id),
+        )
+        .await
+        .into_response();
+
+        let mut stream = result.into_body();
+        let mut responses = Vec::new();
+        while let Some(chunk) = stream.data().await {
+            let bytes = chunk.expect("Error while reading chunk");
+            let response: ApiResponse<String> = serde_json::from_slice(&bytes)?;
+            responses.push(response);
+        }
+
+        // Validate
+        assert!(responses.iter().all(|r| r.status == "success"));
+        assert!(responses
+            .iter()
+            .any(|r| r.message.contains("1 inserted, 0 skipped")));
+
+        let session = IngestSession::get(&pool, ingest_id).await?.unwrap();
+        assert_eq!(session.status, IngestStatus::Completed);
+        assert_eq!(session.checkpoint_record, 2);
+        assert_eq!(session.checkpoint_batch, 2);
+
+        // Cleanup
+        let mut transaction = pool
+            .begin()
+            .await
+            .expect("Error setting up test transaction.");
+        Instrument::delete_instrument(&mut transaction, id)
+            .await
+            .expect("Error on delete.");
+        let _ = transaction.commit().await;
+
+        if path.exists() {
+            std::fs::remove_file(&path).expect("Failed to delete the test file.");
+        }
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_bulk_upload_validate_only() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let mut transaction = pool.begin().await.expect("Error settign up database.");
+
+        // Create instrument
+        let ticker = "AAPL";
+        let name = "Apple Inc.";
+        let instrument = Instrument::new(
+            None,
+            ticker,
+            name,
+            Vendors::Databento,
+            Some("continuous".to_string()),
+            Some("GLBX.MDP3".to_string()),
+            1704672000000000000,
+            1704672000000000000,
+            true,
+        );
+        let id: i32 = instrument
+            .insert_instrument(&mut transaction)
+            .await
+            .expect("Error inserting symbol.");
+        let _ = transaction.commit().await;
+
+        // Records, the first already stored, the second crossed, the third unknown
+        let mbp_1 = Mbp1Msg {
+            hd: { RecordHeader::new::<Mbp1Msg>(id as u32, 1704209103644092564) },
+            price: 6770,
+            size: 1,
+            action: 1,
+            side: 2,
+            depth: 0,
+            flags: 0,
+            ts_recv: 1704209103644092564,
+            ts_in_delta: 17493,
+            sequence: 739763,
+            discriminator: 0,
+            levels: [BidAskPair {
+                bid_px: 1,
+                ask_px: 2,
+                bid_sz: 1,
+                ask_sz: 1,
+                bid_ct: 10,
+                ask_ct: 20,
+            }],
+        };
+        let mut mbp_2 = mbp_1;
+        mbp_2.sequence = 739764;
+        mbp_2.levels[0].bid_px = 3;
+        let mut mbp_3 = mbp_1;
+        mbp_3.hd = RecordHeader::new::<Mbp1Msg>(id as u32 + 1000, 1704209103644092564);
+        mbp_3.sequence = 739765;
+
+        let mut transaction = pool.begin().await?;
+        let mut insert_batch = InsertBatch::new(IngestMode::Strict, None);
+        insert_batch.process(&mbp_1).await?;
+        insert_batch.execute(&mut transaction).await?;
+        transaction.commit().await?;
+
+        let record_ref1: RecordRef = (&mbp_1).into();
+        let record_ref2: RecordRef = (&mbp_2).into();
+        let record_ref3: RecordRef = (&mbp_3).into();
+
+        let mut buffer = Vec::new();
+        let mut encoder = RecordEncoder::new(&mut buffer);
+        encoder
+            .encode_records(&[record_ref1, record_ref2, record_ref3])
+            .expect("Encoding failed");
+
+        let file = "tests/data/test_bulk_upload_validate.bin";
+        let path = PathBuf::from(file);
+        let _ = encoder.write_to_file(&path, false);
+
+        // Test
+        let params = LoadParams {
+            validate_only: true,
+            ..Default::default()
+        };
+        let result = bulk_upload(
+            Extension(pool.clone()),
+            Query(params),
+            Json(file.to_string()),
+        )
+        .await
+        .into_response();
+
+        let mut stream = result.into_body();
+        let mut last_chunk = Vec::new();
+        while let Some(chunk) = stream.data().await {
+            last_chunk = chunk.expect("Error while reading chunk").to_vec();
+        }
+
+        // Validate
+        let response: ApiResponse<ValidationReport> = serde_json::from_slice(&last_chunk)?;
+        let report = response.data;
+        assert_eq!(report.records_checked, 3);
+        assert_eq!(report.existing_duplicates, 1);
+        assert_eq!(report.crossed_books, 1);
+        assert_eq!(report.unknown_instruments, vec![id as u32 + 1000]);
+
+        let stored: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM mbp WHERE instrument_id = $1")
+            .bind(id)
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(stored, 1);
+
+        // Cleanup
+        let mut transaction = pool
+            .begin()
+            .await
+            .expect("Error setting up test transaction.");
+        Instrument::delete_instrument(&mut transaction, id)
+            .await
+            .expect("Error on delete.");
+        let _ = transaction.commit().await;
+
+        if path.exists() {
+            std::fs::remove_file(&path).expect("Failed to delete the test file.");
+        }
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_import_upload_csv() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let mut transaction = pool.begin().await.expect("Error settign up database.");
+
+        // Create instrument
+        let ticker = "AAPL";
+        let name = "Apple Inc.";
+        let instrument = Instrument::new(
+            None,
+            ticker,
+            name,
+            Vendors::Databento,
+            Some("continuous".to_string()),
+            Some("GLBX.MDP3".to_string()),
+            1704672000000000000,
+            1704672000000000000,
+            true,
+        );
+        let id: i32 = instrument
+            .insert_instrument(&mut transaction)
+            .await
+            .expect("Error inserting symbol.");
+        let _ = transaction.commit().await;
+
+        // Records
+        let file = "tests/data/test_import_upload.csv";
+        let path = PathBuf::from(file);
+        std::fs::write(
+            &path,
+            "timestamp,ticker,price,size,action,side,sequence,bid_px,ask_px,bid_sz,ask_sz\n\
+             1704209103644092564,AAPL,6770,1,T,B,739763,6769,6771,5,5\n\
+             1704209103644092566,AAPL,6870,2,T,A,739764,6869,6871,5,5\n",
+        )?;
+
+        // Test
+        let request = ImportRequest {
+            file_path: file.to_string(),
+            columns: ColumnMap::from([
+                ("ts_event".to_string(), "timestamp".to_string()),
+                ("symbol".to_string(), "ticker".to_string()),
+            ]),
+        };
+        let result = import_upload(
+            Extension(pool.clone()),
+            Query(LoadParams::default()),
+            Json(request),
+        )
+        .await
+        .into_response();
+
+        let mut stream = result.into_body();
+        while let Some(chunk) = stream.data().await {
+            chunk.expect("Error while reading chunk");
+        }
+
+        // Validate
+        let stored: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM mbp WHERE instrument_id = $1")
+            .bind(id)
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(stored, 2);
+
+        // Cleanup
+        let mut transaction = pool
+            .begin()
+            .await
+            .expect("Error setting up test transaction.");
+        Instrument::delete_instrument(&mut transaction, id)
+            .await
+            .expect("Error on delete.");
+        let _ = transaction.commit().await;
+
+        if path.exists() {
+            std::fs::remove_file(&path).expect("Failed to delete the test file.");
+        }
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_resume_upload_stored_params() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let id = create_instrument(&pool, "AAPL").await?;
+
+        // Records, in columns only the stored mapping can read
+        let file = "tests/data/test_resume_upload_stored_params.csv";
+        let path = PathBuf::from(file);
+        std::fs::write(
+            &path,
+            "timestamp,ticker,price,size,action,side,sequence,bid_px,ask_px,bid_sz,ask_sz\n\
+             1704209103644092564,AAPL,6770,1,T,B,739763,6769,6771,5,5\n\
+             1704209103644092566,AAPL,6870,2,T,A,739764,6869,6871,5,5\n",
+        )?;
+
+        // Failed session that committed the first record
+        let load = SessionLoad {
+            params: LoadParams {
+                resumable: true,
+                ..Default::default()
+            },
+            columns: ColumnMap::from([
+                ("ts_event".to_string(), "timestamp".to_string()),
+                ("symbol".to_string(), "ticker".to_string()),
+            ]),
+        };
+        let ingest_id = IngestSession::create(&pool, file, IngestMode::Strict, true).await?;
+        IngestSession::set_load_params(&pool, ingest_id, &load.to_json()?).await?;
+        let mut tx = pool.begin().await?;
+        IngestSession::checkpoint(&mut tx, ingest_id, 1, 1).await?;
+        tx.commit().await?;
+        IngestSession::update_status(&pool, ingest_id, IngestStatus::Failed).await?;
+
+        // Test
+        let conflicting = LoadParams {
+            mode: IngestMode::SkipDuplicates,
+            ..Default::default()
+        };
+        let rejected = resume_upload(Extension(pool.clone()), Query(conflicting), Json(ingest_id))
+            .await
+            .is_err();
+
+        let result = resume_upload(
+            Extension(pool.clone()),
+            Query(LoadParams::default()),
+            Json(ingest_id),
+        )
+        .await
+        .into_response();
+
+        let mut stream = result.into_body();
+        while let Some(chunk) = stream.data().await {
+            chunk.expect("Error while reading chunk");
+        }
+
+        // Validate
+        assert!(rejected);
+        let stored: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM mbp WHERE instrument_id = $1")
+            .bind(id)
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(stored, 1);
+
+        let session = IngestSession::get(&pool, ingest_id).await?.unwrap();
+        assert_eq!(session.status, IngestStatus::Completed);
+        assert_eq!(session.checkpoint_record, 2);
+
+        // Cleanup
+        delete_instrument(&pool, id).await?;
+        sqlx::query("DELETE FROM ingest_session WHERE id = $1")
+            .bind(ingest_id)
+            .execute(&pool)
+            .await?;
+
+        if path.exists() {
+            std::fs::remove_file(&path).expect("Failed to delete the test file.");
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,543 @@
+use crate::database::ingest::{IngestSession, IngestStatus};
+use crate::database::market_data::create::IngestMode;
+use crate::database::market_data::mbo::{insert_mbo, MboMsg};
+use crate::database::market_data::mbp10::{Mbp10Msg, MBP10_SCHEMA};
+use crate::database::market_data::read::{FromRow, RecordsQuery, RetrieveParams};
+use crate::database::symbols::{query_symbols_map, refresh_availability};
+use crate::database::versions::{DataVersion, VersionKind};
+use crate::response::ApiResponse;
+use crate::services::market_data::load::LoadParams;
+use crate::services::market_data::mbp10;
+use crate::services::market_data::order_book::{OrderBook, PriceLevel};
+use crate::{Error, Result};
+use async_stream::stream;
+use axum::extract::Query;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::{body::StreamBody, Extension, Json};
+use bytes::Bytes;
+use futures::stream::StreamExt;
+use mbn::encode::{MetadataEncoder, RecordEncoder};
+use mbn::enums::Schema;
+use mbn::metadata::Metadata;
+use mbn::record_ref::RecordRef;
+use mbn::records::Mbp1Msg;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::collections::HashMap;
+use std::io::Cursor;
+use tracing::{error, info};
+
+/// Snapshots encoded per streamed chunk.
+const REPLAY_BATCH_SIZE: usize = 1000;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BookStateParams {
+    pub symbol: String,
+    pub ts: i64,
+    pub depth: usize,
+}
+
+/// Order book of an instrument after every event received up to and including `ts`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BookState {
+    pub instrument_id: u32,
+    pub ts: i64,
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+}
+
+// Handlers
+pub async fn create_mbo(
+    Extension(pool): Extension<PgPool>,
+    Query(params): Query<LoadParams>,
+    Json(records): Json<Vec<MboMsg>>,
+) -> Result<impl IntoResponse> {
+    info!("Handling request to create {} mbo events", records.len());
+
+    if params.mode == IngestMode::Replace {
+        return Err(crate::error!(
+            CustomError,
+            "Replace uploads only support mbp records."
+        ));
+    }
+
+    let ingest_id = IngestSession::create(&pool, "mbo", params.mode, false).await?;
+    let mut tx = pool.begin().await?;
+
+    match insert_events(&mut tx, &records, params.mode, ingest_id).await {
+        Ok(inserted) => {
+            tx.commit().await?;
+            IngestSession::update_status(&pool, ingest_id, IngestStatus::Completed).await?;
+            Ok(ApiResponse::new(
+                "success",
+                &format!(
+                    "Successfully loaded records: {} inserted, {} skipped.",
+                    inserted,
+                    records.len() - inserted
+                ),
+                StatusCode::OK,
+                ingest_id,
+            ))
+        }
+        Err(e) => {
+            tx.rollback().await?;
+            IngestSession::update_status(&pool, ingest_id, IngestStatus::Failed).await?;
+            error!("Failed to create mbo events: {:?}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Inserts the events as one committed version and returns how many were written.
+async fn insert_events(
+    tx: &mut Transaction<'_, Postgres>,
+    records: &[MboMsg],
+    mode: IngestMode,
+    ingest_id: i32,
+) -> Result<usize> {
+    let inserted = insert_mbo(&mut *tx, records, mode, Some(ingest_id)).await?;
+
+    let mut instrument_ids: Vec<i32> = records.iter().map(|r| r.instrument_id as i32).collect();
+    instrument_ids.sort();
+    instrument_ids.dedup();
+    refresh_availability(&mut *tx, Some(&instrument_ids)).await?;
+    IngestSession::record_instruments(&mut *tx, ingest_id, &instrument_ids).await?;
+
+    let version = DataVersion::create(&mut *tx, VersionKind::Ingest, Some(ingest_id)).await?;
+    DataVersion::commit(tx, version).await?;
+
+    Ok(inserted)
+}
+
+/// Replays stored MBO events and streams a record of the requested schema per event in the
+/// range: MBP-1 encoded like `get_records` responses, or MBP-10 as JSON lines like `get_mbp10`.
+pub async fn replay(
+    Extension(pool): Extension<PgPool>,
+    Json(params): Json<RetrieveParams>,
+) -> Result<Response> {
+    if params.schema == MBP10_SCHEMA {
+        Ok(replay_mbp10(pool, params).await?.into_response())
+    } else {
+        Ok(replay_mbp1(pool, params).await?.into_response())
+    }
+}
+
+async fn replay_mbp1(pool: PgPool, params: RetrieveParams) -> Result<impl IntoResponse> {
+    info!("Handling request to replay mbo events into mbp-1.");
+
+    let symbol_map = query_symbols_map(&pool, &params.symbols).await?;
+    let metadata = Metadata::new(
+        Schema::Mbp1,
+        params.start_ts as u64,
+        params.end_ts as u64,
+        symbol_map,
+    );
+    let mut metadata_cursor = Cursor::new(Vec::new());
+    MetadataEncoder::new(&mut metadata_cursor).encode_metadata(&metadata)?;
+
+    let start_ts = params.start_ts as u64;
+    let mut cursor = MboMsg::retrieve_query(&pool, params).await?;
+
+    let replay_stream = stream! {
+        yield Ok::<Bytes, Error>(Bytes::from(metadata_cursor.into_inner()));
+
+        let mut books: HashMap<u32, OrderBook> = HashMap::new();
+        let mut batch: Vec<Mbp1Msg> = Vec::new();
+
+        while let Some(row_result) = cursor.next().await {
+            let msg = match row_result.map_err(Error::from).and_then(|row| MboMsg::from_row(&row)) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    error!("Error processing row: {:?}", e);
+                    yield Ok(e.bytes());
+                    return;
+                }
+            };
+
+            let book = books.entry(msg.instrument_id).or_default();
+            book.apply(&msg);
+
+            // Earlier events only build up the book
+            if msg.ts_recv >= start_ts {
+                batch.push(book.mbp1_snapshot(&msg));
+            }
+
+            if batch.len() >= REPLAY_BATCH_SIZE {
+                match encode_batch(&batch) {
+                    Ok(bytes) => yield Ok(bytes),
+                    Err(e) => {
+                        yield Ok(e.bytes());
+                        return;
+                    }
+                }
+                batch.clear();
+            }
+        }
+
+        if !batch.is_empty() {
+            match encode_batch(&batch) {
+                Ok(bytes) => yield Ok(bytes),
+                Err(e) => {
+                    yield Ok(e.bytes());
+                    return;
+                }
+            }
+        }
+
+        info!("Finished streaming all batches");
+        yield Ok(Bytes::from("Finished streaming all batches"));
+    };
+
+    Ok(StreamBody::new(Box::pin(replay_stream)))
+}
+
+async fn replay_mbp10(pool: PgPool, params: RetrieveParams) -> Result<impl IntoResponse> {
+    info!("Handling request to replay mbo events into mbp-10.");
+
+    let header = mbp10::encode_metadata(&pool, &params).await?;
+    let start_ts = params.start_ts as u64;
+    let mut cursor = MboMsg::retrieve_query(&pool, params).await?;
+
+    let replay_stream = stream! {
+        yield Ok::<Bytes, Error>(header);
+
+        let mut books: HashMap<u32, OrderBook> = HashMap::new();
+        let mut batch: Vec<Mbp10Msg> = Vec::new();
+
+        while let Some(row_result) = cursor.next().await {
+            let msg = match row_result.map_err(Error::from).and_then(|row| MboMsg::from_row(&row)) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    error!("Error processing row: {:?}", e);
+                    yield Ok(e.bytes());
+                    return;
+                }
+            };
+
+            let book = books.entry(msg.instrument_id).or_default();
+            book.apply(&msg);
+
+            // Earlier events only build up the book
+            if msg.ts_recv >= start_ts {
+                batch.push(book.mbp10_snapshot(&msg));
+            }
+
+            if batch.len() >= mbp10::MBP10_BATCH_SIZE {
+                match mbp10::encode_batch(&batch) {
+                    Ok(bytes) => yield Ok(bytes),
+                    Err(e) => {
+                        yield Ok(e.bytes());
+                        return;
+                    }
+                }
+                batch.clear();
+            }
+        }
+
+        if !batch.is_empty() {
+            match mbp10::encode_batch(&batch) {
+                Ok(bytes) => yield Ok(bytes),
+                Err(e) => {
+                    yield Ok(e.bytes());
+                    return;
+                }
+            }
+        }
+
+        info!("Finished streaming all batches");
+    };
+
+    Ok(StreamBody::new(Box::pin(replay_stream)))
+}
+
+pub async fn book_state(
+    Extension(pool): Extension<PgPool>,
+    Json(params): Json<BookStateParams>,
+) -> Result<impl IntoResponse> {
+    info!(
+        "Handling request to get the {} book at {}",
+        params.symbol, params.ts
+    );
+
+    let retrieve_params = RetrieveParams {
+        symbols: vec![params.symbol.clone()],
+        start_ts: params.ts,
+        end_ts: params.ts + 1,
+        schema: String::from("mbp-1"),
+        as_of_version: None,
+        as_of_time: None,
+        interval: None,
+    };
+    let mut cursor = MboMsg::retrieve_query(&pool, retrieve_params).await?;
+
+    let mut book = OrderBook::new();
+    let mut instrument_id = None;
+    while let Some(row_result) = cursor.next().await {
+        let msg = MboMsg::from_row(&row_result?)?;
+        instrument_id = Some(msg.instrument_id);
+        book.apply(&msg);
+    }
+
+    match instrument_id {
+        Some(instrument_id) => Ok(ApiResponse::new(
+            "success",
+            &format!("Successfully rebuilt the {} book", params.symbol),
+            StatusCode::OK,
+            Some(BookState {
+                instrument_id,
+                ts: params.ts,
+                bids: book.bids(params.depth),
+                asks: book.asks(params.depth),
+            }),
+        )),
+        None => Ok(ApiResponse::new(
+            "success",
+            &format!("No mbo events found for {}", params.symbol),
+            StatusCode::NOT_FOUND,
+            None,
+        )),
+    }
+}
+
+fn encode_batch(batch: &[Mbp1Msg]) -> Result<Bytes> {
+    let mut buffer = Vec::new();
+    let mut encoder = RecordEncoder::new(&mut buffer);
+    let refs: Vec<RecordRef> = batch.iter().map(|msg| msg.into()).collect();
+    encoder.encode_records(&refs)?;
+
+    Ok(Bytes::from(buffer))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::init::init_db;
+    use crate::test_utils::{create_instrument, delete_instrument};
+    use mbn::enums::{Action, Side};
+    use serial_test::serial;
+    use std::os::raw::c_char;
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_book_state() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let ticker = "AAPL";
+        let id = create_instrument(&pool, ticker).await?;
+
+        // Events
+        let event = |order_id: u64, action: Action, side: Side, price: i64, ts: u64| MboMsg {
+            instrument_id: id as u32,
+            ts_event: ts,
+            ts_recv: ts,
+            order_id,
+            action: action as c_char,
+            side: side as c_char,
+            price,
+            size: 10,
+            flags: 0,
+            sequence: order_id as u32,
+        };
+        let records = vec![
+            event(1, Action::Add, Side::Bid, 100, 1704209103644092564),
+            event(2, Action::Add, Side::Ask, 101, 1704209103644092565),
+            event(1, Action::Cancel, Side::Bid, 100, 1704209103644092566),
+        ];
+        let response = create_mbo(
+            Extension(pool.clone()),
+            Query(LoadParams::default()),
+            Json(records),
+        )
+        .await?
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Test
+        let params = BookStateParams {
+            symbol: ticker.to_string(),
+            ts: 1704209103644092565,
+            depth: 10,
+        };
+        let response = book_state(Extension(pool.clone()), Json(params))
+            .await?
+            .into_response();
+
+        // Validate
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let api_response: ApiResponse<Option<BookState>> = serde_json::from_slice(&body)?;
+        let state = api_response.data.unwrap();
+        assert_eq!(
+            state.bids,
+            vec![PriceLevel {
+                price: 100,
+                size: 10,
+                count: 1
+            }]
+        );
+        assert_eq!(
+            state.asks,
+            vec![PriceLevel {
+                price: 101,
+                size: 10,
+                count: 1
+            }]
+        );
+
+        // Cleanup
+        delete_instrument(&pool, id).await?;
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_replay_mbp10() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let ticker = "AAPL";
+        let id = create_instrument(&pool, ticker).await?;
+
+        // Twelve bid levels, the last two events in the replayed range
+        let start: u64 = 1704209103644092564;
+        let records: Vec<MboMsg> = (0..12)
+            .map(|i| MboMsg {
+                instrument_id: id as u32,
+                ts_event: start + i,
+                ts_recv: start + i,
+                order_id: i + 1,
+                action: Action::Add as c_char,
+                side: Side::Bid as c_char,
+                price: 100 - i as i64,
+                size: 10,
+                flags: 0,
+                sequence: i as u32,
+            })
+            .collect();
+        let response = create_mbo(
+            Extension(pool.clone()),
+            Query(LoadParams::default()),
+            Json(records),
+        )
+        .await?
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Test
+        let params = RetrieveParams {
+            symbols: vec![ticker.to_string()],
+            start_ts: (start + 10) as i64,
+            end_ts: (start + 12) as i64,
+            schema: MBP10_SCHEMA.to_string(),
+            as_of_version: None,
+            as_of_time: None,
+            interval: None,
+        };
+        let response = replay(Extension(pool.clone()), Json(params)).await?;
+
+        // Validate
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let lines: Vec<&[u8]> = body.split(|b| *b == b'\n').filter(|l| !l.is_empty()).collect();
+        let snapshots: Vec<Mbp10Msg> = lines[1..]
+            .iter()
+            .map(|line| serde_json::from_slice(line))
+            .collect::<std::result::Result<_, _>>()?;
+        assert_eq!(snapshots.len(), 2);
+        let bid_px: Vec<i64> = snapshots[1].levels.iter().map(|l| l.bid_px).collect();
+        assert_eq!(bid_px, (91..=100).rev().collect::<Vec<i64>>());
+        assert_eq!(snapshots[0].levels[9].bid_px, 91);
+        assert_eq!(snapshots[0].levels[9].bid_sz, 10);
+
+        // Cleanup
+        delete_instrument(&pool, id).await?;
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_book_state_from_clear() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let ticker = "AAPL";
+        let id = create_instrument(&pool, ticker).await?;
+
+        // A bid cleared before the ask is added
+        let start: u64 = 1704209103644092564;
+        let event = |order_id: u64, action: Action, side: Side, price: i64, ts: u64| MboMsg {
+            instrument_id: id as u32,
+            ts_event: ts,
+            ts_recv: ts,
+            order_id,
+            action: action as c_char,
+            side: side as c_char,
+            price,
+            size: 10,
+            flags: 0,
+            sequence: ts as u32,
+        };
+        let records = vec![
+            event(1, Action::Add, Side::Bid, 100, start),
+            event(0, Action::Clear, Side::None, 0, start + 1),
+            event(2, Action::Add, Side::Ask, 101, start + 2),
+        ];
+        let response = create_mbo(
+            Extension(pool.clone()),
+            Query(LoadParams::default()),
+            Json(records),
+        )
+        .await?
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Test
+        let params = BookStateParams {
+            symbol: ticker.to_string(),
+            ts: (start + 2) as i64,
+            depth: 10,
+        };
+        let response = book_state(Extension(pool.clone()), Json(params))
+            .await?
+            .into_response();
+        let retrieve_params = RetrieveParams {
+            symbols: vec![ticker.to_string()],
+            start_ts: (start + 2) as i64,
+            end_ts: (start + 3) as i64,
+            schema: String::from("mbp-1"),
+            as_of_version: None,
+            as_of_time: None,
+            interval: None,
+        };
+        let replayed: Vec<MboMsg> = MboMsg::retrieve_query(&pool, retrieve_params)
+            .await?
+            .map(|row| MboMsg::from_row(&row?))
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<Result<_>>()?;
+
+        // Validate
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let api_response: ApiResponse<Option<BookState>> = serde_json::from_slice(&body)?;
+        let state = api_response.data.unwrap();
+        assert!(state.bids.is_empty());
+        assert_eq!(
+            state.asks,
+            vec![PriceLevel {
+                price: 101,
+                size: 10,
+                count: 1
+            }]
+        );
+        let orders: Vec<u64> = replayed.iter().map(|msg| msg.order_id).collect();
+        assert_eq!(orders, vec![0, 2]);
+
+        // Cleanup
+        delete_instrument(&pool, id).await?;
+
+        Ok(())
+    }
+}
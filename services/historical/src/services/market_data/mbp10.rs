@@ -0,0 +1,313 @@
+use crate::database::ingest::{IngestSession, IngestStatus};
+use crate::database::market_data::create::{IngestMode, InsertBatch};
+use crate::database::market_data::mbp10::{Mbp10Msg, MBP10_SCHEMA};
+use crate::database::market_data::read::{FromRow, RecordsQuery, RetrieveParams};
+use crate::database::market_data::rollup::refresh_queued_rollups;
+use crate::database::symbols::{query_instrument_ids, refresh_availability};
+use crate::database::versions::{DataVersion, VersionKind};
+use crate::response::ApiResponse;
+use crate::services::market_data::load::LoadParams;
+use crate::{Error, Result};
+use async_stream::stream;
+use axum::extract::Query;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::{body::StreamBody, Extension, Json};
+use bytes::Bytes;
+use futures::stream::StreamExt;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::collections::HashMap;
+use tracing::{error, info, warn};
+
+/// Records serialized per streamed chunk.
+pub const MBP10_BATCH_SIZE: usize = 1000;
+
+/// First line of `/mbp10/get` responses, mbn's `Metadata` has no MBP-10 schema to record.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Mbp10Metadata {
+    pub schema: String,
+    pub start: u64,
+    pub end: u64,
+    /// Instrument id of every requested symbol that exists.
+    pub mappings: HashMap<String, u32>,
+}
+
+// Handlers
+pub async fn create_mbp10(
+    Extension(pool): Extension<PgPool>,
+    Query(params): Query<LoadParams>,
+    Json(records): Json<Vec<Mbp10Msg>>,
+) -> Result<impl IntoResponse> {
+    info!(
+        "Handling request to create {} mbp-10 records",
+        records.len()
+    );
+
+    if params.mode == IngestMode::Replace {
+        return Err(crate::error!(
+            CustomError,
+            "Replace uploads only support mbp-1 records."
+        ));
+    }
+
+    let ingest_id = IngestSession::create(&pool, MBP10_SCHEMA, params.mode, false).await?;
+    let mut tx = pool.begin().await?;
+
+    match insert_mbp10(&mut tx, &records, &params, ingest_id).await {
+        Ok(inserted) => {
+            tx.commit().await?;
+            IngestSession::update_status(&pool, ingest_id, IngestStatus::Completed).await?;
+            if let Err(e) = refresh_queued_rollups(&pool).await {
+                warn!("Failed to refresh rollup bars: {:?}", e);
+            }
+            Ok(ApiResponse::new(
+                "success",
+                &format!(
+                    "Successfully loaded records: {} inserted, {} skipped.",
+                    inserted,
+                    records.len() - inserted
+                ),
+                StatusCode::OK,
+                ingest_id,
+            ))
+        }
+        Err(e) => {
+            tx.rollback().await?;
+            IngestSession::update_status(&pool, ingest_id, IngestStatus::Failed).await?;
+            error!("Failed to create mbp-10 records: {:?}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Inserts the records as one committed version and returns how many were written.
+async fn insert_mbp10(
+    tx: &mut Transaction<'_, Postgres>,
+    records: &[Mbp10Msg],
+    params: &LoadParams,
+    ingest_id: i32,
+) -> Result<usize> {
+    let mut batch = InsertBatch::new(params.mode, Some(ingest_id)).with_backend(params.backend);
+    for record in records {
+        batch.process_mbp10(record).await?;
+    }
+    let inserted = batch.execute(&mut *tx).await?.inserted;
+
+    let mut instrument_ids: Vec<i32> = records.iter().map(|r| r.instrument_id as i32).collect();
+    instrument_ids.sort();
+    instrument_ids.dedup();
+    refresh_availability(&mut *tx, Some(&instrument_ids)).await?;
+    IngestSession::record_instruments(&mut *tx, ingest_id, &instrument_ids).await?;
+
+    let version = DataVersion::create(&mut *tx, VersionKind::Ingest, Some(ingest_id)).await?;
+    DataVersion::commit(tx, version).await?;
+
+    Ok(inserted)
+}
+
+/// Streams the mbp-10 records of the range as JSON lines, the first one an `Mbp10Metadata`.
+pub async fn get_mbp10(
+    Extension(pool): Extension<PgPool>,
+    Json(params): Json<RetrieveParams>,
+) -> Result<impl IntoResponse> {
+    info!("Handling request to get mbp-10 records.");
+
+    if params.schema != MBP10_SCHEMA {
+        return Err(crate::error!(
+            CustomError,
+            "Only {} records are served here, not {}.",
+            MBP10_SCHEMA,
+            params.schema
+        ));
+    }
+
+    let header = encode_metadata(&pool, &params).await?;
+    let mut cursor = Mbp10Msg::retrieve_query(&pool, params).await?;
+
+    let records_stream = stream! {
+        yield Ok::<Bytes, Error>(header);
+
+        let mut batch: Vec<Mbp10Msg> = Vec::new();
+
+        while let Some(row_result) = cursor.next().await {
+            match row_result.map_err(Error::from).and_then(|row| Mbp10Msg::from_row(&row)) {
+                Ok(msg) => batch.push(msg),
+                Err(e) => {
+                    error!("Error processing row: {:?}", e);
+                    yield Ok(e.bytes());
+                    return;
+                }
+            }
+
+            if batch.len() >= MBP10_BATCH_SIZE {
+                match encode_batch(&batch) {
+                    Ok(bytes) => yield Ok(bytes),
+                    Err(e) => {
+                        yield Ok(e.bytes());
+                        return;
+                    }
+                }
+                batch.clear();
+            }
+        }
+
+        if !batch.is_empty() {
+            match encode_batch(&batch) {
+                Ok(bytes) => yield Ok(bytes),
+                Err(e) => {
+                    yield Ok(e.bytes());
+                    return;
+                }
+            }
+        }
+
+        info!("Finished streaming all batches");
+    };
+
+    Ok(StreamBody::new(Box::pin(records_stream)))
+}
+
+/// The `Mbp10Metadata` line of a response for the requested symbols and range.
+pub async fn encode_metadata(pool: &PgPool, params: &RetrieveParams) -> Result<Bytes> {
+    let instrument_ids = query_instrument_ids(pool).await?;
+    let metadata = Mbp10Metadata {
+        schema: MBP10_SCHEMA.to_string(),
+        start: params.start_ts as u64,
+        end: params.end_ts as u64,
+        mappings: params
+            .symbols
+            .iter()
+            .filter_map(|symbol| instrument_ids.get(symbol).map(|id| (symbol.clone(), *id)))
+            .collect(),
+    };
+
+    let mut buffer = serde_json::to_vec(&metadata).map_err(|e| Error::GeneralError(Box::new(e)))?;
+    buffer.push(b'\n');
+    Ok(Bytes::from(buffer))
+}
+
+/// One JSON line per record.
+pub fn encode_batch(batch: &[Mbp10Msg]) -> Result<Bytes> {
+    let mut buffer = Vec::new();
+    for msg in batch {
+        serde_json::to_writer(&mut buffer, msg).map_err(|e| Error::GeneralError(Box::new(e)))?;
+        buffer.push(b'\n');
+    }
+
+    Ok(Bytes::from(buffer))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::init::init_db;
+    use crate::database::market_data::mbp10::{BidAskLevel, MBP10_DEPTH};
+    use crate::test_utils::{create_instrument, delete_instrument};
+    use mbn::enums::{Action, Side};
+    use serial_test::serial;
+    use std::os::raw::c_char;
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_create_get_mbp10() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let ticker = "AAPL";
+        let id = create_instrument(&pool, ticker).await?;
+
+        // Records with ten distinct levels
+        let record = |ts: u64, price: i64| {
+            let mut levels = [BidAskLevel::default(); MBP10_DEPTH];
+            for (depth, level) in levels.iter_mut().enumerate() {
+                *level = BidAskLevel {
+                    bid_px: price - 1 - depth as i64,
+                    ask_px: price + 1 + depth as i64,
+                    bid_sz: 10 + depth as u32,
+                    ask_sz: 20 + depth as u32,
+                    bid_ct: 1,
+                    ask_ct: 2,
+                };
+            }
+            Mbp10Msg {
+                instrument_id: id as u32,
+                ts_event: ts,
+                price,
+                size: 1,
+                action: Action::Add as c_char,
+                side: Side::Bid as c_char,
+                flags: 0,
+                ts_recv: ts,
+                ts_in_delta: 17493,
+                sequence: 739763,
+                levels,
+            }
+        };
+        let records = vec![
+            record(1704209103644092564, 100),
+            record(1704209103644092565, 200),
+        ];
+
+        // Test
+        let response = create_mbp10(
+            Extension(pool.clone()),
+            Query(LoadParams::default()),
+            Json(records.clone()),
+        )
+        .await?
+        .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let params = LoadParams {
+            mode: IngestMode::SkipDuplicates,
+            ..Default::default()
+        };
+        let response = create_mbp10(
+            Extension(pool.clone()),
+            Query(params),
+            Json(records.clone()),
+        )
+        .await?
+        .into_response();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let api_response: ApiResponse<i32> = serde_json::from_slice(&body)?;
+        assert_eq!(
+            api_response.message,
+            "Successfully loaded records: 0 inserted, 2 skipped."
+        );
+
+        let params = RetrieveParams {
+            symbols: vec![ticker.to_string()],
+            start_ts: 1704209103644092564,
+            end_ts: 1704209103644092566,
+            schema: MBP10_SCHEMA.to_string(),
+            as_of_version: None,
+            as_of_time: None,
+            interval: None,
+        };
+        let response = get_mbp10(Extension(pool.clone()), Json(params.clone()))
+            .await?
+            .into_response();
+
+        // Validate
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        let mut lines = body.split(|b| *b == b'\n').filter(|line| !line.is_empty());
+        let metadata: Mbp10Metadata = serde_json::from_slice(lines.next().unwrap())?;
+        let served: Vec<Mbp10Msg> = lines
+            .map(serde_json::from_slice)
+            .collect::<std::result::Result<_, _>>()?;
+        assert_eq!(metadata.schema, MBP10_SCHEMA);
+        assert_eq!(metadata.mappings.get(ticker), Some(&(id as u32)));
+        assert_eq!(served, records);
+
+        // The other endpoints point to this one
+        let error = params.rtype().unwrap_err();
+        assert!(error.to_string().contains("/mbp10/get"));
+
+        // Cleanup
+        delete_instrument(&pool, id).await?;
+
+        Ok(())
+    }
+}
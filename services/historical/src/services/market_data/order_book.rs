@@ -0,0 +1,321 @@
+use crate::database::market_data::mbo::MboMsg;
+use crate::database::market_data::mbp10::{BidAskLevel, Mbp10Msg, MBP10_DEPTH};
+use mbn::enums::{Action, Side};
+use mbn::records::{BidAskPair, Mbp1Msg, RecordHeader};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::os::raw::c_char;
+
+const ADD: c_char = Action::Add as c_char;
+const CANCEL: c_char = Action::Cancel as c_char;
+const MODIFY: c_char = Action::Modify as c_char;
+const CLEAR: c_char = Action::Clear as c_char;
+const BID: c_char = Side::Bid as c_char;
+const ASK: c_char = Side::Ask as c_char;
+
+/// Resting size and order count at a single price.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PriceLevel {
+    pub price: i64,
+    pub size: u32,
+    pub count: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RestingOrder {
+    side: c_char,
+    price: i64,
+    size: u32,
+}
+
+/// Order book of a single instrument, rebuilt by replaying MBO events.
+#[derive(Debug, Default)]
+pub struct OrderBook {
+    orders: HashMap<u64, RestingOrder>,
+    bids: BTreeMap<i64, PriceLevel>,
+    asks: BTreeMap<i64, PriceLevel>,
+}
+
+impl OrderBook {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn apply(&mut self, msg: &MboMsg) {
+        match msg.action {
+            ADD => self.add(msg.order_id, msg.side, msg.price, msg.size),
+            CANCEL => self.reduce(msg.order_id, msg.size),
+            MODIFY => {
+                self.reduce(msg.order_id, u32::MAX);
+                self.add(msg.order_id, msg.side, msg.price, msg.size);
+            }
+            CLEAR => {
+                self.orders.clear();
+                self.bids.clear();
+                self.asks.clear();
+            }
+            // Fills and trades leave the book as is, the resting order changes through the
+            // cancel or modify that follows them
+            _ => {}
+        }
+    }
+
+    fn side_levels(&mut self, side: c_char) -> Option<&mut BTreeMap<i64, PriceLevel>> {
+        match side {
+            BID => Some(&mut self.bids),
+            ASK => Some(&mut self.asks),
+            _ => None,
+        }
+    }
+
+    fn add(&mut self, order_id: u64, side: c_char, price: i64, size: u32) {
+        let Some(levels) = self.side_levels(side) else {
+            return;
+        };
+        let level = levels.entry(price).or_insert(PriceLevel {
+            price,
+            size: 0,
+            count: 0,
+        });
+        level.size += size;
+        level.count += 1;
+
+        self.orders
+            .insert(order_id, RestingOrder { side, price, size });
+    }
+
+    /// Removes `size` from a resting order, and the order itself once nothing is left.
+    fn reduce(&mut self, order_id: u64, size: u32) {
+        let Some(order) = self.orders.get_mut(&order_id) else {
+            return;
+        };
+        let removed = size.min(order.size);
+        order.size -= removed;
+        let order = *order;
+        if order.size == 0 {
+            self.orders.remove(&order_id);
+        }
+
+        let Some(levels) = self.side_levels(order.side) else {
+            return;
+        };
+        if let Some(level) = levels.get_mut(&order.price) {
+            level.size -= removed;
+            if order.size == 0 {
+                level.count -= 1;
+            }
+            if level.count == 0 {
+                levels.remove(&order.price);
+            }
+        }
+    }
+
+    /// Best `depth` bid levels, highest price first.
+    pub fn bids(&self, depth: usize) -> Vec<PriceLevel> {
+        self.bids.values().rev().take(depth).copied().collect()
+    }
+
+    /// Best `depth` ask levels, lowest price first.
+    pub fn asks(&self, depth: usize) -> Vec<PriceLevel> {
+        self.asks.values().take(depth).copied().collect()
+    }
+
+    pub fn top_of_book(&self) -> BidAskPair {
+        let bid = self.bids(1).pop().unwrap_or_default();
+        let ask = self.asks(1).pop().unwrap_or_default();
+
+        BidAskPair {
+            bid_px: bid.price,
+            ask_px: ask.price,
+            bid_sz: bid.size,
+            ask_sz: ask.size,
+            bid_ct: bid.count,
+            ask_ct: ask.count,
+        }
+    }
+
+    /// MBP-1 record for `msg`, with the top of book after it was applied.
+    pub fn mbp1_snapshot(&self, msg: &MboMsg) -> Mbp1Msg {
+        Mbp1Msg {
+            hd: RecordHeader::new::<Mbp1Msg>(msg.instrument_id, msg.ts_event),
+            price: msg.price,
+            size: msg.size,
+            action: msg.action,
+            side: msg.side,
+            depth: 0,
+            flags: msg.flags,
+            ts_recv: msg.ts_recv,
+            ts_in_delta: 0,
+            sequence: msg.sequence,
+            discriminator: 0,
+            levels: [self.top_of_book()],
+        }
+    }
+
+    /// MBP-10 record for `msg`, with the ten best levels after it was applied. Missing levels
+    /// are left empty.
+    pub fn mbp10_snapshot(&self, msg: &MboMsg) -> Mbp10Msg {
+        let mut levels = [BidAskLevel::default(); MBP10_DEPTH];
+        for (level, bid) in levels.iter_mut().zip(self.bids(MBP10_DEPTH)) {
+            level.bid_px = bid.price;
+            level.bid_sz = bid.size;
+            level.bid_ct = bid.count;
+        }
+        for (level, ask) in levels.iter_mut().zip(self.asks(MBP10_DEPTH)) {
+            level.ask_px = ask.price;
+            level.ask_sz = ask.size;
+            level.ask_ct = ask.count;
+        }
+
+        Mbp10Msg {
+            instrument_id: msg.instrument_id,
+            ts_event: msg.ts_event,
+            price: msg.price,
+            size: msg.size,
+            action: msg.action,
+            side: msg.side,
+            flags: msg.flags,
+            ts_recv: msg.ts_recv,
+            ts_in_delta: 0,
+            sequence: msg.sequence,
+            levels,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn event(order_id: u64, action: Action, side: Side, price: i64, size: u32) -> MboMsg {
+        MboMsg {
+            instrument_id: 1,
+            ts_event: 1704209103644092564,
+            ts_recv: 1704209103644092564,
+            order_id,
+            action: action as c_char,
+            side: side as c_char,
+            price,
+            size,
+            flags: 0,
+            sequence: order_id as u32,
+        }
+    }
+
+    #[test]
+    fn test_order_book_levels() {
+        let mut book = OrderBook::new();
+        book.apply(&event(1, Action::Add, Side::Bid, 100, 5));
+        book.apply(&event(2, Action::Add, Side::Bid, 100, 3));
+        book.apply(&event(3, Action::Add, Side::Bid, 99, 1));
+        book.apply(&event(4, Action::Add, Side::Ask, 101, 2));
+
+        // Test
+        book.apply(&event(1, Action::Cancel, Side::Bid, 100, 2));
+        book.apply(&event(4, Action::Cancel, Side::Ask, 101, 2));
+        book.apply(&event(2, Action::Modify, Side::Bid, 102, 3));
+
+        // Validate
+        assert_eq!(
+            book.bids(10),
+            vec![
+                PriceLevel {
+                    price: 102,
+                    size: 3,
+                    count: 1
+                },
+                PriceLevel {
+                    price: 100,
+                    size: 3,
+                    count: 1
+                },
+                PriceLevel {
+                    price: 99,
+                    size: 1,
+                    count: 1
+                },
+            ]
+        );
+        assert!(book.asks(10).is_empty());
+        assert_eq!(
+            book.top_of_book(),
+            BidAskPair {
+                bid_px: 102,
+                ask_px: 0,
+                bid_sz: 3,
+                ask_sz: 0,
+                bid_ct: 1,
+                ask_ct: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_order_book_mbp10_snapshot() {
+        let mut book = OrderBook::new();
+        for price in 0..12 {
+            book.apply(&event(price as u64, Action::Add, Side::Bid, 100 - price, 1));
+        }
+        let last = event(20, Action::Add, Side::Ask, 101, 2);
+        book.apply(&last);
+
+        // Test
+        let snapshot = book.mbp10_snapshot(&last);
+
+        // Validate
+        let bid_px: Vec<i64> = snapshot.levels.iter().map(|l| l.bid_px).collect();
+        assert_eq!(bid_px, (91..=100).rev().collect::<Vec<i64>>());
+        assert_eq!(
+            (snapshot.levels[0].ask_px, snapshot.levels[0].ask_sz),
+            (101, 2)
+        );
+        assert!(snapshot.levels[1..]
+            .iter()
+            .all(|l| l.ask_px == 0 && l.ask_ct == 0));
+        assert_eq!(snapshot.price, 101);
+    }
+
+    #[test]
+    fn test_order_book_clear() {
+        let mut book = OrderBook::new();
+        book.apply(&event(1, Action::Add, Side::Bid, 100, 5));
+        book.apply(&event(2, Action::Add, Side::Ask, 101, 5));
+
+        // Test
+        book.apply(&event(0, Action::Clear, Side::None, 0, 0));
+
+        // Validate
+        assert!(book.bids(1).is_empty());
+        assert!(book.asks(1).is_empty());
+    }
+
+    #[test]
+    fn test_order_book_fill_then_cancel() {
+        let mut book = OrderBook::new();
+        book.apply(&event(1, Action::Add, Side::Ask, 101, 5));
+
+        // Test
+        book.apply(&event(1, Action::Fill, Side::Ask, 101, 2));
+        book.apply(&event(1, Action::Trade, Side::Ask, 101, 2));
+        let after_fill = book.asks(1);
+        book.apply(&event(1, Action::Cancel, Side::Ask, 101, 2));
+
+        // Validate
+        assert_eq!(
+            after_fill,
+            vec![PriceLevel {
+                price: 101,
+                size: 5,
+                count: 1
+            }]
+        );
+        assert_eq!(
+            book.asks(1),
+            vec![PriceLevel {
+                price: 101,
+                size: 3,
+                count: 1
+            }]
+        );
+    }
+}
@@ -1,104 +1,581 @@
-use crate::database::market_data::create::{rollback_all_batches, InsertBatch};
-use crate::database::market_data::read::get_lastest_mbp_id;
+use crate::database::ingest::{IngestSession, IngestStatus, LoadProgress};
+use crate::database::market_data::create::{
+    rollback_all_batches, session_instruments, supersede_mbp_range, BatchSummary, InsertBatch,
+};
+use crate::database::market_data::rollup::refresh_queued_rollups;
+use crate::database::symbols::refresh_availability;
+use crate::database::versions::{DataVersion, VersionKind};
 use crate::response::ApiResponse;
+use crate::services::market_data::load::LoadParams;
+use crate::services::market_data::record_source::RecordSource;
 use crate::{Error, Result};
 use async_stream::stream;
 use axum::http::StatusCode;
 use bytes::Bytes;
 use futures::stream::Stream;
-use mbn::decode::RecordDecoder;
 use mbn::record_enum::RecordEnum;
-use sqlx::PgPool;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::collections::{BTreeSet, HashSet};
 use std::pin::Pin;
-use tracing::error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::task::{JoinError, JoinSet};
+use tracing::{error, info};
 
+/// Decoded records buffered ahead of the loader.
+const DECODE_BUFFER: usize = 1024;
+
+/// Decodes on a blocking thread, so readers that block (files, bridged request bodies) stay off
+/// the runtime. Decoding stops after the first error or once the receiver is dropped.
+pub(crate) fn spawn_decoder<S>(source: S) -> mpsc::Receiver<Result<RecordEnum>>
+where
+    S: RecordSource,
+{
+    let (tx, rx) = mpsc::channel(DECODE_BUFFER);
+    tokio::task::spawn_blocking(move || {
+        Box::new(source).read_records(&mut |record_result| {
+            let failed = record_result.is_err();
+            tx.blocking_send(record_result).is_ok() && !failed
+        });
+    });
+    rx
+}
+
+/// Writers used when the upload does not ask for a number.
+const DEFAULT_WRITERS: usize = 4;
+/// Upper bound on concurrent writers, below the pool's connection limit.
+const MAX_WRITERS: usize = 8;
+
+/// A full batch handed to a writer.
+struct PendingBatch {
+    batch: InsertBatch,
+    records: usize,
+    position: u64, // Records up to and including the batch
+    number: u32,
+}
+
+/// Commits a batch on its own connection. Resumable sessions also move their checkpoint in the
+/// same transaction.
+async fn write_batch(
+    pool: PgPool,
+    ingest_id: i32,
+    mut pending: PendingBatch,
+    checkpoint: bool,
+    progress: Arc<Mutex<LoadProgress>>,
+) -> Result<ApiResponse<String>> {
+    let mut tx = pool.begin().await?;
+    let mut result = pending.batch.execute(&mut tx).await;
+    if let (Ok(_), true) = (&result, checkpoint) {
+        if let Err(e) =
+            IngestSession::checkpoint(&mut tx, ingest_id, pending.position, pending.number).await
+        {
+            result = Err(e);
+        }
+    }
+
+    match result {
+        Ok(summary) => {
+            tx.commit().await?;
+            {
+                let mut progress = progress.lock().unwrap();
+                progress.records_committed += summary.inserted as u64;
+                progress.records_skipped += summary.skipped as u64;
+                progress.current_batch += 1;
+            }
+            let response = ApiResponse::new(
+                "success",
+                &format!(
+                    "Processed {} records: {} inserted, {} skipped.",
+                    pending.records, summary.inserted, summary.skipped
+                ),
+                StatusCode::OK,
+                "".to_string(),
+            );
+            Ok(response)
+        }
+        Err(e) => {
+            tx.rollback().await?;
+            error!("Error during batch execution: {:?}", e);
+            progress
+                .lock()
+                .unwrap()
+                .errors
+                .push(format!("Error during batch execution: {:?}", e));
+            let response = ApiResponse::new(
+                "failed",
+                &format!("Error during batch execution: {:?}", e),
+                StatusCode::CONFLICT,
+                "".to_string(),
+            );
+            Ok(response)
+        }
+    }
+}
+
+/// Transaction of a `replace` upload. Every batch is written to it, after superseding the range
+/// of the instruments the batch is the first to hold, so readers never see a partly replaced
+/// range.
+struct Replacement {
+    tx: Transaction<'static, Postgres>,
+    version: i32,
+    start: i64,
+    end: i64,
+    cleared: HashSet<i32>,
+    deleted: u64,
+}
+
+impl Replacement {
+    async fn write(&mut self, batch: &mut InsertBatch) -> Result<BatchSummary> {
+        if batch.len() != batch.mbp_values.len() {
+            return Err(crate::error!(
+                CustomError,
+                "Replace uploads only support mbp records."
+            ));
+        }
+
+        let mut instrument_ids = BTreeSet::new();
+        for row in &batch.mbp_values {
+            if row.ts_recv < self.start || row.ts_recv >= self.end {
+                return Err(crate::error!(
+                    CustomError,
+                    "Record with ts_recv {} is outside of the replaced range [{}, {}).",
+                    row.ts_recv,
+                    self.start,
+                    self.end
+                ));
+            }
+            if !self.cleared.contains(&row.instrument_id) {
+                instrument_ids.insert(row.instrument_id);
+            }
+        }
+
+        if !instrument_ids.is_empty() {
+            let ids: Vec<i32> = instrument_ids.into_iter().collect();
+            self.deleted +=
+                supersede_mbp_range(&mut self.tx, self.version, &ids, self.start, self.end).await?;
+            self.cleared.extend(ids);
+        }
+
+        batch.execute(&mut self.tx).await
+    }
+}
+
+/// Loads decoded records in batches. Batches are built while up to `writers` earlier ones are
+/// committed concurrently, so at most `writers + 1` batches are held in memory.
 pub struct RecordLoader {
     batch_size: usize,
     records_in_batch: usize,
     batch: InsertBatch,
     pool: PgPool,
-    last_id: i32,
+    ingest_id: i32,
+    resumable: bool,
+    resume_from: u64,
+    position: u64,
+    checkpoint: u64,
+    batches_dispatched: u32,
+    writers: usize,
+    writes: JoinSet<Result<(ApiResponse<String>, u64)>>,
+    progress: Arc<Mutex<LoadProgress>>,
+    cancelled: Arc<AtomicBool>,
+    replacement: Option<Replacement>,
+    expected_records: Option<u64>,
 }
 
 impl RecordLoader {
-    pub async fn new(batch_size: usize, pool: PgPool) -> Result<Self> {
-        let last_id = get_lastest_mbp_id(&pool).await?;
+    pub async fn new(
+        batch_size: usize,
+        pool: PgPool,
+        params: &LoadParams,
+        source: &str,
+    ) -> Result<Self> {
+        let replace_range = params.replace_range()?;
+        let ingest_id = IngestSession::create(&pool, source, params.mode, params.resumable).await?;
+        let replacement = match replace_range {
+            Some((start, end)) => {
+                let mut tx = pool.begin().await?;
+                let version =
+                    DataVersion::create(&mut tx, VersionKind::Replace, Some(ingest_id)).await?;
+                Some(Replacement {
+                    tx,
+                    version,
+                    start,
+                    end,
+                    cleared: HashSet::new(),
+                    deleted: 0,
+                })
+            }
+            None => None,
+        };
+
+        Ok(RecordLoader {
+            batch_size,
+            records_in_batch: 0,
+            batch: InsertBatch::new(params.mode, Some(ingest_id))
+                .with_backend(params.backend)
+                .with_interval(params.interval_ns()?),
+            pool,
+            ingest_id,
+            resumable: params.resumable,
+            resume_from: 0,
+            position: 0,
+            checkpoint: 0,
+            batches_dispatched: 0,
+            writers: Self::writers(params, params.resumable),
+            writes: JoinSet::new(),
+            progress: Arc::new(Mutex::new(LoadProgress::default())),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            replacement,
+            expected_records: None,
+        })
+    }
+
+    /// Continues a failed resumable session from its last committed checkpoint.
+    pub async fn resume(
+        batch_size: usize,
+        pool: PgPool,
+        session: &IngestSession,
+        params: &LoadParams,
+    ) -> Result<Self> {
+        IngestSession::update_status(&pool, session.id, IngestStatus::Running).await?;
+
+        let progress = LoadProgress {
+            current_batch: session.checkpoint_batch as u32,
+            ..Default::default()
+        };
 
         Ok(RecordLoader {
             batch_size,
             records_in_batch: 0,
-            batch: InsertBatch::new(),
+            batch: InsertBatch::new(session.mode, Some(session.id))
+                .with_backend(params.backend)
+                .with_interval(params.interval_ns()?),
             pool,
-            last_id,
+            ingest_id: session.id,
+            resumable: session.resumable,
+            resume_from: session.checkpoint_record as u64,
+            position: 0,
+            checkpoint: session.checkpoint_record as u64,
+            batches_dispatched: session.checkpoint_batch as u32,
+            writers: Self::writers(params, session.resumable),
+            writes: JoinSet::new(),
+            progress: Arc::new(Mutex::new(progress)),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            replacement: None,
+            expected_records: None,
         })
     }
 
+    /// Fails the load, before it completes, if the source does not hold this many records.
+    pub fn with_expected_records(mut self, expected_records: Option<u64>) -> Self {
+        self.expected_records = expected_records;
+        self
+    }
+
+    /// Resumable sessions commit one batch at a time, so their checkpoint never skips a batch
+    /// that is still being written.
+    fn writers(params: &LoadParams, resumable: bool) -> usize {
+        if resumable {
+            return 1;
+        }
+        params
+            .writers
+            .unwrap_or(DEFAULT_WRITERS)
+            .clamp(1, MAX_WRITERS)
+    }
+
+    /// Handle to the load's running totals.
+    pub fn progress(&self) -> Arc<Mutex<LoadProgress>> {
+        Arc::clone(&self.progress)
+    }
+
+    /// Setting the flag stops the load before the next record and rolls it back.
+    pub fn cancel_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.cancelled)
+    }
+
+    fn record_error(&self, message: String) {
+        self.progress.lock().unwrap().errors.push(message);
+    }
+
     pub fn get_records_in_batch(&self) -> usize {
         self.records_in_batch
     }
 
-    pub async fn update_batch(
-        &mut self,
-        record: RecordEnum,
-    ) -> Result<Option<ApiResponse<String>>> {
+    pub fn ingest_id(&self) -> i32 {
+        self.ingest_id
+    }
+
+    /// Adds a record to the current batch, handing the batch to a writer once it is full.
+    /// Returns the responses of writes that finished in the meantime.
+    pub async fn update_batch(&mut self, record: RecordEnum) -> Result<Vec<ApiResponse<String>>> {
+        self.progress.lock().unwrap().records_decoded += 1;
+        self.position += 1;
+
         match record {
-            RecordEnum::Mbp1(msg) => {
-                self.batch.process(&msg).await?;
-                self.records_in_batch += 1;
-            }
-            _ => {
-                return Err(crate::error!(
-                    CustomError,
-                    "Invalid record type {:?}",
-                    record
-                ))
-            }
+            RecordEnum::Mbp1(msg) | RecordEnum::Tbbo(msg) => self.batch.process(&msg).await?,
+            RecordEnum::Trade(msg) => self.batch.process_trade(&msg).await?,
+            RecordEnum::Ohlcv(msg) => self.batch.process_ohlcv(&msg).await?,
+            RecordEnum::Bbo(msg) => self.batch.process_bbo(&msg).await?,
         }
+        self.records_in_batch += 1;
 
         if self.records_in_batch >= self.batch_size {
-            let response = self.commit_batch().await?;
-            self.records_in_batch = 0;
-            return Ok(Some(response));
+            return self.commit_batch().await;
         }
 
-        Ok(None)
+        Ok(Vec::new())
     }
 
-    pub async fn commit_batch(&mut self) -> Result<ApiResponse<String>> {
-        let mut tx = self.pool.begin().await?;
-        match self.batch.execute(&mut tx).await {
-            Ok(_) => {
-                tx.commit().await?;
-                let response = ApiResponse::new(
+    /// Swaps the current batch for an empty one.
+    fn take_batch(&mut self) -> PendingBatch {
+        let empty = InsertBatch::new(self.batch.mode, self.batch.ingest_id)
+            .with_backend(self.batch.backend)
+            .with_interval(self.batch.interval_ns);
+        self.batches_dispatched += 1;
+        let pending = PendingBatch {
+            batch: std::mem::replace(&mut self.batch, empty),
+            records: self.records_in_batch,
+            position: self.position,
+            number: self.batches_dispatched,
+        };
+        self.records_in_batch = 0;
+        pending
+    }
+
+    /// Hands the current batch to a writer, first waiting for one to finish if all are busy.
+    /// Returns the responses of writes that finished in the meantime.
+    pub async fn commit_batch(&mut self) -> Result<Vec<ApiResponse<String>>> {
+        if self.replacement.is_some() {
+            return Ok(vec![self.write_replacement().await]);
+        }
+
+        let mut responses = Vec::new();
+        while self.writes.len() >= self.writers {
+            if let Some(response) = self.next_write().await? {
+                responses.push(response);
+            }
+        }
+
+        let pending = self.take_batch();
+        let position = pending.position;
+        let write = write_batch(
+            self.pool.clone(),
+            self.ingest_id,
+            pending,
+            self.resumable,
+            Arc::clone(&self.progress),
+        );
+        self.writes
+            .spawn(async move { write.await.map(|response| (response, position)) });
+
+        while let Some(joined) = self.writes.try_join_next() {
+            responses.push(self.finish_write(joined)?);
+        }
+
+        Ok(responses)
+    }
+
+    /// Writes the current batch to the replacement transaction.
+    async fn write_replacement(&mut self) -> ApiResponse<String> {
+        let mut pending = self.take_batch();
+        let result = match self.replacement.as_mut() {
+            Some(replacement) => replacement.write(&mut pending.batch).await,
+            None => Err(crate::error!(CustomError, "Upload is not a replacement.")),
+        };
+
+        match result {
+            Ok(summary) => {
+                {
+                    let mut progress = self.progress.lock().unwrap();
+                    progress.records_committed += summary.inserted as u64;
+                    progress.records_skipped += summary.skipped as u64;
+                    progress.current_batch += 1;
+                }
+                ApiResponse::new(
                     "success",
-                    &format!("Processed {} records.", self.records_in_batch),
+                    &format!(
+                        "Processed {} records: {} inserted, {} skipped.",
+                        pending.records, summary.inserted, summary.skipped
+                    ),
                     StatusCode::OK,
                     "".to_string(),
-                );
-                Ok(response)
+                )
             }
             Err(e) => {
-                tx.rollback().await?;
                 error!("Error during batch execution: {:?}", e);
-                let response = ApiResponse::new(
+                self.record_error(format!("Error during batch execution: {:?}", e));
+                ApiResponse::new(
                     "failed",
                     &format!("Error during batch execution: {:?}", e),
                     StatusCode::CONFLICT,
                     "".to_string(),
-                );
-                Ok(response)
+                )
+            }
+        }
+    }
+
+    /// Commits the replacement transaction, making the whole upload visible at once. Returns
+    /// `None` for the other modes.
+    async fn commit_replacement(&mut self) -> Result<Option<ApiResponse<String>>> {
+        let mut replacement = match self.replacement.take() {
+            Some(replacement) => replacement,
+            None => return Ok(None),
+        };
+        DataVersion::commit(&mut replacement.tx, replacement.version).await?;
+        replacement.tx.commit().await?;
+        info!(
+            "Ingest session {} replaced {} records of {} instruments in [{}, {})",
+            self.ingest_id,
+            replacement.deleted,
+            replacement.cleared.len(),
+            replacement.start,
+            replacement.end
+        );
+
+        let response = ApiResponse::new(
+            "success",
+            &format!(
+                "Replaced {} records of {} instruments in [{}, {}).",
+                replacement.deleted,
+                replacement.cleared.len(),
+                replacement.start,
+                replacement.end
+            ),
+            StatusCode::OK,
+            "".to_string(),
+        );
+        Ok(Some(response))
+    }
+
+    /// Waits for the next write to finish, `None` once no write is running.
+    async fn next_write(&mut self) -> Result<Option<ApiResponse<String>>> {
+        match self.writes.join_next().await {
+            Some(joined) => self.finish_write(joined).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn finish_write(
+        &mut self,
+        joined: std::result::Result<Result<(ApiResponse<String>, u64)>, JoinError>,
+    ) -> Result<ApiResponse<String>> {
+        let (response, position) =
+            joined.map_err(|e| crate::error!(CustomError, "Batch writer failed: {}", e))??;
+        if response.status == "success" {
+            self.checkpoint = self.checkpoint.max(position);
+        }
+        Ok(response)
+    }
+
+    /// Waits for every running write. Returns their responses, or the first error once all
+    /// writes have finished.
+    pub async fn finish_writes(&mut self) -> Result<Vec<ApiResponse<String>>> {
+        let mut responses = Vec::new();
+        let mut first_error = None;
+        while let Some(joined) = self.writes.join_next().await {
+            match self.finish_write(joined) {
+                Ok(response) => responses.push(response),
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
             }
         }
+
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(responses),
+        }
     }
 
-    pub async fn cleanup(&self) -> Result<ApiResponse<String>> {
+    /// Creates and commits the version of a completed session. A replacement's version was
+    /// committed with its rows and is returned as is.
+    async fn commit_version(&self) -> Result<i32> {
         let mut tx = self.pool.begin().await?;
-        match rollback_all_batches(self.last_id, &mut tx).await {
-            Ok(_) => {
+        let version =
+            DataVersion::create(&mut tx, VersionKind::Ingest, Some(self.ingest_id)).await?;
+        DataVersion::commit(&mut tx, version).await?;
+        tx.commit().await?;
+
+        Ok(version)
+    }
+
+    /// Refreshes `first_available` and `last_available` of the instruments loaded by the session,
+    /// and records them as ingested by it.
+    pub async fn refresh_availability(&self) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        let instrument_ids = session_instruments(self.ingest_id, &mut tx).await?;
+        refresh_availability(&mut tx, Some(&instrument_ids)).await?;
+        IngestSession::record_instruments(&mut tx, self.ingest_id, &instrument_ids).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Recomputes the rollup bars of the trades queued by the committed batches. Done once the
+    /// writers are finished, so they never wait on each other for a shared bar.
+    pub async fn refresh_rollups(&self) -> Result<()> {
+        let seconds = refresh_queued_rollups(&self.pool).await?;
+        info!(
+            "Ingest session {} refreshed the rollup bars of {} seconds",
+            self.ingest_id, seconds
+        );
+
+        Ok(())
+    }
+
+    /// Waits for running writes, then fails the session and rolls back what it committed.
+    pub async fn cleanup(&mut self) -> Result<ApiResponse<String>> {
+        if let Err(e) = self.finish_writes().await {
+            error!("Error finishing batch writes: {:?}", e);
+        }
+        IngestSession::update_status(&self.pool, self.ingest_id, IngestStatus::Failed).await?;
+
+        // Nothing of a replacement is committed before its last batch
+        if let Some(replacement) = self.replacement.take() {
+            replacement.tx.rollback().await?;
+            let response = ApiResponse::new(
+                "success",
+                &format!(
+                    "Rolled back the replacement of ingest session {}.",
+                    self.ingest_id
+                ),
+                StatusCode::OK,
+                "".to_string(),
+            );
+            return Ok(response);
+        }
+
+        // Resumable sessions keep committed batches so the load can continue from the checkpoint
+        if self.resumable {
+            if let Err(e) = self.refresh_availability().await {
+                error!("Error refreshing instrument availability: {:?}", e);
+            }
+            if let Err(e) = self.refresh_rollups().await {
+                error!("Error refreshing rollup bars: {:?}", e);
+            }
+            let response = ApiResponse::new(
+                "success",
+                &format!(
+                    "Kept records committed by resumable ingest session {} up to record {}.",
+                    self.ingest_id, self.checkpoint
+                ),
+                StatusCode::OK,
+                "".to_string(),
+            );
+            return Ok(response);
+        }
+
+        let mut tx = self.pool.begin().await?;
+        match rollback_all_batches(self.ingest_id, &mut tx).await {
+            Ok(deleted) => {
                 tx.commit().await?;
                 let response = ApiResponse::new(
                     "success",
-                    "Removed all commits from this batch process.",
+                    &format!(
+                        "Removed {} records committed by ingest session {}.",
+                        deleted, self.ingest_id
+                    ),
                     StatusCode::OK,
                     "".to_string(),
                 );
@@ -118,45 +595,87 @@ impl RecordLoader {
         }
     }
 
-    pub async fn process_records<R>(
+    pub async fn process_records<S>(
         mut self,
-        mut decoder: RecordDecoder<R>,
+        decoder: S,
     ) -> Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>
     where
-        R: std::io::Read + Send + 'static,
+        S: RecordSource,
     {
         let p_stream = stream! {
-            // First responnse will contain the status of the mbp table before beginning
+            // First response contains the ingest session every inserted row is tagged with
             let response = ApiResponse::new(
                 "success",
-                &format!("Max ID before inserting {}", self.last_id),
+                &format!("Started ingest session {}", self.ingest_id),
                 StatusCode::OK,
-                format!("{}", self.last_id),
+                format!("{}", self.ingest_id),
             );
             yield Ok(response.bytes());
 
             // Initialize the decoder
-            let mut decode_iter = decoder.decode_iterator();
+            let mut records = spawn_decoder(decoder);
+
+            // Skip records already committed by a previous run of the session
+            while self.position < self.resume_from {
+                match records.recv().await {
+                    Some(Ok(_)) => self.position += 1,
+                    Some(Err(e)) => {
+                        error!("Error decoding record: {:?}", e);
+                        self.record_error(format!("Error decoding record: {:?}", e));
+                        let response = ApiResponse::new(
+                            "failed",
+                            &format!("Error decoding record: {:?}", e),
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            "".to_string(),
+                        );
+                        yield Ok(response.bytes());
+
+                        let c_response : Bytes = self.cleanup().await.unwrap().bytes();
+                        yield Ok(c_response);
+
+                        return;
+                    }
+                    None => break,
+                }
+            }
+
+            while let Some(record_result) = records.recv().await {
+                if self.cancelled.load(Ordering::Relaxed) {
+                    info!("Ingest session {} cancelled.", self.ingest_id);
+                    let response = ApiResponse::new(
+                        "failed",
+                        &format!("Ingest session {} cancelled.", self.ingest_id),
+                        StatusCode::OK,
+                        "".to_string(),
+                    );
+                    yield Ok(response.bytes());
+
+                    let c_response : Bytes = self.cleanup().await.unwrap().bytes();
+                    yield Ok(c_response);
+
+                    return;
+                }
 
-            while let Some(record_result) = decode_iter.next() {
                 match record_result {
                     Ok(record) => {
                         match self.update_batch(record).await {
-                            Ok(Some(response)) =>{
-                                // Yield response as Bytes to the stream
-                                yield Ok::<Bytes, Error>(response.bytes());
+                            Ok(responses) => {
+                                let failed = responses.iter().any(|r| r.status != "success");
+                                for response in responses {
+                                    yield Ok::<Bytes, Error>(response.bytes());
+                                }
 
-                                // Break the stream if the response status is not "success"
-                                if response.status != "success" {
+                                // Break the stream if a batch failed
+                                if failed {
                                     let c_response : Bytes = self.cleanup().await.unwrap().bytes();
                                     yield Ok(c_response);
 
                                     return;
                                 }
                             }
-                            Ok(None) => {}
                             Err(e) => {
                                 error!("Error processing record: {:?}", e);
+                                self.record_error(format!("Error processing record: {:?}", e));
                                 yield Ok(e.bytes());
 
                                 let c_response : Bytes = self.cleanup().await.unwrap().bytes();
@@ -168,6 +687,7 @@ impl RecordLoader {
                     },
                     Err(e) => {
                         error!("Error decoding record: {:?}", e);
+                        self.record_error(format!("Error decoding record: {:?}", e));
                         let response = ApiResponse::new(
                             "failed",
                             &format!("Error decoding record: {:?}", e),
@@ -184,40 +704,120 @@ impl RecordLoader {
                 }
             }
 
-            // Final clean-up
-            if self.get_records_in_batch() > 0 {
-                match self.commit_batch().await {
-                    Ok(response) => {
-                        // Yield response as Bytes to the stream
+            // Final clean-up, the last partial batch and the writes still running
+            let flushed = if self.get_records_in_batch() > 0 {
+                self.commit_batch().await
+            } else {
+                Ok(Vec::new())
+            };
+            let finished = match flushed {
+                Ok(mut responses) => self.finish_writes().await.map(|rest| {
+                    responses.extend(rest);
+                    responses
+                }),
+                Err(e) => Err(e),
+            };
+            match finished {
+                Ok(responses) => {
+                    let failed = responses.iter().any(|r| r.status != "success");
+                    for response in responses {
                         yield Ok::<Bytes, Error>(response.bytes());
-
-                        // Break the stream if the response status is not "success"
-                        if response.status != "success" {
-                            let c_response : Bytes = self.cleanup().await.unwrap().bytes();
-                            yield Ok(c_response);
-                            return;
-                        }
                     }
-                    Err(e) => {
-                        error!("Error processing record: {:?}", e);
-                        yield Ok(e.bytes());
 
+                    // Break the stream if a batch failed
+                    if failed {
                         let c_response : Bytes = self.cleanup().await.unwrap().bytes();
                         yield Ok(c_response);
-
                         return;
                     }
+                }
+                Err(e) => {
+                    error!("Error processing record: {:?}", e);
+                    self.record_error(format!("Error processing record: {:?}", e));
+                    yield Ok(e.bytes());
+
+                    let c_response : Bytes = self.cleanup().await.unwrap().bytes();
+                    yield Ok(c_response);
+
+                    return;
+                }
+            }
+
+            // A source holding another count than expected is rolled back before it has a version
+            if let Some(expected) = self.expected_records.filter(|expected| *expected != self.position) {
+                let message = format!("Expected {} records, decoded {}", expected, self.position);
+                error!("{}", message);
+                self.record_error(message.clone());
+                let response = ApiResponse::new(
+                    "failed",
+                    &message,
+                    StatusCode::CONFLICT,
+                    "".to_string(),
+                );
+                yield Ok(response.bytes());
+
+                let c_response : Bytes = self.cleanup().await.unwrap().bytes();
+                yield Ok(c_response);
 
+                return;
+            }
+
+            match self.commit_replacement().await {
+                Ok(Some(response)) => yield Ok(response.bytes()),
+                Ok(None) => {}
+                Err(e) => {
+                    error!("Error committing replacement: {:?}", e);
+                    self.record_error(format!("Error committing replacement: {:?}", e));
+                    yield Ok(e.bytes());
+
+                    let c_response : Bytes = self.cleanup().await.unwrap().bytes();
+                    yield Ok(c_response);
+
+                    return;
                 }
+            }
+
+            if let Err(e) = self.refresh_availability().await {
+                error!("Error refreshing instrument availability: {:?}", e);
+                self.record_error(format!("Error refreshing instrument availability: {:?}", e));
+            }
 
+            if let Err(e) = self.refresh_rollups().await {
+                error!("Error refreshing rollup bars: {:?}", e);
+                self.record_error(format!("Error refreshing rollup bars: {:?}", e));
             }
 
+            if let Err(e) = IngestSession::update_status(&self.pool, self.ingest_id, IngestStatus::Completed).await {
+                error!("Error completing ingest session: {:?}", e);
+                self.record_error(format!("Error completing ingest session: {:?}", e));
+                yield Ok(e.bytes());
+                return;
+            }
+
+            // Replacements already have the version their rows were superseded by
+            let version = match self.commit_version().await {
+                Ok(version) => version,
+                Err(e) => {
+                    error!("Error creating dataset version: {:?}", e);
+                    self.record_error(format!("Error creating dataset version: {:?}", e));
+                    yield Ok(e.bytes());
+                    return;
+                }
+            };
+
             // Success response
+            let (inserted, skipped) = {
+                let progress = self.progress.lock().unwrap();
+                (progress.records_committed, progress.records_skipped)
+            };
             let response = ApiResponse::new(
                 "success",
-                "Successfully inserted records and merged staging.",
+                &format!(
+                    "Successfully loaded records: {} inserted, {} skipped.",
+                    inserted, skipped
+                ),
                 StatusCode::OK,
-                "".to_string(),
+                format!("{}", version),
             );
             yield Ok(response.bytes());
 
@@ -225,3 +825,266 @@ impl RecordLoader {
         Box::pin(p_stream) // Explicitly pin the stream and return it
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::init::init_db;
+    use crate::database::market_data::create::IngestMode;
+    use crate::test_utils::{create_instrument, delete_instrument, mbp1};
+    use futures::StreamExt;
+    use mbn::decode::RecordDecoder;
+    use mbn::encode::RecordEncoder;
+    use mbn::record_ref::RecordRef;
+    use mbn::enums::Action;
+    use mbn::records::Mbp1Msg;
+    use serial_test::serial;
+    use std::io::Cursor;
+
+    fn encode_mbp(id: i32, timestamps: &[u64]) -> Vec<u8> {
+        let records: Vec<Mbp1Msg> = timestamps
+            .iter()
+            .map(|ts| mbp1(id, *ts, 6770, Action::Add))
+            .collect();
+        let refs: Vec<RecordRef> = records.iter().map(|record| record.into()).collect();
+
+        let mut buffer = Vec::new();
+        let mut encoder = RecordEncoder::new(&mut buffer);
+        encoder.encode_records(&refs).expect("Encoding failed");
+        buffer
+    }
+
+    async fn load(
+        pool: &PgPool,
+        buffer: Vec<u8>,
+        params: &LoadParams,
+    ) -> anyhow::Result<(i32, Vec<ApiResponse<String>>)> {
+        let loader = RecordLoader::new(1, pool.clone(), params, "test").await?;
+        let ingest_id = loader.ingest_id();
+        let mut stream = loader
+            .process_records(RecordDecoder::new(Cursor::new(buffer)))
+            .await;
+
+        let mut responses = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            responses.push(serde_json::from_slice(&chunk?)?);
+        }
+        Ok((ingest_id, responses))
+    }
+
+    async fn session_rows(pool: &PgPool, ingest_id: i32) -> anyhow::Result<i64> {
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM mbp WHERE ingest_id = $1")
+            .bind(ingest_id)
+            .fetch_one(pool)
+            .await?;
+        Ok(count)
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_process_records_parallel_writers() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let id = create_instrument(&pool, "AAPL").await?;
+        let timestamps: Vec<u64> = (0..6).map(|i| 1704209103644092564 + i).collect();
+        let params = LoadParams {
+            writers: Some(3),
+            ..Default::default()
+        };
+
+        // Test
+        let (ingest_id, responses) = load(&pool, encode_mbp(id, &timestamps), &params).await?;
+
+        // Validate
+        assert!(responses.iter().all(|r| r.status == "success"));
+        assert_eq!(session_rows(&pool, ingest_id).await?, 6);
+        let session = IngestSession::get(&pool, ingest_id).await?.unwrap();
+        assert_eq!(session.status, IngestStatus::Completed);
+
+        // Cleanup
+        delete_instrument(&pool, id).await?;
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_process_records_parallel_writers_error() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let id = create_instrument(&pool, "AAPL").await?;
+        let mut timestamps: Vec<u64> = (0..6).map(|i| 1704209103644092564 + i).collect();
+        timestamps.push(timestamps[0]); // Duplicate fails its batch in strict mode
+        let params = LoadParams {
+            writers: Some(3),
+            ..Default::default()
+        };
+
+        // Test
+        let (ingest_id, responses) = load(&pool, encode_mbp(id, &timestamps), &params).await?;
+
+        // Validate
+        assert!(responses.iter().any(|r| r.status == "failed"));
+        assert_eq!(session_rows(&pool, ingest_id).await?, 0);
+        let session = IngestSession::get(&pool, ingest_id).await?.unwrap();
+        assert_eq!(session.status, IngestStatus::Failed);
+
+        // Cleanup
+        delete_instrument(&pool, id).await?;
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_process_records_expected_records() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let id = create_instrument(&pool, "AAPL").await?;
+        let timestamps: Vec<u64> = (0..2).map(|i| 1704209103644092564 + i).collect();
+
+        // Test
+        let loader = RecordLoader::new(1, pool.clone(), &LoadParams::default(), "test")
+            .await?
+            .with_expected_records(Some(3));
+        let ingest_id = loader.ingest_id();
+        let mut stream = loader
+            .process_records(RecordDecoder::new(Cursor::new(encode_mbp(id, &timestamps))))
+            .await;
+        let mut responses: Vec<ApiResponse<String>> = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            responses.push(serde_json::from_slice(&chunk?)?);
+        }
+
+        // Validate
+        assert!(responses
+            .iter()
+            .any(|r| r.message == "Expected 3 records, decoded 2"));
+        assert_eq!(session_rows(&pool, ingest_id).await?, 0);
+        let session = IngestSession::get(&pool, ingest_id).await?.unwrap();
+        assert_eq!(session.status, IngestStatus::Failed);
+        let versions: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM data_version WHERE ingest_id = $1")
+                .bind(ingest_id)
+                .fetch_one(&pool)
+                .await?;
+        assert_eq!(versions, 0);
+
+        // Cleanup
+        delete_instrument(&pool, id).await?;
+
+        Ok(())
+    }
+
+    async fn range_rows(pool: &PgPool, id: i32) -> anyhow::Result<Vec<(i64, Option<i32>)>> {
+        let rows = sqlx::query_as(
+            r#"
+            SELECT ts_recv, ingest_id FROM mbp
+            WHERE instrument_id = $1 AND superseded_by IS NULL
+            ORDER BY ts_recv
+            "#,
+        )
+        .bind(id)
+        .fetch_all(pool)
+        .await?;
+        Ok(rows)
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_process_records_replace() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let id = create_instrument(&pool, "AAPL").await?;
+        let day = 86_400_000_000_000;
+        let start = 1704153600000000000; // 2024-01-02
+        let (original, _) = load(
+            &pool,
+            encode_mbp(id, &[start + 1, start + 2, start + day + 1]),
+            &LoadParams::default(),
+        )
+        .await?;
+        let params = LoadParams {
+            mode: IngestMode::Replace,
+            replace_start: Some(start as i64),
+            replace_end: Some((start + day) as i64),
+            ..Default::default()
+        };
+
+        // Test
+        let (ingest_id, responses) =
+            load(&pool, encode_mbp(id, &[start + 2, start + 3]), &params).await?;
+
+        // Validate
+        assert!(responses.iter().all(|r| r.status == "success"));
+        assert_eq!(
+            range_rows(&pool, id).await?,
+            vec![
+                ((start + 2) as i64, Some(ingest_id)),
+                ((start + 3) as i64, Some(ingest_id)),
+                ((start + day + 1) as i64, Some(original)),
+            ]
+        );
+        let superseded: Vec<i64> = sqlx::query_scalar(
+            r#"
+            SELECT m.ts_recv FROM mbp m
+            INNER JOIN data_version v ON v.id = m.superseded_by
+            WHERE m.instrument_id = $1 AND v.ingest_id = $2
+            ORDER BY m.ts_recv
+            "#,
+        )
+        .bind(id)
+        .bind(ingest_id)
+        .fetch_all(&pool)
+        .await?;
+        assert_eq!(superseded, vec![(start + 1) as i64, (start + 2) as i64]);
+        let session = IngestSession::get(&pool, ingest_id).await?.unwrap();
+        assert_eq!(session.status, IngestStatus::Completed);
+
+        // Cleanup
+        delete_instrument(&pool, id).await?;
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_process_records_replace_error() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let id = create_instrument(&pool, "AAPL").await?;
+        let day = 86_400_000_000_000;
+        let start = 1704153600000000000;
+        let (original, _) =
+            load(&pool, encode_mbp(id, &[start + 1]), &LoadParams::default()).await?;
+        let params = LoadParams {
+            mode: IngestMode::Replace,
+            replace_start: Some(start as i64),
+            replace_end: Some((start + day) as i64),
+            ..Default::default()
+        };
+
+        // Test, the second batch is outside of the range after the first deleted the day
+        let (ingest_id, responses) =
+            load(&pool, encode_mbp(id, &[start + 2, start + day]), &params).await?;
+
+        // Validate
+        assert!(responses.iter().any(|r| r.status == "failed"));
+        assert_eq!(
+            range_rows(&pool, id).await?,
+            vec![((start + 1) as i64, Some(original))]
+        );
+        let session = IngestSession::get(&pool, ingest_id).await?.unwrap();
+        assert_eq!(session.status, IngestStatus::Failed);
+
+        // Cleanup
+        delete_instrument(&pool, id).await?;
+
+        Ok(())
+    }
+}
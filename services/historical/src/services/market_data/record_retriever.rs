@@ -10,14 +10,13 @@ use futures::stream::Stream;
 use futures::stream::StreamExt;
 use mbn::encode::AsyncRecordEncoder;
 use mbn::encode::MetadataEncoder;
-use mbn::enums::{RType, Schema};
+use mbn::enums::RType;
 use mbn::metadata::Metadata;
 use mbn::{record_enum::RecordEnum, symbols::SymbolMap};
 use sqlx::{PgPool, Row};
 use std::io::Cursor;
 use std::io::{self};
 use std::pin::Pin;
-use std::str::FromStr;
 use std::sync::Arc;
 use std::task::{Context, Poll};
 use tokio::io::AsyncWrite;
@@ -109,7 +108,7 @@ impl RecordGetter {
         let symbol_map = query_symbols_map(&self.pool, &retrieve_params.symbols).await?;
 
         let metadata = Metadata::new(
-            Schema::from_str(&retrieve_params.schema).unwrap(),
+            retrieve_params.metadata_schema()?,
             retrieve_params.start_ts as u64,
             retrieve_params.end_ts as u64,
             symbol_map,
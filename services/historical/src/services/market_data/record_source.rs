@@ -0,0 +1,697 @@
+use crate::database::symbols::{create_missing_instruments, query_instrument_ids};
+use crate::services::market_data::dbn::open_dbn;
+use crate::services::market_data::load::LoadParams;
+use crate::{Error, Result};
+use arrow_array::cast::AsArray;
+use arrow_array::types::Int64Type;
+use arrow_array::{Array, ArrayRef, RecordBatch};
+use arrow_cast::cast;
+use arrow_schema::DataType;
+use flate2::read::MultiGzDecoder;
+use mbn::decode::{Decoder, RecordDecoder};
+use mbn::enums::Schema;
+use mbn::record_enum::RecordEnum;
+use mbn::records::{BboMsg, BidAskPair, Mbp1Msg, OhlcvMsg, RecordHeader, TradeMsg};
+use parquet::arrow::arrow_reader::{ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read};
+use std::os::raw::c_char;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Record fields read as text, every other field is read as an integer.
+const TEXT_FIELDS: [&str; 3] = ["symbol", "action", "side"];
+
+/// Rows per batch read from parquet files.
+const PARQUET_BATCH_SIZE: usize = 8192;
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+/// Input read by the loader. Sources run on a blocking thread and hand each record to `emit`,
+/// stopping once it returns false.
+pub trait RecordSource: Send + 'static {
+    fn read_records(self: Box<Self>, emit: &mut dyn FnMut(Result<RecordEnum>) -> bool);
+}
+
+impl<R> RecordSource for RecordDecoder<R>
+where
+    R: Read + Send + 'static,
+{
+    fn read_records(mut self: Box<Self>, emit: &mut dyn FnMut(Result<RecordEnum>) -> bool) {
+        for record_result in self.decode_iterator() {
+            if !emit(record_result.map_err(Error::from)) {
+                break;
+            }
+        }
+    }
+}
+
+/// mbn records from a reader that may be zstd or gzip compressed. The compression is detected
+/// once reading starts, so blocking readers are only read on the decoding thread.
+pub struct MbnSource<R> {
+    reader: R,
+}
+
+impl<R> MbnSource<R>
+where
+    R: Read + Send + 'static,
+{
+    pub fn new(reader: R) -> Self {
+        MbnSource { reader }
+    }
+}
+
+impl<R> RecordSource for MbnSource<R>
+where
+    R: Read + Send + 'static,
+{
+    fn read_records(self: Box<Self>, emit: &mut dyn FnMut(Result<RecordEnum>) -> bool) {
+        match decompress(self.reader) {
+            Ok(reader) => Box::new(RecordDecoder::new(reader)).read_records(emit),
+            Err(e) => {
+                emit(Err(e));
+            }
+        }
+    }
+}
+
+/// Wraps `reader` in a zstd or gzip decoder when its first bytes are the format's magic number.
+pub fn decompress<R>(mut reader: R) -> Result<Box<dyn Read + Send>>
+where
+    R: Read + Send + 'static,
+{
+    let mut header = Vec::with_capacity(ZSTD_MAGIC.len());
+    (&mut reader)
+        .take(ZSTD_MAGIC.len() as u64)
+        .read_to_end(&mut header)?;
+    let reader = Cursor::new(header.clone()).chain(reader);
+
+    if header.starts_with(&ZSTD_MAGIC) {
+        Ok(Box::new(zstd::Decoder::new(reader)?))
+    } else if header.starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(MultiGzDecoder::new(reader)))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+impl<R> RecordSource for Decoder<R>
+where
+    R: Read + Send + 'static,
+{
+    fn read_records(mut self: Box<Self>, emit: &mut dyn FnMut(Result<RecordEnum>) -> bool) {
+        for record_result in self.decode_iterator() {
+            if !emit(record_result.map_err(Error::from)) {
+                break;
+            }
+        }
+    }
+}
+
+/// Rewrites the instrument ids of an upload to the server's ids.
+struct RemapSource {
+    inner: Box<dyn RecordSource>,
+    instrument_ids: HashMap<u32, u32>,
+}
+
+impl RecordSource for RemapSource {
+    fn read_records(self: Box<Self>, emit: &mut dyn FnMut(Result<RecordEnum>) -> bool) {
+        let instrument_ids = self.instrument_ids;
+        self.inner.read_records(&mut |record_result| {
+            emit(record_result.and_then(|mut record| {
+                let hd = match &mut record {
+                    RecordEnum::Mbp1(msg) | RecordEnum::Tbbo(msg) => &mut msg.hd,
+                    RecordEnum::Trade(msg) => &mut msg.hd,
+                    RecordEnum::Ohlcv(msg) => &mut msg.hd,
+                    RecordEnum::Bbo(msg) => &mut msg.hd,
+                };
+                hd.instrument_id = *instrument_ids.get(&hd.instrument_id).ok_or_else(|| {
+                    crate::error!(
+                        CustomError,
+                        "Instrument id {} is not in the upload's symbol map",
+                        hd.instrument_id
+                    )
+                })?;
+                Ok(record)
+            }))
+        });
+    }
+}
+
+/// Reads the mbn metadata header on a blocking thread and creates the instruments of its symbol
+/// map that the server does not have yet. Records are remapped from the upload's instrument ids
+/// to the server's.
+pub async fn open_with_metadata<R>(
+    pool: &PgPool,
+    reader: R,
+    params: &LoadParams,
+) -> Result<Box<dyn RecordSource>>
+where
+    R: Read + Send + 'static,
+{
+    let decoder = tokio::task::spawn_blocking(move || -> Result<_> {
+        Ok(Decoder::new(decompress(reader)?)?)
+    })
+    .await
+    .map_err(|e| crate::error!(CustomError, "Metadata task failed: {}", e))??;
+
+    let metadata = decoder.metadata.clone().ok_or_else(|| {
+        crate::error!(
+            CustomError,
+            "Upload has no metadata to create instruments from."
+        )
+    })?;
+    let tickers: Vec<String> = metadata.mappings.map.values().cloned().collect();
+    let server_ids = create_missing_instruments(
+        pool,
+        &tickers,
+        params.vendor.as_deref(),
+        params.dataset.as_deref(),
+        params.stype.as_deref(),
+    )
+    .await?;
+
+    let instrument_ids = metadata
+        .mappings
+        .map
+        .iter()
+        .filter_map(|(id, ticker)| server_ids.get(ticker).map(|server_id| (*id, *server_id)))
+        .collect();
+
+    Ok(Box::new(RemapSource {
+        inner: Box::new(decoder),
+        instrument_ids,
+    }))
+}
+
+impl RecordSource for Box<dyn RecordSource> {
+    fn read_records(self: Box<Self>, emit: &mut dyn FnMut(Result<RecordEnum>) -> bool) {
+        (*self).read_records(emit)
+    }
+}
+
+/// File formats accepted by the uploads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputFormat {
+    #[default]
+    Mbn,
+    /// Databento DBN, read by `DbnSource`.
+    Dbn,
+    Csv,
+    Parquet,
+}
+
+impl InputFormat {
+    /// Format implied by the file extension, mbn when it is not recognised. A compression
+    /// extension is skipped, so `trades.csv.gz` is read as CSV.
+    pub fn from_path(path: &Path) -> Self {
+        let extension = |path: &Path| {
+            path.extension()
+                .and_then(|extension| extension.to_str())
+                .map(|extension| extension.to_ascii_lowercase())
+        };
+
+        let mut format_extension = extension(path);
+        if matches!(format_extension.as_deref(), Some("zst" | "zstd" | "gz")) {
+            format_extension = path.file_stem().and_then(|stem| extension(Path::new(stem)));
+        }
+
+        match format_extension.as_deref() {
+            Some("dbn") => InputFormat::Dbn,
+            Some("csv") => InputFormat::Csv,
+            Some("parquet") => InputFormat::Parquet,
+            _ => InputFormat::Mbn,
+        }
+    }
+}
+
+/// Record field to file column, e.g. `{"ts_event": "timestamp", "symbol": "ticker"}`. Fields
+/// that are not listed are read from the column of the same name.
+pub type ColumnMap = HashMap<String, String>;
+
+/// Opens `path` in the upload's format, decompressing zstd and gzip files as they are read.
+/// Tabular rows are built into records of the upload's schema, mbp-1 by default, with symbols
+/// resolved through the `instrument` table.
+pub async fn open_source(
+    pool: &PgPool,
+    path: &Path,
+    params: &LoadParams,
+    columns: ColumnMap,
+) -> Result<Box<dyn RecordSource>> {
+    let format = params
+        .format
+        .unwrap_or_else(|| InputFormat::from_path(path));
+    if format == InputFormat::Mbn {
+        let reader = BufReader::new(File::open(path)?);
+        if params.create_instruments {
+            return open_with_metadata(pool, reader, params).await;
+        }
+        return Ok(Box::new(MbnSource::new(reader)));
+    }
+    if format == InputFormat::Dbn {
+        return open_dbn(pool, path, params).await;
+    }
+
+    let schema = match &params.schema {
+        Some(schema) => Schema::from_str(schema)?,
+        None => Schema::Mbp1,
+    };
+    let mapper = RecordMapper {
+        schema,
+        columns,
+        instruments: query_instrument_ids(pool).await?,
+    };
+
+    match format {
+        InputFormat::Csv => Ok(Box::new(CsvSource {
+            reader: csv::Reader::from_reader(decompress(BufReader::new(File::open(path)?))?),
+            mapper,
+        })),
+        // Parquet compresses its own pages and needs a seekable file
+        InputFormat::Parquet => {
+            let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?
+                .with_batch_size(PARQUET_BATCH_SIZE)
+                .build()?;
+            Ok(Box::new(ParquetSource { reader, mapper }))
+        }
+        InputFormat::Mbn | InputFormat::Dbn => unreachable!(),
+    }
+}
+
+/// Values of a single tabular row, looked up by column name.
+trait Row {
+    fn text(&self, column: &str) -> Result<Option<Cow<'_, str>>>;
+    fn int(&self, column: &str) -> Result<Option<i64>>;
+}
+
+/// Builds records of one schema from tabular rows.
+struct RecordMapper {
+    schema: Schema,
+    columns: ColumnMap,
+    instruments: HashMap<String, u32>,
+}
+
+impl RecordMapper {
+    fn column<'a>(&'a self, field: &'a str) -> &'a str {
+        self.columns.get(field).map(String::as_str).unwrap_or(field)
+    }
+
+    fn optional(&self, row: &impl Row, field: &str) -> Result<Option<i64>> {
+        row.int(self.column(field))
+    }
+
+    fn int(&self, row: &impl Row, field: &str) -> Result<i64> {
+        Ok(self.optional(row, field)?.unwrap_or(0))
+    }
+
+    fn required(&self, row: &impl Row, field: &str) -> Result<i64> {
+        self.optional(row, field)?.ok_or_else(|| {
+            crate::error!(
+                CustomError,
+                "Missing value in column {} for {}",
+                self.column(field),
+                field
+            )
+        })
+    }
+
+    /// Actions and sides are given either as their character, e.g. `A`, or its code.
+    fn char(&self, row: &impl Row, field: &str) -> Result<c_char> {
+        let Some(value) = row.text(self.column(field))? else {
+            return Ok(0);
+        };
+        let value = value.trim();
+        if let Ok(code) = value.parse::<u8>() {
+            return Ok(code as c_char);
+        }
+        match value.as_bytes() {
+            [code] => Ok(*code as c_char),
+            _ => Err(crate::error!(CustomError, "Invalid {}: {}", field, value)),
+        }
+    }
+
+    fn instrument_id(&self, row: &impl Row) -> Result<u32> {
+        let column = self.column("symbol");
+        let symbol = row
+            .text(column)?
+            .ok_or_else(|| crate::error!(CustomError, "Missing value in column {}", column))?;
+
+        self.instruments
+            .get(symbol.trim())
+            .copied()
+            .ok_or_else(|| crate::error!(CustomError, "Unknown symbol: {}", symbol))
+    }
+
+    fn levels(&self, row: &impl Row) -> Result<[BidAskPair; 1]> {
+        Ok([BidAskPair {
+            bid_px: self.int(row, "bid_px")?,
+            ask_px: self.int(row, "ask_px")?,
+            bid_sz: self.int(row, "bid_sz")? as u32,
+            ask_sz: self.int(row, "ask_sz")? as u32,
+            bid_ct: self.int(row, "bid_ct")? as u32,
+            ask_ct: self.int(row, "ask_ct")? as u32,
+        }])
+    }
+
+    fn map_row(&self, row: &impl Row) -> Result<RecordEnum> {
+        let instrument_id = self.instrument_id(row)?;
+        let ts_event = self.required(row, "ts_event")? as u64;
+        // Exports without a receive time use the event time
+        let ts_recv = self
+            .optional(row, "ts_recv")?
+            .map_or(ts_event, |ts_recv| ts_recv as u64);
+
+        let record = match self.schema {
+            Schema::Mbp1 | Schema::Tbbo => {
+                let msg = Mbp1Msg {
+                    hd: RecordHeader::new::<Mbp1Msg>(instrument_id, ts_event),
+                    price: self.required(row, "price")?,
+                    size: self.int(row, "size")? as u32,
+                    action: self.char(row, "action")?,
+                    side: self.char(row, "side")?,
+                    depth: self.int(row, "depth")? as u8,
+                    flags: self.int(row, "flags")? as u8,
+                    ts_recv,
+                    ts_in_delta: self.int(row, "ts_in_delta")? as i32,
+                    sequence: self.int(row, "sequence")? as u32,
+                    discriminator: 0,
+                    levels: self.levels(row)?,
+                };
+                if self.schema == Schema::Tbbo {
+                    RecordEnum::Tbbo(msg)
+                } else {
+                    RecordEnum::Mbp1(msg)
+                }
+            }
+            Schema::Trade => RecordEnum::Trade(TradeMsg {
+                hd: RecordHeader::new::<TradeMsg>(instrument_id, ts_event),
+                price: self.required(row, "price")?,
+                size: self.int(row, "size")? as u32,
+                action: self.char(row, "action")?,
+                side: self.char(row, "side")?,
+                flags: self.int(row, "flags")? as u8,
+                depth: self.int(row, "depth")? as u8,
+                ts_recv,
+                ts_in_delta: self.int(row, "ts_in_delta")? as i32,
+                sequence: self.int(row, "sequence")? as u32,
+            }),
+            Schema::Ohlcv1S | Schema::Ohlcv1M | Schema::Ohlcv1H | Schema::Ohlcv1D => {
+                RecordEnum::Ohlcv(OhlcvMsg {
+                    hd: RecordHeader::new::<OhlcvMsg>(instrument_id, ts_event),
+                    open: self.required(row, "open")?,
+                    high: self.required(row, "high")?,
+                    low: self.required(row, "low")?,
+                    close: self.required(row, "close")?,
+                    volume: self.int(row, "volume")? as u64,
+                })
+            }
+            Schema::Bbo1S | Schema::Bbo1M => RecordEnum::Bbo(BboMsg {
+                hd: RecordHeader::new::<BboMsg>(instrument_id, ts_event),
+                price: self.int(row, "price")?,
+                size: self.int(row, "size")? as u32,
+                side: self.char(row, "side")?,
+                flags: self.int(row, "flags")? as u8,
+                ts_recv,
+                sequence: self.int(row, "sequence")? as u32,
+                levels: self.levels(row)?,
+            }),
+        };
+
+        Ok(record)
+    }
+}
+
+struct CsvRow<'a> {
+    headers: &'a HashMap<String, usize>,
+    record: &'a csv::StringRecord,
+}
+
+impl Row for CsvRow<'_> {
+    fn text(&self, column: &str) -> Result<Option<Cow<'_, str>>> {
+        Ok(self
+            .headers
+            .get(column)
+            .and_then(|idx| self.record.get(*idx))
+            .filter(|value| !value.is_empty())
+            .map(Cow::Borrowed))
+    }
+
+    fn int(&self, column: &str) -> Result<Option<i64>> {
+        match self.text(column)? {
+            Some(value) => value.trim().parse::<i64>().map(Some).map_err(|_| {
+                crate::error!(
+                    CustomError,
+                    "Invalid integer in column {}: {}",
+                    column,
+                    value
+                )
+            }),
+            None => Ok(None),
+        }
+    }
+}
+
+/// CSV with a header row, prices given as fixed-point integers like the mbn records.
+struct CsvSource<R> {
+    reader: csv::Reader<R>,
+    mapper: RecordMapper,
+}
+
+impl<R> CsvSource<R>
+where
+    R: Read + Send + 'static,
+{
+    fn headers(&mut self) -> Result<HashMap<String, usize>> {
+        Ok(self
+            .reader
+            .headers()?
+            .iter()
+            .enumerate()
+            .map(|(idx, name)| (name.trim().to_string(), idx))
+            .collect())
+    }
+}
+
+impl<R> RecordSource for CsvSource<R>
+where
+    R: Read + Send + 'static,
+{
+    fn read_records(mut self: Box<Self>, emit: &mut dyn FnMut(Result<RecordEnum>) -> bool) {
+        let headers = match self.headers() {
+            Ok(headers) => headers,
+            Err(e) => {
+                emit(Err(e));
+                return;
+            }
+        };
+
+        for record_result in self.reader.records() {
+            let record_result = record_result.map_err(Error::from).and_then(|record| {
+                self.mapper.map_row(&CsvRow {
+                    headers: &headers,
+                    record: &record,
+                })
+            });
+            if !emit(record_result) {
+                break;
+            }
+        }
+    }
+}
+
+struct ParquetRow<'a> {
+    columns: &'a HashMap<String, ArrayRef>,
+    idx: usize,
+}
+
+impl ParquetRow<'_> {
+    fn array(&self, column: &str) -> Option<&ArrayRef> {
+        self.columns
+            .get(column)
+            .filter(|array| !array.is_null(self.idx))
+    }
+}
+
+impl Row for ParquetRow<'_> {
+    fn text(&self, column: &str) -> Result<Option<Cow<'_, str>>> {
+        Ok(self
+            .array(column)
+            .and_then(|array| array.as_string_opt::<i32>())
+            .map(|array| Cow::Borrowed(array.value(self.idx))))
+    }
+
+    fn int(&self, column: &str) -> Result<Option<i64>> {
+        Ok(self
+            .array(column)
+            .and_then(|array| array.as_primitive_opt::<Int64Type>())
+            .map(|array| array.value(self.idx)))
+    }
+}
+
+struct ParquetSource {
+    reader: ParquetRecordBatchReader,
+    mapper: RecordMapper,
+}
+
+impl ParquetSource {
+    /// Casts the batch's columns to the types the mapper reads. Columns that do not cast, like
+    /// unmapped text, are left out.
+    fn columns(&self, batch: &RecordBatch) -> HashMap<String, ArrayRef> {
+        let text_columns: Vec<&str> = TEXT_FIELDS
+            .iter()
+            .map(|field| self.mapper.column(field))
+            .collect();
+
+        let mut columns = HashMap::new();
+        for (field, array) in batch.schema().fields().iter().zip(batch.columns()) {
+            let data_type = if text_columns.contains(&field.name().as_str()) {
+                DataType::Utf8
+            } else {
+                DataType::Int64
+            };
+            if let Ok(array) = cast(array, &data_type) {
+                columns.insert(field.name().clone(), array);
+            }
+        }
+        columns
+    }
+}
+
+impl RecordSource for ParquetSource {
+    fn read_records(mut self: Box<Self>, emit: &mut dyn FnMut(Result<RecordEnum>) -> bool) {
+        while let Some(batch_result) = self.reader.next() {
+            let batch = match batch_result {
+                Ok(batch) => batch,
+                Err(e) => {
+                    emit(Err(e.into()));
+                    return;
+                }
+            };
+
+            let columns = self.columns(&batch);
+            for idx in 0..batch.num_rows() {
+                let row = ParquetRow {
+                    columns: &columns,
+                    idx,
+                };
+                if !emit(self.mapper.map_row(&row)) {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_csv(data: &str, schema: Schema, columns: ColumnMap) -> Vec<Result<RecordEnum>> {
+        let source = Box::new(CsvSource {
+            reader: csv::Reader::from_reader(std::io::Cursor::new(data.to_string())),
+            mapper: RecordMapper {
+                schema,
+                columns,
+                instruments: HashMap::from([("AAPL".to_string(), 7)]),
+            },
+        });
+
+        let mut records = Vec::new();
+        source.read_records(&mut |record| {
+            records.push(record);
+            true
+        });
+        records
+    }
+
+    #[test]
+    fn test_csv_column_mapping() {
+        let data = "timestamp,ticker,price,size,action,side,bid_px,ask_px\n\
+                    1704209103644092564,AAPL,6770,1,T,B,6769,6771\n";
+        let columns = ColumnMap::from([
+            ("ts_event".to_string(), "timestamp".to_string()),
+            ("symbol".to_string(), "ticker".to_string()),
+        ]);
+
+        // Test
+        let records = read_csv(data, Schema::Mbp1, columns);
+
+        // Validate
+        assert_eq!(records.len(), 1);
+        match records[0].as_ref().unwrap() {
+            RecordEnum::Mbp1(msg) => {
+                assert_eq!(msg.hd.instrument_id, 7);
+                assert_eq!(msg.hd.ts_event, 1704209103644092564);
+                assert_eq!(msg.ts_recv, 1704209103644092564);
+                assert_eq!(msg.price, 6770);
+                assert_eq!(msg.action, b'T' as c_char);
+                assert_eq!(msg.side, b'B' as c_char);
+                assert_eq!(msg.levels[0].bid_px, 6769);
+                assert_eq!(msg.levels[0].ask_px, 6771);
+            }
+            record => panic!("Unexpected record {:?}", record),
+        }
+    }
+
+    #[test]
+    fn test_csv_unknown_symbol() {
+        let data = "ts_event,symbol,open,high,low,close,volume\n\
+                    1704209103644092564,MSFT,1,2,0,1,100\n";
+
+        // Test
+        let records = read_csv(data, Schema::Ohlcv1M, ColumnMap::new());
+
+        // Validate
+        assert_eq!(records.len(), 1);
+        assert!(records[0].is_err());
+    }
+
+    #[test]
+    fn test_decompress() {
+        let data = b"ts_event,symbol\n".to_vec();
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        std::io::Write::write_all(&mut gzip, &data).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let zstd = zstd::encode_all(&data[..], 0).unwrap();
+
+        for input in [data.clone(), gzip, zstd] {
+            // Test
+            let mut output = Vec::new();
+            decompress(Cursor::new(input))
+                .unwrap()
+                .read_to_end(&mut output)
+                .unwrap();
+
+            // Validate
+            assert_eq!(output, data);
+        }
+    }
+
+    #[test]
+    fn test_input_format_from_path() {
+        assert_eq!(InputFormat::from_path(Path::new("a.csv")), InputFormat::Csv);
+        assert_eq!(
+            InputFormat::from_path(Path::new("a.csv.gz")),
+            InputFormat::Csv
+        );
+        assert_eq!(
+            InputFormat::from_path(Path::new("a.parquet")),
+            InputFormat::Parquet
+        );
+        assert_eq!(
+            InputFormat::from_path(Path::new("a.bin.zst")),
+            InputFormat::Mbn
+        );
+        assert_eq!(InputFormat::from_path(Path::new("a.zst")), InputFormat::Mbn);
+        assert_eq!(
+            InputFormat::from_path(Path::new("a.dbn.zst")),
+            InputFormat::Dbn
+        );
+    }
+}
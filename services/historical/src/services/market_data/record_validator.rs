@@ -0,0 +1,279 @@
+use crate::database::market_data::create::existing_mbp;
+use crate::response::ApiResponse;
+use crate::services::market_data::record_loader::spawn_decoder;
+use crate::services::market_data::record_source::RecordSource;
+use crate::Result;
+use async_stream::stream;
+use axum::http::StatusCode;
+use bytes::Bytes;
+use futures::stream::Stream;
+use mbn::record_enum::RecordEnum;
+use mbn::records::{BidAskPair, Mbp1Msg};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::collections::{HashMap, HashSet};
+use std::pin::Pin;
+use tracing::{error, info};
+
+/// Issues listed individually in the report, further ones are only counted.
+const MAX_LISTED_ISSUES: usize = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValidationIssue {
+    pub record: u64, // Position in the file
+    pub check: String,
+    pub message: String,
+}
+
+/// Data-quality report of an upload checked with `validate_only`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValidationReport {
+    pub records_checked: u64,
+    pub unknown_instruments: Vec<u32>,
+    pub non_monotonic_ts_recv: u64,
+    pub sequence_gaps: u64,
+    pub crossed_books: u64,
+    pub locked_books: u64,
+    pub invalid_prices: u64,
+    pub invalid_sizes: u64,
+    pub existing_duplicates: u64,
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl ValidationReport {
+    pub fn issue_count(&self) -> u64 {
+        self.non_monotonic_ts_recv
+            + self.sequence_gaps
+            + self.crossed_books
+            + self.locked_books
+            + self.invalid_prices
+            + self.invalid_sizes
+            + self.existing_duplicates
+            + self.unknown_instruments.len() as u64
+    }
+}
+
+/// Checks decoded records without writing anything.
+pub struct RecordValidator {
+    batch_size: usize,
+    pool: PgPool,
+    known_instruments: HashSet<u32>,
+    last_ts_recv: Option<u64>,
+    last_sequence: HashMap<u32, u32>,
+    pending_mbp: Vec<(u64, Mbp1Msg)>,
+    report: ValidationReport,
+}
+
+impl RecordValidator {
+    pub async fn new(batch_size: usize, pool: PgPool) -> Result<Self> {
+        let ids: Vec<i32> = sqlx::query_scalar("SELECT id FROM instrument")
+            .fetch_all(&pool)
+            .await?;
+
+        Ok(RecordValidator {
+            batch_size,
+            pool,
+            known_instruments: ids.into_iter().map(|id| id as u32).collect(),
+            last_ts_recv: None,
+            last_sequence: HashMap::new(),
+            pending_mbp: Vec::new(),
+            report: ValidationReport::default(),
+        })
+    }
+
+    fn issue(&mut self, record: u64, check: &str, message: String) {
+        if self.report.issues.len() < MAX_LISTED_ISSUES {
+            self.report.issues.push(ValidationIssue {
+                record,
+                check: check.to_string(),
+                message,
+            });
+        }
+    }
+
+    fn check_instrument(&mut self, position: u64, instrument_id: u32) {
+        if !self.known_instruments.contains(&instrument_id)
+            && !self.report.unknown_instruments.contains(&instrument_id)
+        {
+            self.report.unknown_instruments.push(instrument_id);
+            self.issue(
+                position,
+                "unknown_instrument",
+                format!("Unknown instrument_id {}", instrument_id),
+            );
+        }
+    }
+
+    fn check_ts_recv(&mut self, position: u64, ts_recv: u64) {
+        if let Some(last) = self.last_ts_recv {
+            if ts_recv < last {
+                self.report.non_monotonic_ts_recv += 1;
+                self.issue(
+                    position,
+                    "non_monotonic_ts_recv",
+                    format!("ts_recv {} is before the previous {}", ts_recv, last),
+                );
+            }
+        }
+        self.last_ts_recv = Some(ts_recv);
+    }
+
+    fn check_sequence(&mut self, position: u64, instrument_id: u32, sequence: u32) {
+        if let Some(last) = self.last_sequence.insert(instrument_id, sequence) {
+            if sequence > last.wrapping_add(1) {
+                self.report.sequence_gaps += 1;
+                self.issue(
+                    position,
+                    "sequence_gap",
+                    format!(
+                        "Sequence jumps from {} to {} for instrument {}",
+                        last, sequence, instrument_id
+                    ),
+                );
+            }
+        }
+    }
+
+    fn check_book(&mut self, position: u64, level: &BidAskPair) {
+        // An empty side has no price to compare
+        if level.bid_px <= 0 || level.ask_px <= 0 {
+            return;
+        }
+        if level.bid_px > level.ask_px {
+            self.report.crossed_books += 1;
+            self.issue(
+                position,
+                "crossed_book",
+                format!("Bid {} is above ask {}", level.bid_px, level.ask_px),
+            );
+        } else if level.bid_px == level.ask_px {
+            self.report.locked_books += 1;
+            self.issue(
+                position,
+                "locked_book",
+                format!("Bid and ask are both {}", level.bid_px),
+            );
+        }
+    }
+
+    fn check_price_size(&mut self, position: u64, prices: &[i64], size: Option<u64>) {
+        if let Some(price) = prices.iter().find(|price| **price <= 0) {
+            self.report.invalid_prices += 1;
+            self.issue(position, "invalid_price", format!("Price {}", price));
+        }
+        if size == Some(0) {
+            self.report.invalid_sizes += 1;
+            self.issue(position, "invalid_size", "Size 0".to_string());
+        }
+    }
+
+    pub async fn check_record(&mut self, record: RecordEnum) -> Result<()> {
+        let position = self.report.records_checked;
+        self.report.records_checked += 1;
+
+        match record {
+            RecordEnum::Mbp1(msg) | RecordEnum::Tbbo(msg) => {
+                self.check_instrument(position, msg.hd.instrument_id);
+                self.check_ts_recv(position, msg.ts_recv);
+                self.check_sequence(position, msg.hd.instrument_id, msg.sequence);
+                self.check_book(position, &msg.levels[0]);
+                self.check_price_size(position, &[msg.price], Some(msg.size as u64));
+                self.pending_mbp.push((position, msg));
+            }
+            RecordEnum::Trade(msg) => {
+                self.check_instrument(position, msg.hd.instrument_id);
+                self.check_ts_recv(position, msg.ts_recv);
+                self.check_sequence(position, msg.hd.instrument_id, msg.sequence);
+                self.check_price_size(position, &[msg.price], Some(msg.size as u64));
+            }
+            RecordEnum::Bbo(msg) => {
+                self.check_instrument(position, msg.hd.instrument_id);
+                self.check_ts_recv(position, msg.ts_recv);
+                self.check_book(position, &msg.levels[0]);
+            }
+            RecordEnum::Ohlcv(msg) => {
+                self.check_instrument(position, msg.hd.instrument_id);
+                self.check_price_size(position, &[msg.open, msg.high, msg.low, msg.close], None);
+            }
+        }
+
+        if self.pending_mbp.len() >= self.batch_size {
+            self.check_duplicates().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Looks up the pending mbp records in the database.
+    async fn check_duplicates(&mut self) -> Result<()> {
+        let pending = std::mem::take(&mut self.pending_mbp);
+        let records: Vec<Mbp1Msg> = pending.iter().map(|(_, msg)| *msg).collect();
+
+        for idx in existing_mbp(&self.pool, &records).await? {
+            self.report.existing_duplicates += 1;
+            self.issue(
+                pending[idx].0,
+                "existing_duplicate",
+                "Record already exists in mbp".to_string(),
+            );
+        }
+
+        Ok(())
+    }
+
+    pub async fn validate_records<S>(
+        mut self,
+        decoder: S,
+    ) -> Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>
+    where
+        S: RecordSource,
+    {
+        let v_stream = stream! {
+            let mut records = spawn_decoder(decoder);
+
+            while let Some(record_result) = records.recv().await {
+                let result = match record_result {
+                    Ok(record) => self.check_record(record).await,
+                    Err(e) => Err(e),
+                };
+
+                if let Err(e) = result {
+                    error!("Error validating record: {:?}", e);
+                    yield Ok(e.bytes());
+                    return;
+                }
+
+                if self.report.records_checked.is_multiple_of(self.batch_size as u64) {
+                    let response = ApiResponse::new(
+                        "success",
+                        &format!("Validated {} records.", self.report.records_checked),
+                        StatusCode::OK,
+                        "".to_string(),
+                    );
+                    yield Ok(response.bytes());
+                }
+            }
+
+            if let Err(e) = self.check_duplicates().await {
+                error!("Error validating record: {:?}", e);
+                yield Ok(e.bytes());
+                return;
+            }
+
+            info!("Validated {} records", self.report.records_checked);
+            let response = ApiResponse::new(
+                "success",
+                &format!(
+                    "Validation finished: {} records, {} issues.",
+                    self.report.records_checked,
+                    self.report.issue_count()
+                ),
+                StatusCode::OK,
+                self.report,
+            );
+            yield Ok(response.bytes());
+        };
+
+        Box::pin(v_stream)
+    }
+}
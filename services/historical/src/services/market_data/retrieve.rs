@@ -7,27 +7,36 @@ use sqlx::PgPool;
 use std::sync::Arc;
 use tracing::info;
 
+/// Response header with the bar or sampling interval of the records in nanoseconds, which the
+/// metadata schema only names for the intervals that have a schema of their own.
+pub const INTERVAL_HEADER: &str = "x-interval-ns";
+
 pub async fn get_records(
     Extension(pool): Extension<PgPool>,
     Json(params): Json<RetrieveParams>,
 ) -> Result<impl IntoResponse> {
     info!("Handling request to get records.");
+    let interval_ns = params.schema_interval()?;
 
     // Initialize the loader
     let loader = Arc::new(RecordGetter::new(1000, params, pool).await?);
     let progress_stream = loader.stream().await;
 
-    Ok(StreamBody::new(progress_stream))
+    Ok((
+        [(INTERVAL_HEADER, interval_ns.to_string())],
+        StreamBody::new(progress_stream),
+    ))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::database::init::init_db;
-    use crate::database::market_data::read::RetrieveParams;
+    use crate::database::market_data::read::{Interval, RetrieveParams};
     use crate::database::symbols::InstrumentsQueries;
     use crate::response::ApiResponse;
-    use crate::services::market_data::load::create_record;
+    use crate::services::market_data::load::{create_record, LoadParams};
+    use axum::extract::Query;
     use axum::response::IntoResponse;
     use axum::{Extension, Json};
     use hyper::body::HttpBody as _;
@@ -125,10 +134,14 @@ mod test {
             .encode_records(&[record_ref1, record_ref2])
             .expect("Encoding failed");
 
-        let response = create_record(Extension(pool.clone()), Json(buffer))
-            .await
-            .expect("Error creating records.")
-            .into_response();
+        let response = create_record(
+            Extension(pool.clone()),
+            Query(LoadParams::default()),
+            Json(buffer),
+        )
+        .await
+        .expect("Error creating records.")
+        .into_response();
         let mut stream = response.into_body();
 
         // Collect streamed responses
@@ -156,6 +169,9 @@ mod test {
             start_ts: 1704209103644092563,
             end_ts: 1704209903644092569,
             schema: Schema::Mbp1.to_string(),
+            as_of_version: None,
+            as_of_time: None,
+            interval: None,
         };
 
         let response = get_records(Extension(pool.clone()), Json(params))
@@ -229,6 +245,9 @@ mod test {
             start_ts: 1704209103644092563,
             end_ts: 1704209903644092569,
             schema: Schema::Mbp1.to_string(),
+            as_of_version: None,
+            as_of_time: None,
+            interval: None,
         };
 
         let response = get_records(Extension(pool.clone()), Json(params))
@@ -282,6 +301,9 @@ mod test {
             start_ts: 1704209103644092563,
             end_ts: 1704209903644092569,
             schema: Schema::Mbp1.to_string(),
+            as_of_version: None,
+            as_of_time: None,
+            interval: None,
         };
 
         let response = get_records(Extension(pool.clone()), Json(params))
@@ -308,4 +330,48 @@ mod test {
 
         Ok(())
     }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_get_records_interval_header() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+
+        for (schema, interval, expected) in [
+            (
+                "ohlcv-1m",
+                Some(Interval::Duration("15m".to_string())),
+                "900000000000",
+            ),
+            ("bbo-1s", Some(Interval::Nanos(5_000_000_000)), "5000000000"),
+            ("ohlcv-1h", None, "3600000000000"),
+            ("mbp-1", None, "1"),
+        ] {
+            let params = RetrieveParams {
+                symbols: vec!["AAPL".to_string()],
+                start_ts: 1704209103644092563,
+                end_ts: 1704209903644092569,
+                schema: schema.to_string(),
+                as_of_version: None,
+                as_of_time: None,
+                interval,
+            };
+
+            // Test
+            let response = get_records(Extension(pool.clone()), Json(params))
+                .await
+                .into_response();
+
+            // Validate
+            assert_eq!(
+                response.headers().get(INTERVAL_HEADER).unwrap(),
+                expected,
+                "{}",
+                schema
+            );
+        }
+
+        Ok(())
+    }
 }
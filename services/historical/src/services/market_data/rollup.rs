@@ -0,0 +1,80 @@
+use crate::database::market_data::rollup::{rebuild_rollups, ROLLUP_INTERVALS};
+use crate::database::symbols::query_instrument_ids;
+use crate::error::Result;
+use crate::response::ApiResponse;
+use crate::Error;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tracing::{error, info};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RebuildParams {
+    /// Instruments to rebuild, every instrument when empty.
+    #[serde(default)]
+    pub symbols: Vec<String>,
+    pub start_ts: i64,
+    pub end_ts: i64,
+}
+
+// Handlers
+/// Rebuilds the rollup bars of `[start_ts, end_ts)` from the stored trades, after backfills or
+/// corrections that bypassed the loader. Each day is rebuilt in its own transaction.
+pub async fn rebuild_rollup_bars(
+    Extension(pool): Extension<PgPool>,
+    Json(params): Json<RebuildParams>,
+) -> Result<impl IntoResponse> {
+    info!("Handling request to rebuild rollup bars {:?}", params);
+
+    match rebuild(&pool, &params).await {
+        Ok(written) => Ok(ApiResponse::new(
+            "success",
+            &format!("Successfully rebuilt {} rollup bars", written),
+            StatusCode::OK,
+            written,
+        )),
+        Err(e) => {
+            error!("Failed to rebuild rollup bars: {:?}", e);
+            Err(e)
+        }
+    }
+}
+
+async fn rebuild(pool: &PgPool, params: &RebuildParams) -> Result<u64> {
+    if params.start_ts >= params.end_ts {
+        return Err(crate::error!(
+            CustomError,
+            "start_ts must be before end_ts."
+        ));
+    }
+
+    let instrument_ids = query_instrument_ids(pool).await?;
+    let ids: Vec<i32> = if params.symbols.is_empty() {
+        instrument_ids.values().map(|id| *id as i32).collect()
+    } else {
+        params
+            .symbols
+            .iter()
+            .map(|symbol| {
+                instrument_ids
+                    .get(symbol)
+                    .map(|id| *id as i32)
+                    .ok_or_else(|| crate::error!(CustomError, "Unknown symbol: {}", symbol))
+            })
+            .collect::<Result<_>>()?
+    };
+
+    let day = ROLLUP_INTERVALS[ROLLUP_INTERVALS.len() - 1];
+    let mut written = 0;
+    let mut start = params.start_ts - params.start_ts.rem_euclid(day);
+    while start < params.end_ts {
+        let mut tx = pool.begin().await?;
+        written += rebuild_rollups(&mut tx, &ids, start, start + day).await?;
+        tx.commit().await?;
+        start += day;
+    }
+
+    Ok(written)
+}
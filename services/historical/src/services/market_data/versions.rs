@@ -0,0 +1,27 @@
+use crate::database::versions::DataVersion;
+use crate::error::Result;
+use crate::response::ApiResponse;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Extension;
+use sqlx::PgPool;
+use tracing::{error, info};
+
+// Handlers
+/// Lists the dataset versions, newest first, for `as_of_version` retrievals.
+pub async fn list_versions(Extension(pool): Extension<PgPool>) -> Result<impl IntoResponse> {
+    info!("Handling request to list dataset versions");
+
+    match DataVersion::list(&pool).await {
+        Ok(versions) => Ok(ApiResponse::new(
+            "success",
+            "Successfully retrieved list of dataset versions.",
+            StatusCode::OK,
+            versions,
+        )),
+        Err(e) => {
+            error!("Failed to retrieve dataset version list: {:?}", e);
+            Err(e)
+        }
+    }
+}
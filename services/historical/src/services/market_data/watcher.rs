@@ -0,0 +1,259 @@
+use crate::database::jobs::{IngestJob, JobStatus};
+use crate::database::market_data::create::IngestMode;
+use crate::services::market_data::jobs::run_job;
+use crate::services::market_data::load::LoadParams;
+use crate::{Error, Result};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{error, info};
+
+/// Drop folder ingestion, enabled by setting `HISTORICAL_WATCH_DIR`.
+#[derive(Debug, Clone)]
+pub struct WatcherConfig {
+    pub directory: PathBuf,
+    /// How often the folder is scanned, `HISTORICAL_WATCH_INTERVAL_SECS`.
+    pub poll_interval: Duration,
+    /// How long a file has to stay unchanged before it is loaded, `HISTORICAL_WATCH_STABLE_SECS`.
+    pub stable_for: Duration,
+    /// Load options, with the mode from `HISTORICAL_WATCH_MODE`.
+    pub params: LoadParams,
+}
+
+impl WatcherConfig {
+    pub fn from_env() -> Result<Option<Self>> {
+        let directory = match env::var("HISTORICAL_WATCH_DIR") {
+            Ok(directory) if !directory.is_empty() => PathBuf::from(directory),
+            _ => return Ok(None),
+        };
+
+        let seconds = |name: &str, default: u64| -> Result<Duration> {
+            match env::var(name) {
+                Ok(value) => value
+                    .parse()
+                    .map(Duration::from_secs)
+                    .map_err(|_| crate::error!(CustomError, "{} is not a valid integer.", name)),
+                Err(_) => Ok(Duration::from_secs(default)),
+            }
+        };
+        let mode = match env::var("HISTORICAL_WATCH_MODE") {
+            Ok(mode) => IngestMode::from_str(&mode)?,
+            Err(_) => IngestMode::default(),
+        };
+
+        Ok(Some(WatcherConfig {
+            directory,
+            poll_interval: seconds("HISTORICAL_WATCH_INTERVAL_SECS", 30)?,
+            stable_for: seconds("HISTORICAL_WATCH_STABLE_SECS", 60)?,
+            params: LoadParams {
+                mode,
+                ..Default::default()
+            },
+        }))
+    }
+}
+
+/// Size and modification time of a file when it was last seen changing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileState {
+    len: u64,
+    modified: Option<SystemTime>,
+    since: Instant,
+}
+
+/// Tracks files across scans until they stop changing.
+#[derive(Debug, Default)]
+struct StableFiles {
+    files: HashMap<PathBuf, FileState>,
+}
+
+impl StableFiles {
+    /// Records the file's current state and returns whether it has not changed for `stable_for`.
+    fn observe(
+        &mut self,
+        path: &Path,
+        len: u64,
+        modified: Option<SystemTime>,
+        now: Instant,
+        stable_for: Duration,
+    ) -> bool {
+        let state = self.files.entry(path.to_path_buf()).or_insert(FileState {
+            len,
+            modified,
+            since: now,
+        });
+        if state.len != len || state.modified != modified {
+            *state = FileState {
+                len,
+                modified,
+                since: now,
+            };
+        }
+
+        now.duration_since(state.since) >= stable_for
+    }
+
+    fn forget(&mut self, path: &Path) {
+        self.files.remove(path);
+    }
+
+    /// Drops files that are no longer in the folder.
+    fn retain(&mut self, present: &[PathBuf]) {
+        self.files.retain(|path, _| present.contains(path));
+    }
+}
+
+/// Scans the drop folder until the service stops. Stable files are loaded one at a time as
+/// ingest jobs, then moved to `done/` or `failed/` depending on the job's outcome.
+pub async fn watch_folder(pool: PgPool, config: WatcherConfig) {
+    info!(
+        "Watching {} for new files every {:?}",
+        config.directory.display(),
+        config.poll_interval
+    );
+
+    let mut stable = StableFiles::default();
+    let mut ticker = tokio::time::interval(config.poll_interval);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = scan_folder(&pool, &config, &mut stable).await {
+            error!("Error scanning {}: {:?}", config.directory.display(), e);
+        }
+    }
+}
+
+async fn scan_folder(
+    pool: &PgPool,
+    config: &WatcherConfig,
+    stable: &mut StableFiles,
+) -> Result<()> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(&config.directory)? {
+        let entry = entry?;
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        if entry.file_type()?.is_file() && !hidden {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    stable.retain(&files);
+
+    for path in files {
+        let metadata = std::fs::metadata(&path)?;
+        let now = Instant::now();
+        if !stable.observe(
+            &path,
+            metadata.len(),
+            metadata.modified().ok(),
+            now,
+            config.stable_for,
+        ) {
+            continue;
+        }
+
+        ingest_file(pool, config, &path).await?;
+        stable.forget(&path);
+    }
+
+    Ok(())
+}
+
+async fn ingest_file(pool: &PgPool, config: &WatcherConfig, path: &Path) -> Result<()> {
+    info!("Ingesting dropped file {}", path.display());
+
+    let source = path.display().to_string();
+    let job_id = IngestJob::create(pool, &source, config.params.mode).await?;
+    run_job(
+        pool.clone(),
+        job_id,
+        path.to_path_buf(),
+        config.params.clone(),
+    )
+    .await;
+
+    let status = IngestJob::get(pool, job_id)
+        .await?
+        .map(|job| job.status)
+        .unwrap_or(JobStatus::Failed);
+    let folder = if status == JobStatus::Completed {
+        "done"
+    } else {
+        "failed"
+    };
+    let target = move_file(path, &config.directory.join(folder), job_id)?;
+    info!(
+        "Ingest job {} for {} finished as {}, moved to {}",
+        job_id,
+        source,
+        status.as_str(),
+        target.display()
+    );
+
+    Ok(())
+}
+
+/// Moves `path` into `folder`, prefixing the job id if a file of that name is already there.
+fn move_file(path: &Path, folder: &Path, job_id: i32) -> Result<PathBuf> {
+    std::fs::create_dir_all(folder)?;
+
+    let name = path
+        .file_name()
+        .ok_or_else(|| crate::error!(CustomError, "Invalid file name: {}", path.display()))?;
+    let mut target = folder.join(name);
+    if target.exists() {
+        target = folder.join(format!("{}-{}", job_id, name.to_string_lossy()));
+    }
+    std::fs::rename(path, &target)?;
+
+    Ok(target)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stable_files() {
+        let mut stable = StableFiles::default();
+        let path = PathBuf::from("data/test.bin");
+        let stable_for = Duration::from_secs(60);
+        let start = Instant::now();
+
+        // Test
+        let first = stable.observe(&path, 10, None, start, stable_for);
+        let grown = stable.observe(&path, 20, None, start + stable_for, stable_for);
+        let waiting = stable.observe(&path, 20, None, start + stable_for * 3 / 2, stable_for);
+        let done = stable.observe(&path, 20, None, start + stable_for * 2, stable_for);
+
+        // Validate
+        assert!(!first);
+        assert!(!grown);
+        assert!(!waiting);
+        assert!(done);
+    }
+
+    #[test]
+    fn test_move_file() -> anyhow::Result<()> {
+        let folder = PathBuf::from("tests/data/test_move_file");
+        let path = PathBuf::from("tests/data/test_move_file.bin");
+        std::fs::write(&path, b"records")?;
+        std::fs::create_dir_all(&folder)?;
+        std::fs::write(folder.join("test_move_file.bin"), b"earlier")?;
+
+        // Test
+        let target = move_file(&path, &folder, 7)?;
+
+        // Validate
+        assert_eq!(target, folder.join("7-test_move_file.bin"));
+        assert!(!path.exists());
+        assert_eq!(std::fs::read(&target)?, b"records");
+
+        // Cleanup
+        std::fs::remove_dir_all(&folder)?;
+
+        Ok(())
+    }
+}
@@ -1,3 +1,6 @@
+pub mod admin;
 pub mod market_data;
+pub mod retention;
+pub mod stats;
 pub mod symbols;
 pub mod utils;
@@ -0,0 +1,658 @@
+use crate::database::market_data::create::{compute_dedup_key, compute_mbp10_dedup_key};
+use crate::database::market_data::mbp10::Mbp10Msg;
+use crate::database::market_data::read::FromRow;
+use crate::database::partitions::{civil_from_days, PartitionInterval, NANOS_PER_DAY};
+use crate::database::retention::{
+    archive_query, delete_range, oldest_expired, ArchiveDigest, ArchiveStatus, RetentionArchive,
+    RetentionPolicy, RetentionPolicyParams, RetentionStatus,
+};
+use crate::database::symbols::refresh_availability;
+use crate::error::Result;
+use crate::response::ApiResponse;
+use crate::services::market_data::record_source::decompress;
+use crate::Error;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::{delete, get, post};
+use axum::{Extension, Json, Router};
+use futures::stream::StreamExt;
+use mbn::decode::Decoder;
+use mbn::encode::{MetadataEncoder, RecordEncoder};
+use mbn::enums::Schema;
+use mbn::metadata::Metadata;
+use mbn::record_enum::RecordEnum;
+use mbn::records::Mbp1Msg;
+use mbn::symbols::SymbolMap;
+use sqlx::{PgPool, Row};
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tracing::{error, info};
+
+/// Records handed to the archive writer at a time.
+const ARCHIVE_CHUNK: usize = 10_000;
+
+/// Archival of expired mbp data, enabled by setting `HISTORICAL_ARCHIVE_DIR`.
+#[derive(Debug, Clone)]
+pub struct RetentionConfig {
+    pub archive_dir: PathBuf,
+    /// How often policies are enforced, `HISTORICAL_RETENTION_INTERVAL_SECS`.
+    pub interval: Duration,
+}
+
+impl RetentionConfig {
+    pub fn from_env() -> Result<Option<Self>> {
+        let archive_dir = match env::var("HISTORICAL_ARCHIVE_DIR") {
+            Ok(directory) if !directory.is_empty() => PathBuf::from(directory),
+            _ => return Ok(None),
+        };
+        let interval = match env::var("HISTORICAL_RETENTION_INTERVAL_SECS") {
+            Ok(value) => value.parse().map(Duration::from_secs).map_err(|_| {
+                crate::error!(
+                    CustomError,
+                    "HISTORICAL_RETENTION_INTERVAL_SECS is not a valid integer."
+                )
+            })?,
+            Err(_) => Duration::from_secs(3600),
+        };
+
+        Ok(Some(RetentionConfig {
+            archive_dir,
+            interval,
+        }))
+    }
+}
+
+// Service
+pub fn retention_service() -> Router {
+    Router::new()
+        .route("/policies/set", post(set_policy))
+        .route("/policies/list", get(list_policies))
+        .route("/policies/delete", delete(delete_policy))
+        .route("/status", get(retention_status))
+        .route("/archives/list", get(list_archives))
+}
+
+// Handlers
+/// Sets the months of data kept for an instrument or a dataset.
+pub async fn set_policy(
+    Extension(pool): Extension<PgPool>,
+    Json(params): Json<RetentionPolicyParams>,
+) -> Result<impl IntoResponse> {
+    info!("Handling request to set retention policy {:?}", params);
+
+    match RetentionPolicy::set(&pool, &params).await {
+        Ok(policy) => Ok(ApiResponse::new(
+            "success",
+            &format!("Successfully set retention policy {}", policy.id),
+            StatusCode::OK,
+            policy,
+        )),
+        Err(e) => {
+            error!("Failed to set retention policy: {:?}", e);
+            Err(e)
+        }
+    }
+}
+
+pub async fn list_policies(Extension(pool): Extension<PgPool>) -> Result<impl IntoResponse> {
+    info!("Handling request to list retention policies");
+
+    match RetentionPolicy::list(&pool).await {
+        Ok(policies) => Ok(ApiResponse::new(
+            "success",
+            &format!("Found {} retention policies", policies.len()),
+            StatusCode::OK,
+            policies,
+        )),
+        Err(e) => {
+            error!("Failed to list retention policies: {:?}", e);
+            Err(e)
+        }
+    }
+}
+
+pub async fn delete_policy(
+    Extension(pool): Extension<PgPool>,
+    Json(id): Json<i32>,
+) -> Result<impl IntoResponse> {
+    info!("Handling request to delete retention policy {}", id);
+
+    match RetentionPolicy::delete(&pool, id).await {
+        Ok(true) => Ok(ApiResponse::new(
+            "success",
+            &format!("Successfully deleted retention policy {}", id),
+            StatusCode::OK,
+            "".to_string(),
+        )),
+        Ok(false) => Ok(ApiResponse::new(
+            "failed",
+            &format!("Retention policy {} not found", id),
+            StatusCode::NOT_FOUND,
+            "".to_string(),
+        )),
+        Err(e) => {
+            error!("Failed to delete retention policy: {:?}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Retention state of every instrument covered by a policy.
+pub async fn retention_status(Extension(pool): Extension<PgPool>) -> Result<impl IntoResponse> {
+    info!("Handling request to get retention status");
+
+    match RetentionStatus::list(&pool, now()).await {
+        Ok(statuses) => Ok(ApiResponse::new(
+            "success",
+            &format!(
+                "Found {} instruments under retention, {} with expired data",
+                statuses.len(),
+                statuses.iter().filter(|status| status.expired()).count()
+            ),
+            StatusCode::OK,
+            statuses,
+        )),
+        Err(e) => {
+            error!("Failed to get retention status: {:?}", e);
+            Err(e)
+        }
+    }
+}
+
+pub async fn list_archives(Extension(pool): Extension<PgPool>) -> Result<impl IntoResponse> {
+    info!("Handling request to list retention archives");
+
+    match RetentionArchive::list(&pool, None).await {
+        Ok(archives) => Ok(ApiResponse::new(
+            "success",
+            &format!("Found {} archives", archives.len()),
+            StatusCode::OK,
+            archives,
+        )),
+        Err(e) => {
+            error!("Failed to list retention archives: {:?}", e);
+            Err(e)
+        }
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as i64
+}
+
+/// Archives expired data every `config.interval` until the service stops.
+pub async fn enforce_retention(pool: PgPool, config: RetentionConfig) {
+    info!(
+        "Archiving expired data to {} every {:?}",
+        config.archive_dir.display(),
+        config.interval
+    );
+
+    let mut ticker = tokio::time::interval(config.interval);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = enforce_once(&pool, &config.archive_dir, now()).await {
+            error!("Error enforcing retention policies: {:?}", e);
+        }
+    }
+}
+
+/// Archives the expired data of every instrument under a policy. An instrument whose archive
+/// fails is retried on the next run, the others carry on.
+pub async fn enforce_once(pool: &PgPool, archive_dir: &Path, now: i64) -> Result<()> {
+    for target in RetentionStatus::list(pool, now).await? {
+        if !target.expired() {
+            continue;
+        }
+        if let Err(e) = archive_expired(pool, archive_dir, &target).await {
+            error!("Error archiving {}: {:?}", target.ticker, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Archives the instrument's expired rows one calendar month at a time, oldest first.
+async fn archive_expired(
+    pool: &PgPool,
+    archive_dir: &Path,
+    target: &RetentionStatus,
+) -> Result<()> {
+    while let Some(oldest) = oldest_expired(pool, target.instrument_id, target.cutoff).await? {
+        let (start, end) = PartitionInterval::Month.bounds(oldest);
+        archive_range(pool, archive_dir, target, start, end.min(target.cutoff)).await?;
+    }
+
+    Ok(())
+}
+
+/// Exports the instrument's rows in `[start, end)`, verifies the file and deletes the rows.
+/// Returns the number of archived records. Failures are recorded on the archive.
+pub async fn archive_range(
+    pool: &PgPool,
+    archive_dir: &Path,
+    target: &RetentionStatus,
+    start: i64,
+    end: i64,
+) -> Result<i64> {
+    let files = ArchiveFiles::new(archive_path(archive_dir, &target.ticker, start)?);
+    let id = RetentionArchive::create(pool, target, start, end, &files.records.to_string_lossy())
+        .await?;
+    info!(
+        "Archiving {} from {} to {} into {}",
+        target.ticker,
+        start,
+        end,
+        files.records.display()
+    );
+
+    match export_and_delete(pool, target, start, end, &files, id).await {
+        Ok(records) => {
+            info!("Archived {} records of {}", records, target.ticker);
+            Ok(records)
+        }
+        Err(e) => {
+            let _ = std::fs::remove_file(partial(&files.records));
+            let _ = std::fs::remove_file(partial(&files.levels));
+            RetentionArchive::update(pool, id, ArchiveStatus::Failed, 0, Some(&e.to_string()))
+                .await?;
+            Err(e)
+        }
+    }
+}
+
+async fn export_and_delete(
+    pool: &PgPool,
+    target: &RetentionStatus,
+    start: i64,
+    end: i64,
+    files: &ArchiveFiles,
+    id: i32,
+) -> Result<i64> {
+    // Export, records are encoded on a blocking thread as they are read
+    let mut symbol_map = SymbolMap::new();
+    symbol_map.add_instrument(&target.ticker, target.instrument_id as u32);
+    let metadata = Metadata::new(Schema::Mbp1, start as u64, end as u64, symbol_map);
+    let (sender, receiver) = mpsc::channel(2);
+    let writer_files = files.clone();
+    let writer =
+        tokio::task::spawn_blocking(move || write_archive(&writer_files, &metadata, receiver));
+
+    let mut exported = ArchiveDigest::default();
+    let mut chunk = ArchiveChunk::default();
+    let mut rows = archive_query(pool, target.instrument_id, start, end);
+    while let Some(row) = rows.next().await {
+        let row = row?;
+        let record = Mbp10Msg::from_row(&row)?;
+        let top = record.top_of_book(row.try_get::<i32, _>("discriminator")? as u32);
+        // Rows stored with more than the top level are mbp-10 ones, keyed over all of them
+        if row.try_get::<i32, _>("depth_count")? > 1 {
+            exported.add(compute_mbp10_dedup_key(&record));
+            chunk.levels.push(record);
+        } else {
+            exported.add(compute_dedup_key(&top));
+        }
+        chunk.records.push(top);
+        if chunk.records.len() == ARCHIVE_CHUNK
+            && sender.send(std::mem::take(&mut chunk)).await.is_err()
+        {
+            break; // Writer failed, its error is returned below
+        }
+    }
+    drop(rows);
+    if !chunk.records.is_empty() {
+        let _ = sender.send(chunk).await;
+    }
+    drop(sender);
+    let levels_written = writer
+        .await
+        .map_err(|e| crate::error!(CustomError, "Archive writer failed: {}", e))??;
+
+    // Verify
+    let verify_files = files.clone();
+    let archived =
+        tokio::task::spawn_blocking(move || verify_archive(&verify_files, levels_written))
+            .await
+            .map_err(|e| crate::error!(CustomError, "Archive verification failed: {}", e))??;
+    if archived != exported {
+        return Err(crate::error!(
+            CustomError,
+            "Archive holds {} records that do not match the {} exported.",
+            archived.records,
+            exported.records
+        ));
+    }
+    std::fs::rename(partial(&files.records), &files.records)?;
+    if levels_written {
+        std::fs::rename(partial(&files.levels), &files.levels)?;
+        RetentionArchive::set_levels_path(pool, id, &files.levels.to_string_lossy()).await?;
+    }
+    let records = exported.records as i64;
+    RetentionArchive::update(pool, id, ArchiveStatus::Verified, records, None).await?;
+
+    // Delete, unless rows arrived in the range since the export
+    let mut tx = pool.begin().await?;
+    let deleted = delete_range(&mut tx, target.instrument_id, start, end).await?;
+    if deleted != exported {
+        let _ = tx.rollback().await;
+        return Err(crate::error!(
+            CustomError,
+            "Archived {} records but {} different ones were in the range, rows were kept.",
+            exported.records,
+            deleted.records
+        ));
+    }
+    refresh_availability(&mut tx, Some(&[target.instrument_id])).await?;
+    RetentionArchive::update(&mut tx, id, ArchiveStatus::Deleted, records, None).await?;
+    tx.commit().await?;
+
+    Ok(records)
+}
+
+/// `<archive_dir>/<ticker>/<ticker>_mbp-1_<YYYYMMDD>.mbn.zst`, numbered when a range is archived
+/// more than once.
+fn archive_path(archive_dir: &Path, ticker: &str, start: i64) -> Result<PathBuf> {
+    let ticker: String = ticker
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let directory = archive_dir.join(&ticker);
+    std::fs::create_dir_all(&directory)?;
+
+    let date = partition_date(start);
+    let mut path = directory.join(format!("{}_mbp-1_{}.mbn.zst", ticker, date));
+    let mut copy = 1;
+    while path.exists() {
+        path = directory.join(format!("{}_mbp-1_{}_{}.mbn.zst", ticker, date, copy));
+        copy += 1;
+    }
+
+    Ok(path)
+}
+
+/// The mbn file of an archive and the JSON lines file of its mbp-10 levels,
+/// `<name>.mbp-10.jsonl.zst` next to it.
+#[derive(Debug, Clone)]
+struct ArchiveFiles {
+    records: PathBuf,
+    levels: PathBuf,
+}
+
+impl ArchiveFiles {
+    fn new(records: PathBuf) -> Self {
+        let name = records.to_string_lossy();
+        let levels = PathBuf::from(format!(
+            "{}.mbp-10.jsonl.zst",
+            name.strip_suffix(".mbn.zst").unwrap_or(&name)
+        ));
+        ArchiveFiles { records, levels }
+    }
+}
+
+/// Where a file is written until the archive is verified.
+fn partial(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.tmp", path.display()))
+}
+
+/// Records handed to the archive writer, with the mbp-10 rows among them.
+#[derive(Debug, Default)]
+struct ArchiveChunk {
+    records: Vec<Mbp1Msg>,
+    levels: Vec<Mbp10Msg>,
+}
+
+/// `YYYYMMDD` of the UTC day holding `ts`.
+fn partition_date(ts: i64) -> String {
+    let (year, month, day) = civil_from_days(ts.div_euclid(NANOS_PER_DAY));
+    format!("{:04}{:02}{:02}", year, month, day)
+}
+
+/// Writes the metadata and the received records to a zstd compressed mbn file, and the mbp-10
+/// rows to the levels file when there are any, synced to disk before the rows can be deleted.
+/// Returns whether the levels file was written.
+fn write_archive(
+    files: &ArchiveFiles,
+    metadata: &Metadata,
+    mut receiver: mpsc::Receiver<ArchiveChunk>,
+) -> Result<bool> {
+    let mut writer = zstd::Encoder::new(BufWriter::new(File::create(partial(&files.records))?), 0)?;
+    MetadataEncoder::new(&mut writer).encode_metadata(metadata)?;
+    let mut levels_writer = None;
+
+    {
+        let mut encoder = RecordEncoder::new(&mut writer);
+        while let Some(chunk) = receiver.blocking_recv() {
+            for record in &chunk.records {
+                encoder.encode_record(&record.into())?;
+            }
+            if chunk.levels.is_empty() {
+                continue;
+            }
+            let levels = match &mut levels_writer {
+                Some(levels) => levels,
+                None => levels_writer.insert(zstd::Encoder::new(
+                    BufWriter::new(File::create(partial(&files.levels))?),
+                    0,
+                )?),
+            };
+            for record in &chunk.levels {
+                serde_json::to_writer(&mut *levels, record)
+                    .map_err(|e| Error::GeneralError(Box::new(e)))?;
+                levels.write_all(b"\n")?;
+            }
+        }
+    }
+
+    sync(writer)?;
+    let levels_written = levels_writer.is_some();
+    if let Some(levels) = levels_writer {
+        sync(levels)?;
+    }
+    Ok(levels_written)
+}
+
+fn sync(writer: zstd::Encoder<'static, BufWriter<File>>) -> Result<()> {
+    let mut file = writer.finish()?;
+    file.flush()?;
+    file.get_ref().sync_all()?;
+    Ok(())
+}
+
+/// Decodes the archive into the digest of its records. The mbp-10 rows of the levels file take
+/// the place of their top level in the mbn file.
+fn verify_archive(files: &ArchiveFiles, levels_written: bool) -> Result<ArchiveDigest> {
+    let mut decoder = Decoder::new(decompress(BufReader::new(File::open(partial(
+        &files.records,
+    ))?))?)?;
+
+    let mut digest = ArchiveDigest::default();
+    for record in decoder.decode_iterator() {
+        match record? {
+            RecordEnum::Mbp1(msg) => digest.add(compute_dedup_key(&msg)),
+            _ => {
+                return Err(crate::error!(
+                    CustomError,
+                    "Archive holds a record that is not mbp-1."
+                ))
+            }
+        }
+    }
+
+    if levels_written {
+        let file = File::open(partial(&files.levels))?;
+        for line in BufReader::new(decompress(BufReader::new(file))?).lines() {
+            let record: Mbp10Msg =
+                serde_json::from_str(&line?).map_err(|e| Error::GeneralError(Box::new(e)))?;
+            digest.replace(
+                compute_dedup_key(&record.top_of_book(0)),
+                compute_mbp10_dedup_key(&record),
+            );
+        }
+    }
+
+    Ok(digest)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::init::init_db;
+    use crate::database::market_data::create::{IngestMode, InsertBatch};
+    use crate::database::market_data::mbp10::{BidAskLevel, Mbp10Msg, MBP10_DEPTH};
+    use crate::test_utils::{create_instrument, delete_instrument, mbp1};
+    use mbn::enums::Action;
+    use serial_test::serial;
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_enforce_retention() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let id = create_instrument(&pool, "AAPL").await?;
+        let archive_dir = PathBuf::from("tests/data/retention");
+        // 2024-01-02 and 2024-02-02
+        let mut tx = pool.begin().await?;
+        let mut batch = InsertBatch::new(IngestMode::Strict, None);
+        batch
+            .process(&mbp1(id, 1704209103644092564, 6770, Action::Add))
+            .await?;
+        batch
+            .process(&mbp1(id, 1706887503644092564, 6770, Action::Add))
+            .await?;
+        batch.execute(&mut tx).await?;
+        tx.commit().await?;
+        let policy = RetentionPolicy::set(
+            &pool,
+            &RetentionPolicyParams {
+                instrument_id: Some(id),
+                retention_months: 1,
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        // Test
+        enforce_once(&pool, &archive_dir, 1710460800000000000).await?; // 2024-03-15, keeps February
+
+        // Validate
+        let archives = RetentionArchive::list(&pool, Some(id)).await?;
+        assert_eq!(archives.len(), 1);
+        assert_eq!(archives[0].status, ArchiveStatus::Deleted);
+        assert_eq!(archives[0].records, 1);
+        assert!(Path::new(&archives[0].file_path).exists());
+        let statuses = RetentionStatus::list(&pool, 1710460800000000000).await?;
+        let status = statuses.iter().find(|s| s.instrument_id == id).unwrap();
+        assert_eq!(status.oldest_ts_recv, Some(1706887503644092564));
+        assert_eq!(status.archived_records, 1);
+        assert!(!status.expired());
+
+        // Cleanup
+        RetentionPolicy::delete(&pool, policy.id).await?;
+        delete_instrument(&pool, id).await?;
+        sqlx::query("DELETE FROM retention_archive WHERE instrument_id = $1")
+            .bind(id)
+            .execute(&pool)
+            .await?;
+        std::fs::remove_dir_all(&archive_dir)?;
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    #[serial]
+    // #[ignore]
+    async fn test_archive_mbp10_levels() -> anyhow::Result<()> {
+        dotenv::dotenv().ok();
+        let pool = init_db().await.unwrap();
+        let id = create_instrument(&pool, "AAPL").await?;
+        let archive_dir = PathBuf::from("tests/data/retention_levels");
+
+        // An mbp-1 row and an mbp-10 one in January
+        let top = mbp1(id, 1704209103644092564, 6770, Action::Add);
+        let mut levels = [BidAskLevel::default(); MBP10_DEPTH];
+        levels[0] = BidAskLevel {
+            bid_px: 1,
+            ask_px: 1,
+            bid_sz: 1,
+            ask_sz: 1,
+            bid_ct: 10,
+            ask_ct: 20,
+        };
+        levels[1].bid_px = 2;
+        let deep = Mbp10Msg {
+            instrument_id: id as u32,
+            ts_event: top.hd.ts_event + 1,
+            price: top.price,
+            size: top.size,
+            action: top.action,
+            side: top.side,
+            flags: top.flags,
+            ts_recv: top.ts_recv + 1,
+            ts_in_delta: top.ts_in_delta,
+            sequence: top.sequence,
+            levels,
+        };
+        let mut tx = pool.begin().await?;
+        let mut batch = InsertBatch::new(IngestMode::Strict, None);
+        batch.process(&top).await?;
+        batch.process_mbp10(&deep).await?;
+        batch.execute(&mut tx).await?;
+        tx.commit().await?;
+        let policy = RetentionPolicy::set(
+            &pool,
+            &RetentionPolicyParams {
+                instrument_id: Some(id),
+                retention_months: 1,
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        // Test
+        enforce_once(&pool, &archive_dir, 1710460800000000000).await?;
+        enforce_once(&pool, &archive_dir, 1710460800000000000).await?;
+
+        // Validate
+        let archives = RetentionArchive::list(&pool, Some(id)).await?;
+        assert_eq!(archives.len(), 1);
+        assert_eq!(archives[0].status, ArchiveStatus::Deleted);
+        assert_eq!(archives[0].records, 2);
+        let levels_path = archives[0].levels_path.clone().unwrap();
+        let reader = BufReader::new(decompress(BufReader::new(File::open(&levels_path)?))?);
+        let archived: Vec<Mbp10Msg> = reader
+            .lines()
+            .map(|line| serde_json::from_str(&line?).map_err(anyhow::Error::from))
+            .collect::<anyhow::Result<_>>()?;
+        assert_eq!(archived, vec![deep]);
+        let rows: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM mbp WHERE instrument_id = $1")
+            .bind(id)
+            .fetch_one(&pool)
+            .await?;
+        assert_eq!(rows, 0);
+
+        // Cleanup
+        RetentionPolicy::delete(&pool, policy.id).await?;
+        delete_instrument(&pool, id).await?;
+        sqlx::query("DELETE FROM retention_archive WHERE instrument_id = $1")
+            .bind(id)
+            .execute(&pool)
+            .await?;
+        std::fs::remove_dir_all(&archive_dir)?;
+
+        Ok(())
+    }
+}
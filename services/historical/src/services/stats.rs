@@ -0,0 +1,56 @@
+use crate::database::stats::{day_coverage, InstrumentStats};
+use crate::error::Result;
+use crate::response::ApiResponse;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{Extension, Json, Router};
+use sqlx::PgPool;
+use tracing::{error, info};
+
+// Service
+pub fn stats_service() -> Router {
+    Router::new()
+        .route("/", get(get_stats))
+        .route("/coverage", get(get_coverage))
+}
+
+// Handlers
+/// Row counts, ranges, days with data, approximate size and last ingest of every instrument.
+pub async fn get_stats(Extension(pool): Extension<PgPool>) -> Result<impl IntoResponse> {
+    info!("Handling request to get storage stats");
+
+    match InstrumentStats::list(&pool).await {
+        Ok(stats) => Ok(ApiResponse::new(
+            "success",
+            &format!("Found stats of {} instruments", stats.len()),
+            StatusCode::OK,
+            stats,
+        )),
+        Err(e) => {
+            error!("Failed to get storage stats: {:?}", e);
+            Err(e)
+        }
+    }
+}
+
+/// Per-day calendar of an instrument's mbp rows, days without rows have a count of 0.
+pub async fn get_coverage(
+    Extension(pool): Extension<PgPool>,
+    Json(symbol): Json<String>,
+) -> Result<impl IntoResponse> {
+    info!("Handling request to get the coverage of {}", symbol);
+
+    match day_coverage(&pool, &symbol).await {
+        Ok(days) => Ok(ApiResponse::new(
+            "success",
+            &format!("Found coverage of {} days", days.len()),
+            StatusCode::OK,
+            days,
+        )),
+        Err(e) => {
+            error!("Failed to get coverage: {:?}", e);
+            Err(e)
+        }
+    }
+}
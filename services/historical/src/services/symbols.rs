@@ -1,5 +1,5 @@
 use super::utils::start_transaction;
-use crate::database::symbols::InstrumentsQueries;
+use crate::database::symbols::{refresh_availability, InstrumentsQueries};
 use crate::error::Result;
 use crate::response::ApiResponse;
 use axum::http::StatusCode;
@@ -23,9 +23,41 @@ pub fn instrument_service() -> Router {
         .route("/vendor_list", get(vendor_list_instruments))
         .route("/update", put(update_instrument_id))
         .route("/get", get(get_instrument))
+        .route(
+            "/refresh_availability",
+            post(refresh_instrument_availability),
+        )
 }
 
 // Handlers
+/// Recomputes `first_available` and `last_available` of every instrument from the stored records.
+pub async fn refresh_instrument_availability(
+    Extension(pool): Extension<PgPool>,
+) -> Result<impl IntoResponse> {
+    info!("Handling request to refresh instrument availability");
+
+    let mut tx = start_transaction(&pool).await?;
+    match refresh_availability(&mut tx, None).await {
+        Ok(updated) => {
+            tx.commit().await?;
+            Ok(ApiResponse::new(
+                "success",
+                &format!(
+                    "Successfully refreshed availability of {} instruments",
+                    updated
+                ),
+                StatusCode::OK,
+                updated,
+            ))
+        }
+        Err(e) => {
+            error!("Failed to refresh instrument availability: {:?}", e);
+            let _ = tx.rollback().await;
+            Err(e)
+        }
+    }
+}
+
 pub async fn create_instrument(
     Extension(pool): Extension<PgPool>,
     Json(instrument): Json<Instrument>,
@@ -0,0 +1,62 @@
+//! Fixtures shared by the tests of the database and service modules.
+use crate::database::symbols::InstrumentsQueries;
+use crate::Result;
+use mbn::enums::{Action, Side};
+use mbn::records::{BidAskPair, Mbp1Msg, RecordHeader};
+use mbn::symbols::{Instrument, Vendors};
+use sqlx::PgPool;
+use std::os::raw::c_char;
+
+/// Inserts a Databento instrument for the ticker and returns its id.
+pub async fn create_instrument(pool: &PgPool, ticker: &str) -> Result<i32> {
+    let instrument = Instrument::new(
+        None,
+        ticker,
+        &format!("{} Inc.", ticker),
+        Vendors::Databento,
+        Some("continuous".to_string()),
+        Some("GLBX.MDP3".to_string()),
+        1704672000000000000,
+        1704672000000000000,
+        true,
+    );
+
+    let mut tx = pool.begin().await?;
+    let id = instrument.insert_instrument(&mut tx).await?;
+    tx.commit().await?;
+    Ok(id)
+}
+
+/// Deletes the instrument, its records cascade with it.
+pub async fn delete_instrument(pool: &PgPool, id: i32) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    Instrument::delete_instrument(&mut tx, id).await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// A one lot bid mbp-1 record at `ts` over a fixed top of book level, tests override the fields
+/// they check with struct update syntax.
+pub fn mbp1(instrument_id: i32, ts: u64, price: i64, action: Action) -> Mbp1Msg {
+    Mbp1Msg {
+        hd: RecordHeader::new::<Mbp1Msg>(instrument_id as u32, ts),
+        price,
+        size: 1,
+        action: action as c_char,
+        side: Side::Bid as c_char,
+        depth: 0,
+        flags: 0,
+        ts_recv: ts,
+        ts_in_delta: 17493,
+        sequence: 739763,
+        discriminator: 0,
+        levels: [BidAskPair {
+            bid_px: 1,
+            ask_px: 1,
+            bid_sz: 1,
+            ask_sz: 1,
+            bid_ct: 10,
+            ask_ct: 20,
+        }],
+    }
+}
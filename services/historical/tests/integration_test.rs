@@ -545,6 +545,133 @@ async fn test_records_create() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+#[serial]
+#[ignore]
+async fn test_records_stream_upload() -> Result<()> {
+    // Create instrument
+    let app = create_app().await;
+    let intstrument_json = json!({
+        "ticker": "TSLA6",
+        "name": "Tesla",
+        "vendor":"databento",
+        "stype": "continuous",
+        "dataset": "GLBX.MDP3",
+        "last_available": 1704672000000000000_i64,
+        "first_available" : 1704672000000000001_i64,
+        "active" : true});
+
+    let request = Request::builder()
+        .method("POST")
+        .uri("/historical/instruments/create")
+        .header("content-type", "application/json")
+        .body(Body::from(intstrument_json.to_string()))
+        .unwrap();
+
+    let response = app.oneshot(request).await.unwrap();
+
+    let api_response: ApiResponse<i32> = parse_response(response).await.unwrap();
+    let id = api_response.data;
+
+    // Records
+    let mbp_1 = Mbp1Msg {
+        hd: { RecordHeader::new::<Mbp1Msg>(id as u32, 1704209103644092564) },
+        price: 6770,
+        size: 1,
+        action: 1,
+        side: 2,
+        depth: 0,
+        flags: 0,
+        ts_recv: 1704209103644092564,
+        ts_in_delta: 17493,
+        sequence: 739763,
+        discriminator: 0,
+        levels: [BidAskPair {
+            bid_px: 1,
+            ask_px: 1,
+            bid_sz: 1,
+            ask_sz: 1,
+            bid_ct: 10,
+            ask_ct: 20,
+        }],
+    };
+    let mbp_2 = Mbp1Msg {
+        hd: { RecordHeader::new::<Mbp1Msg>(id as u32, 1704209103644092564) },
+        price: 6870,
+        size: 2,
+        action: 1,
+        side: 1,
+        depth: 0,
+        flags: 0,
+        ts_recv: 1704209103644092564,
+        ts_in_delta: 17493,
+        sequence: 739763,
+        discriminator: 0,
+        levels: [BidAskPair {
+            bid_px: 1,
+            ask_px: 1,
+            bid_sz: 1,
+            ask_sz: 1,
+            bid_ct: 10,
+            ask_ct: 20,
+        }],
+    };
+    let record_ref1: RecordRef = (&mbp_1).into();
+    let record_ref2: RecordRef = (&mbp_2).into();
+
+    let mut buffer = Vec::new();
+    let mut encoder = RecordEncoder::new(&mut buffer);
+    encoder
+        .encode_records(&[record_ref1, record_ref2])
+        .expect("Encoding failed");
+
+    // Test
+    let request = Request::builder()
+        .method("POST")
+        .uri("/historical/mbp/stream_upload")
+        .header("content-type", "application/octet-stream")
+        .body(Body::from(buffer))
+        .unwrap();
+
+    let app = create_app().await;
+    let response = app.oneshot(request).await?;
+
+    // Validate
+    let mut body_stream = response.into_body();
+    let mut responses = Vec::new();
+    while let Some(chunk) = body_stream.data().await {
+        match chunk {
+            Ok(bytes) => {
+                let bytes_str = String::from_utf8_lossy(&bytes);
+                let api_response: ApiResponse<String> =
+                    serde_json::from_str::<ApiResponse<String>>(&bytes_str)?;
+                responses.push(api_response);
+            }
+            Err(e) => {
+                panic!("Error while reading stream: {:?}", e);
+            }
+        }
+    }
+
+    assert!(responses.iter().all(|r| r.status == "success"));
+    assert!(responses[responses.len() - 1]
+        .message
+        .contains("2 inserted, 0 skipped"));
+
+    // Cleanup
+    let request = Request::builder()
+        .method("DELETE")
+        .uri("/historical/instruments/delete")
+        .header("content-type", "application/json")
+        .body(Body::from(id.to_string()))
+        .unwrap();
+
+    let app = create_app().await;
+    let _ = app.oneshot(request).await.unwrap();
+
+    Ok(())
+}
+
 #[tokio::test]
 #[serial]
 async fn test_records_get() -> Result<()> {
@@ -663,6 +790,9 @@ async fn test_records_get() -> Result<()> {
         start_ts: 1704209103644092563,
         end_ts: 1704209903644092564,
         schema: Schema::Mbp1.to_string(),
+        as_of_version: None,
+        as_of_time: None,
+        interval: None,
     };
     let json_body = json!(params);
 
@@ -699,3 +829,28 @@ async fn test_records_get() -> Result<()> {
 
     Ok(())
 }
+
+// -- Ingest jobs --
+#[tokio::test]
+#[serial]
+async fn test_job_get_none() -> Result<()> {
+    // Test
+    let request = Request::builder()
+        .method("GET")
+        .uri("/historical/mbp/jobs/get")
+        .header("content-type", "application/json")
+        .body(Body::from(json!(i32::MAX).to_string()))
+        .unwrap();
+
+    let app = create_app().await;
+    let response = app.oneshot(request).await?;
+
+    // Validate
+    let api_response: ApiResponse<Option<serde_json::Value>> =
+        parse_response(response).await.unwrap();
+
+    assert!(api_response.data.is_none());
+    assert_eq!(api_response.code, StatusCode::NOT_FOUND);
+
+    Ok(())
+}